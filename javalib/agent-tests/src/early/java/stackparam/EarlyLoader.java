@@ -0,0 +1,19 @@
+package stackparam;
+
+import java.net.URL;
+import java.net.URLClassLoader;
+
+/**
+ * The system class loader of the unloadTest JVM. It is made before the
+ * agent's VM init, so the natives it calls here have no state to use yet.
+ * Everything else is left to the parent.
+ */
+public class EarlyLoader extends URLClassLoader {
+
+    public EarlyLoader(ClassLoader parent) {
+        super(new URL[0], parent);
+        for (StackTraceElement elem : new Exception("early").getStackTrace()) {
+            elem.toString();
+        }
+    }
+}
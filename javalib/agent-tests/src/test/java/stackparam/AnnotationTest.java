@@ -0,0 +1,141 @@
+package stackparam;
+
+import static org.junit.Assert.*;
+import org.junit.Test;
+
+import java.io.ByteArrayOutputStream;
+import java.io.InputStream;
+import java.lang.annotation.ElementType;
+import java.lang.annotation.Retention;
+import java.lang.annotation.RetentionPolicy;
+import java.lang.annotation.Target;
+import java.lang.reflect.InvocationTargetException;
+import java.nio.charset.StandardCharsets;
+
+public class AnnotationTest {
+
+    @Retention(RetentionPolicy.CLASS)
+    @Target(ElementType.PARAMETER)
+    @interface Sensitive { }
+
+    @Retention(RetentionPolicy.CLASS)
+    @Target({ ElementType.METHOD, ElementType.TYPE })
+    @interface NoStackParams { }
+
+    @Test
+    public void testSensitiveParam() throws Exception {
+        String traceStr = getTestElement(true).toString();
+        traceStr = traceStr.substring(traceStr.indexOf('['));
+        assertEquals("[this=" + this + ", user=bob, pass=<redacted>]", traceStr);
+    }
+
+    @Test
+    public void testNoStackParams() throws Exception {
+        String traceStr = getTestElement(false).toString();
+        assertEquals(-1, traceStr.indexOf('['));
+    }
+
+    @Test
+    public void testSensitiveInnerClassConstructorParam() throws Exception {
+        // The outer instance is a leading param with no annotations of its own
+        try {
+            new Inner("hunter2", 5);
+            fail();
+        } catch (RuntimeException e) {
+            String traceStr = e.getStackTrace()[0].toString();
+            assertTrue(traceStr, traceStr.contains("pw=<redacted>"));
+            assertTrue(traceStr, traceStr.contains("n=5"));
+            assertFalse(traceStr, traceStr.contains("hunter2"));
+        }
+    }
+
+    @Test
+    public void testSameClassNameInOtherLoader() throws Exception {
+        try {
+            TwinA.fail("x");
+            fail();
+        } catch (RuntimeException e) {
+            assertEquals(-1, e.getStackTrace()[0].toString().indexOf('['));
+        }
+        // TwinB's bytes renamed to TwinA, so this loader's TwinA has no annotations
+        byte[] bytes = renamed(readClassBytes(TwinB.class), "TwinB", "TwinA");
+        Class<?> otherTwin = new ClassLoader(null) {
+            Class<?> define() {
+                return defineClass(TwinA.class.getName(), bytes, 0, bytes.length);
+            }
+        }.define();
+        try {
+            otherTwin.getMethod("fail", String.class).invoke(null, "x");
+            fail();
+        } catch (InvocationTargetException e) {
+            String traceStr = e.getCause().getStackTrace()[0].toString();
+            assertTrue(traceStr, traceStr.contains("[arg=x]"));
+        }
+    }
+
+    private static byte[] readClassBytes(Class<?> cls) throws Exception {
+        String name = cls.getName();
+        try (InputStream in = cls.getResourceAsStream(name.substring(name.lastIndexOf('.') + 1) + ".class")) {
+            ByteArrayOutputStream bytes = new ByteArrayOutputStream();
+            byte[] buf = new byte[4096];
+            int read;
+            while ((read = in.read(buf)) != -1) bytes.write(buf, 0, read);
+            return bytes.toByteArray();
+        }
+    }
+
+    /** Replaces each occurrence of the ASCII string with another of the same length */
+    private static byte[] renamed(byte[] bytes, String from, String to) {
+        byte[] fromBytes = from.getBytes(StandardCharsets.US_ASCII);
+        byte[] toBytes = to.getBytes(StandardCharsets.US_ASCII);
+        byte[] ret = bytes.clone();
+        for (int i = 0; i + fromBytes.length <= ret.length; i++) {
+            boolean match = true;
+            for (int j = 0; j < fromBytes.length && match; j++) match = ret[i + j] == fromBytes[j];
+            if (match) System.arraycopy(toBytes, 0, ret, i, toBytes.length);
+        }
+        return ret;
+    }
+
+    @NoStackParams
+    public static class TwinA {
+        public static void fail(String arg) {
+            throw new RuntimeException("OH!");
+        }
+    }
+
+    public static class TwinB {
+        public static void fail(String arg) {
+            throw new RuntimeException("OH!");
+        }
+    }
+
+    private class Inner {
+        Inner(@Sensitive String pw, int n) {
+            throw new RuntimeException("OH!");
+        }
+    }
+
+    private StackTraceElement getTestElement(boolean sensitive) {
+        try {
+            if (sensitive) {
+                methodWithSensitiveParam("bob", "hunter2");
+            } else {
+                methodWithNoStackParams("bob", "hunter2");
+            }
+            fail();
+            return null;
+        } catch (RuntimeException e) {
+            return e.getStackTrace()[0];
+        }
+    }
+
+    private void methodWithSensitiveParam(String user, @Sensitive String pass) {
+        throw new RuntimeException("OH!");
+    }
+
+    @NoStackParams
+    private void methodWithNoStackParams(String user, String pass) {
+        throw new RuntimeException("OH!");
+    }
+}
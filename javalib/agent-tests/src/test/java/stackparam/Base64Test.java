@@ -0,0 +1,34 @@
+package stackparam;
+
+import static org.junit.Assert.*;
+import org.junit.Test;
+
+import java.nio.charset.StandardCharsets;
+
+/** Only run by the base64Test task, which starts the agent with base64 bytes */
+public class Base64Test {
+
+    @Test
+    public void testKnownVectors() {
+        // From RFC 4648, covering each amount of padding
+        String[][] vectors = {
+            { "", "" },
+            { "f", "Zg==" },
+            { "fo", "Zm8=" },
+            { "foo", "Zm9v" },
+            { "foob", "Zm9vYg==" },
+            { "fooba", "Zm9vYmE=" },
+            { "foobar", "Zm9vYmFy" }
+        };
+        for (String[] vector : vectors) {
+            Object[] params = { "b", "[B", vector[0].getBytes(StandardCharsets.US_ASCII) };
+            assertEquals("f [b=" + vector[1] + "]", StackParamNative.appendParamsToFrameString("f", params));
+        }
+    }
+
+    @Test
+    public void testHighBytes() {
+        Object[] params = { "b", "[B", new byte[] { (byte) 0xfb, (byte) 0xff, (byte) 0xbf } };
+        assertEquals("f [b=+/+/]", StackParamNative.appendParamsToFrameString("f", params));
+    }
+}
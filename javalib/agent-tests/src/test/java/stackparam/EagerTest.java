@@ -0,0 +1,48 @@
+package stackparam;
+
+import static org.junit.Assert.*;
+import org.junit.Test;
+
+/** Only run by the eagerTest task, which starts the agent with the "eager" option */
+public class EagerTest {
+
+    @Test
+    public void testEagerRendering() throws Exception {
+        StringBuilder builder = new StringBuilder("before");
+        try {
+            methodWithBuilder(builder);
+            fail();
+        } catch (RuntimeException e) {
+            builder.append(" and after");
+            String traceStr = e.getStackTrace()[0].toString();
+            traceStr = traceStr.substring(traceStr.indexOf('['));
+            assertEquals("[this=" + this + ", builder=before]", traceStr);
+        }
+    }
+
+    @Test
+    public void testEagerToStringThatThrows() throws Exception {
+        // Each toString fills in more throwables with this as a param, which must not be rendered eagerly again
+        Object reentrant = new Object() {
+            @Override
+            public String toString() {
+                throw new IllegalStateException(String.valueOf(new Exception("in toString").getStackTrace().length));
+            }
+        };
+        try {
+            methodWithObject(reentrant);
+            fail();
+        } catch (RuntimeException e) {
+            String traceStr = e.getStackTrace()[0].toString();
+            assertTrue(traceStr, traceStr.contains("obj=toString err: java.lang.IllegalStateException: "));
+        }
+    }
+
+    private void methodWithObject(Object obj) {
+        throw new RuntimeException("OH!");
+    }
+
+    private void methodWithBuilder(StringBuilder builder) {
+        throw new RuntimeException("OH!");
+    }
+}
@@ -0,0 +1,69 @@
+package stackparam;
+
+import static org.junit.Assert.*;
+import org.junit.Test;
+
+/**
+ * Only run by the fieldsTest task, which starts the agent with "this_fields" options: a depth of 2, at most 5 fields,
+ * and only name, config and baseId included for {@link Service}
+ */
+public class FieldsTest {
+
+    @Test
+    public void testIncludedFieldsAtDepth() throws Exception {
+        Service service = new Service();
+        try {
+            service.fail("a");
+            fail();
+        } catch (RuntimeException e) {
+            String traceStr = e.getStackTrace()[0].toString();
+            traceStr = traceStr.substring(traceStr.indexOf('['));
+            // Config has no include so all of its fields are there in place of it, the inherited field is included
+            // by the runtime class, and hidden and count are left out
+            assertEquals("[this=" + service + ", this.name=svc, this.config.url=http://foo, this.config.port=80, " +
+                    "this.config.extra=x, this.baseId=7, arg=a]", traceStr);
+        }
+    }
+
+    @Test
+    public void testMaxFields() throws Exception {
+        Wide wide = new Wide();
+        try {
+            wide.fail("a");
+            fail();
+        } catch (RuntimeException e) {
+            String traceStr = e.getStackTrace()[0].toString();
+            traceStr = traceStr.substring(traceStr.indexOf('['));
+            assertEquals("[this=" + wide + ", this.a=1, this.b=2, this.c=3, this.d=4, this.e=5, arg=a]", traceStr);
+        }
+    }
+
+    static class Config {
+        String url = "http://foo";
+        int port = 80;
+        Object extra = "x";
+    }
+
+    static class Base {
+        int baseId = 7;
+    }
+
+    static class Service extends Base {
+        String name = "svc";
+        Config config = new Config();
+        String hidden = "nope";
+        long count = 3;
+
+        void fail(String arg) {
+            throw new RuntimeException("OH!");
+        }
+    }
+
+    static class Wide {
+        int a = 1, b = 2, c = 3, d = 4, e = 5, f = 6;
+
+        void fail(String arg) {
+            throw new RuntimeException("OH!");
+        }
+    }
+}
@@ -0,0 +1,47 @@
+package stackparam;
+
+import static org.junit.Assert.*;
+import org.junit.Test;
+
+/** Only run by the localsTest task, which starts the agent with the "locals" option */
+public class LocalsTest {
+
+    @Test
+    public void testLocalsInTrace() throws Exception {
+        try {
+            methodWithLocals("p");
+            fail();
+        } catch (RuntimeException e) {
+            String traceStr = e.getStackTrace()[0].toString();
+            traceStr = traceStr.substring(traceStr.indexOf('['));
+            // The block's local is out of scope at the throw
+            assertEquals("[this=" + this + ", param=p] locals [count=5, after=here]", traceStr);
+        }
+    }
+
+    @Test
+    public void testLocalsFromLoadStackParams() throws Exception {
+        String before = "kept";
+        {
+            int gone = before.length();
+            assertEquals(4, gone);
+        }
+        Object[] params = StackParamNative.loadStackParams(Thread.currentThread(), 2)[1];
+        // Only the locals in scope at the call, after the param triplets
+        Object[] expected = {
+            "this", "Lstackparam/LocalsTest;", this,
+            new Object[] { "before", "Ljava/lang/String;", "kept" }
+        };
+        assertArrayEquals(expected, params);
+    }
+
+    private void methodWithLocals(String param) {
+        int count = 1;
+        {
+            String inBlock = "gone";
+            count += inBlock.length();
+        }
+        String after = "here";
+        throw new RuntimeException("OH!");
+    }
+}
@@ -0,0 +1,36 @@
+package stackparam;
+
+import static org.junit.Assert.*;
+import org.junit.Test;
+
+import java.util.Arrays;
+
+/** Only run by the maxRetainedSizeTest task, which starts the agent with "retention_max_size=64" */
+public class MaxRetainedSizeTest {
+
+    @Test
+    public void testOnlyLargeValuesKeptAsRendered() throws Exception {
+        // Twenty ints are well over 64 bytes, the builder itself is under
+        int[] large = new int[20];
+        StringBuilder small = new StringBuilder("before");
+        RuntimeException ex = catchFrom(large, small);
+        large[0] = 5;
+        small.append(" and after");
+        String traceStr = ex.getStackTrace()[0].toString();
+        traceStr = traceStr.substring(traceStr.indexOf('['));
+        assertEquals("[large=" + Arrays.toString(new int[20]) + ", small=before and after]", traceStr);
+    }
+
+    private static RuntimeException catchFrom(int[] large, StringBuilder small) {
+        try {
+            methodWithValues(large, small);
+        } catch (RuntimeException e) {
+            return e;
+        }
+        throw new AssertionError("Expected throw");
+    }
+
+    private static void methodWithValues(int[] large, StringBuilder small) {
+        throw new RuntimeException("OH!");
+    }
+}
@@ -0,0 +1,23 @@
+package stackparam;
+
+import static org.junit.Assert.*;
+import org.junit.Test;
+
+public class RedactTest {
+
+    @Test
+    public void testRedactedParams() throws Exception {
+        try {
+            methodWithSecrets("bob", "hunter2", new char[] { '1', '2' });
+            fail();
+        } catch (RuntimeException e) {
+            String traceStr = e.getStackTrace()[0].toString();
+            traceStr = traceStr.substring(traceStr.indexOf('['));
+            assertEquals("[this=" + this + ", user=bob, secretArg=<redacted>, pin=<redacted>]", traceStr);
+        }
+    }
+
+    private void methodWithSecrets(String user, String secretArg, char[] pin) {
+        throw new RuntimeException("OH!");
+    }
+}
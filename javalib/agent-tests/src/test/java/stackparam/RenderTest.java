@@ -0,0 +1,78 @@
+package stackparam;
+
+import static org.junit.Assert.*;
+import org.junit.Test;
+
+import java.util.ArrayList;
+import java.util.Arrays;
+import java.util.LinkedHashMap;
+import java.util.List;
+import java.util.Map;
+
+/**
+ * Only run by the renderTest task, which starts the agent with hex bytes, a render depth of 2, at most 3 elements
+ * and a trace budget of 30
+ */
+public class RenderTest {
+
+    @Test
+    public void testHexBytes() {
+        Object[] params = { "b", "[B", new byte[] { 0x01, (byte) 0xab, 0x7f } };
+        assertEquals("f [b=01ab7f]", StackParamNative.appendParamsToFrameString("f", params));
+        params = new Object[] { "b", "[B", new byte[] { 1, 2, 3, 4 } };
+        assertEquals("f [b=010203...]", StackParamNative.appendParamsToFrameString("f", params));
+    }
+
+    @Test
+    public void testDepthCutoff() {
+        Object[] params = { "o", "[Ljava/lang/Object;", new Object[] { new Object[] { new int[] { 1 } } } };
+        assertEquals("f [o=[[[...]]]]", StackParamNative.appendParamsToFrameString("f", params));
+        Map<String, Object> map = new LinkedHashMap<>();
+        map.put("m", new LinkedHashMap<>(map));
+        map.put("l", Arrays.asList(Arrays.asList(1)));
+        params = new Object[] { "m", "Ljava/util/Map;", map };
+        assertEquals("f [m={m={}, l=[[...]]}]", StackParamNative.appendParamsToFrameString("f", params));
+    }
+
+    @Test
+    public void testMaxElements() {
+        List<Integer> list = new ArrayList<>(Arrays.asList(1, 2, 3, 4, 5));
+        Map<String, Integer> map = new LinkedHashMap<>();
+        map.put("a", 1);
+        map.put("b", 2);
+        map.put("c", 3);
+        map.put("d", 4);
+        Object[] params = { "i", "[I", new int[] { 1, 2, 3, 4 } };
+        assertEquals("f [i=[1, 2, 3, ...]]", StackParamNative.appendParamsToFrameString("f", params));
+        params = new Object[] { "l", "Ljava/util/List;", list };
+        assertEquals("f [l=[1, 2, 3, ...]]", StackParamNative.appendParamsToFrameString("f", params));
+        params = new Object[] { "m", "Ljava/util/Map;", map };
+        assertEquals("f [m={a=1, b=2, c=3, ...}]", StackParamNative.appendParamsToFrameString("f", params));
+    }
+
+    @Test
+    public void testTraceBudgetAcrossFrames() throws Exception {
+        try {
+            recurse("0123456789", 2);
+            fail();
+        } catch (RuntimeException e) {
+            StackTraceElement[] trace = e.getStackTrace();
+            String[] paramStrs = new String[3];
+            for (int i = 0; i < paramStrs.length; i++) {
+                String traceStr = trace[i].toString();
+                paramStrs[i] = traceStr.substring(traceStr.indexOf('['));
+            }
+            // Each var's text counts against the budget until it runs out part way through the second frame
+            assertArrayEquals(new String[] {
+                "[text=0123456789, times=0]",
+                "[text=012..., ...]",
+                "[...]"
+            }, paramStrs);
+        }
+    }
+
+    private static void recurse(String text, int times) {
+        if (times == 0) throw new RuntimeException("OH!");
+        recurse(text, times - 1);
+    }
+}
@@ -0,0 +1,87 @@
+package stackparam;
+
+import static org.junit.Assert.*;
+import org.junit.Test;
+
+import java.util.ArrayList;
+import java.util.List;
+import java.util.UUID;
+
+/**
+ * Only run by the safeTest task, which starts the agent with "render_safe", {@link Trusted} trusted, and fields
+ * named "password", declared long or with "tok_" values redacted with the mask "&lt;hidden, safe&gt;"
+ */
+public class SafeTest {
+
+    @Test
+    public void testUntrustedToStringNeverRun() throws Exception {
+        Dangerous dangerous = new Dangerous();
+        UUID uuid = UUID.fromString("123e4567-e89b-12d3-a456-426655440000");
+        List<Object> list = new ArrayList<>();
+        list.add(dangerous);
+        try {
+            methodWithValues(dangerous, uuid, list, new Trusted());
+            fail();
+        } catch (RuntimeException e) {
+            String traceStr = e.getStackTrace()[0].toString();
+            traceStr = traceStr.substring(traceStr.indexOf('['));
+            // Only the primitive and string fields, and the elements of trusted collections are safe as well
+            String rendered = "stackparam.SafeTest$Dangerous@" + Integer.toHexString(System.identityHashCode(dangerous)) +
+                    "{name=bob, id=5}";
+            assertEquals("[dangerous=" + rendered + ", uuid=123e4567-e89b-12d3-a456-426655440000, " +
+                    "list=[" + rendered + "], trusted=trusted]", traceStr);
+            assertEquals(0, Dangerous.toStringCalls);
+        }
+    }
+
+    @Test
+    public void testRedactedFields() throws Exception {
+        Credentials creds = new Credentials();
+        try {
+            methodWithCredentials(creds);
+            fail();
+        } catch (RuntimeException e) {
+            String traceStr = e.getStackTrace()[0].toString();
+            traceStr = traceStr.substring(traceStr.indexOf('['));
+            // Masked by name, value and declared type, the mask having been decoded from the options
+            assertEquals("[creds=stackparam.SafeTest$Credentials@" + Integer.toHexString(System.identityHashCode(creds)) +
+                    "{user=bob, password=<hidden, safe>, apiKey=<hidden, safe>, pin=<hidden, safe>}]", traceStr);
+        }
+    }
+
+    private static void methodWithCredentials(Credentials creds) {
+        throw new RuntimeException("OH!");
+    }
+
+    private static void methodWithValues(Dangerous dangerous, UUID uuid, List<Object> list, Trusted trusted) {
+        throw new RuntimeException("OH!");
+    }
+
+    static class Dangerous {
+        static int toStringCalls;
+
+        String name = "bob";
+        int id = 5;
+        Object other = new Object();
+
+        @Override
+        public String toString() {
+            toStringCalls++;
+            throw new IllegalStateException("Should not be called");
+        }
+    }
+
+    static class Credentials {
+        String user = "bob";
+        String password = "hunter2";
+        String apiKey = "tok_abc";
+        long pin = 1234;
+    }
+
+    static class Trusted {
+        @Override
+        public String toString() {
+            return "trusted";
+        }
+    }
+}
@@ -0,0 +1,19 @@
+package stackparam;
+
+import java.io.FileInputStream;
+
+import stackparam.serial.ParamObjectInputStream;
+
+/**
+ * Run in its own JVM without the agent to read back a throwable serialized by
+ * one with it. Prints the string of the throwable's first element along with
+ * its params.
+ */
+public class SerializedReader {
+
+    public static void main(String[] args) throws Exception {
+        try (ParamObjectInputStream in = new ParamObjectInputStream(new FileInputStream(args[0]))) {
+            System.out.print(in.toString(((Throwable) in.readObject()).getStackTrace()[0]));
+        }
+    }
+}
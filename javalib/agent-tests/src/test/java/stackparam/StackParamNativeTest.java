@@ -2,7 +2,17 @@ package stackparam;
 
 import org.junit.Test;
 
+import java.lang.ref.WeakReference;
+import java.lang.reflect.Method;
+import java.util.ArrayList;
+import java.util.LinkedHashMap;
+import java.util.List;
+import java.util.Map;
+
 import static org.junit.Assert.assertArrayEquals;
+import static org.junit.Assert.assertEquals;
+import static org.junit.Assert.assertNull;
+import static org.junit.Assert.assertTrue;
 
 public class StackParamNativeTest {
 
@@ -38,6 +48,82 @@ public class StackParamNativeTest {
         assertArrayEquals(expectedStringMethodArgs, stackParams[2]);
     }
 
+    @Test
+    public void testAppendParamsToFrameStringStructured() {
+        List<Object> list = new ArrayList<>();
+        list.add(1);
+        list.add(list);
+        Map<String, Object> map = new LinkedHashMap<>();
+        map.put("ints", new int[] { 1, 2 });
+        map.put("none", null);
+        Object[] params = {
+            "list", "Ljava/util/List;", list,
+            "map", "Ljava/util/Map;", map,
+            "bad", "Ljava/lang/Object;", new Object() {
+                @Override
+                public String toString() {
+                    throw new IllegalStateException("nope");
+                }
+            }
+        };
+        assertEquals("foo [list=[1, <cycle>], map={ints=[1, 2], none=null}, " +
+                        "bad=toString err: java.lang.IllegalStateException: nope]",
+                StackParamNative.appendParamsToFrameString("foo", params));
+    }
+
+    @Test
+    public void testFrameFormat() {
+        Object[] params = {
+            "this", "Lstackparam/StackParamNativeTest;", this,
+            "num", "I", 5,
+            "strs", "[Ljava/lang/String;", new String[] { "a", "{name}" }
+        };
+        StackParamNative.setFrameFormat("<{vars}>", "{name}: {value} ({type})", "; ", true);
+        try {
+            assertEquals("foo <num: 5 (int); strs: [a, {name}] (java.lang.String[])>",
+                    StackParamNative.appendParamsToFrameString("foo", params));
+            StackParamNative.setFrameFormat(null, null, " | ", false);
+            assertEquals("foo [this=" + this + " | num=5 | strs=[a, {name}]]",
+                    StackParamNative.appendParamsToFrameString("foo", params));
+        } finally {
+            StackParamNative.resetFrameFormat();
+        }
+        assertEquals("foo [this=" + this + ", num=5, strs=[a, {name}]]",
+                StackParamNative.appendParamsToFrameString("foo", params));
+    }
+
+    @Test
+    public void testAttachedThrowableCanBeCollected() throws Exception {
+        WeakReference<Throwable> ref = new WeakReference<Throwable>(newThrowable());
+        Throwable throwable = ref.get();
+        fillAttached(throwable);
+        // The side table keeps the params of the frame the throwable was given to, but only rendered
+        Method getAttached = StackParamNative.class.getDeclaredMethod("getAttached", Object.class);
+        getAttached.setAccessible(true);
+        Object[][] params = (Object[][]) getAttached.invoke(null, throwable);
+        assertEquals("throwable", params[0][0]);
+        assertTrue(params[0][2] instanceof String);
+        throwable = null;
+        for (int i = 0; i < 50 && ref.get() != null; i++) {
+            System.gc();
+            Thread.sleep(10);
+        }
+        assertNull(ref.get());
+    }
+
+    // Same depth as fillAttached so the filled frames start there
+    private static Throwable newThrowable() {
+        return new Exception("Attached");
+    }
+
+    private static void fillAttached(Throwable throwable) {
+        fillFrom(throwable);
+    }
+
+    private static void fillFrom(Throwable throwable) {
+        StackParamNative.fillInStackTraceHook(throwable, Thread.currentThread());
+    }
+
     private Object[][] instanceWithStringArg(String stringArg) {
         return withOtherArgs(true, (byte) 100, (char) 101,
                 (short) 102, 103, 104L,
@@ -0,0 +1,52 @@
+package stackparam;
+
+import static org.junit.Assert.*;
+import org.junit.Test;
+
+import java.lang.ref.WeakReference;
+
+/** Only run by the summaryRetentionTest task, which starts the agent with "retention=summary" */
+public class SummaryRetentionTest {
+
+    @Test
+    public void testValueKeptAsRendered() throws Exception {
+        StringBuilder value = new StringBuilder("before");
+        WeakReference<Object> ref = new WeakReference<Object>(value);
+        RuntimeException ex = catchFrom(value);
+        value.append(" and after");
+        value = null;
+        for (int i = 0; i < 50 && ref.get() != null; i++) {
+            System.gc();
+            Thread.sleep(10);
+        }
+        // Only the string rendered at the throw is kept
+        assertNull(ref.get());
+        String traceStr = ex.getStackTrace()[0].toString();
+        traceStr = traceStr.substring(traceStr.indexOf('['));
+        assertEquals("[value=before]", traceStr);
+    }
+
+    @Test
+    public void testLoadStackParamsUnaffected() throws Exception {
+        StringBuilder value = new StringBuilder("live");
+        Object[] params = paramsOf(value);
+        assertSame(value, params[2]);
+    }
+
+    private static Object[] paramsOf(Object value) {
+        return StackParamNative.loadStackParams(Thread.currentThread(), 2)[1];
+    }
+
+    private static RuntimeException catchFrom(Object value) {
+        try {
+            methodWithValue(value);
+        } catch (RuntimeException e) {
+            return e;
+        }
+        throw new AssertionError("Expected throw");
+    }
+
+    private static void methodWithValue(Object value) {
+        throw new RuntimeException("OH!");
+    }
+}
@@ -3,23 +3,138 @@ package stackparam;
 import static org.junit.Assert.*;
 import org.junit.Test;
 
+import java.io.ByteArrayInputStream;
+import java.io.ByteArrayOutputStream;
+import java.io.File;
+import java.io.FileOutputStream;
+import java.io.InputStream;
+import java.io.ObjectInputStream;
+import java.io.ObjectOutputStream;
+
+import stackparam.serial.ParamObjectInputStream;
+
 public class ThrowableTest {
 
     private static final Object CONST_OBJ = new Object();
 
     @Test
     public void testStackTraceElementToString() throws Exception {
-        String expected = "[this=" + this + ", boolArg=true, byteArg=100, " +
-                "charArg=e, shortArg=102, intArg=103, longArg=104, " +
-                "floatArg=105.6, doubleArg=106.7, nullArg=null, " +
-                "objectExactArg=" + CONST_OBJ + ", stringVarArgs=[foo, bar, baz]]";
         StackTraceElement elem = getTestElement();
         String traceStr = elem.toString();
         traceStr = traceStr.substring(traceStr.indexOf('['));
-        assertEquals(expected, traceStr);
+        assertEquals(getTestElementParams(), traceStr);
+    }
+
+    @Test
+    public void testCaptureDisabled() throws Exception {
+        assertTrue(StackParamNative.isCaptureEnabled());
+        StackParamNative.setCaptureEnabled(false);
+        try {
+            assertFalse(StackParamNative.isCaptureEnabled());
+            String traceStr = getTestElement().toString();
+            assertEquals(-1, traceStr.indexOf('['));
+        } finally {
+            StackParamNative.setCaptureEnabled(true);
+        }
+        assertTrue(getTestElement().toString().contains("[this="));
+    }
+
+    @Test
+    public void testSerializedParams() throws Exception {
+        // paramInfo is transient, and CONST_OBJ couldn't be written anyway, so the params only come back
+        // through the paramStr rendered when written
+        StackTraceElement elem = getTestElement();
+        ByteArrayOutputStream bytes = new ByteArrayOutputStream();
+        try (ObjectOutputStream out = new ObjectOutputStream(bytes)) {
+            out.writeObject(elem);
+        }
+        try (ObjectInputStream in = new ObjectInputStream(new ByteArrayInputStream(bytes.toByteArray()))) {
+            StackTraceElement readElem = (StackTraceElement) in.readObject();
+            assertEquals(elem.toString(), readElem.toString());
+            assertTrue(readElem.toString().contains("[this="));
+        }
+    }
+
+    @Test
+    public void testSerializedThrowableParams() throws Exception {
+        RuntimeException ex = getTestThrowable();
+        ByteArrayOutputStream bytes = new ByteArrayOutputStream();
+        try (ObjectOutputStream out = new ObjectOutputStream(bytes)) {
+            out.writeObject(ex);
+        }
+        try (ObjectInputStream in = new ObjectInputStream(new ByteArrayInputStream(bytes.toByteArray()))) {
+            StackTraceElement readElem = ((RuntimeException) in.readObject()).getStackTrace()[0];
+            assertEquals(getTestElementParams(), StackParamNative.getSerializedParams(readElem));
+            assertEquals("methodThatWillThrow", readElem.getMethodName());
+            assertEquals(ex.getStackTrace()[0].toString(), readElem.toString());
+        }
+        assertNull(StackParamNative.getSerializedParams(new StackTraceElement("Foo", "bar", null, -1)));
+    }
+
+    @Test
+    public void testSerializedParamsReadWithoutAgent() throws Exception {
+        File file = File.createTempFile("stackparam", ".ser");
+        try {
+            try (ObjectOutputStream out = new ObjectOutputStream(new FileOutputStream(file))) {
+                out.writeObject(getTestThrowable());
+            }
+            // A plain JVM with just the reader and ParamObjectInputStream on its class path still gets the params
+            String classPath = classPathOf(SerializedReader.class) + File.pathSeparator +
+                    classPathOf(ParamObjectInputStream.class);
+            Process process = new ProcessBuilder(
+                    new File(System.getProperty("java.home"), "bin/java").getPath(),
+                    "-cp", classPath, SerializedReader.class.getName(), file.getPath())
+                    .redirectErrorStream(true)
+                    .start();
+            String output = readAll(process.getInputStream());
+            assertEquals(output, 0, process.waitFor());
+            assertEquals("stackparam.ThrowableTest.methodThatWillThrow(ThrowableTest.java:" +
+                    getTestElement().getLineNumber() + ") " + getTestElementParams(), output);
+        } finally {
+            file.delete();
+        }
+    }
+
+    @Test
+    public void testToJson() throws Exception {
+        RuntimeException ex = new RuntimeException("outer \"quoted\"", getTestThrowable());
+        String json = StackParamNative.toJson(ex);
+        assertTrue(json.startsWith("{\"class\": \"java.lang.RuntimeException\", " +
+                "\"message\": \"outer \\\"quoted\\\"\", \"frames\": [{\"class\": \"stackparam.ThrowableTest\", " +
+                "\"method\": \"testToJson\""));
+        assertTrue(json.contains("\"cause\": {\"class\": \"java.lang.RuntimeException\", \"message\": \"OH!\""));
+        assertTrue(json.contains("\"method\": \"methodThatWillThrow\", \"file\": \"ThrowableTest.java\""));
+        assertTrue(json.contains("{\"name\": \"intArg\", \"type\": \"I\", \"value\": \"103\"}, " +
+                "{\"name\": \"longArg\", \"type\": \"J\", \"value\": \"104\"}"));
+        assertTrue(json.contains("{\"name\": \"stringVarArgs\", \"type\": \"[Ljava/lang/String;\", " +
+                "\"value\": \"[foo, bar, baz]\"}"));
+        assertTrue(json.endsWith("\"cause\": null, \"suppressed\": []}, \"suppressed\": []}"));
+    }
+
+    private static String classPathOf(Class<?> cls) throws Exception {
+        return new File(cls.getProtectionDomain().getCodeSource().getLocation().toURI()).getPath();
+    }
+
+    private static String readAll(InputStream in) throws Exception {
+        ByteArrayOutputStream bytes = new ByteArrayOutputStream();
+        byte[] buf = new byte[4096];
+        int read;
+        while ((read = in.read(buf)) != -1) bytes.write(buf, 0, read);
+        return bytes.toString("UTF-8");
+    }
+
+    private String getTestElementParams() {
+        return "[this=" + this + ", boolArg=true, byteArg=100, " +
+                "charArg=e, shortArg=102, intArg=103, longArg=104, " +
+                "floatArg=105.6, doubleArg=106.7, nullArg=null, " +
+                "objectExactArg=" + CONST_OBJ + ", stringVarArgs=[foo, bar, baz]]";
     }
 
     private StackTraceElement getTestElement() {
+        return getTestThrowable().getStackTrace()[0];
+    }
+
+    private RuntimeException getTestThrowable() {
         try {
             methodThatWillThrow(true, (byte) 100, (char) 101,
                     (short) 102, 103, 104L,
@@ -28,7 +143,7 @@ public class ThrowableTest {
             fail();
             return null;
         } catch (RuntimeException e) {
-            return e.getStackTrace()[0];
+            return e;
         }
     }
 
@@ -0,0 +1,20 @@
+package stackparam;
+
+import static org.junit.Assert.*;
+import org.junit.Test;
+
+/** Only run by the unloadTest task, whose system class loader calls the natives before VM init */
+public class UnloadTest {
+
+    @Test
+    public void testUnloadAfterEarlyNatives() {
+        // Otherwise nothing was called before VM init
+        assertEquals("stackparam.EarlyLoader", ClassLoader.getSystemClassLoader().getClass().getName());
+        assertTrue(StackParamNative.isCaptureEnabled());
+        // Nothing is using the state, so it is let go of right away instead of timing out
+        assertTrue(StackParamNative.unload());
+        assertFalse(StackParamNative.isCaptureEnabled());
+        String traceStr = new Exception().getStackTrace()[0].toString();
+        assertFalse(traceStr, traceStr.contains("["));
+    }
+}
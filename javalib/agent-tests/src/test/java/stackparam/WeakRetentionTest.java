@@ -0,0 +1,50 @@
+package stackparam;
+
+import static org.junit.Assert.*;
+import org.junit.Test;
+
+import java.lang.ref.WeakReference;
+
+/** Only run by the weakRetentionTest task, which starts the agent with "retention=weak" */
+public class WeakRetentionTest {
+
+    @Test
+    public void testCollectedValue() throws Exception {
+        Object value = new StringBuilder("gone");
+        WeakReference<Object> ref = new WeakReference<Object>(value);
+        RuntimeException ex = catchFrom(value, "kept");
+        value = null;
+        for (int i = 0; i < 50 && ref.get() != null; i++) {
+            System.gc();
+            Thread.sleep(10);
+        }
+        // The throwable didn't keep it from being collected, the string is kept as is
+        assertNull(ref.get());
+        String traceStr = ex.getStackTrace()[0].toString();
+        traceStr = traceStr.substring(traceStr.indexOf('['));
+        assertEquals("[value=<collected>, str=kept]", traceStr);
+    }
+
+    @Test
+    public void testLiveValue() throws Exception {
+        StringBuilder value = new StringBuilder("before");
+        RuntimeException ex = catchFrom(value, "kept");
+        value.append(" and after");
+        String traceStr = ex.getStackTrace()[0].toString();
+        traceStr = traceStr.substring(traceStr.indexOf('['));
+        assertEquals("[value=before and after, str=kept]", traceStr);
+    }
+
+    private static RuntimeException catchFrom(Object value, String str) {
+        try {
+            methodWithValue(value, str);
+        } catch (RuntimeException e) {
+            return e;
+        }
+        throw new AssertionError("Expected throw");
+    }
+
+    private static void methodWithValue(Object value, String str) {
+        throw new RuntimeException("OH!");
+    }
+}
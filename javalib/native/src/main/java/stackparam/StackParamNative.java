@@ -1,6 +1,10 @@
 package stackparam;
 
+import java.lang.ref.WeakReference;
+import java.util.ArrayList;
 import java.util.Arrays;
+import java.util.HashMap;
+import java.util.Iterator;
 
 public class StackParamNative {
 
@@ -23,6 +27,29 @@ public class StackParamNative {
      * methods), the string "<unknown>" becomes the value regardless of the
      * type's signature.
      *
+     * When the agent is started with the "this_fields" option, the fields of
+     * "this" come right after it, 3 values per field like the params and
+     * named by their path, e.g. "this.name" or "this.config.url".
+     *
+     * When the agent is started with the "locals" option, each sub array for
+     * a method with local variable debug info has one more value after the
+     * params: an Object[] of the other locals in scope at the frame's current
+     * location, 3 values per local like the params.
+     *
+     * When the agent is started with any of the "redact" options, values the
+     * rules match are replaced with the mask string (by default "<redacted>")
+     * regardless of the type's signature.
+     *
+     * When the agent is started with the "annotations" option, params marked
+     * with a "Sensitive" annotation are masked the same way and the sub array
+     * is null for frames whose method or class is marked with a
+     * "NoStackParams" annotation.
+     *
+     * The values are always the live ones on the stack. The "eager" and
+     * "retention" options only change what throwables keep, e.g. a
+     * WeakReference in place of the value with weak retention, never what is
+     * returned here.
+     *
      * @param thread The thread to get params for
      * @param maxDepth The maximum depth to go to
      * @return Array where each value represents params for a frame. Each param
@@ -33,39 +60,347 @@ public class StackParamNative {
      */
     public static native Object[][] loadStackParams(Thread thread, int maxDepth);
 
+    /**
+     * Turns the capture of params for new throwables on or off. When off, the
+     * stack trace strings are the same as without the agent. Capture is on by
+     * default.
+     *
+     * @param enabled Whether to capture params
+     */
+    public static native void setCaptureEnabled(boolean enabled);
+
+    /**
+     * Whether params are being captured. This is false after unload or if the
+     * agent never initialized.
+     *
+     * @return True if new throwables get params
+     */
+    public static native boolean isCaptureEnabled();
+
+    /**
+     * Turns off capture for good and lets go of everything the agent holds
+     * onto. The agent stops hooking class loads and the methods it replaced
+     * act as the originals from then on. It cannot be turned back on without
+     * restarting the JVM.
+     *
+     * @return False if not everything could be let go of, e.g. when other
+     *         threads were still in the agent's natives after a second, in
+     *         which case what they were using is left allocated
+     */
+    public static boolean unload() {
+        return release();
+    }
+
+    /**
+     * Stops the agent's events and lets go of its state. The attached side
+     * table is emptied natively through clearAttached, the same as when the
+     * JVM unloads the agent.
+     */
+    private static native boolean release();
+
+    /**
+     * Sets how params are laid out after the frame string, in place of what
+     * the agent options give. Any null part keeps what the agent options give
+     * for it, or the default if none.
+     *
+     * @param frameFormat The text around the vars, with "{vars}" where they
+     *                    go. Default is "[{vars}]".
+     * @param varFormat Each var, with "{name}", "{type}" and "{value}"
+     *                  replaced by its own, e.g. "{name}: {value}". The type
+     *                  is the Java type name, e.g. "int" or
+     *                  "java.lang.String[]". Default is "{name}={value}".
+     * @param separator The text between vars. Default is ", ".
+     * @param hideThis Whether to leave out the "this" param
+     * @throws IllegalArgumentException If frameFormat has no "{vars}"
+     */
+    public static void setFrameFormat(String frameFormat, String varFormat, String separator, boolean hideThis) {
+        if (frameFormat != null && !frameFormat.contains("{vars}")) {
+            throw new IllegalArgumentException("Frame format has no {vars}");
+        }
+        StackParamNative.frameFormat = new Object[] { frameFormat, varFormat, separator, hideThis };
+    }
+
+    /**
+     * Goes back to the frame format the agent options give, undoing
+     * setFrameFormat.
+     */
+    public static void resetFrameFormat() {
+        frameFormat = null;
+    }
+
+    /**
+     * The frame, var and separator strings, any of which can be null, then the
+     * hide this boolean. Read natively as a whole so each frame sees one
+     * format.
+     */
+    private static volatile Object[] frameFormat;
+
+    /**
+     * Same as loadStackParams but for the frames a throwable being filled in
+     * on the given thread will have. Only used when the agent was attached to
+     * a running VM. Param values other than strings and boxed primitives are
+     * replaced with their rendered strings, or weak references with weak
+     * retention, since they can refer back to the throwable.
+     *
+     * @param throwable The throwable being filled in
+     * @param thread The thread to get params for
+     * @return Same as loadStackParams or null on any failure
+     */
+    private static native Object[][] loadThrowableStackParams(Throwable throwable, Thread thread);
+
+    /**
+     * Retransformed classes cannot have new fields, so when attached the params
+     * of throwables and trace elements are kept here instead. Keyed by identity
+     * hash code with each value a list of {WeakReference key, value} pairs so
+     * nothing here keeps the throwable or element alive. The values themselves
+     * only hold strings or weak references, see loadThrowableStackParams. The
+     * table is split into stripes each locked on its own so throwables filled
+     * in on different threads rarely wait on each other.
+     */
+    private static final HashMap<Integer, ArrayList<Object[]>>[] attached;
+
+    private static final int[] putsSinceSweep;
+
+    private static final int ATTACHED_STRIPES = 64;
+
+    private static final int PUTS_PER_SWEEP = 1000;
+
+    static {
+        @SuppressWarnings("unchecked")
+        HashMap<Integer, ArrayList<Object[]>>[] stripes = new HashMap[ATTACHED_STRIPES];
+        for (int i = 0; i < stripes.length; i++) {
+            stripes[i] = new HashMap<Integer, ArrayList<Object[]>>();
+        }
+        attached = stripes;
+        putsSinceSweep = new int[ATTACHED_STRIPES];
+    }
+
+    /**
+     * Empties the attached side table. Called natively on release.
+     */
+    private static void clearAttached() {
+        for (HashMap<Integer, ArrayList<Object[]>> table : attached) {
+            synchronized (table) {
+                table.clear();
+            }
+        }
+    }
+
+    private static int attachedStripe(int hash) {
+        return (hash ^ (hash >>> 16)) & (ATTACHED_STRIPES - 1);
+    }
+
+    private static void putAttached(Object key, Object value) {
+        Integer hash = System.identityHashCode(key);
+        int stripe = attachedStripe(hash);
+        HashMap<Integer, ArrayList<Object[]>> table = attached[stripe];
+        synchronized (table) {
+            if (++putsSinceSweep[stripe] >= PUTS_PER_SWEEP) {
+                putsSinceSweep[stripe] = 0;
+                sweepAttached(table);
+            }
+            ArrayList<Object[]> entries = table.get(hash);
+            if (entries == null) {
+                entries = new ArrayList<Object[]>(1);
+                table.put(hash, entries);
+            }
+            for (Object[] entry : entries) {
+                if (((WeakReference<?>) entry[0]).get() == key) {
+                    entry[1] = value;
+                    return;
+                }
+            }
+            entries.add(new Object[] { new WeakReference<Object>(key), value });
+        }
+    }
+
+    private static Object getAttached(Object key) {
+        Integer hash = System.identityHashCode(key);
+        HashMap<Integer, ArrayList<Object[]>> table = attached[attachedStripe(hash)];
+        synchronized (table) {
+            ArrayList<Object[]> entries = table.get(hash);
+            if (entries == null) return null;
+            for (Object[] entry : entries) {
+                if (((WeakReference<?>) entry[0]).get() == key) return entry[1];
+            }
+            return null;
+        }
+    }
+
+    private static void sweepAttached(HashMap<Integer, ArrayList<Object[]>> table) {
+        Iterator<ArrayList<Object[]>> lists = table.values().iterator();
+        while (lists.hasNext()) {
+            ArrayList<Object[]> entries = lists.next();
+            Iterator<Object[]> iter = entries.iterator();
+            while (iter.hasNext()) {
+                if (((WeakReference<?>) iter.next()[0]).get() == null) iter.remove();
+            }
+            if (entries.isEmpty()) lists.remove();
+        }
+    }
+
+    /**
+     * Called by a retransformed Throwable right after fillInStackTrace(0).
+     * Public only so java.lang classes can call it.
+     */
+    public static Throwable fillInStackTraceHook(Throwable throwable, Thread thread) {
+        try {
+            Object[][] params = loadThrowableStackParams(throwable, thread);
+            if (params != null) putAttached(throwable, params);
+        } catch (Throwable ignored) {
+        }
+        return throwable;
+    }
+
+    /**
+     * Called by a retransformed Throwable with the trace getOurStackTrace is
+     * about to return. Gives each element the params of its frame.
+     */
+    public static StackTraceElement[] ourStackTraceHook(StackTraceElement[] trace, Throwable throwable) {
+        try {
+            if (!isCaptureEnabled()) return trace;
+            beginTrace(trace);
+            Object[][] params = (Object[][]) getAttached(throwable);
+            if (trace != null && params != null) {
+                for (int i = 0; i < trace.length && i < params.length; i++) {
+                    if (params[i] != null) putAttached(trace[i], params[i]);
+                }
+            }
+        } catch (Throwable ignored) {
+        }
+        return trace;
+    }
+
+    /**
+     * Called by a retransformed StackTraceElement with the string toString is
+     * about to return.
+     */
+    public static String elementToStringHook(String frameString, StackTraceElement elem) {
+        try {
+            if (!isCaptureEnabled()) return frameString;
+            return appendParamsToFrameString(frameString, (Object[]) getAttached(elem), elem);
+        } catch (Throwable ignored) {
+            return frameString;
+        }
+    }
+
     /**
      * Appends params string, e.g. "[foo=bar, baz=null]" to the given frame
-     * string. Any exceptions during string building are trapped.
+     * string. If there are locals, they are appended after, e.g.
+     * "[foo=bar] locals [qux=5]". Any exceptions during string building are
+     * trapped.
+     *
+     * Values are rendered by the agent within the bounds given in its options.
+     * Each value is still cut at MAX_PARAM_STR_LEN chars. The layout is the
+     * frame format from the agent options or setFrameFormat.
      *
      * @param frameString The string to append to
      * @param params The array for params. Must be multiple of 3 as returned by
-     *               loadStackParams.
+     *               loadStackParams, plus the locals array if any.
      * @return The resulting string
      */
     public static String appendParamsToFrameString(String frameString, Object[] params) {
+        return appendParamsToFrameString(frameString, params, null);
+    }
+
+    /**
+     * Returns the throwable as JSON, along with its cause and suppressed
+     * throwables and theirs. Each throwable is an object like:
+     *
+     * <pre>
+     * {"class": "java.lang.IllegalStateException", "message": "Oops",
+     *  "frames": [{"class": "com.foo.Bar", "method": "baz", "file": "Bar.java", "line": 12,
+     *              "params": [{"name": "this", "type": "Lcom/foo/Bar;", "value": "com.foo.Bar@6d06d69c"}]}],
+     *  "cause": null,
+     *  "suppressed": []}
+     * </pre>
+     *
+     * The "message" and "file" may be null and "line" is as given by the
+     * element. The "params" of a frame are null if none were captured for it,
+     * e.g. while capture is disabled. Each "type" is the JVM type signature
+     * and each "value" is rendered as it would be in the frame string. With
+     * the "locals" option, frames with params also have "locals" in the same
+     * form. A throwable already written further up the chain only has its
+     * "class" and "message" along with "circular": true.
+     *
+     * @param throwable The throwable to write
+     * @return The JSON
+     * @throws NullPointerException If throwable is null
+     * @throws IllegalStateException If the agent isn't initialized
+     * @throws RuntimeException Any internal error we were not prepared for
+     */
+    public static native String toJson(Throwable throwable);
+
+    /**
+     * Returns the params of the given element as they were rendered when it
+     * was serialized, e.g. "[foo=bar, baz=null]". This is what the element's
+     * toString shows for elements read back from a stream, which no longer
+     * have the values themselves. It is only kept when the agent was loaded
+     * at startup on the JVM reading the element.
+     *
+     * @param elem The element read back from a stream
+     * @return The rendered params or null if none were written or read
+     * @throws NullPointerException If elem is null
+     * @throws IllegalStateException If the agent isn't initialized
+     */
+    public static native String getSerializedParams(StackTraceElement elem);
+
+    private static String appendParamsToFrameString(String frameString, Object[] params, StackTraceElement elem) {
         try {
             if (params == null) return frameString;
+            String rendered = null;
+            try {
+                rendered = renderParams(frameString, params, elem);
+            } catch (UnsatisfiedLinkError e) {
+                // Not the class defined by the agent
+            }
+            if (rendered != null) return rendered;
+            // Without the agent, render simply and in the default format
             StringBuilder ret = new StringBuilder(frameString);
-            ret.append(" [");
-            for (int i = 0; i < params.length / 3; i++) {
-                if (i > 0) ret.append(", ");
-                ret.append((String) params[i * 3]).append("=");
-                String param;
-                try {
-                    param = paramValToString(params[(i * 3) + 2]);
-                } catch (Exception e) {
-                    ret.append("toString err: ").append(e.toString());
-                    continue;
-                }
-                if (param.length() <= MAX_PARAM_STR_LEN) ret.append(param);
-                else ret.append(param, 0, MAX_PARAM_STR_LEN).append("...");
+            ret.append(" ");
+            appendVars(ret, params);
+            if (params.length % 3 == 1 && params[params.length - 1] instanceof Object[]) {
+                ret.append(" locals ");
+                appendVars(ret, (Object[]) params[params.length - 1]);
             }
-            return ret.append("]").toString();
+            return ret.toString();
         } catch (Exception e) {
             return frameString + "[failed getting params: " + e + "]";
         }
     }
 
+    /**
+     * Renders the params natively. The element, if given, is used to count
+     * against the byte budget of the trace it is in.
+     *
+     * @return The resulting string or null if the agent isn't initialized
+     */
+    private static native String renderParams(String frameString, Object[] params, StackTraceElement elem);
+
+    /**
+     * Starts the byte budget for the trace about to be rendered on this
+     * thread.
+     */
+    private static native void beginTrace(StackTraceElement[] trace);
+
+    private static void appendVars(StringBuilder ret, Object[] vars) {
+        ret.append("[");
+        for (int i = 0; i < vars.length / 3; i++) {
+            if (i > 0) ret.append(", ");
+            ret.append((String) vars[i * 3]).append("=");
+            String var;
+            try {
+                var = paramValToString(vars[(i * 3) + 2]);
+            } catch (Exception e) {
+                ret.append("toString err: ").append(e.toString());
+                continue;
+            }
+            if (var.length() <= MAX_PARAM_STR_LEN) ret.append(var);
+            else ret.append(var, 0, MAX_PARAM_STR_LEN).append("...");
+        }
+        ret.append("]");
+    }
+
     private static String paramValToString(Object paramVal) {
         if (paramVal != null && paramVal.getClass().isArray()) {
             if (paramVal instanceof boolean[]) return Arrays.toString((boolean[]) paramVal);
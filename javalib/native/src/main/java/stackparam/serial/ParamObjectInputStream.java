@@ -0,0 +1,77 @@
+package stackparam.serial;
+
+import java.io.IOException;
+import java.io.InputStream;
+import java.io.ObjectInputStream;
+import java.io.ObjectStreamClass;
+import java.util.IdentityHashMap;
+
+/**
+ * Reads objects written by a JVM running the agent on one that isn't, keeping
+ * the params rendered into each stack trace element when it was written. A
+ * plain ObjectInputStream drops them, since a StackTraceElement without the
+ * agent has no field for them. Only needs this class on the class path, not
+ * the agent.
+ * <p>
+ * The params are kept by this stream for the elements it read, so ask it for
+ * them while it's still around. A JVM running the agent at startup keeps them
+ * on the elements themselves, see StackParamNative.getSerializedParams, and
+ * this stream keeps none.
+ */
+public class ParamObjectInputStream extends ObjectInputStream {
+
+    private final IdentityHashMap<java.lang.StackTraceElement, String> params =
+            new IdentityHashMap<java.lang.StackTraceElement, String>();
+
+    public ParamObjectInputStream(InputStream in) throws IOException {
+        super(in);
+        enableResolveObject(true);
+    }
+
+    /**
+     * Returns the params of the given element as they were rendered when it
+     * was serialized, e.g. "[foo=bar, baz=null]".
+     *
+     * @param elem An element read by this stream
+     * @return The rendered params or null if none were written or the element
+     *         wasn't read by this stream
+     * @throws NullPointerException If elem is null
+     */
+    public String getSerializedParams(java.lang.StackTraceElement elem) {
+        if (elem == null) throw new NullPointerException();
+        return params.get(elem);
+    }
+
+    /**
+     * Returns the element's string with its serialized params after it, the
+     * same as its toString gives on the JVM that wrote it.
+     *
+     * @param elem An element read by this stream
+     * @return The element's string, with no params if it has none
+     * @throws NullPointerException If elem is null
+     */
+    public String toString(java.lang.StackTraceElement elem) {
+        String elemParams = getSerializedParams(elem);
+        return elemParams == null ? elem.toString() : elem + " " + elemParams;
+    }
+
+    @Override
+    protected Class<?> resolveClass(ObjectStreamClass desc) throws IOException, ClassNotFoundException {
+        // Read the element as our stand in only if it has params this JVM's elements can't hold
+        if (desc.getName().equals(java.lang.StackTraceElement.class.getName()) &&
+                desc.getField("paramStr") != null &&
+                ObjectStreamClass.lookup(java.lang.StackTraceElement.class).getField("paramStr") == null) {
+            return StackTraceElement.class;
+        }
+        return super.resolveClass(desc);
+    }
+
+    @Override
+    protected Object resolveObject(Object obj) throws IOException {
+        if (!(obj instanceof StackTraceElement)) return obj;
+        StackTraceElement read = (StackTraceElement) obj;
+        java.lang.StackTraceElement elem = read.toElement();
+        if (read.paramStr != null) params.put(elem, read.paramStr);
+        return elem;
+    }
+}
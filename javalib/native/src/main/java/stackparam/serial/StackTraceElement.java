@@ -0,0 +1,60 @@
+package stackparam.serial;
+
+import java.io.InvalidObjectException;
+import java.io.Serializable;
+import java.lang.reflect.Constructor;
+
+/**
+ * Stands in for java.lang.StackTraceElement while ParamObjectInputStream reads
+ * one that was written with params. A stream only accepts a local class with
+ * the same simple name and serialVersionUID, hence the name. It has the
+ * fields the element has on any JDK along with paramStr, so nothing written
+ * is dropped before ParamObjectInputStream swaps it for a real element.
+ */
+final class StackTraceElement implements Serializable {
+
+    private static final long serialVersionUID = 6992337162326171013L;
+
+    // Bits of the format field that hide the loader name and module version in toString, since Java 9
+    private static final int BUILTIN_CLASS_LOADER = 0x1;
+    private static final int JDK_NON_UPGRADEABLE_MODULE = 0x2;
+
+    private static final Constructor<java.lang.StackTraceElement> MODULE_CONSTRUCTOR = moduleConstructor();
+
+    private String classLoaderName;
+    private String moduleName;
+    private String moduleVersion;
+    private String declaringClass;
+    private String methodName;
+    private String fileName;
+    private int lineNumber;
+    private byte format;
+    String paramStr;
+
+    private static Constructor<java.lang.StackTraceElement> moduleConstructor() {
+        try {
+            return java.lang.StackTraceElement.class.getConstructor(String.class, String.class, String.class,
+                    String.class, String.class, String.class, int.class);
+        } catch (NoSuchMethodException e) {
+            // Before Java 9
+            return null;
+        }
+    }
+
+    java.lang.StackTraceElement toElement() throws InvalidObjectException {
+        try {
+            if (MODULE_CONSTRUCTOR == null) {
+                return new java.lang.StackTraceElement(declaringClass, methodName, fileName, lineNumber);
+            }
+            // The format can't be given to the constructor, so leave out what it would hide instead
+            String loaderName = (format & BUILTIN_CLASS_LOADER) != 0 ? null : classLoaderName;
+            String version = (format & JDK_NON_UPGRADEABLE_MODULE) != 0 ? null : moduleVersion;
+            return MODULE_CONSTRUCTOR.newInstance(loaderName, moduleName, version,
+                    declaringClass, methodName, fileName, lineNumber);
+        } catch (Exception e) {
+            InvalidObjectException ex = new InvalidObjectException("Invalid stack trace element");
+            ex.initCause(e);
+            throw ex;
+        }
+    }
+}
@@ -0,0 +1,222 @@
+use bytecode::classfile::{Annotation, Attribute, ConstantPool, Field, Method};
+use bytecode::io::reader::ClassReader;
+use bytecode::visitor::{ClassHeader, ClassVisitor};
+use error::AgentError;
+use jni_sys::{JNIEnv, JNI_FALSE, jmethodID, jobject, jweak};
+use jvmti::Jvmti;
+use std::collections::HashMap;
+use std::io::Error;
+use std::ptr;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicPtr, Ordering};
+
+// Only set when annotations are scanned, never changed after load
+static ANNOTATION_INDEX: AtomicPtr<AnnotationIndex> = AtomicPtr::new(0 as *mut AnnotationIndex);
+
+// Matched by simple name so any package's annotation works, e.g. "Lcom/foo/Sensitive;"
+const SENSITIVE_NAME: &'static str = "Sensitive";
+
+const NO_STACK_PARAMS_NAME: &'static str = "NoStackParams";
+
+///
+/// The stack param annotations found on loaded classes. Classes without any aren't kept.
+pub struct AnnotationIndex {
+    // Keyed by internal class name, e.g. "com/foo/Service", with one entry per loader that defined a
+    // class by that name with annotations
+    classes: Mutex<HashMap<String, Vec<LoaderClass>>>,
+}
+
+// The annotations of a class along with its defining loader, held weakly so it can still be
+// unloaded. The loader is null for the bootstrap loader.
+struct LoaderClass {
+    loader: jweak,
+    class: ClassAnnotations,
+}
+
+// Weak global refs can be used from any thread
+unsafe impl Send for LoaderClass {}
+
+#[derive(Default)]
+struct ClassAnnotations {
+    no_params: bool,
+    // Keyed by method name and descriptor together, e.g. "login(Ljava/lang/String;)V"
+    methods: HashMap<String, MethodAnnotations>,
+}
+
+/// What the annotations say about capturing a method's params
+#[derive(Clone, Default)]
+pub struct MethodAnnotations {
+    /// The method or its class is marked @NoStackParams
+    pub no_params: bool,
+    /// The params marked @Sensitive by their place in the descriptor, 0 being the first after "this"
+    pub sensitive_params: Vec<usize>,
+}
+
+impl AnnotationIndex {
+    pub fn new() -> AnnotationIndex {
+        return AnnotationIndex { classes: Mutex::new(HashMap::new()) };
+    }
+
+    /// The index set at load or `None` if annotations aren't scanned
+    pub fn global() -> Option<&'static AnnotationIndex> {
+        let index = ANNOTATION_INDEX.load(Ordering::SeqCst);
+        return if index.is_null() { None } else { Some(unsafe { &*index }) };
+    }
+
+    /// Turns scanning on with this index for the rest of the VM's life
+    pub fn set_global(self) {
+        ANNOTATION_INDEX.store(Box::into_raw(Box::new(self)), Ordering::SeqCst);
+    }
+
+    /// Records the annotations of the class being loaded by the given loader, if it has any
+    pub unsafe fn scan(&self,
+                       jni_env: *mut JNIEnv,
+                       loader: jobject,
+                       class_name: &str,
+                       class_bytes: &[u8]) -> Result<(), AgentError> {
+        // Most classes have neither, so skip parsing them
+        if !contains(class_bytes, SENSITIVE_NAME.as_bytes()) && !contains(class_bytes, NO_STACK_PARAMS_NAME.as_bytes()) {
+            return Result::Ok(());
+        }
+        let mut scanner = AnnotationScanner { class: ClassAnnotations::default() };
+        ClassReader::accept(class_bytes, &mut scanner)?;
+        if scanner.class.no_params || !scanner.class.methods.is_empty() {
+            trace!("Found stack param annotations on {}", class_name);
+            let weak = if loader.is_null() { ptr::null_mut() } else { (**jni_env).NewWeakGlobalRef.unwrap()(jni_env, loader) };
+            let mut classes = self.classes.lock().unwrap();
+            let entries = classes.entry(class_name.to_string()).or_insert_with(Vec::new);
+            // Those of unloaded loaders can go now that the name is being defined again
+            entries.retain(|entry| {
+                let collected = !entry.loader.is_null() &&
+                    (**jni_env).IsSameObject.unwrap()(jni_env, entry.loader, ptr::null_mut()) != JNI_FALSE;
+                if collected { (**jni_env).DeleteWeakGlobalRef.unwrap()(jni_env, entry.loader); }
+                !collected
+            });
+            entries.push(LoaderClass { loader: weak, class: scanner.class });
+        }
+        return Result::Ok(());
+    }
+
+    /// The annotations for the method, or `None` if it and its class have none
+    pub unsafe fn for_method(&self, jvmti: Jvmti, jni_env: *mut JNIEnv, method: jmethodID) -> Result<Option<MethodAnnotations>, AgentError> {
+        if self.classes.lock().unwrap().is_empty() {
+            return Result::Ok(None);
+        }
+        let method_key = format!("{}{}", jvmti.method_name(method)?, jvmti.method_signature(method)?);
+        let class = jvmti.method_declaring_class(method)?;
+        let class_info = jvmti.class_signature(class).and_then(|sig| jvmti.class_loader(class).map(|loader| (sig, loader)));
+        (**jni_env).DeleteLocalRef.unwrap()(jni_env, class);
+        let (class_sig, loader) = class_info?;
+        // The index has "com/foo/Service" for "Lcom/foo/Service;"
+        let class_name = if class_sig.len() > 2 { &class_sig[1..class_sig.len() - 1] } else { &class_sig[..] };
+        let classes = self.classes.lock().unwrap();
+        let found = classes.get(class_name).and_then(|entries| entries.iter().find(|entry| {
+            if entry.loader.is_null() || loader.is_null() {
+                entry.loader.is_null() && loader.is_null()
+            } else {
+                (**jni_env).IsSameObject.unwrap()(jni_env, entry.loader, loader) != JNI_FALSE
+            }
+        }));
+        if !loader.is_null() { (**jni_env).DeleteLocalRef.unwrap()(jni_env, loader); }
+        return Result::Ok(found.and_then(|entry| {
+            let class = &entry.class;
+            let mut ret = class.methods.get(&method_key).cloned();
+            if class.no_params {
+                ret.get_or_insert_with(MethodAnnotations::default).no_params = true;
+            }
+            ret
+        }));
+    }
+}
+
+/// Collects the annotations of a class without writing anything
+struct AnnotationScanner {
+    class: ClassAnnotations,
+}
+
+impl<'a> ClassVisitor<'a> for AnnotationScanner {
+    fn visit_header(&mut self, _cp: &mut ConstantPool<'a>, _header: ClassHeader) -> Result<(), Error> {
+        return Result::Ok(());
+    }
+
+    fn visit_field(&mut self, _cp: &mut ConstantPool<'a>, _field: Field<'a>) -> Result<(), Error> {
+        return Result::Ok(());
+    }
+
+    fn visit_method(&mut self, cp: &mut ConstantPool<'a>, method: Method<'a>) -> Result<(), Error> {
+        let name = cp.get_utf8_string(method.name_index.idx as u16).unwrap_or_default();
+        let desc = cp.get_utf8_string(method.descriptor_index.idx as u16).unwrap_or_default();
+        let mut annotations = MethodAnnotations::default();
+        for attr in method.attributes.iter() {
+            match attr {
+                &Attribute::RuntimeVisibleAnnotations(ref table) | &Attribute::RuntimeInvisibleAnnotations(ref table) => {
+                    if has_annotation(cp, table, NO_STACK_PARAMS_NAME) { annotations.no_params = true; }
+                },
+                &Attribute::RuntimeVisibleParameterAnnotations(ref params) |
+                &Attribute::RuntimeInvisibleParameterAnnotations(ref params) => {
+                    // javac leaves out leading synthetic params, e.g. the outer instance of an inner
+                    // class constructor, so the annotated ones are the last of the descriptor's
+                    let first_index = descriptor_param_count(&desc).saturating_sub(params.len());
+                    for (index, table) in params.iter().enumerate() {
+                        let index = first_index + index;
+                        if has_annotation(cp, table, SENSITIVE_NAME) && !annotations.sensitive_params.contains(&index) {
+                            annotations.sensitive_params.push(index);
+                        }
+                    }
+                },
+                _ => (),
+            }
+        }
+        if annotations.no_params || !annotations.sensitive_params.is_empty() {
+            self.class.methods.insert(format!("{}{}", name, desc), annotations);
+        }
+        return Result::Ok(());
+    }
+
+    fn visit_attribute(&mut self, cp: &mut ConstantPool<'a>, attribute: Attribute<'a>) -> Result<(), Error> {
+        match attribute {
+            Attribute::RuntimeVisibleAnnotations(ref table) | Attribute::RuntimeInvisibleAnnotations(ref table) => {
+                if has_annotation(cp, table, NO_STACK_PARAMS_NAME) { self.class.no_params = true; }
+            },
+            _ => (),
+        }
+        return Result::Ok(());
+    }
+
+    fn visit_end(&mut self, _cp: &mut ConstantPool<'a>) -> Result<(), Error> {
+        return Result::Ok(());
+    }
+}
+
+fn has_annotation(cp: &ConstantPool, table: &Vec<Annotation>, simple_name: &str) -> bool {
+    return table.iter().any(|annotation| {
+        match cp.get_utf8_string(annotation.type_index.idx as u16) {
+            // E.g. "Lcom/foo/Outer$Sensitive;"
+            Some(ref desc) if desc.len() > 2 =>
+                desc[1..desc.len() - 1].rsplit(|c| c == '/' || c == '$').next() == Some(simple_name),
+            Some(_) => false,
+            None => false,
+        }
+    });
+}
+
+/// How many params the method descriptor has, e.g. 2 for "(J[Ljava/lang/String;)V"
+fn descriptor_param_count(desc: &str) -> usize {
+    let params = if desc.starts_with('(') { &desc[1..] } else { desc };
+    let params = params.split(')').next().unwrap_or("");
+    let mut count = 0;
+    let mut chars = params.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '[' => continue,
+            'L' => { while chars.next().map_or(false, |c| c != ';') {} },
+            _ => (),
+        }
+        count += 1;
+    }
+    return count;
+}
+
+fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+    return haystack.windows(needle.len()).any(|window| window == needle);
+}
@@ -1,3 +1,7 @@
+use std::borrow::Cow;
+use super::io::reader::ClassReader;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 
 ///
 /// A `Classfile` represents a definition of a single JVM class or interface. Unlike the bytecode
@@ -5,26 +9,42 @@
 /// encoded in the type system instead. This approach may seem restrictive but it helps achieving
 /// bytecode safety.
 #[derive(Debug)]
-pub struct Classfile {
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Classfile<'a> {
     pub version: ClassfileVersion,
-    pub constant_pool: ConstantPool,
+    pub constant_pool: ConstantPool<'a>,
     pub access_flags: AccessFlags,
     pub this_class: ConstantPoolIndex,
     pub super_class: ConstantPoolIndex,
     pub interfaces: Vec<ConstantPoolIndex>,
-    pub fields: Vec<Field>,
-    pub methods: Vec<Method>,
-    pub attributes: Vec<Attribute>
+    pub fields: Vec<Field<'a>>,
+    pub methods: Vec<Method<'a>>,
+    pub attributes: Vec<Attribute<'a>>
 }
 
-impl Classfile {
+impl<'a> Classfile<'a> {
     /// Create a new classfile, initialised with sensible default values
-    pub fn new() -> Classfile {
+    pub fn new() -> Classfile<'a> {
         Classfile::default()
     }
+
+    /// Copy everything still borrowed from the class bytes so the classfile can outlive them
+    pub fn into_owned(self) -> Classfile<'static> {
+        Classfile {
+            version: self.version,
+            constant_pool: self.constant_pool.into_owned(),
+            access_flags: self.access_flags,
+            this_class: self.this_class,
+            super_class: self.super_class,
+            interfaces: self.interfaces,
+            fields: self.fields.into_iter().map(|f| f.into_owned()).collect(),
+            methods: self.methods.into_iter().map(|m| m.into_owned()).collect(),
+            attributes: self.attributes.into_iter().map(|a| a.into_owned()).collect()
+        }
+    }
 }
 
-impl Default for Classfile {
+impl<'a> Default for Classfile<'a> {
     fn default() -> Self {
         Classfile {
             version: ClassfileVersion::default(),
@@ -43,6 +63,7 @@ impl Default for Classfile {
 ///
 /// Describe a classfile version number.
 #[derive(Ord, PartialOrd, Eq, PartialEq, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct ClassfileVersion {
     pub minor_version: u16,
     pub major_version: u16
@@ -67,21 +88,22 @@ impl Default for ClassfileVersion {
 /// A `ConstantPool` is a table of various string and number literal constants that are referred
 /// within the substructures of the `Classfile`.
 #[derive(Debug)]
-pub struct ConstantPool {
-    pub constants: Vec<Constant>
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct ConstantPool<'a> {
+    pub constants: Vec<Constant<'a>>
 }
 
-impl ConstantPool {
-    pub fn new(constants: Vec<Constant>) -> ConstantPool {
+impl<'a> ConstantPool<'a> {
+    pub fn new(constants: Vec<Constant<'a>>) -> ConstantPool<'a> {
         ConstantPool {
             constants: constants
         }
     }
 
-    pub fn get_utf8(&self, idx: u16) -> Option<&Vec<u8>> {
+    pub fn get_utf8(&self, idx: u16) -> Option<&[u8]> {
         match self.constants.get(idx as usize) {
             Some(constant) => match constant {
-                &Constant::Utf8(ref bytes) => Some(bytes),
+                &Constant::Utf8(ref bytes) => Some(bytes.as_ref()),
                 _ => None
             },
             _ => None
@@ -90,7 +112,7 @@ impl ConstantPool {
 
     pub fn get_utf8_string(&self, idx: u16) -> Option<String> {
         match self.get_utf8(idx) {
-            Some(bytes) => match String::from_utf8(bytes.clone()) {
+            Some(bytes) => match String::from_utf8(bytes.to_vec()) {
                 Ok(string) => Some(string),
                 _ => None
             },
@@ -102,7 +124,7 @@ impl ConstantPool {
         for i in 0..self.constants.len() {
             match self.constants[i] {
                 Constant::Utf8(ref bytes) => {
-                    if bytes.as_slice() == utf8.as_bytes() {
+                    if bytes.as_ref() == utf8.as_bytes() {
                         return Some(i);
                     }
                 },
@@ -124,9 +146,13 @@ impl ConstantPool {
         //self.constants.iter().fold(0, |acc, x| acc + x.cp_size())
         self.constants.len()
     }
+
+    pub fn into_owned(self) -> ConstantPool<'static> {
+        ConstantPool::new(self.constants.into_iter().map(|c| c.into_owned()).collect())
+    }
 }
 
-impl Default for ConstantPool {
+impl<'a> Default for ConstantPool<'a> {
     fn default() -> Self {
         ConstantPool {
             constants: vec![]
@@ -135,6 +161,7 @@ impl Default for ConstantPool {
 }
 
 #[derive(Default, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct ConstantPoolIndex {
     pub idx: usize
 }
@@ -146,8 +173,9 @@ impl ConstantPoolIndex {
 }
 
 #[derive(Debug)]
-pub enum Constant {
-    Utf8(Vec<u8>),
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum Constant<'a> {
+    Utf8(Cow<'a, [u8]>),
     Integer(u32),
     Float(u32),
     Long(u64),
@@ -165,7 +193,7 @@ pub enum Constant {
     Placeholder
 }
 
-impl Constant {
+impl<'a> Constant<'a> {
     pub fn cp_size(&self) -> usize {
         match self {
             &Constant::Long(_) => 2,
@@ -174,9 +202,31 @@ impl Constant {
             _ => 1
         }
     }
+
+    pub fn into_owned(self) -> Constant<'static> {
+        match self {
+            Constant::Utf8(bytes) => Constant::Utf8(Cow::Owned(bytes.into_owned())),
+            Constant::Integer(value) => Constant::Integer(value),
+            Constant::Float(value) => Constant::Float(value),
+            Constant::Long(value) => Constant::Long(value),
+            Constant::Double(value) => Constant::Double(value),
+            Constant::Class(idx) => Constant::Class(idx),
+            Constant::FieldRef { class_index, name_and_type_index } => Constant::FieldRef { class_index: class_index, name_and_type_index: name_and_type_index },
+            Constant::MethodRef { class_index, name_and_type_index } => Constant::MethodRef { class_index: class_index, name_and_type_index: name_and_type_index },
+            Constant::InterfaceMethodRef { class_index, name_and_type_index } => Constant::InterfaceMethodRef { class_index: class_index, name_and_type_index: name_and_type_index },
+            Constant::String(idx) => Constant::String(idx),
+            Constant::NameAndType { name_index, descriptor_index } => Constant::NameAndType { name_index: name_index, descriptor_index: descriptor_index },
+            Constant::MethodHandle { reference_kind, reference_index } => Constant::MethodHandle { reference_kind: reference_kind, reference_index: reference_index },
+            Constant::MethodType(idx) => Constant::MethodType(idx),
+            Constant::InvokeDynamic { bootstrap_method_attr_index, name_and_type_index } => Constant::InvokeDynamic { bootstrap_method_attr_index: bootstrap_method_attr_index, name_and_type_index: name_and_type_index },
+            Constant::Unknown(tag) => Constant::Unknown(tag),
+            Constant::Placeholder => Constant::Placeholder
+        }
+    }
 }
 
 #[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum ReferenceKind {
     GetField = 1,
     GetStatic = 2,
@@ -223,6 +273,7 @@ impl ReferenceKind {
 }
 
 #[derive(Default, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct AccessFlags {
     pub flags: u16
 }
@@ -307,25 +358,50 @@ pub enum ParameterAccessFlags {
 }
 
 #[derive(Default, Debug)]
-pub struct Field {
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Field<'a> {
     pub access_flags: AccessFlags,
     pub name_index: ConstantPoolIndex,
     pub descriptor_index: ConstantPoolIndex,
-    pub attributes: Vec<Attribute>
+    pub attributes: Vec<Attribute<'a>>
+}
+
+impl<'a> Field<'a> {
+    pub fn into_owned(self) -> Field<'static> {
+        Field {
+            access_flags: self.access_flags,
+            name_index: self.name_index,
+            descriptor_index: self.descriptor_index,
+            attributes: self.attributes.into_iter().map(|a| a.into_owned()).collect()
+        }
+    }
 }
 
 #[derive(Default, Debug)]
-pub struct Method {
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Method<'a> {
     pub access_flags: AccessFlags,
     pub name_index: ConstantPoolIndex,
     pub descriptor_index: ConstantPoolIndex,
-    pub attributes: Vec<Attribute>
+    pub attributes: Vec<Attribute<'a>>
+}
+
+impl<'a> Method<'a> {
+    pub fn into_owned(self) -> Method<'static> {
+        Method {
+            access_flags: self.access_flags,
+            name_index: self.name_index,
+            descriptor_index: self.descriptor_index,
+            attributes: self.attributes.into_iter().map(|a| a.into_owned()).collect()
+        }
+    }
 }
 
 #[derive(Debug)]
-pub enum Attribute {
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum Attribute<'a> {
     ConstantValue(ConstantPoolIndex),
-    Code { max_stack: u16, max_locals: u16, code: Vec<Instruction>, exception_table: Vec<ExceptionHandler>, attributes: Vec<Attribute> },
+    Code { max_stack: u16, max_locals: u16, code: Code<'a>, exception_table: Vec<ExceptionHandler>, attributes: Vec<Attribute<'a>> },
     StackMapTable(Vec<StackMapFrame>),
     Exceptions(Vec<ConstantPoolIndex>),
     InnerClasses(Vec<InnerClass>),
@@ -333,7 +409,7 @@ pub enum Attribute {
     Synthetic,
     Signature(ConstantPoolIndex),
     SourceFile(ConstantPoolIndex),
-    SourceDebugExtension(Vec<u8>),
+    SourceDebugExtension(Cow<'a, [u8]>),
     LineNumberTable(Vec<LineNumberTable>),
     LocalVariableTable(Vec<LocalVariableTable>),
     LocalVariableTypeTable(Vec<LocalVariableTypeTable>),
@@ -347,10 +423,106 @@ pub enum Attribute {
     AnnotationDefault(ElementValue),
     BootstrapMethods(Vec<BootstrapMethod>),
     MethodParameters(Vec<MethodParameter>),
-    RawAttribute { name_index: ConstantPoolIndex, info: Vec<u8> }
+    RawAttribute { name_index: ConstantPoolIndex, info: Cow<'a, [u8]> }
 }
 
+impl<'a> Attribute<'a> {
+    pub fn into_owned(self) -> Attribute<'static> {
+        match self {
+            Attribute::ConstantValue(idx) => Attribute::ConstantValue(idx),
+            Attribute::Code { max_stack, max_locals, code, exception_table, attributes } => Attribute::Code {
+                max_stack: max_stack,
+                max_locals: max_locals,
+                code: code.into_owned(),
+                exception_table: exception_table,
+                attributes: attributes.into_iter().map(|a| a.into_owned()).collect()
+            },
+            Attribute::StackMapTable(table) => Attribute::StackMapTable(table),
+            Attribute::Exceptions(table) => Attribute::Exceptions(table),
+            Attribute::InnerClasses(table) => Attribute::InnerClasses(table),
+            Attribute::EnclosingMethod { class_index, method_index } => Attribute::EnclosingMethod { class_index: class_index, method_index: method_index },
+            Attribute::Synthetic => Attribute::Synthetic,
+            Attribute::Signature(idx) => Attribute::Signature(idx),
+            Attribute::SourceFile(idx) => Attribute::SourceFile(idx),
+            Attribute::SourceDebugExtension(bytes) => Attribute::SourceDebugExtension(Cow::Owned(bytes.into_owned())),
+            Attribute::LineNumberTable(table) => Attribute::LineNumberTable(table),
+            Attribute::LocalVariableTable(table) => Attribute::LocalVariableTable(table),
+            Attribute::LocalVariableTypeTable(table) => Attribute::LocalVariableTypeTable(table),
+            Attribute::Deprecated => Attribute::Deprecated,
+            Attribute::RuntimeVisibleAnnotations(table) => Attribute::RuntimeVisibleAnnotations(table),
+            Attribute::RuntimeInvisibleAnnotations(table) => Attribute::RuntimeInvisibleAnnotations(table),
+            Attribute::RuntimeVisibleParameterAnnotations(table) => Attribute::RuntimeVisibleParameterAnnotations(table),
+            Attribute::RuntimeInvisibleParameterAnnotations(table) => Attribute::RuntimeInvisibleParameterAnnotations(table),
+            Attribute::RuntimeVisibleTypeAnnotations(table) => Attribute::RuntimeVisibleTypeAnnotations(table),
+            Attribute::RuntimeInvisibleTypeAnnotations(table) => Attribute::RuntimeInvisibleTypeAnnotations(table),
+            Attribute::AnnotationDefault(value) => Attribute::AnnotationDefault(value),
+            Attribute::BootstrapMethods(table) => Attribute::BootstrapMethods(table),
+            Attribute::MethodParameters(table) => Attribute::MethodParameters(table),
+            Attribute::RawAttribute { name_index, info } => Attribute::RawAttribute { name_index: name_index, info: Cow::Owned(info.into_owned()) }
+        }
+    }
+}
+
+///
+/// The bytecode of a method. It is kept as the bytes it was read from until the instructions are
+/// asked for, so code nobody looks at is written back exactly as it was read.
 #[derive(Debug)]
+pub enum Code<'a> {
+    Raw(Cow<'a, [u8]>),
+    Decoded(Vec<Instruction>)
+}
+
+impl<'a> Code<'a> {
+    /// Returns the instructions for reading or changing, decoding them first if needed
+    pub fn instructions_mut(&mut self) -> &mut Vec<Instruction> {
+        let decoded = match self {
+            &mut Code::Raw(ref bytes) => Some(ClassReader::read_instructions(bytes)),
+            &mut Code::Decoded(_) => None
+        };
+        if let Some(instructions) = decoded {
+            *self = Code::Decoded(instructions);
+        }
+        match self {
+            &mut Code::Decoded(ref mut instructions) => instructions,
+            &mut Code::Raw(_) => unreachable!()
+        }
+    }
+
+    pub fn is_decoded(&self) -> bool {
+        match self {
+            &Code::Decoded(_) => true,
+            &Code::Raw(_) => false
+        }
+    }
+
+    pub fn into_owned(self) -> Code<'static> {
+        match self {
+            Code::Raw(bytes) => Code::Raw(Cow::Owned(bytes.into_owned())),
+            Code::Decoded(instructions) => Code::Decoded(instructions)
+        }
+    }
+}
+
+/// Code is always serialized as its instructions, as that is what anyone reading it wants to see
+#[cfg(feature = "serde")]
+impl<'a> Serialize for Code<'a> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error> where S: Serializer {
+        match self {
+            &Code::Raw(ref bytes) => ClassReader::read_instructions(bytes).serialize(serializer),
+            &Code::Decoded(ref instructions) => instructions.serialize(serializer)
+        }
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de, 'a> Deserialize<'de> for Code<'a> {
+    fn deserialize<D>(deserializer: D) -> Result<Code<'a>, D::Error> where D: Deserializer<'de> {
+        Vec::deserialize(deserializer).map(Code::Decoded)
+    }
+}
+
+#[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum StackMapFrame {
     SameFrame { tag: u8 },
     SameLocals1StackItemFrame { tag: u8, stack: VerificationType },
@@ -378,6 +550,7 @@ impl StackMapFrame {
 }
 
 #[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum VerificationType {
     Top,
     Integer,
@@ -401,6 +574,7 @@ impl VerificationType {
 }
 
 #[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct ExceptionHandler {
     pub start_pc: u16,
     pub end_pc: u16,
@@ -409,6 +583,7 @@ pub struct ExceptionHandler {
 }
 
 #[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct InnerClass {
     pub inner_class_info_index: ConstantPoolIndex,
     pub outer_class_info_index: ConstantPoolIndex,
@@ -417,12 +592,14 @@ pub struct InnerClass {
 }
 
 #[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct LineNumberTable {
     pub start_pc: u16,
     pub line_number: u16
 }
 
 #[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct LocalVariableTable {
     pub start_pc: u16,
     pub length: u16,
@@ -432,6 +609,7 @@ pub struct LocalVariableTable {
 }
 
 #[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct LocalVariableTypeTable {
     pub start_pc: u16,
     pub length: u16,
@@ -441,6 +619,7 @@ pub struct LocalVariableTypeTable {
 }
 
 #[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Annotation {
     pub type_index: ConstantPoolIndex,
     pub element_value_pairs: Vec<ElementValuePair>
@@ -454,6 +633,7 @@ impl Annotation {
 }
 
 #[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct ElementValuePair {
     pub element_name_index: ConstantPoolIndex,
     pub value: ElementValue
@@ -466,6 +646,7 @@ impl ElementValuePair {
 }
 
 #[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum ElementValue {
     ConstantValue(u8, ConstantPoolIndex),
     Enum { type_name_index: ConstantPoolIndex, const_name_index: ConstantPoolIndex },
@@ -487,6 +668,7 @@ impl ElementValue {
 }
 
 #[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct TypeAnnotation {
     pub target_info: TargetInfo,
     pub target_path: TypePath,
@@ -501,6 +683,7 @@ impl TypeAnnotation {
 }
 
 #[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum TargetInfo {
     TypeParameter { subtype: u8, idx: u8 },
     SuperType { idx: u16 },
@@ -547,6 +730,7 @@ impl TargetInfo {
 }
 
 #[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct TypePath {
     pub path: Vec<(TypePathKind, u8)>
 }
@@ -558,6 +742,7 @@ impl TypePath {
 }
 
 #[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum TypePathKind {
     Array, // Annotation is deeper in an array type
     Nested, // Annotation is deeper in a nested type
@@ -577,6 +762,7 @@ impl TypePathKind {
 }
 
 #[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct BootstrapMethod {
     pub bootstrap_method_ref: ConstantPoolIndex,
     pub bootstrap_arguments: Vec<ConstantPoolIndex>
@@ -586,6 +772,7 @@ impl BootstrapMethod {
 }
 
 #[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct MethodParameter {
     pub name_index: ConstantPoolIndex,
     pub access_flags: AccessFlags
@@ -597,6 +784,7 @@ impl MethodParameter {
 
 #[allow(non_camel_case_types)]
 #[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum Instruction {
     AALOAD,
     AASTORE,
@@ -816,11 +1004,15 @@ pub enum Instruction {
 }
 
 impl Instruction {
-    pub fn len(&self) -> usize {
+    /// Returns the number of bytes the instruction takes when it starts at the given code offset.
+    /// The offset decides the padding before a switch's operands; `wide` forms include the prefix.
+    pub fn len_at(&self, offset: usize) -> usize {
+        let padding = (4 - ((offset + 1) % 4)) % 4;
         match self {
             &Instruction::ALOAD(_) => 2,
             &Instruction::ANEWARRAY(_) => 3,
             &Instruction::ASTORE(_) => 2,
+            &Instruction::BIPUSH(_) => 2,
             &Instruction::CHECKCAST(_) => 3,
             &Instruction::DLOAD(_) => 2,
             &Instruction::DSTORE(_) => 2,
@@ -849,8 +1041,8 @@ impl Instruction {
             &Instruction::IINC(_, _) => 3,
             &Instruction::ILOAD(_) => 2,
             &Instruction::INSTANCEOF(_) => 3,
-            &Instruction::INVOKEDYNAMIC(_) => 3,
-            &Instruction::INVOKEINTERFACE(_, _) => 4,
+            &Instruction::INVOKEDYNAMIC(_) => 5,
+            &Instruction::INVOKEINTERFACE(_, _) => 5,
             &Instruction::INVOKESPECIAL(_) => 3,
             &Instruction::INVOKESTATIC(_) => 3,
             &Instruction::INVOKEVIRTUAL(_) => 3,
@@ -861,7 +1053,7 @@ impl Instruction {
             &Instruction::LDC_W(_) => 3,
             &Instruction::LDC2_W(_) => 3,
             &Instruction::LLOAD(_) => 2,
-            &Instruction::LOOKUPSWITCH(_, ref pairs) => { 5 + pairs.len() * 4 },
+            &Instruction::LOOKUPSWITCH(_, ref pairs) => 9 + padding + pairs.len() * 8,
             &Instruction::LSTORE(_) => 2,
             &Instruction::MULTIANEWARRAY(_, _) => 4,
             &Instruction::NEW(_) => 3,
@@ -870,20 +1062,20 @@ impl Instruction {
             &Instruction::PUTSTATIC(_) => 3,
             &Instruction::RET(_) => 2,
             &Instruction::SIPUSH(_) => 3,
-            &Instruction::TABLESWITCH(_, _, _, ref indices) => { 13 + (indices.len() * 4) },
-            &Instruction::IINC_W(_, _) => 9,
-            &Instruction::ILOAD_W(_) => 5,
-            &Instruction::FLOAD_W(_) => 5,
-            &Instruction::ALOAD_W(_) => 5,
-            &Instruction::LLOAD_W(_) => 5,
-            &Instruction::DLOAD_W(_) => 5,
-            &Instruction::ISTORE_W(_) => 5,
-            &Instruction::FSTORE_W(_) => 5,
-            &Instruction::ASTORE_W(_) => 5,
-            &Instruction::LSTORE_W(_) => 5,
-            &Instruction::DSTORE_W(_) => 5,
-            &Instruction::RET_W(_) => 5,
-            &Instruction::PADDED_INSTRUCTION(padding) => padding,
+            &Instruction::TABLESWITCH(_, _, _, ref indices) => 13 + padding + indices.len() * 4,
+            &Instruction::IINC_W(_, _) => 6,
+            &Instruction::ILOAD_W(_) => 4,
+            &Instruction::FLOAD_W(_) => 4,
+            &Instruction::ALOAD_W(_) => 4,
+            &Instruction::LLOAD_W(_) => 4,
+            &Instruction::DLOAD_W(_) => 4,
+            &Instruction::ISTORE_W(_) => 4,
+            &Instruction::FSTORE_W(_) => 4,
+            &Instruction::ASTORE_W(_) => 4,
+            &Instruction::LSTORE_W(_) => 4,
+            &Instruction::DSTORE_W(_) => 4,
+            &Instruction::RET_W(_) => 4,
+            &Instruction::PADDED_INSTRUCTION(size) => size,
             _ => 1
         }
     }
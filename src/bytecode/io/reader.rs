@@ -1,16 +1,32 @@
-use std::io::{ Cursor, Read, Error, ErrorKind };
+use std::borrow::Cow;
+use std::io::{ Read, Error, ErrorKind };
 use std::cell::Cell;
 use super::super::classfile::*;
+use super::super::visitor::*;
 
 pub struct ClassReader {
 }
 
+type ReadFn = for<'a> fn(&mut BlockReader<'a>, &ClassFragment<'a>) -> Result<ClassFragment<'a>, Error>;
+
 impl ClassReader {
 
-    pub fn read_class<T>(source: &mut T) -> Result<Classfile, Error> where T: Read {
-        let mut reader = BlockReader::new(source);
+    /// Reads a whole class from the given source. The returned class owns all of its data, see
+    /// `read_class_bytes` for reading a class that borrows from the bytes instead.
+    pub fn read_class<T>(source: &mut T) -> Result<Classfile<'static>, Error> where T: Read {
+        let mut bytes: Vec<u8> = vec![];
+
+        source.read_to_end(&mut bytes)?;
+
+        ClassReader::read_class_bytes(&bytes).map(|class| class.into_owned())
+    }
 
-        let fns: Vec<fn(&mut BlockReader, &ClassFragment) -> Result<ClassFragment, Error>> = vec![
+    /// Reads a whole class from the given bytes. UTF-8 constants and raw attribute bodies are
+    /// borrowed from the bytes instead of copied.
+    pub fn read_class_bytes<'a>(bytes: &'a [u8]) -> Result<Classfile<'a>, Error> {
+        let mut reader = BlockReader::new(bytes);
+
+        let fns: Vec<ReadFn> = vec![
             ClassReader::read_magic_bytes,
             ClassReader::read_classfile_version,
             ClassReader::read_constant_pool,
@@ -39,14 +55,68 @@ impl ClassReader {
         }
     }
 
-    fn read_magic_bytes(reader: &mut BlockReader, _: &ClassFragment) -> Result<ClassFragment, Error> {
+    /// Reads a class from the given bytes and hands it to the visitor part by part instead of
+    /// building a whole `Classfile`. Like `read_class_bytes`, the parts borrow from the bytes.
+    pub fn accept<'a>(bytes: &'a [u8], visitor: &mut ClassVisitor<'a>) -> Result<(), Error> {
+        let mut reader = BlockReader::new(bytes);
+
+        let fns: Vec<ReadFn> = vec![
+            ClassReader::read_magic_bytes,
+            ClassReader::read_classfile_version,
+            ClassReader::read_constant_pool,
+            ClassReader::read_access_flags,
+            ClassReader::read_this_class,
+            ClassReader::read_super_class,
+            ClassReader::read_interfaces
+        ];
+
+        let mut fragment = ClassFragment::default();
+        for read_fn in fns.iter() {
+            let cur_fragment = read_fn(&mut reader, &fragment)?;
+            fragment = fragment.merge(cur_fragment);
+        }
+
+        let header = ClassHeader {
+            version: fragment.version.take().unwrap_or(ClassfileVersion::default()),
+            access_flags: fragment.access_flags.take().unwrap_or(AccessFlags::new()),
+            this_class: fragment.this_class.take().unwrap_or(ConstantPoolIndex::default()),
+            super_class: fragment.super_class.take().unwrap_or(ConstantPoolIndex::default()),
+            interfaces: fragment.interfaces.take().unwrap_or(vec![])
+        };
+        visitor.visit_header(fragment.constant_pool_mut(), header)?;
+
+        let fields_len = reader.read_u16()?;
+        for _ in 0..fields_len {
+            let field = ClassReader::read_field(&mut reader, &fragment)?;
+            visitor.visit_field(fragment.constant_pool_mut(), field)?;
+        }
+
+        let methods_len = reader.read_u16()?;
+        for _ in 0..methods_len {
+            let method = ClassReader::read_method(&mut reader, &fragment)?;
+            visitor.visit_method(fragment.constant_pool_mut(), method)?;
+        }
+
+        for attribute in ClassReader::read_attributes(&mut reader, &fragment)? {
+            visitor.visit_attribute(fragment.constant_pool_mut(), attribute)?;
+        }
+
+        visitor.visit_end(fragment.constant_pool_mut())
+    }
+
+    /// Decodes the instructions of a method body
+    pub fn read_instructions(bytes: &[u8]) -> Vec<Instruction> {
+        ClassReader::parse_code(bytes.len(), &mut BlockReader::new(bytes))
+    }
+
+    fn read_magic_bytes<'a>(reader: &mut BlockReader<'a>, _: &ClassFragment<'a>) -> Result<ClassFragment<'a>, Error> {
         match reader.read_u32() {
             Ok(0xCAFEBABE) => Ok(ClassFragment::default()),
             _ => Err(Error::new(ErrorKind::InvalidData, "Invalid magic bytes"))
         }
     }
 
-    fn read_classfile_version(reader: &mut BlockReader, _: &ClassFragment) -> Result<ClassFragment, Error> {
+    fn read_classfile_version<'a>(reader: &mut BlockReader<'a>, _: &ClassFragment<'a>) -> Result<ClassFragment<'a>, Error> {
         match (reader.read_u16(), reader.read_u16()) {
             (Ok(minor_version), Ok(major_version)) => {
                 Ok(ClassFragment {
@@ -58,10 +128,10 @@ impl ClassReader {
         }
     }
 
-    fn read_constant_pool(reader: &mut BlockReader, _: &ClassFragment) -> Result<ClassFragment, Error> {
+    fn read_constant_pool<'a>(reader: &mut BlockReader<'a>, _: &ClassFragment<'a>) -> Result<ClassFragment<'a>, Error> {
         match reader.read_u16() {
             Ok(cp_len) => {
-                let mut constants: Vec<Constant> = vec![ Constant::Placeholder ];
+                let mut constants: Vec<Constant<'a>> = vec![ Constant::Placeholder ];
 
                 for _ in 1..cp_len {
                     if constants.len() < cp_len as usize {
@@ -89,14 +159,14 @@ impl ClassReader {
         }
     }
 
-    fn read_constant(reader: &mut BlockReader) -> Result<Constant, Error> {
+    fn read_constant<'a>(reader: &mut BlockReader<'a>) -> Result<Constant<'a>, Error> {
         let tag = reader.read_u8();
 
         match tag {
             Ok(1) => match reader.read_u16() {
                 Ok(str_len) => match reader.read_n(str_len as usize) {
                     Ok(bytes) => {
-                        Ok(Constant::Utf8(bytes))
+                        Ok(Constant::Utf8(Cow::Borrowed(bytes)))
                     },
                     Err(err) => Err(err)
                 },
@@ -138,7 +208,7 @@ impl ClassReader {
         }
     }
 
-    fn read_access_flags(reader: &mut BlockReader, _: &ClassFragment) -> Result<ClassFragment, Error> {
+    fn read_access_flags<'a>(reader: &mut BlockReader<'a>, _: &ClassFragment<'a>) -> Result<ClassFragment<'a>, Error> {
         match reader.read_u16() {
             Ok(val) => Ok(ClassFragment {
                 access_flags: Some(AccessFlags::of(val)),
@@ -148,7 +218,7 @@ impl ClassReader {
         }
     }
 
-    fn read_this_class(reader: &mut BlockReader, _: &ClassFragment) -> Result<ClassFragment, Error> {
+    fn read_this_class<'a>(reader: &mut BlockReader<'a>, _: &ClassFragment<'a>) -> Result<ClassFragment<'a>, Error> {
         match ClassReader::read_constant_pool_index(reader) {
             Ok(idx) => Ok(ClassFragment {
                 this_class: Some(idx),
@@ -158,7 +228,7 @@ impl ClassReader {
         }
     }
 
-    fn read_super_class(reader: &mut BlockReader, _: &ClassFragment) -> Result<ClassFragment, Error> {
+    fn read_super_class<'a>(reader: &mut BlockReader<'a>, _: &ClassFragment<'a>) -> Result<ClassFragment<'a>, Error> {
         match ClassReader::read_constant_pool_index(reader) {
             Ok(idx) => Ok(ClassFragment {
                 super_class: Some(idx),
@@ -168,7 +238,7 @@ impl ClassReader {
         }
     }
 
-    fn read_interfaces(reader: &mut BlockReader, _: &ClassFragment) -> Result<ClassFragment, Error> {
+    fn read_interfaces<'a>(reader: &mut BlockReader<'a>, _: &ClassFragment<'a>) -> Result<ClassFragment<'a>, Error> {
         match reader.read_u16() {
             Ok(ifs_len) => {
                 (0..ifs_len).fold(Ok(vec![]), |acc, _| {
@@ -191,7 +261,7 @@ impl ClassReader {
         })
     }
 
-    fn read_fields(reader: &mut BlockReader, cf: &ClassFragment) -> Result<ClassFragment, Error> {
+    fn read_fields<'a>(reader: &mut BlockReader<'a>, cf: &ClassFragment<'a>) -> Result<ClassFragment<'a>, Error> {
         match reader.read_u16() {
             Ok(fields_len) => {
                 (0..fields_len).fold(Ok(vec![]), |acc, _| {
@@ -214,7 +284,7 @@ impl ClassReader {
         })
     }
 
-    fn read_field(reader: &mut BlockReader, cf: &ClassFragment) -> Result<Field, Error> {
+    fn read_field<'a>(reader: &mut BlockReader<'a>, cf: &ClassFragment<'a>) -> Result<Field<'a>, Error> {
         match ClassReader::require_n(reader, 6, |mut r| { (r.get_u16(), r.get_u16(), r.get_u16()) }) {
             Ok((flags, n_idx, d_idx)) => match ClassReader::read_attributes(reader, cf) {
                 Ok(attributes) => Ok(Field {
@@ -229,7 +299,7 @@ impl ClassReader {
         }
     }
 
-    fn read_methods(reader: &mut BlockReader, cf: &ClassFragment) -> Result<ClassFragment, Error> {
+    fn read_methods<'a>(reader: &mut BlockReader<'a>, cf: &ClassFragment<'a>) -> Result<ClassFragment<'a>, Error> {
         match reader.read_u16() {
             Ok(methods_len) => {
                 (0..methods_len).fold(Ok(vec![]), |acc, _| {
@@ -252,7 +322,7 @@ impl ClassReader {
         })
     }
 
-    fn read_method(reader: &mut BlockReader, cf: &ClassFragment) -> Result<Method, Error> {
+    fn read_method<'a>(reader: &mut BlockReader<'a>, cf: &ClassFragment<'a>) -> Result<Method<'a>, Error> {
         match ClassReader::require_n(reader, 6, |mut r| { (r.get_u16(), r.get_u16(), r.get_u16()) }) {
             Ok((flags, n_idx, d_idx)) => match ClassReader::read_attributes(reader, cf) {
                 Ok(attributes) => Ok(Method {
@@ -267,7 +337,7 @@ impl ClassReader {
         }
     }
 
-    fn read_class_attributes(reader: &mut BlockReader, cf: &ClassFragment) -> Result<ClassFragment, Error> {
+    fn read_class_attributes<'a>(reader: &mut BlockReader<'a>, cf: &ClassFragment<'a>) -> Result<ClassFragment<'a>, Error> {
         match ClassReader::read_attributes(reader, cf) {
             Ok(attributes) => Ok(ClassFragment {
                 attributes: Some(attributes),
@@ -277,7 +347,7 @@ impl ClassReader {
         }
     }
 
-    fn read_attributes(reader: &mut BlockReader, cf: &ClassFragment) -> Result<Vec<Attribute>, Error> {
+    fn read_attributes<'a>(reader: &mut BlockReader<'a>, cf: &ClassFragment<'a>) -> Result<Vec<Attribute<'a>>, Error> {
         match reader.read_u16() {
             Ok(attr_len) => (0..attr_len).fold(Ok(vec![]), |acc, _| {
                 match acc {
@@ -295,11 +365,11 @@ impl ClassReader {
         }
     }
 
-    fn read_attribute(reader: &mut BlockReader, cf: &ClassFragment) -> Result<Attribute, Error> {
+    fn read_attribute<'a>(reader: &mut BlockReader<'a>, cf: &ClassFragment<'a>) -> Result<Attribute<'a>, Error> {
         match reader.read_u16() {
             Ok(n_idx) => match reader.read_u32() {
                 Ok(a_len) => match reader.read_n(a_len as usize) {
-                    Ok(mut bytes) => Ok(ClassReader::parse_attribute(n_idx, BlockReader::new(&mut Cursor::new(&mut bytes)), cf)),
+                    Ok(bytes) => Ok(ClassReader::parse_attribute(n_idx, BlockReader::new(bytes), cf)),
                     Err(err) => Err(err)
                 },
                 Err(err) => Err(err)
@@ -308,7 +378,7 @@ impl ClassReader {
         }
     }
 
-    fn parse_code(len: usize, reader: &mut BlockReader) -> Vec<Instruction> {
+    fn parse_code<'a>(len: usize, reader: &mut BlockReader<'a>) -> Vec<Instruction> {
         /*
         let read_bytes: Cell<usize> = Cell::new(0);
 
@@ -328,7 +398,7 @@ impl ClassReader {
         }).collect()
     }
 
-    fn parse_instruction(reader: &mut BlockReader, current_offset: usize) -> Instruction {
+    fn parse_instruction<'a>(reader: &mut BlockReader<'a>, current_offset: usize) -> Instruction {
         let opcode = reader.get_u8();
 
         let instruction = match opcode {
@@ -582,7 +652,7 @@ impl ClassReader {
         instruction
     }
 
-    fn parse_attribute(idx: u16, mut reader: BlockReader, cf: &ClassFragment) -> Attribute {
+    fn parse_attribute<'a>(idx: u16, mut reader: BlockReader<'a>, cf: &ClassFragment<'a>) -> Attribute<'a> {
         match cf.constant_pool {
             Some(ref cp) => match cp.get_utf8_string(idx) {
                 Some(ref s) => match s.as_str() {
@@ -592,7 +662,7 @@ impl ClassReader {
                         max_locals: reader.get_u16(),
                         code: {
                             let n = reader.get_u32() as usize;
-                            ClassReader::parse_code(n, &mut BlockReader::new(&mut Cursor::new(reader.get_n(n as usize))))
+                            Code::Raw(Cow::Borrowed(reader.get_n(n)))
                         },
                         exception_table: {
                             let n = reader.get_u16();
@@ -653,7 +723,7 @@ impl ClassReader {
                     "Synthetic" => Some(Attribute::Synthetic),
                     "Signature" => Some(Attribute::Signature(ConstantPoolIndex::new(reader.get_u16() as usize))),
                     "SourceFile" => Some(Attribute::SourceFile(ConstantPoolIndex::new(reader.get_u16() as usize))),
-                    "SourceDebugExtension" => Some(Attribute::SourceDebugExtension(reader.get_bytes())),
+                    "SourceDebugExtension" => Some(Attribute::SourceDebugExtension(Cow::Borrowed(reader.get_bytes()))),
                     "LineNumberTable" => Some(Attribute::LineNumberTable({
                         let n = reader.get_u16();
                         (0..n).map(|_| LineNumberTable {
@@ -733,10 +803,10 @@ impl ClassReader {
                 _ => None
             },
             _ => None
-        }.unwrap_or(Attribute::RawAttribute { name_index: ConstantPoolIndex::new(idx as usize), info: reader.get_bytes() })
+        }.unwrap_or(Attribute::RawAttribute { name_index: ConstantPoolIndex::new(idx as usize), info: Cow::Borrowed(reader.get_bytes()) })
     }
 
-    fn read_annotation(reader: &mut BlockReader) -> Annotation {
+    fn read_annotation<'a>(reader: &mut BlockReader<'a>) -> Annotation {
         Annotation {
             type_index: ConstantPoolIndex::new(reader.get_u16() as usize),
             element_value_pairs: {
@@ -749,7 +819,7 @@ impl ClassReader {
         }
     }
 
-    fn read_type_annotation(reader: &mut BlockReader) -> TypeAnnotation {
+    fn read_type_annotation<'a>(reader: &mut BlockReader<'a>) -> TypeAnnotation {
         TypeAnnotation {
             target_info: match reader.get_u8() {
                 // 0x00 type parameter declaration of generic class or interface
@@ -815,7 +885,7 @@ impl ClassReader {
         }
     }
 
-    fn read_element_value(reader: &mut BlockReader) -> ElementValue {
+    fn read_element_value<'a>(reader: &mut BlockReader<'a>) -> ElementValue {
         let tag = reader.get_u8();
 
         match tag {
@@ -841,21 +911,16 @@ impl ClassReader {
         }
     }
 
-    fn read_constant_pool_index(reader: &mut BlockReader) -> Result<ConstantPoolIndex, Error> {
+    fn read_constant_pool_index<'a>(reader: &mut BlockReader<'a>) -> Result<ConstantPoolIndex, Error> {
         match reader.read_u16() {
             Ok(idx) => Ok(ConstantPoolIndex::new(idx as usize)),
             Err(err) => Err(err)
         }
     }
 
-    fn require_n<T, U>(reader: &mut BlockReader, count: usize, extractor: U) -> Result<T, Error> where U: Fn(BlockReader) -> T {
+    fn require_n<'a, T, U>(reader: &mut BlockReader<'a>, count: usize, extractor: U) -> Result<T, Error> where U: Fn(BlockReader<'a>) -> T {
         match reader.read_n(count) {
-            Ok(bytes) => {
-                let mut cursor = Cursor::new(bytes);
-                let r = BlockReader::new(&mut cursor);
-
-                Ok(extractor(r))
-            },
+            Ok(bytes) => Ok(extractor(BlockReader::new(bytes))),
             Err(err) => Err(err)
         }
     }
@@ -863,24 +928,29 @@ impl ClassReader {
 
 // TODO remove pub after testing
 pub struct BlockReader<'a> {
-    source: &'a mut Read,
+    source: &'a [u8],
     position: usize
 }
 
 impl<'a> BlockReader<'a> {
 
-    pub fn new<T>(source: &'a mut T) -> BlockReader where T: Read {
+    pub fn new(source: &'a [u8]) -> BlockReader<'a> {
         BlockReader { source: source, position: 0 }
     }
 
-    pub fn read_u64(&mut self) -> Result<u64, Error> {
-        let mut buf: [u8; 8] = [0; 8];
-
-        match self.source.read_exact(&mut buf) {
-            Ok(_) => {
-                self.position += 8;
+    fn read_exact(&mut self, count: usize) -> Result<&'a [u8], Error> {
+        if self.source.len() - self.position < count {
+            Err(Error::new(ErrorKind::UnexpectedEof, "Unexpected end of class data"))
+        } else {
+            let bytes = &self.source[self.position..self.position + count];
+            self.position += count;
+            Ok(bytes)
+        }
+    }
 
-                Ok(
+    pub fn read_u64(&mut self) -> Result<u64, Error> {
+        match self.read_exact(8) {
+            Ok(buf) => Ok(
                 ((buf[0] as u64) << 56) +
                 ((buf[1] as u64) << 48) +
                 ((buf[2] as u64) << 40) +
@@ -888,8 +958,7 @@ impl<'a> BlockReader<'a> {
                 ((buf[4] as u64) << 24) +
                 ((buf[5] as u64) << 16) +
                 ((buf[6] as u64) << 8) +
-                buf[7] as u64)
-            },
+                buf[7] as u64),
             Err(err) => Err(err)
         }
     }
@@ -900,17 +969,12 @@ impl<'a> BlockReader<'a> {
     }
 
     pub fn read_u32(&mut self) -> Result<u32, Error> {
-        let mut buf: [u8; 4] = [0; 4];
-
-        match self.source.read_exact(&mut buf) {
-            Ok(_) => {
-                self.position += 4;
-                Ok(
+        match self.read_exact(4) {
+            Ok(buf) => Ok(
                 ((buf[0] as u32) << 24) +
                 ((buf[1] as u32) << 16) +
                 ((buf[2] as u32) << 8) +
-                buf[3] as u32)
-            },
+                buf[3] as u32),
             Err(err) => Err(err)
         }
     }
@@ -921,13 +985,8 @@ impl<'a> BlockReader<'a> {
     }
 
     pub fn read_u16(&mut self) -> Result<u16, Error> {
-        let mut buf: [u8; 2] = [0; 2];
-
-        match self.source.read_exact(&mut buf) {
-            Ok(_) => {
-                self.position += 2;
-                Ok(((buf[0] as u16) << 8) + buf[1] as u16)
-            },
+        match self.read_exact(2) {
+            Ok(buf) => Ok(((buf[0] as u16) << 8) + buf[1] as u16),
             Err(err) => Err(err)
         }
     }
@@ -937,13 +996,8 @@ impl<'a> BlockReader<'a> {
     }
 
     pub fn read_u8(&mut self) -> Result<u8, Error> {
-        let mut buf: [u8; 1] = [0; 1];
-
-        match self.source.read_exact(&mut buf) {
-            Ok(_) => {
-                self.position += 1;
-                Ok(buf[0])
-            },
+        match self.read_exact(1) {
+            Ok(buf) => Ok(buf[0]),
             Err(err) => Err(err)
         }
     }
@@ -952,43 +1006,32 @@ impl<'a> BlockReader<'a> {
         self.read_u8().unwrap_or(0)
     }
 
-    pub fn read_n(&mut self, count: usize) -> Result<Vec<u8>, Error> {
-        let mut tmp: Vec<u8> = Vec::with_capacity(count);
+    /// Reads up to `count` bytes without copying them. Fewer bytes are returned if the source
+    /// ends first.
+    pub fn read_n(&mut self, count: usize) -> Result<&'a [u8], Error> {
+        let end = if self.source.len() - self.position < count { self.source.len() } else { self.position + count };
+        let bytes = &self.source[self.position..end];
 
-        match self.source.take(count as u64).read_to_end(&mut tmp) {
-            Ok(_) => {
-                self.position += count;
-                Ok(tmp)
-            },
-            Err(err) => Err(err)
-        }
+        self.position = end;
+        Ok(bytes)
     }
 
-    pub fn get_n(&mut self, count: usize) -> Vec<u8> {
+    pub fn get_n(&mut self, count: usize) -> &'a [u8] {
         match self.read_n(count) {
             Ok(bytes) => bytes,
-            Err(_) => vec![]
+            Err(_) => &[]
         }
     }
 
-    pub fn read_bytes(&mut self) -> Result<Vec<u8>, Error> {
-        let mut tmp: Vec<u8> = vec![];
+    pub fn read_bytes(&mut self) -> Result<&'a [u8], Error> {
+        let bytes = &self.source[self.position..];
 
-        match self.source.read_to_end(&mut tmp) {
-            Ok(_) => {
-                self.position += tmp.len();
-                Ok(tmp)
-            },
-            Err(err) => Err(err)
-        }
+        self.position = self.source.len();
+        Ok(bytes)
     }
 
-    pub fn get_bytes(&mut self) -> Vec<u8> {
-        let mut tmp: Vec<u8> = vec![];
-
-        let _ = self.source.read_to_end(&mut tmp);
-
-        tmp
+    pub fn get_bytes(&mut self) -> &'a [u8] {
+        self.read_bytes().unwrap_or(&[])
     }
 
     pub fn position(&self) -> usize {
@@ -997,19 +1040,19 @@ impl<'a> BlockReader<'a> {
 }
 
 
-struct ClassFragment {
+struct ClassFragment<'a> {
     pub version: Option<ClassfileVersion>,
-    pub constant_pool: Option<ConstantPool>,
+    pub constant_pool: Option<ConstantPool<'a>>,
     pub access_flags: Option<AccessFlags>,
     pub this_class: Option<ConstantPoolIndex>,
     pub super_class: Option<ConstantPoolIndex>,
     pub interfaces: Option<Vec<ConstantPoolIndex>>,
-    pub fields: Option<Vec<Field>>,
-    pub methods: Option<Vec<Method>>,
-    pub attributes: Option<Vec<Attribute>>
+    pub fields: Option<Vec<Field<'a>>>,
+    pub methods: Option<Vec<Method<'a>>>,
+    pub attributes: Option<Vec<Attribute<'a>>>
 }
 
-impl ClassFragment {
+impl<'a> ClassFragment<'a> {
     pub fn merge(mut self, other: Self) -> Self {
         self.version = other.version.or(self.version);
         self.constant_pool = other.constant_pool.or(self.constant_pool);
@@ -1023,9 +1066,13 @@ impl ClassFragment {
         self
     }
 
+    pub fn constant_pool_mut(&mut self) -> &mut ConstantPool<'a> {
+        self.constant_pool.get_or_insert_with(ConstantPool::default)
+    }
+
     /// Transform this class fragment into a final class file. Members set on the fragment will
     /// be defined on the class too, other members will be initialized with their default values
-    pub fn to_class(self) -> Classfile {
+    pub fn to_class(self) -> Classfile<'a> {
         Classfile {
             version: self.version.unwrap_or(ClassfileVersion::default()),
             constant_pool: self.constant_pool.unwrap_or(ConstantPool::default()),
@@ -1040,7 +1087,7 @@ impl ClassFragment {
     }
 }
 
-impl Default for ClassFragment {
+impl<'a> Default for ClassFragment<'a> {
     fn default() -> Self {
         ClassFragment {
             version: None,
@@ -1,13 +1,30 @@
 use std::io::{ Write, Error, ErrorKind };
+use std::mem;
 use super::super::classfile::*;
+use super::super::visitor::*;
 
 pub struct ClassWriter<'a> {
-    target: &'a mut Write
+    target: &'a mut Write,
+    visited: VisitedClass
+}
+
+/// The sections of a class seen so far when the writer is used as a `ClassVisitor`. Members are
+/// written as they come, but nothing reaches the target until the end of the visit because the
+/// constant pool goes first and may still grow.
+#[derive(Default)]
+struct VisitedClass {
+    header: Option<ClassHeader>,
+    field_count: u16,
+    fields: Vec<u8>,
+    method_count: u16,
+    methods: Vec<u8>,
+    attribute_count: u16,
+    attributes: Vec<u8>
 }
 
 impl<'a> ClassWriter<'a> {
     pub fn new<T>(target: &'a mut T) -> ClassWriter where T: Write {
-        ClassWriter { target: target }
+        ClassWriter { target: target, visited: VisitedClass::default() }
     }
 
     pub fn write_class(&mut self, classfile: &Classfile) -> Result<usize, Error> {
@@ -23,6 +40,16 @@ impl<'a> ClassWriter<'a> {
         .and(self.write_attributes(&classfile.attributes, &classfile.constant_pool))
     }
 
+    fn write_class_header(&mut self, header: &ClassHeader, cp: &ConstantPool) -> Result<usize, Error> {
+        self.write_magic_bytes()
+        .and(self.write_classfile_version(&header.version))
+        .and(self.write_constant_pool(cp))
+        .and(self.write_access_flags(&header.access_flags))
+        .and(self.write_constant_pool_index(&header.this_class))
+        .and(self.write_constant_pool_index(&header.super_class))
+        .and(self.write_interfaces(&header.interfaces))
+    }
+
     pub fn write_magic_bytes(&mut self) -> Result<usize, Error> {
         self.write_u32(0xCAFEBABE)
     }
@@ -132,7 +159,7 @@ impl<'a> ClassWriter<'a> {
 
                     let _ = code_writer.write_u16(max_stack)
                     .and(code_writer.write_u16(max_locals))
-                    .and(code_writer.write_instructions(code))
+                    .and(code_writer.write_code(code))
                     .and(code_writer.write_exception_handlers(exception_table))
                     .and(code_writer.write_attributes(attributes, cp));
                 }
@@ -356,6 +383,14 @@ impl<'a> ClassWriter<'a> {
         .and(annotation.element_value_pairs.iter().fold(Ok(0), |_, x| self.write_element_value_pair(x, cp)))
     }
 
+    fn write_code(&mut self, code: &Code) -> Result<usize, Error> {
+        match code {
+            // Code that was never decoded can't have changed
+            &Code::Raw(ref bytes) => self.write_u32(bytes.len() as u32).and_then(|x| self.write_n(bytes).map(|y| x + y)),
+            &Code::Decoded(ref instructions) => self.write_instructions(instructions)
+        }
+    }
+
     fn write_instructions(&mut self, instructions: &Vec<Instruction>) -> Result<usize, Error> {
         let mut target: Vec<u8> = vec![];
         let _ /*written_bytes*/ = {
@@ -639,11 +674,8 @@ impl<'a> ClassWriter<'a> {
             }))
     }
 
-    pub fn write_n(&mut self, bytes: &Vec<u8>) -> Result<usize, Error> {
-        bytes.iter().fold(Ok(0), |acc, x| match acc {
-            Ok(ctr) => self.write_u8(*x).map(|c| c + ctr),
-            err@_ => err
-        })
+    pub fn write_n(&mut self, bytes: &[u8]) -> Result<usize, Error> {
+        self.target.write_all(bytes).map(|_| bytes.len())
     }
 
     pub fn write_u64(&mut self, value: u64) -> Result<usize, Error> {
@@ -682,3 +714,38 @@ impl<'a> ClassWriter<'a> {
         self.target.write(&[value])
     }
 }
+
+impl<'a, 'c> ClassVisitor<'c> for ClassWriter<'a> {
+    fn visit_header(&mut self, _cp: &mut ConstantPool<'c>, header: ClassHeader) -> Result<(), Error> {
+        self.visited = VisitedClass { header: Some(header), ..Default::default() };
+        Ok(())
+    }
+
+    fn visit_field(&mut self, cp: &mut ConstantPool<'c>, field: Field<'c>) -> Result<(), Error> {
+        self.visited.field_count += 1;
+        ClassWriter::new(&mut self.visited.fields).write_field(&field, cp).map(|_| ())
+    }
+
+    fn visit_method(&mut self, cp: &mut ConstantPool<'c>, method: Method<'c>) -> Result<(), Error> {
+        self.visited.method_count += 1;
+        ClassWriter::new(&mut self.visited.methods).write_method(&method, cp).map(|_| ())
+    }
+
+    fn visit_attribute(&mut self, cp: &mut ConstantPool<'c>, attribute: Attribute<'c>) -> Result<(), Error> {
+        self.visited.attribute_count += 1;
+        ClassWriter::new(&mut self.visited.attributes).write_attribute(&attribute, cp).map(|_| ())
+    }
+
+    fn visit_end(&mut self, cp: &mut ConstantPool<'c>) -> Result<(), Error> {
+        let visited = mem::replace(&mut self.visited, VisitedClass::default());
+        let header = visited.header.ok_or(Error::new(ErrorKind::InvalidData, "No class header visited"))?;
+        self.write_class_header(&header, cp)
+        .and(self.write_u16(visited.field_count))
+        .and(self.write_n(&visited.fields))
+        .and(self.write_u16(visited.method_count))
+        .and(self.write_n(&visited.methods))
+        .and(self.write_u16(visited.attribute_count))
+        .and(self.write_n(&visited.attributes))
+        .map(|_| ())
+    }
+}
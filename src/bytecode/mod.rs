@@ -1,8 +1,12 @@
 pub use self::classfile::*;
 pub use self::io::*;
+pub use self::verifier::*;
+pub use self::visitor::*;
 
 pub mod classfile;
 pub mod io;
+pub mod verifier;
+pub mod visitor;
 
 /*
 
@@ -0,0 +1,1130 @@
+use std::collections::BTreeMap;
+use std::error;
+use std::fmt;
+use super::classfile::*;
+use super::io::reader::ClassReader;
+
+///
+/// A `ClassVerifier` type checks the code of a class the way the JVM does (JVMS 4.10.1) so that a
+/// broken transform can be caught before the class is handed to the JVM.
+///
+/// The class hierarchy isn't available while classes are being loaded, so a class or interface
+/// type is taken to be assignable to any other class or interface type. Everything else, from
+/// primitive, array and uninitialized types to stack map frames and exception handlers, is
+/// checked as the specification says. Classes older than version 50 carry no stack map frames
+/// and are not checked.
+pub struct ClassVerifier {
+}
+
+impl ClassVerifier {
+
+    pub fn verify(class: &Classfile) -> Result<(), VerifyError> {
+        let class_name = constant_class_name(&class.constant_pool, class.this_class.idx).map_err(|msg| VerifyError {
+            class_name: "<unknown>".to_string(),
+            method: None,
+            offset: None,
+            message: msg
+        })?;
+        if class.version.major_version < 50 {
+            return Ok(());
+        }
+        let super_class_name = if class.super_class.idx == 0 {
+            None
+        } else {
+            Some(constant_class_name(&class.constant_pool, class.super_class.idx).map_err(|msg| VerifyError {
+                class_name: class_name.clone(),
+                method: None,
+                offset: None,
+                message: msg
+            })?)
+        };
+        for method in class.methods.iter() {
+            ClassVerifier::verify_method(class, &class_name, &super_class_name, method)?;
+        }
+        Ok(())
+    }
+
+    fn verify_method(class: &Classfile,
+                     class_name: &String,
+                     super_class_name: &Option<String>,
+                     method: &Method) -> Result<(), VerifyError> {
+        let cp = &class.constant_pool;
+        let name = cp.get_utf8_string(method.name_index.idx as u16).unwrap_or("<unknown>".to_string());
+        let desc = cp.get_utf8_string(method.descriptor_index.idx as u16).unwrap_or("<unknown>".to_string());
+        let error = |offset: Option<usize>, message: String| VerifyError {
+            class_name: class_name.clone(),
+            method: Some(format!("{}{}", name, desc)),
+            offset: offset,
+            message: message
+        };
+
+        let code_attr = method.attributes.iter().find(|attr| match attr {
+            &&Attribute::Code { .. } => true,
+            _ => false
+        });
+        let has_body = !method.access_flags.has_flag(MethodAccessFlags::Abstract as u16) &&
+            !method.access_flags.has_flag(MethodAccessFlags::Native as u16);
+        let (max_stack, max_locals, code, exception_table, attributes) = match (code_attr, has_body) {
+            (None, false) => return Ok(()),
+            (Some(_), false) => return Err(error(None, "Abstract or native method has code".to_string())),
+            (None, true) => return Err(error(None, "Missing Code attribute".to_string())),
+            (Some(&Attribute::Code { max_stack, max_locals, ref code, ref exception_table, ref attributes }), true) =>
+                (max_stack, max_locals, code, exception_table, attributes),
+            (Some(_), true) => unreachable!()
+        };
+
+        let decoded;
+        let instructions = match code {
+            &Code::Raw(ref bytes) => {
+                decoded = ClassReader::read_instructions(bytes);
+                &decoded
+            },
+            &Code::Decoded(ref instructions) => instructions
+        };
+
+        let (params, return_type) = parse_method_descriptor(&desc)
+            .ok_or_else(|| error(None, format!("Invalid method descriptor {}", desc)))?;
+        let is_static = method.access_flags.has_flag(MethodAccessFlags::Static as u16);
+        let mut verifier = MethodVerifier {
+            cp: cp,
+            class_name: class_name.clone(),
+            super_class_name: super_class_name.clone(),
+            is_init: name == "<init>",
+            return_type: return_type,
+            max_stack: max_stack as usize,
+            max_locals: max_locals as usize,
+            offsets: vec![],
+            code_len: 0,
+            new_classes: BTreeMap::new(),
+            frames: BTreeMap::new()
+        };
+        verifier.verify(instructions, is_static, params, exception_table, attributes)
+            .map_err(|(offset, message)| error(offset, message))
+    }
+}
+
+///
+/// Describes why and where a class failed verification
+#[derive(Debug)]
+pub struct VerifyError {
+    pub class_name: String,
+    /// The name and descriptor of the failed method, if the failure is in a method
+    pub method: Option<String>,
+    /// The code offset of the failed instruction, if the failure is in an instruction
+    pub offset: Option<usize>,
+    pub message: String
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.class_name)?;
+        if let Some(ref method) = self.method {
+            write!(f, ".{}", method)?;
+        }
+        if let Some(offset) = self.offset {
+            write!(f, " at offset {}", offset)?;
+        }
+        write!(f, ": {}", self.message)
+    }
+}
+
+impl error::Error for VerifyError {
+    fn description(&self) -> &str {
+        &self.message
+    }
+}
+
+/// A verification type, see JVMS 4.10.1.2. Long and double values take two slots, the second one
+/// being `Top`.
+#[derive(Clone, PartialEq, Debug)]
+enum VType {
+    Top,
+    Integer,
+    Float,
+    Long,
+    Double,
+    Null,
+    UninitializedThis,
+    Uninitialized(usize),
+    /// A class, interface or array type as an internal name or array descriptor
+    Reference(String)
+}
+
+impl VType {
+    fn is_wide(&self) -> bool {
+        match self {
+            &VType::Long | &VType::Double => true,
+            _ => false
+        }
+    }
+
+    fn is_reference(&self) -> bool {
+        match self {
+            &VType::Null | &VType::UninitializedThis | &VType::Uninitialized(_) | &VType::Reference(_) => true,
+            _ => false
+        }
+    }
+
+    fn is_assignable_to(&self, other: &VType) -> bool {
+        match (self, other) {
+            (_, &VType::Top) => true,
+            (&VType::Null, &VType::Reference(_)) => true,
+            (&VType::Reference(ref from), &VType::Reference(ref to)) => is_reference_assignable(from, to),
+            (from, to) => from == to
+        }
+    }
+}
+
+impl fmt::Display for VType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &VType::Top => write!(f, "top"),
+            &VType::Integer => write!(f, "int"),
+            &VType::Float => write!(f, "float"),
+            &VType::Long => write!(f, "long"),
+            &VType::Double => write!(f, "double"),
+            &VType::Null => write!(f, "null"),
+            &VType::UninitializedThis => write!(f, "uninitializedThis"),
+            &VType::Uninitialized(offset) => write!(f, "uninitialized({})", offset),
+            &VType::Reference(ref name) => write!(f, "{}", name)
+        }
+    }
+}
+
+fn is_reference_assignable(from: &str, to: &str) -> bool {
+    if from == to || to == "java/lang/Object" {
+        return true;
+    }
+    match (from.starts_with('['), to.starts_with('[')) {
+        // Without the hierarchy, any class may implement or extend any other
+        (false, false) => true,
+        (false, true) => false,
+        (true, false) => to == "java/lang/Cloneable" || to == "java/io/Serializable",
+        (true, true) => match (descriptor_type(&from[1..]), descriptor_type(&to[1..])) {
+            (Some(VType::Reference(ref from)), Some(VType::Reference(ref to))) => is_reference_assignable(from, to),
+            // Primitive components have to match exactly
+            _ => false
+        }
+    }
+}
+
+/// Returns the verification type of a field descriptor
+fn descriptor_type(desc: &str) -> Option<VType> {
+    match desc.chars().next() {
+        Some('B') | Some('C') | Some('I') | Some('S') | Some('Z') if desc.len() == 1 => Some(VType::Integer),
+        Some('F') if desc.len() == 1 => Some(VType::Float),
+        Some('J') if desc.len() == 1 => Some(VType::Long),
+        Some('D') if desc.len() == 1 => Some(VType::Double),
+        Some('L') if desc.ends_with(';') && desc.len() > 2 => Some(VType::Reference(desc[1..desc.len() - 1].to_string())),
+        Some('[') if descriptor_type(&desc[1..]).is_some() => Some(VType::Reference(desc.to_string())),
+        _ => None
+    }
+}
+
+/// Returns the parameter types and return type, `None` for void, of a method descriptor
+fn parse_method_descriptor(desc: &str) -> Option<(Vec<VType>, Option<VType>)> {
+    if !desc.starts_with('(') {
+        return None;
+    }
+    let end = match desc.find(')') {
+        Some(end) => end,
+        None => return None
+    };
+    let mut params = vec![];
+    let mut rest = &desc[1..end];
+    while !rest.is_empty() {
+        let dims = rest.chars().take_while(|c| *c == '[').count();
+        let len = match rest[dims..].chars().next() {
+            Some('L') => match rest.find(';') {
+                Some(semi) => semi + 1,
+                None => return None
+            },
+            Some(_) => dims + 1,
+            None => return None
+        };
+        match descriptor_type(&rest[..len]) {
+            Some(param) => params.push(param),
+            None => return None
+        }
+        rest = &rest[len..];
+    }
+    let return_desc = &desc[end + 1..];
+    if return_desc == "V" {
+        Some((params, None))
+    } else {
+        descriptor_type(return_desc).map(|return_type| (params, Some(return_type)))
+    }
+}
+
+fn constant_class_name(cp: &ConstantPool, idx: usize) -> Result<String, String> {
+    match cp.constants.get(idx) {
+        Some(&Constant::Class(ref name_idx)) if idx > 0 => cp.get_utf8_string(name_idx.idx as u16)
+            .ok_or(format!("Invalid class name at constant {}", idx)),
+        _ => Err(format!("Expected class constant at {}", idx))
+    }
+}
+
+/// Expands a list of types so long and double values take two slots
+fn expand_types(types: &Vec<VType>) -> Vec<VType> {
+    let mut expanded = vec![];
+    for t in types.iter() {
+        expanded.push(t.clone());
+        if t.is_wide() {
+            expanded.push(VType::Top);
+        }
+    }
+    expanded
+}
+
+fn format_types(types: &Vec<VType>) -> String {
+    types.iter().map(|t| t.to_string()).collect::<Vec<String>>().join(", ")
+}
+
+#[derive(Clone, Debug)]
+struct Frame {
+    locals: Vec<VType>,
+    /// The operand stack, top last
+    stack: Vec<VType>
+}
+
+impl Frame {
+    fn is_assignable_to(&self, other: &Frame) -> bool {
+        self.locals.len() == other.locals.len() && self.stack.len() == other.stack.len() &&
+            self.locals.iter().zip(other.locals.iter()).all(|(from, to)| from.is_assignable_to(to)) &&
+            self.stack.iter().zip(other.stack.iter()).all(|(from, to)| from.is_assignable_to(to))
+    }
+}
+
+impl fmt::Display for Frame {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "locals [{}], stack [{}]", format_types(&self.locals), format_types(&self.stack))
+    }
+}
+
+/// What a member reference refers to
+enum MemberKind {
+    Field,
+    Method,
+    InterfaceMethod
+}
+
+/// Verifies the code of a single method
+struct MethodVerifier<'c> {
+    cp: &'c ConstantPool<'c>,
+    class_name: String,
+    super_class_name: Option<String>,
+    is_init: bool,
+    return_type: Option<VType>,
+    max_stack: usize,
+    max_locals: usize,
+    /// The code offset of each instruction
+    offsets: Vec<usize>,
+    code_len: usize,
+    /// The class created by each `new` instruction, by offset
+    new_classes: BTreeMap<usize, String>,
+    /// The stack map frames by offset
+    frames: BTreeMap<usize, Frame>
+}
+
+impl<'c> MethodVerifier<'c> {
+
+    fn verify(&mut self,
+              instructions: &Vec<Instruction>,
+              is_static: bool,
+              params: Vec<VType>,
+              exception_table: &Vec<ExceptionHandler>,
+              attributes: &Vec<Attribute>) -> Result<(), (Option<usize>, String)> {
+        if instructions.is_empty() {
+            return Err((None, "Method has no code".to_string()));
+        }
+        for instruction in instructions.iter() {
+            let offset = self.code_len;
+            self.offsets.push(offset);
+            self.code_len += instruction.len_at(offset);
+            if let &Instruction::NEW(idx) = instruction {
+                let class_name = self.class_name(idx as usize).map_err(|msg| (Some(offset), msg))?;
+                self.new_classes.insert(offset, class_name);
+            }
+        }
+
+        // Initial frame from the descriptor, before expanding to slots
+        let mut initial_locals = vec![];
+        if !is_static {
+            initial_locals.push(if self.is_init && self.class_name != "java/lang/Object" {
+                VType::UninitializedThis
+            } else {
+                VType::Reference(self.class_name.clone())
+            });
+        }
+        initial_locals.extend(params.into_iter());
+        let initial_frame = self.frame_of(&initial_locals, &vec![]).map_err(|msg| (None, msg))?;
+
+        for attr in attributes.iter() {
+            if let &Attribute::StackMapTable(ref table) = attr {
+                self.read_stack_map(table, initial_locals.clone()).map_err(|msg| (None, format!("Invalid stack map: {}", msg)))?;
+            }
+        }
+
+        for handler in exception_table.iter() {
+            self.check_handler(handler).map_err(|msg| (None, msg))?;
+        }
+
+        let mut current = Some(initial_frame);
+        for (idx, instruction) in instructions.iter().enumerate() {
+            let offset = self.offsets[idx];
+            if let Some(map_frame) = self.frames.get(&offset) {
+                if let Some(ref frame) = current {
+                    if !frame.is_assignable_to(map_frame) {
+                        return Err((Some(offset), format!("Type state ({}) is not assignable to stack map frame ({})", frame, map_frame)));
+                    }
+                }
+                current = Some(map_frame.clone());
+            }
+            let frame = current.take().ok_or((Some(offset), "Expecting a stack map frame after an unconditional branch".to_string()))?;
+            self.check_handlers_at(offset, &frame, exception_table)
+                .map_err(|msg| (Some(offset), format!("{} ({})", msg, frame)))?;
+            let next_offset = offset + instruction.len_at(offset);
+            current = self.execute(offset, next_offset, instruction, frame.clone())
+                .map_err(|msg| (Some(offset), format!("{:?}: {} ({})", instruction, msg, frame)))?;
+        }
+        if current.is_some() {
+            return Err((Some(self.code_len), "Falling off the end of the code".to_string()));
+        }
+        Ok(())
+    }
+
+    /// Makes a frame with the given unexpanded types
+    fn frame_of(&self, locals: &Vec<VType>, stack: &Vec<VType>) -> Result<Frame, String> {
+        let mut frame = Frame { locals: expand_types(locals), stack: expand_types(stack) };
+        if frame.locals.len() > self.max_locals {
+            return Err(format!("Locals [{}] exceed max locals of {}", format_types(&frame.locals), self.max_locals));
+        }
+        if frame.stack.len() > self.max_stack {
+            return Err(format!("Stack [{}] exceeds max stack of {}", format_types(&frame.stack), self.max_stack));
+        }
+        while frame.locals.len() < self.max_locals {
+            frame.locals.push(VType::Top);
+        }
+        Ok(frame)
+    }
+
+    fn read_stack_map(&mut self, table: &Vec<StackMapFrame>, mut locals: Vec<VType>) -> Result<(), String> {
+        let mut prev_offset: Option<usize> = None;
+        for map_frame in table.iter() {
+            let (delta, stack) = match map_frame {
+                &StackMapFrame::SameFrame { tag } => (tag as usize, vec![]),
+                &StackMapFrame::SameLocals1StackItemFrame { tag, ref stack } => ((tag - 64) as usize, vec![self.map_type(stack)?]),
+                &StackMapFrame::SameLocals1StackItemFrameExtended { offset_delta, ref stack } => (offset_delta as usize, vec![self.map_type(stack)?]),
+                &StackMapFrame::ChopFrame { tag, offset_delta } => {
+                    let chopped = (251 - tag) as usize;
+                    if chopped > locals.len() {
+                        return Err(format!("Cannot chop {} locals from [{}]", chopped, format_types(&locals)));
+                    }
+                    let len = locals.len() - chopped;
+                    locals.truncate(len);
+                    (offset_delta as usize, vec![])
+                },
+                &StackMapFrame::SameFrameExtended { offset_delta } => (offset_delta as usize, vec![]),
+                &StackMapFrame::AppendFrame { offset_delta, locals: ref appended, .. } => {
+                    for t in appended.iter() {
+                        locals.push(self.map_type(t)?);
+                    }
+                    (offset_delta as usize, vec![])
+                },
+                &StackMapFrame::FullFrame { offset_delta, locals: ref full_locals, ref stack } => {
+                    locals = vec![];
+                    for t in full_locals.iter() {
+                        locals.push(self.map_type(t)?);
+                    }
+                    let mut full_stack = vec![];
+                    for t in stack.iter() {
+                        full_stack.push(self.map_type(t)?);
+                    }
+                    (offset_delta as usize, full_stack)
+                },
+                &StackMapFrame::FutureUse { tag } => return Err(format!("Unknown frame type {}", tag))
+            };
+            let offset = match prev_offset {
+                None => delta,
+                Some(prev) => prev + delta + 1
+            };
+            if !self.is_instruction_start(offset) {
+                return Err(format!("Frame at {} is not at an instruction", offset));
+            }
+            let frame = self.frame_of(&locals, &stack).map_err(|msg| format!("Frame at {}: {}", offset, msg))?;
+            self.frames.insert(offset, frame);
+            prev_offset = Some(offset);
+        }
+        Ok(())
+    }
+
+    fn map_type(&self, t: &VerificationType) -> Result<VType, String> {
+        match t {
+            &VerificationType::Top => Ok(VType::Top),
+            &VerificationType::Integer => Ok(VType::Integer),
+            &VerificationType::Float => Ok(VType::Float),
+            &VerificationType::Long => Ok(VType::Long),
+            &VerificationType::Double => Ok(VType::Double),
+            &VerificationType::Null => Ok(VType::Null),
+            &VerificationType::UninitializedThis => Ok(VType::UninitializedThis),
+            &VerificationType::Object { ref cpool_index } => self.class_name(cpool_index.idx).map(VType::Reference),
+            &VerificationType::Uninitialized { offset } => if self.new_classes.contains_key(&(offset as usize)) {
+                Ok(VType::Uninitialized(offset as usize))
+            } else {
+                Err(format!("Uninitialized type at {} does not refer to a new instruction", offset))
+            }
+        }
+    }
+
+    fn is_instruction_start(&self, offset: usize) -> bool {
+        self.offsets.binary_search(&offset).is_ok()
+    }
+
+    fn check_handler(&self, handler: &ExceptionHandler) -> Result<(), String> {
+        let (start, end, target) = (handler.start_pc as usize, handler.end_pc as usize, handler.handler_pc as usize);
+        if start >= end || !self.is_instruction_start(start) || !(end == self.code_len || self.is_instruction_start(end)) {
+            return Err(format!("Invalid exception handler range {} to {}", start, end));
+        }
+        if !self.frames.contains_key(&target) {
+            return Err(format!("Expecting a stack map frame at exception handler {}", target));
+        }
+        if handler.catch_type.idx != 0 {
+            self.class_name(handler.catch_type.idx)?;
+        }
+        Ok(())
+    }
+
+    fn check_handlers_at(&self, offset: usize, frame: &Frame, exception_table: &Vec<ExceptionHandler>) -> Result<(), String> {
+        for handler in exception_table.iter() {
+            if offset < handler.start_pc as usize || offset >= handler.end_pc as usize {
+                continue;
+            }
+            let catch_type = if handler.catch_type.idx == 0 {
+                "java/lang/Throwable".to_string()
+            } else {
+                self.class_name(handler.catch_type.idx)?
+            };
+            let handler_frame = Frame { locals: frame.locals.clone(), stack: vec![VType::Reference(catch_type)] };
+            let target = &self.frames[&(handler.handler_pc as usize)];
+            if !handler_frame.is_assignable_to(target) {
+                return Err(format!("Type state is not assignable to the frame of exception handler {} ({})", handler.handler_pc, target));
+            }
+        }
+        Ok(())
+    }
+
+    fn check_branch(&self, offset: usize, relative: i32, frame: &Frame) -> Result<(), String> {
+        let target = offset as i64 + relative as i64;
+        if target < 0 || target as usize >= self.code_len || !self.is_instruction_start(target as usize) {
+            return Err(format!("Branch to invalid offset {}", target));
+        }
+        match self.frames.get(&(target as usize)) {
+            Some(target_frame) => if frame.is_assignable_to(target_frame) {
+                Ok(())
+            } else {
+                Err(format!("Type state is not assignable to the frame at branch target {} ({})", target, target_frame))
+            },
+            None => Err(format!("Expecting a stack map frame at branch target {}", target))
+        }
+    }
+
+    fn class_name(&self, idx: usize) -> Result<String, String> {
+        constant_class_name(self.cp, idx)
+    }
+
+    /// Returns the class name, member name and descriptor of a member reference
+    fn member(&self, idx: usize, kinds: &[MemberKind]) -> Result<(String, String, String), String> {
+        let (kind, class_idx, nat_idx) = match self.cp.constants.get(idx) {
+            Some(&Constant::FieldRef { ref class_index, ref name_and_type_index }) => (MemberKind::Field, class_index, name_and_type_index),
+            Some(&Constant::MethodRef { ref class_index, ref name_and_type_index }) => (MemberKind::Method, class_index, name_and_type_index),
+            Some(&Constant::InterfaceMethodRef { ref class_index, ref name_and_type_index }) => (MemberKind::InterfaceMethod, class_index, name_and_type_index),
+            _ => return Err(format!("Expected member reference at constant {}", idx))
+        };
+        if !kinds.iter().any(|k| match (k, &kind) {
+            (&MemberKind::Field, &MemberKind::Field) => true,
+            (&MemberKind::Method, &MemberKind::Method) => true,
+            (&MemberKind::InterfaceMethod, &MemberKind::InterfaceMethod) => true,
+            _ => false
+        }) {
+            return Err(format!("Wrong kind of member reference at constant {}", idx));
+        }
+        let class_name = self.class_name(class_idx.idx)?;
+        let (name, desc) = self.name_and_type(nat_idx.idx)?;
+        Ok((class_name, name, desc))
+    }
+
+    fn name_and_type(&self, idx: usize) -> Result<(String, String), String> {
+        match self.cp.constants.get(idx) {
+            Some(&Constant::NameAndType { ref name_index, ref descriptor_index }) => {
+                match (self.cp.get_utf8_string(name_index.idx as u16), self.cp.get_utf8_string(descriptor_index.idx as u16)) {
+                    (Some(name), Some(desc)) => Ok((name, desc)),
+                    _ => Err(format!("Invalid name and type at constant {}", idx))
+                }
+            },
+            _ => Err(format!("Expected name and type at constant {}", idx))
+        }
+    }
+
+    fn push(&self, frame: &mut Frame, t: VType) -> Result<(), String> {
+        let wide = t.is_wide();
+        frame.stack.push(t);
+        if wide {
+            frame.stack.push(VType::Top);
+        }
+        if frame.stack.len() > self.max_stack {
+            return Err(format!("Exceeded max stack of {}", self.max_stack));
+        }
+        Ok(())
+    }
+
+    /// Pops a value of the given type, returning the actual type
+    fn pop(&self, frame: &mut Frame, expected: &VType) -> Result<VType, String> {
+        if expected.is_wide() {
+            match frame.stack.pop() {
+                Some(VType::Top) => (),
+                _ => return Err(format!("Expected {} on the stack", expected))
+            }
+        }
+        match frame.stack.pop() {
+            Some(VType::Top) | None => Err(format!("Expected {} on the stack", expected)),
+            Some(actual) => if actual.is_assignable_to(expected) {
+                Ok(actual)
+            } else {
+                Err(format!("Expected {} on the stack, found {}", expected, actual))
+            }
+        }
+    }
+
+    /// Pops any reference, initialized or not
+    fn pop_reference(&self, frame: &mut Frame) -> Result<VType, String> {
+        match frame.stack.pop() {
+            Some(ref t) if t.is_reference() => Ok(t.clone()),
+            Some(t) => Err(format!("Expected reference on the stack, found {}", t)),
+            None => Err("Expected reference on the stack".to_string())
+        }
+    }
+
+    /// Pops an array reference or null, returning the array descriptor if not null
+    fn pop_array(&self, frame: &mut Frame) -> Result<Option<String>, String> {
+        match frame.stack.pop() {
+            Some(VType::Null) => Ok(None),
+            Some(VType::Reference(ref desc)) if desc.starts_with('[') => Ok(Some(desc.clone())),
+            Some(t) => Err(format!("Expected array on the stack, found {}", t)),
+            None => Err("Expected array on the stack".to_string())
+        }
+    }
+
+    /// Pops an array reference or null whose descriptor is one of the given
+    fn pop_array_of(&self, frame: &mut Frame, descs: &[&str]) -> Result<(), String> {
+        match self.pop_array(frame)? {
+            Some(ref desc) if !descs.contains(&desc.as_str()) => Err(format!("Expected {} array, found {}", descs.join(" or "), desc)),
+            _ => Ok(())
+        }
+    }
+
+    /// Checks that the given number of slots can be taken off the stack without splitting a long
+    /// or double value
+    fn check_slots(&self, frame: &Frame, count: usize) -> Result<(), String> {
+        if frame.stack.len() < count {
+            return Err("Stack underflow".to_string());
+        }
+        match frame.stack.get(frame.stack.len() - count) {
+            Some(&VType::Top) => Err("Operation splits a long or double value".to_string()),
+            _ => Ok(())
+        }
+    }
+
+    /// Duplicates the top slots and inserts them below the given number of further slots
+    fn dup(&self, frame: &mut Frame, count: usize, below: usize) -> Result<(), String> {
+        self.check_slots(frame, count)?;
+        self.check_slots(frame, count + below)?;
+        let len = frame.stack.len();
+        let dup: Vec<VType> = frame.stack[len - count..].to_vec();
+        for (i, t) in dup.into_iter().enumerate() {
+            frame.stack.insert(len - count - below + i, t);
+        }
+        if frame.stack.len() > self.max_stack {
+            return Err(format!("Exceeded max stack of {}", self.max_stack));
+        }
+        Ok(())
+    }
+
+    fn load(&self, frame: &mut Frame, index: usize, expected: &VType) -> Result<(), String> {
+        let actual = match frame.locals.get(index) {
+            Some(actual) => actual.clone(),
+            None => return Err(format!("Local {} exceeds max locals of {}", index, self.max_locals))
+        };
+        let valid = match expected {
+            &VType::Reference(_) => actual.is_reference(),
+            _ => actual == *expected && (!actual.is_wide() || frame.locals.get(index + 1) == Some(&VType::Top))
+        };
+        if !valid {
+            return Err(format!("Expected {} in local {}, found {}", expected, index, actual));
+        }
+        self.push(frame, actual)
+    }
+
+    fn store(&self, frame: &mut Frame, index: usize, expected: &VType) -> Result<(), String> {
+        let actual = match expected {
+            &VType::Reference(_) => self.pop_reference(frame)?,
+            _ => self.pop(frame, expected)?
+        };
+        let wide = actual.is_wide();
+        if index + if wide { 1 } else { 0 } >= self.max_locals {
+            return Err(format!("Local {} exceeds max locals of {}", index, self.max_locals));
+        }
+        if index > 0 && frame.locals[index - 1].is_wide() {
+            frame.locals[index - 1] = VType::Top;
+        }
+        frame.locals[index] = actual;
+        if wide {
+            frame.locals[index + 1] = VType::Top;
+        }
+        Ok(())
+    }
+
+    fn binary(&self, frame: &mut Frame, t: VType) -> Result<(), String> {
+        self.pop(frame, &t)?;
+        self.pop(frame, &t)?;
+        self.push(frame, t)
+    }
+
+    fn convert(&self, frame: &mut Frame, from: VType, to: VType) -> Result<(), String> {
+        self.pop(frame, &from)?;
+        self.push(frame, to)
+    }
+
+    fn invoke(&self, frame: &mut Frame, desc: &str) -> Result<Option<VType>, String> {
+        let (params, return_type) = parse_method_descriptor(desc).ok_or(format!("Invalid method descriptor {}", desc))?;
+        for param in params.iter().rev() {
+            self.pop(frame, param)?;
+        }
+        Ok(return_type)
+    }
+
+    fn check_return(&self, frame: &Frame, returned: Option<VType>) -> Result<Option<Frame>, String> {
+        match (&self.return_type, &returned) {
+            (&None, &None) => if self.is_init && frame.locals.contains(&VType::UninitializedThis) {
+                Err("Returning from constructor before this is initialized".to_string())
+            } else {
+                Ok(None)
+            },
+            (&Some(ref expected), &Some(ref actual)) if actual.is_assignable_to(expected) => Ok(None),
+            _ => Err(format!("Wrong return for method returning {}",
+                             self.return_type.as_ref().map(|t| t.to_string()).unwrap_or("void".to_string())))
+        }
+    }
+
+    /// Executes the instruction on the incoming frame, returning the frame for the next
+    /// instruction or `None` if it can't be fallen through to
+    fn execute(&self, offset: usize, next_offset: usize, instruction: &Instruction, mut frame: Frame) -> Result<Option<Frame>, String> {
+        let f = &mut frame;
+        match instruction {
+            &Instruction::NOP => (),
+            &Instruction::ACONST_NULL => self.push(f, VType::Null)?,
+            &Instruction::ICONST_M1 | &Instruction::ICONST_0 | &Instruction::ICONST_1 | &Instruction::ICONST_2 |
+            &Instruction::ICONST_3 | &Instruction::ICONST_4 | &Instruction::ICONST_5 |
+            &Instruction::BIPUSH(_) | &Instruction::SIPUSH(_) => self.push(f, VType::Integer)?,
+            &Instruction::LCONST_0 | &Instruction::LCONST_1 => self.push(f, VType::Long)?,
+            &Instruction::FCONST_0 | &Instruction::FCONST_1 | &Instruction::FCONST_2 => self.push(f, VType::Float)?,
+            &Instruction::DCONST_0 | &Instruction::DCONST_1 => self.push(f, VType::Double)?,
+            &Instruction::LDC(idx) => { let t = self.ldc_type(idx as usize, false)?; self.push(f, t)? },
+            &Instruction::LDC_W(idx) => { let t = self.ldc_type(idx as usize, false)?; self.push(f, t)? },
+            &Instruction::LDC2_W(idx) => { let t = self.ldc_type(idx as usize, true)?; self.push(f, t)? },
+
+            &Instruction::ILOAD(n) => self.load(f, n as usize, &VType::Integer)?,
+            &Instruction::ILOAD_W(n) => self.load(f, n as usize, &VType::Integer)?,
+            &Instruction::ILOAD_0 => self.load(f, 0, &VType::Integer)?,
+            &Instruction::ILOAD_1 => self.load(f, 1, &VType::Integer)?,
+            &Instruction::ILOAD_2 => self.load(f, 2, &VType::Integer)?,
+            &Instruction::ILOAD_3 => self.load(f, 3, &VType::Integer)?,
+            &Instruction::LLOAD(n) => self.load(f, n as usize, &VType::Long)?,
+            &Instruction::LLOAD_W(n) => self.load(f, n as usize, &VType::Long)?,
+            &Instruction::LLOAD_0 => self.load(f, 0, &VType::Long)?,
+            &Instruction::LLOAD_1 => self.load(f, 1, &VType::Long)?,
+            &Instruction::LLOAD_2 => self.load(f, 2, &VType::Long)?,
+            &Instruction::LLOAD_3 => self.load(f, 3, &VType::Long)?,
+            &Instruction::FLOAD(n) => self.load(f, n as usize, &VType::Float)?,
+            &Instruction::FLOAD_W(n) => self.load(f, n as usize, &VType::Float)?,
+            &Instruction::FLOAD_0 => self.load(f, 0, &VType::Float)?,
+            &Instruction::FLOAD_1 => self.load(f, 1, &VType::Float)?,
+            &Instruction::FLOAD_2 => self.load(f, 2, &VType::Float)?,
+            &Instruction::FLOAD_3 => self.load(f, 3, &VType::Float)?,
+            &Instruction::DLOAD(n) => self.load(f, n as usize, &VType::Double)?,
+            &Instruction::DLOAD_W(n) => self.load(f, n as usize, &VType::Double)?,
+            &Instruction::DLOAD_0 => self.load(f, 0, &VType::Double)?,
+            &Instruction::DLOAD_1 => self.load(f, 1, &VType::Double)?,
+            &Instruction::DLOAD_2 => self.load(f, 2, &VType::Double)?,
+            &Instruction::DLOAD_3 => self.load(f, 3, &VType::Double)?,
+            &Instruction::ALOAD(n) => self.load(f, n as usize, &object())?,
+            &Instruction::ALOAD_W(n) => self.load(f, n as usize, &object())?,
+            &Instruction::ALOAD_0 => self.load(f, 0, &object())?,
+            &Instruction::ALOAD_1 => self.load(f, 1, &object())?,
+            &Instruction::ALOAD_2 => self.load(f, 2, &object())?,
+            &Instruction::ALOAD_3 => self.load(f, 3, &object())?,
+
+            &Instruction::IALOAD => { self.pop(f, &VType::Integer)?; self.pop_array_of(f, &["[I"])?; self.push(f, VType::Integer)? },
+            &Instruction::BALOAD => { self.pop(f, &VType::Integer)?; self.pop_array_of(f, &["[B", "[Z"])?; self.push(f, VType::Integer)? },
+            &Instruction::CALOAD => { self.pop(f, &VType::Integer)?; self.pop_array_of(f, &["[C"])?; self.push(f, VType::Integer)? },
+            &Instruction::SALOAD => { self.pop(f, &VType::Integer)?; self.pop_array_of(f, &["[S"])?; self.push(f, VType::Integer)? },
+            &Instruction::LALOAD => { self.pop(f, &VType::Integer)?; self.pop_array_of(f, &["[J"])?; self.push(f, VType::Long)? },
+            &Instruction::FALOAD => { self.pop(f, &VType::Integer)?; self.pop_array_of(f, &["[F"])?; self.push(f, VType::Float)? },
+            &Instruction::DALOAD => { self.pop(f, &VType::Integer)?; self.pop_array_of(f, &["[D"])?; self.push(f, VType::Double)? },
+            &Instruction::AALOAD => {
+                self.pop(f, &VType::Integer)?;
+                let component = match self.pop_array(f)? {
+                    None => VType::Null,
+                    Some(desc) => match descriptor_type(&desc[1..]) {
+                        Some(component @ VType::Reference(_)) => component,
+                        _ => return Err(format!("Expected array of references, found {}", desc))
+                    }
+                };
+                self.push(f, component)?
+            },
+
+            &Instruction::ISTORE(n) => self.store(f, n as usize, &VType::Integer)?,
+            &Instruction::ISTORE_W(n) => self.store(f, n as usize, &VType::Integer)?,
+            &Instruction::ISTORE_0 => self.store(f, 0, &VType::Integer)?,
+            &Instruction::ISTORE_1 => self.store(f, 1, &VType::Integer)?,
+            &Instruction::ISTORE_2 => self.store(f, 2, &VType::Integer)?,
+            &Instruction::ISTORE_3 => self.store(f, 3, &VType::Integer)?,
+            &Instruction::LSTORE(n) => self.store(f, n as usize, &VType::Long)?,
+            &Instruction::LSTORE_W(n) => self.store(f, n as usize, &VType::Long)?,
+            &Instruction::LSTORE_0 => self.store(f, 0, &VType::Long)?,
+            &Instruction::LSTORE_1 => self.store(f, 1, &VType::Long)?,
+            &Instruction::LSTORE_2 => self.store(f, 2, &VType::Long)?,
+            &Instruction::LSTORE_3 => self.store(f, 3, &VType::Long)?,
+            &Instruction::FSTORE(n) => self.store(f, n as usize, &VType::Float)?,
+            &Instruction::FSTORE_W(n) => self.store(f, n as usize, &VType::Float)?,
+            &Instruction::FSTORE_0 => self.store(f, 0, &VType::Float)?,
+            &Instruction::FSTORE_1 => self.store(f, 1, &VType::Float)?,
+            &Instruction::FSTORE_2 => self.store(f, 2, &VType::Float)?,
+            &Instruction::FSTORE_3 => self.store(f, 3, &VType::Float)?,
+            &Instruction::DSTORE(n) => self.store(f, n as usize, &VType::Double)?,
+            &Instruction::DSTORE_W(n) => self.store(f, n as usize, &VType::Double)?,
+            &Instruction::DSTORE_0 => self.store(f, 0, &VType::Double)?,
+            &Instruction::DSTORE_1 => self.store(f, 1, &VType::Double)?,
+            &Instruction::DSTORE_2 => self.store(f, 2, &VType::Double)?,
+            &Instruction::DSTORE_3 => self.store(f, 3, &VType::Double)?,
+            &Instruction::ASTORE(n) => self.store(f, n as usize, &object())?,
+            &Instruction::ASTORE_W(n) => self.store(f, n as usize, &object())?,
+            &Instruction::ASTORE_0 => self.store(f, 0, &object())?,
+            &Instruction::ASTORE_1 => self.store(f, 1, &object())?,
+            &Instruction::ASTORE_2 => self.store(f, 2, &object())?,
+            &Instruction::ASTORE_3 => self.store(f, 3, &object())?,
+
+            &Instruction::IASTORE => { self.pop(f, &VType::Integer)?; self.pop(f, &VType::Integer)?; self.pop_array_of(f, &["[I"])? },
+            &Instruction::BASTORE => { self.pop(f, &VType::Integer)?; self.pop(f, &VType::Integer)?; self.pop_array_of(f, &["[B", "[Z"])? },
+            &Instruction::CASTORE => { self.pop(f, &VType::Integer)?; self.pop(f, &VType::Integer)?; self.pop_array_of(f, &["[C"])? },
+            &Instruction::SASTORE => { self.pop(f, &VType::Integer)?; self.pop(f, &VType::Integer)?; self.pop_array_of(f, &["[S"])? },
+            &Instruction::LASTORE => { self.pop(f, &VType::Long)?; self.pop(f, &VType::Integer)?; self.pop_array_of(f, &["[J"])? },
+            &Instruction::FASTORE => { self.pop(f, &VType::Float)?; self.pop(f, &VType::Integer)?; self.pop_array_of(f, &["[F"])? },
+            &Instruction::DASTORE => { self.pop(f, &VType::Double)?; self.pop(f, &VType::Integer)?; self.pop_array_of(f, &["[D"])? },
+            &Instruction::AASTORE => {
+                self.pop(f, &object())?;
+                self.pop(f, &VType::Integer)?;
+                match self.pop_array(f)? {
+                    Some(ref desc) if !desc.starts_with("[L") && !desc.starts_with("[[") =>
+                        return Err(format!("Expected array of references, found {}", desc)),
+                    _ => ()
+                }
+            },
+
+            &Instruction::POP => match f.stack.pop() {
+                Some(VType::Top) => return Err("Cannot pop half of a long or double value".to_string()),
+                Some(_) => (),
+                None => return Err("Stack underflow".to_string())
+            },
+            &Instruction::POP2 => { self.check_slots(f, 2)?; f.stack.pop(); f.stack.pop(); },
+            &Instruction::DUP => self.dup(f, 1, 0)?,
+            &Instruction::DUP_X1 => self.dup(f, 1, 1)?,
+            &Instruction::DUP_X2 => self.dup(f, 1, 2)?,
+            &Instruction::DUP2 => self.dup(f, 2, 0)?,
+            &Instruction::DUP2_X1 => self.dup(f, 2, 1)?,
+            &Instruction::DUP2_X2 => self.dup(f, 2, 2)?,
+            &Instruction::SWAP => {
+                self.check_slots(f, 1)?;
+                self.check_slots(f, 2)?;
+                let len = f.stack.len();
+                f.stack.swap(len - 1, len - 2);
+            },
+
+            &Instruction::IADD | &Instruction::ISUB | &Instruction::IMUL | &Instruction::IDIV | &Instruction::IREM |
+            &Instruction::IAND | &Instruction::IOR | &Instruction::IXOR |
+            &Instruction::ISHL | &Instruction::ISHR | &Instruction::IUSHR => self.binary(f, VType::Integer)?,
+            &Instruction::LADD | &Instruction::LSUB | &Instruction::LMUL | &Instruction::LDIV | &Instruction::LREM |
+            &Instruction::LAND | &Instruction::LOR | &Instruction::LXOR => self.binary(f, VType::Long)?,
+            &Instruction::LSHL | &Instruction::LSHR | &Instruction::LUSHR => {
+                self.pop(f, &VType::Integer)?;
+                self.convert(f, VType::Long, VType::Long)?
+            },
+            &Instruction::FADD | &Instruction::FSUB | &Instruction::FMUL | &Instruction::FDIV | &Instruction::FREM => self.binary(f, VType::Float)?,
+            &Instruction::DADD | &Instruction::DSUB | &Instruction::DMUL | &Instruction::DDIV | &Instruction::DREM => self.binary(f, VType::Double)?,
+            &Instruction::INEG | &Instruction::I2B | &Instruction::I2C | &Instruction::I2S => self.convert(f, VType::Integer, VType::Integer)?,
+            &Instruction::LNEG => self.convert(f, VType::Long, VType::Long)?,
+            &Instruction::FNEG => self.convert(f, VType::Float, VType::Float)?,
+            &Instruction::DNEG => self.convert(f, VType::Double, VType::Double)?,
+            &Instruction::IINC(n, _) => self.increment(f, n as usize)?,
+            &Instruction::IINC_W(n, _) => self.increment(f, n as usize)?,
+            &Instruction::I2L => self.convert(f, VType::Integer, VType::Long)?,
+            &Instruction::I2F => self.convert(f, VType::Integer, VType::Float)?,
+            &Instruction::I2D => self.convert(f, VType::Integer, VType::Double)?,
+            &Instruction::L2I => self.convert(f, VType::Long, VType::Integer)?,
+            &Instruction::L2F => self.convert(f, VType::Long, VType::Float)?,
+            &Instruction::L2D => self.convert(f, VType::Long, VType::Double)?,
+            &Instruction::F2I => self.convert(f, VType::Float, VType::Integer)?,
+            &Instruction::F2L => self.convert(f, VType::Float, VType::Long)?,
+            &Instruction::F2D => self.convert(f, VType::Float, VType::Double)?,
+            &Instruction::D2I => self.convert(f, VType::Double, VType::Integer)?,
+            &Instruction::D2L => self.convert(f, VType::Double, VType::Long)?,
+            &Instruction::D2F => self.convert(f, VType::Double, VType::Float)?,
+            &Instruction::LCMP => { self.binary(f, VType::Long)?; self.convert(f, VType::Long, VType::Integer)? },
+            &Instruction::FCMPL | &Instruction::FCMPG => { self.binary(f, VType::Float)?; self.convert(f, VType::Float, VType::Integer)? },
+            &Instruction::DCMPL | &Instruction::DCMPG => { self.binary(f, VType::Double)?; self.convert(f, VType::Double, VType::Integer)? },
+
+            &Instruction::IFEQ(rel) | &Instruction::IFNE(rel) | &Instruction::IFLT(rel) |
+            &Instruction::IFGE(rel) | &Instruction::IFGT(rel) | &Instruction::IFLE(rel) => {
+                self.pop(f, &VType::Integer)?;
+                self.check_branch(offset, rel as i32, f)?
+            },
+            &Instruction::IF_ICMPEQ(rel) | &Instruction::IF_ICMPNE(rel) | &Instruction::IF_ICMPLT(rel) |
+            &Instruction::IF_ICMPGE(rel) | &Instruction::IF_ICMPGT(rel) | &Instruction::IF_ICMPLE(rel) => {
+                self.pop(f, &VType::Integer)?;
+                self.pop(f, &VType::Integer)?;
+                self.check_branch(offset, rel as i32, f)?
+            },
+            &Instruction::IF_ACMPEQ(rel) | &Instruction::IF_ACMPNE(rel) => {
+                self.pop_reference(f)?;
+                self.pop_reference(f)?;
+                self.check_branch(offset, rel as i32, f)?
+            },
+            &Instruction::IFNULL(rel) | &Instruction::IFNONNULL(rel) => {
+                self.pop_reference(f)?;
+                self.check_branch(offset, rel as i32, f)?
+            },
+            &Instruction::GOTO(rel) => {
+                self.check_branch(offset, rel as i32, f)?;
+                return Ok(None);
+            },
+            &Instruction::GOTO_W(rel) => {
+                self.check_branch(offset, rel, f)?;
+                return Ok(None);
+            },
+            &Instruction::TABLESWITCH(default, low, high, ref rels) => {
+                if low > high || (high as i64 - low as i64 + 1) as usize != rels.len() {
+                    return Err(format!("Invalid table switch range {} to {}", low, high));
+                }
+                self.pop(f, &VType::Integer)?;
+                self.check_branch(offset, default, f)?;
+                for rel in rels.iter() {
+                    self.check_branch(offset, *rel, f)?;
+                }
+                return Ok(None);
+            },
+            &Instruction::LOOKUPSWITCH(default, ref pairs) => {
+                if pairs.windows(2).any(|w| w[0].0 >= w[1].0) {
+                    return Err("Lookup switch keys are not sorted".to_string());
+                }
+                self.pop(f, &VType::Integer)?;
+                self.check_branch(offset, default, f)?;
+                for &(_, rel) in pairs.iter() {
+                    self.check_branch(offset, rel, f)?;
+                }
+                return Ok(None);
+            },
+            &Instruction::JSR(_) | &Instruction::JSR_W(_) | &Instruction::RET(_) | &Instruction::RET_W(_) =>
+                return Err("Subroutines are not allowed in class files of version 50 and above".to_string()),
+
+            &Instruction::IRETURN => { let t = self.pop(f, &VType::Integer)?; return self.check_return(f, Some(t)); },
+            &Instruction::LRETURN => { let t = self.pop(f, &VType::Long)?; return self.check_return(f, Some(t)); },
+            &Instruction::FRETURN => { let t = self.pop(f, &VType::Float)?; return self.check_return(f, Some(t)); },
+            &Instruction::DRETURN => { let t = self.pop(f, &VType::Double)?; return self.check_return(f, Some(t)); },
+            &Instruction::ARETURN => { let t = self.pop(f, &object())?; return self.check_return(f, Some(t)); },
+            &Instruction::RETURN => return self.check_return(f, None),
+            &Instruction::ATHROW => {
+                self.pop(f, &VType::Reference("java/lang/Throwable".to_string()))?;
+                return Ok(None);
+            },
+
+            &Instruction::GETSTATIC(idx) => {
+                let (_, _, desc) = self.member(idx as usize, &[MemberKind::Field])?;
+                self.push(f, descriptor_type(&desc).ok_or(format!("Invalid field descriptor {}", desc))?)?
+            },
+            &Instruction::PUTSTATIC(idx) => {
+                let (_, _, desc) = self.member(idx as usize, &[MemberKind::Field])?;
+                self.pop(f, &descriptor_type(&desc).ok_or(format!("Invalid field descriptor {}", desc))?)?;
+            },
+            &Instruction::GETFIELD(idx) => {
+                let (class_name, _, desc) = self.member(idx as usize, &[MemberKind::Field])?;
+                self.pop(f, &VType::Reference(class_name))?;
+                self.push(f, descriptor_type(&desc).ok_or(format!("Invalid field descriptor {}", desc))?)?
+            },
+            &Instruction::PUTFIELD(idx) => {
+                let (class_name, _, desc) = self.member(idx as usize, &[MemberKind::Field])?;
+                self.pop(f, &descriptor_type(&desc).ok_or(format!("Invalid field descriptor {}", desc))?)?;
+                // Constructors may set their own fields before calling the super constructor
+                match f.stack.last() {
+                    Some(&VType::UninitializedThis) if class_name == self.class_name => { f.stack.pop(); },
+                    _ => { self.pop(f, &VType::Reference(class_name))?; }
+                }
+            },
+
+            &Instruction::INVOKEVIRTUAL(idx) => {
+                let (class_name, name, desc) = self.member(idx as usize, &[MemberKind::Method])?;
+                self.invoke_instance(f, class_name, name, desc)?
+            },
+            &Instruction::INVOKEINTERFACE(idx, count) => {
+                let (class_name, name, desc) = self.member(idx as usize, &[MemberKind::InterfaceMethod])?;
+                let (params, _) = parse_method_descriptor(&desc).ok_or(format!("Invalid method descriptor {}", desc))?;
+                if count as usize != 1 + params.iter().fold(0, |acc, p| acc + if p.is_wide() { 2 } else { 1 }) {
+                    return Err(format!("Wrong argument count {}", count));
+                }
+                self.invoke_instance(f, class_name, name, desc)?
+            },
+            &Instruction::INVOKESPECIAL(idx) => {
+                let (class_name, name, desc) = self.member(idx as usize, &[MemberKind::Method, MemberKind::InterfaceMethod])?;
+                if name == "<init>" {
+                    self.invoke_init(f, class_name, desc)?
+                } else {
+                    self.invoke_instance(f, class_name, name, desc)?
+                }
+            },
+            &Instruction::INVOKESTATIC(idx) => {
+                let (_, name, desc) = self.member(idx as usize, &[MemberKind::Method, MemberKind::InterfaceMethod])?;
+                if name.starts_with('<') {
+                    return Err(format!("Cannot invoke {} statically", name));
+                }
+                if let Some(t) = self.invoke(f, &desc)? {
+                    self.push(f, t)?
+                }
+            },
+            &Instruction::INVOKEDYNAMIC(idx) => {
+                let desc = match self.cp.constants.get(idx as usize) {
+                    Some(&Constant::InvokeDynamic { ref name_and_type_index, .. }) => self.name_and_type(name_and_type_index.idx)?.1,
+                    _ => return Err(format!("Expected invoke dynamic at constant {}", idx))
+                };
+                if let Some(t) = self.invoke(f, &desc)? {
+                    self.push(f, t)?
+                }
+            },
+
+            &Instruction::NEW(idx) => {
+                let class_name = self.class_name(idx as usize)?;
+                if class_name.starts_with('[') {
+                    return Err(format!("Cannot create array type {} with new", class_name));
+                }
+                self.push(f, VType::Uninitialized(offset))?
+            },
+            &Instruction::NEWARRAY(atype) => {
+                let desc = match atype {
+                    4 => "[Z", 5 => "[C", 6 => "[F", 7 => "[D", 8 => "[B", 9 => "[S", 10 => "[I", 11 => "[J",
+                    _ => return Err(format!("Invalid array type {}", atype))
+                };
+                self.convert(f, VType::Integer, VType::Reference(desc.to_string()))?
+            },
+            &Instruction::ANEWARRAY(idx) => {
+                let class_name = self.class_name(idx as usize)?;
+                let desc = if class_name.starts_with('[') { format!("[{}", class_name) } else { format!("[L{};", class_name) };
+                self.convert(f, VType::Integer, VType::Reference(desc))?
+            },
+            &Instruction::MULTIANEWARRAY(idx, dims) => {
+                let class_name = self.class_name(idx as usize)?;
+                if dims == 0 || class_name.chars().take_while(|c| *c == '[').count() < dims as usize {
+                    return Err(format!("Invalid dimensions {} for {}", dims, class_name));
+                }
+                for _ in 0..dims {
+                    self.pop(f, &VType::Integer)?;
+                }
+                self.push(f, VType::Reference(class_name))?
+            },
+            &Instruction::ARRAYLENGTH => {
+                self.pop_array(f)?;
+                self.push(f, VType::Integer)?
+            },
+            &Instruction::CHECKCAST(idx) => {
+                let class_name = self.class_name(idx as usize)?;
+                self.convert(f, object(), VType::Reference(class_name))?
+            },
+            &Instruction::INSTANCEOF(idx) => {
+                self.class_name(idx as usize)?;
+                self.convert(f, object(), VType::Integer)?
+            },
+            &Instruction::MONITORENTER | &Instruction::MONITOREXIT => { self.pop(f, &object())?; },
+            &Instruction::PADDED_INSTRUCTION(_) | &Instruction::WTF(_) =>
+                return Err("Unknown instruction".to_string())
+        }
+        if next_offset >= self.code_len {
+            return Err("Falling off the end of the code".to_string());
+        }
+        Ok(Some(frame))
+    }
+
+    fn ldc_type(&self, idx: usize, wide: bool) -> Result<VType, String> {
+        match (self.cp.constants.get(idx), wide) {
+            (Some(&Constant::Integer(_)), false) => Ok(VType::Integer),
+            (Some(&Constant::Float(_)), false) => Ok(VType::Float),
+            (Some(&Constant::String(_)), false) => Ok(VType::Reference("java/lang/String".to_string())),
+            (Some(&Constant::Class(_)), false) => Ok(VType::Reference("java/lang/Class".to_string())),
+            (Some(&Constant::MethodType(_)), false) => Ok(VType::Reference("java/lang/invoke/MethodType".to_string())),
+            (Some(&Constant::MethodHandle { .. }), false) => Ok(VType::Reference("java/lang/invoke/MethodHandle".to_string())),
+            (Some(&Constant::Long(_)), true) => Ok(VType::Long),
+            (Some(&Constant::Double(_)), true) => Ok(VType::Double),
+            _ => Err(format!("Cannot load constant {}", idx))
+        }
+    }
+
+    fn increment(&self, frame: &mut Frame, index: usize) -> Result<(), String> {
+        match frame.locals.get(index) {
+            Some(&VType::Integer) => Ok(()),
+            Some(t) => Err(format!("Expected int in local {}, found {}", index, t)),
+            None => Err(format!("Local {} exceeds max locals of {}", index, self.max_locals))
+        }
+    }
+
+    fn invoke_instance(&self, frame: &mut Frame, class_name: String, name: String, desc: String) -> Result<(), String> {
+        if name.starts_with('<') {
+            return Err(format!("Cannot invoke {} here", name));
+        }
+        let return_type = self.invoke(frame, &desc)?;
+        // Arrays inherit their methods from Object
+        let target = if class_name.starts_with('[') { object() } else { VType::Reference(class_name) };
+        self.pop(frame, &target)?;
+        if let Some(t) = return_type {
+            self.push(frame, t)?
+        }
+        Ok(())
+    }
+
+    fn invoke_init(&self, frame: &mut Frame, class_name: String, desc: String) -> Result<(), String> {
+        if self.invoke(frame, &desc)?.is_some() {
+            return Err("Constructor must return void".to_string());
+        }
+        let uninitialized = self.pop_reference(frame)?;
+        let initialized = match uninitialized {
+            VType::UninitializedThis => {
+                if class_name != self.class_name && Some(&class_name) != self.super_class_name.as_ref() {
+                    return Err(format!("Cannot initialize this with a constructor of {}", class_name));
+                }
+                VType::Reference(self.class_name.clone())
+            },
+            VType::Uninitialized(new_offset) => {
+                let new_class = &self.new_classes[&new_offset];
+                if *new_class != class_name {
+                    return Err(format!("Cannot initialize {} with a constructor of {}", new_class, class_name));
+                }
+                VType::Reference(class_name)
+            },
+            t => return Err(format!("Expected uninitialized object on the stack, found {}", t))
+        };
+        for t in frame.locals.iter_mut().chain(frame.stack.iter_mut()) {
+            if *t == uninitialized {
+                *t = initialized.clone();
+            }
+        }
+        Ok(())
+    }
+}
+
+fn object() -> VType {
+    VType::Reference("java/lang/Object".to_string())
+}
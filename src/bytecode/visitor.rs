@@ -0,0 +1,189 @@
+use std::io::Error;
+use super::classfile::*;
+
+///
+/// The parts of a class that come before its fields. The constant pool is not part of the header
+/// because it stays open for additions for the whole visit and is passed to every call instead.
+#[derive(Default, Debug)]
+pub struct ClassHeader {
+    pub version: ClassfileVersion,
+    pub access_flags: AccessFlags,
+    pub this_class: ConstantPoolIndex,
+    pub super_class: ConstantPoolIndex,
+    pub interfaces: Vec<ConstantPoolIndex>
+}
+
+///
+/// A `ClassVisitor` receives a class one part at a time in class file order: the header, each
+/// field, each method, each class attribute and then the end. `ClassReader::accept` drives one
+/// and `ClassWriter` implements one, so a transform only has to sit between the two.
+///
+/// Constants may be added to the pool at any point of the visit, but existing constants must not
+/// be removed or reordered as earlier parts may already refer to them. The `'a` lifetime is that
+/// of the class bytes being read, which constants and attributes may still borrow from.
+pub trait ClassVisitor<'a> {
+    fn visit_header(&mut self, cp: &mut ConstantPool<'a>, header: ClassHeader) -> Result<(), Error>;
+    fn visit_field(&mut self, cp: &mut ConstantPool<'a>, field: Field<'a>) -> Result<(), Error>;
+    fn visit_method(&mut self, cp: &mut ConstantPool<'a>, method: Method<'a>) -> Result<(), Error>;
+    fn visit_attribute(&mut self, cp: &mut ConstantPool<'a>, attribute: Attribute<'a>) -> Result<(), Error>;
+    fn visit_end(&mut self, cp: &mut ConstantPool<'a>) -> Result<(), Error>;
+}
+
+///
+/// A `FieldVisitor` is a field level transform used in a `ClassFilter`. By default, every field
+/// is passed on unchanged and nothing is added.
+pub trait FieldVisitor<'a> {
+    /// Returns the field to pass on or `None` to drop it
+    fn visit_field(&mut self, _cp: &mut ConstantPool<'a>, field: Field<'a>) -> Result<Option<Field<'a>>, Error> {
+        Ok(Some(field))
+    }
+
+    /// Returns new fields to add after all existing ones have been visited
+    fn visit_end(&mut self, _cp: &mut ConstantPool<'a>) -> Result<Vec<Field<'a>>, Error> {
+        Ok(vec![])
+    }
+}
+
+///
+/// A `MethodVisitor` is a method level transform used in a `ClassFilter`. By default, every
+/// method is passed on and its code, if any, is handed to `visit_code`.
+pub trait MethodVisitor<'a> {
+    /// Returns the method to pass on or `None` to drop it
+    fn visit_method(&mut self, cp: &mut ConstantPool<'a>, mut method: Method<'a>) -> Result<Option<Method<'a>>, Error> {
+        for attr in method.attributes.iter_mut() {
+            match attr {
+                &mut Attribute::Code { ref mut code, .. } =>
+                    self.visit_code(cp, &method.name_index, &method.descriptor_index, code)?,
+                _ => ()
+            }
+        }
+        Ok(Some(method))
+    }
+
+    /// Called with the code of each method that has code. Instructions are only decoded if asked
+    /// for through `Code::instructions_mut`, so check the method first.
+    fn visit_code(&mut self,
+                  _cp: &mut ConstantPool<'a>,
+                  _name_index: &ConstantPoolIndex,
+                  _descriptor_index: &ConstantPoolIndex,
+                  _code: &mut Code<'a>) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Returns new methods to add after all existing ones have been visited
+    fn visit_end(&mut self, _cp: &mut ConstantPool<'a>) -> Result<Vec<Method<'a>>, Error> {
+        Ok(vec![])
+    }
+}
+
+///
+/// A `ClassFilter` is a `ClassVisitor` that runs fields and methods through a chain of member
+/// visitors, in the order they were added, before passing them on to the next class visitor.
+/// Members a visitor adds are run through the visitors after it, then passed on right after the
+/// last existing field or method, so the next visitor still gets every field before any method
+/// and every method before any attribute. Everything else is passed on untouched. Filters can be
+/// nested to compose transforms.
+pub struct ClassFilter<'v, 'a: 'v> {
+    next: &'v mut ClassVisitor<'a>,
+    field_visitors: Vec<Box<FieldVisitor<'a> + 'v>>,
+    method_visitors: Vec<Box<MethodVisitor<'a> + 'v>>,
+    fields_ended: bool,
+    methods_ended: bool
+}
+
+impl<'v, 'a> ClassFilter<'v, 'a> {
+    pub fn new(next: &'v mut ClassVisitor<'a>) -> ClassFilter<'v, 'a> {
+        ClassFilter { next: next, field_visitors: vec![], method_visitors: vec![], fields_ended: false, methods_ended: false }
+    }
+
+    pub fn with_field_visitor<T>(mut self, visitor: T) -> ClassFilter<'v, 'a> where T: FieldVisitor<'a> + 'v {
+        self.field_visitors.push(Box::new(visitor));
+        self
+    }
+
+    pub fn with_method_visitor<T>(mut self, visitor: T) -> ClassFilter<'v, 'a> where T: MethodVisitor<'a> + 'v {
+        self.method_visitors.push(Box::new(visitor));
+        self
+    }
+
+    /// Runs a field through the field visitors from the given one on and passes it on if kept
+    fn filter_field(&mut self, cp: &mut ConstantPool<'a>, first: usize, field: Field<'a>) -> Result<(), Error> {
+        let mut maybe_field = Some(field);
+        for visitor in self.field_visitors[first..].iter_mut() {
+            maybe_field = match maybe_field {
+                Some(field) => visitor.visit_field(cp, field)?,
+                None => break
+            };
+        }
+        match maybe_field {
+            Some(field) => self.next.visit_field(cp, field),
+            None => Ok(())
+        }
+    }
+
+    /// Runs a method through the method visitors from the given one on and passes it on if kept
+    fn filter_method(&mut self, cp: &mut ConstantPool<'a>, first: usize, method: Method<'a>) -> Result<(), Error> {
+        let mut maybe_method = Some(method);
+        for visitor in self.method_visitors[first..].iter_mut() {
+            maybe_method = match maybe_method {
+                Some(method) => visitor.visit_method(cp, method)?,
+                None => break
+            };
+        }
+        match maybe_method {
+            Some(method) => self.next.visit_method(cp, method),
+            None => Ok(())
+        }
+    }
+
+    /// Passes on the added fields once the existing ones are done. Each visitor's additions go
+    /// through the visitors after it before those are asked for their own.
+    fn end_fields(&mut self, cp: &mut ConstantPool<'a>) -> Result<(), Error> {
+        if self.fields_ended { return Ok(()); }
+        self.fields_ended = true;
+        for i in 0..self.field_visitors.len() {
+            for field in self.field_visitors[i].visit_end(cp)? {
+                self.filter_field(cp, i + 1, field)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Passes on the added methods once the existing ones are done, after the added fields
+    fn end_methods(&mut self, cp: &mut ConstantPool<'a>) -> Result<(), Error> {
+        self.end_fields(cp)?;
+        if self.methods_ended { return Ok(()); }
+        self.methods_ended = true;
+        for i in 0..self.method_visitors.len() {
+            for method in self.method_visitors[i].visit_end(cp)? {
+                self.filter_method(cp, i + 1, method)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<'v, 'a> ClassVisitor<'a> for ClassFilter<'v, 'a> {
+    fn visit_header(&mut self, cp: &mut ConstantPool<'a>, header: ClassHeader) -> Result<(), Error> {
+        self.next.visit_header(cp, header)
+    }
+
+    fn visit_field(&mut self, cp: &mut ConstantPool<'a>, field: Field<'a>) -> Result<(), Error> {
+        self.filter_field(cp, 0, field)
+    }
+
+    fn visit_method(&mut self, cp: &mut ConstantPool<'a>, method: Method<'a>) -> Result<(), Error> {
+        self.end_fields(cp)?;
+        self.filter_method(cp, 0, method)
+    }
+
+    fn visit_attribute(&mut self, cp: &mut ConstantPool<'a>, attribute: Attribute<'a>) -> Result<(), Error> {
+        self.end_methods(cp)?;
+        self.next.visit_attribute(cp, attribute)
+    }
+
+    fn visit_end(&mut self, cp: &mut ConstantPool<'a>) -> Result<(), Error> {
+        self.end_methods(cp)?;
+        self.next.visit_end(cp)
+    }
+}
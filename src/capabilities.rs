@@ -0,0 +1,108 @@
+use jvmti_sys::jvmtiCapabilities;
+use std::fmt;
+use std::os::raw::c_uint;
+
+///
+/// A JVMTI capability the agent knows how to ask for. The bits are laid out as the bitfield in
+/// `jvmtiCapabilities`, i.e. in declaration order of jvmti.h starting at the lowest bit.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Capability {
+    AccessLocalVariables,
+    GenerateAllClassHookEvents,
+    RetransformClasses,
+}
+
+impl Capability {
+    pub fn all() -> Vec<Capability> {
+        return vec![Capability::AccessLocalVariables,
+                    Capability::GenerateAllClassHookEvents,
+                    Capability::RetransformClasses];
+    }
+
+    /// The name as it appears in jvmti.h
+    pub fn name(&self) -> &'static str {
+        return match *self {
+            Capability::AccessLocalVariables => "can_access_local_variables",
+            Capability::GenerateAllClassHookEvents => "can_generate_all_class_hook_events",
+            Capability::RetransformClasses => "can_retransform_classes",
+        };
+    }
+
+    // Which of the four bitfield words and the bit in it
+    fn bit(&self) -> (usize, c_uint) {
+        return match *self {
+            Capability::AccessLocalVariables => (0, 1 << 14),
+            Capability::GenerateAllClassHookEvents => (0, 1 << 26),
+            Capability::RetransformClasses => (1, 1 << 5),
+        };
+    }
+}
+
+impl fmt::Display for Capability {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        return write!(f, "{}", self.name());
+    }
+}
+
+///
+/// A typed set of JVMTI capabilities, built up with `with` and converted to the raw struct when
+/// handed to JVMTI.
+#[derive(Clone, Copy, Default)]
+pub struct Capabilities {
+    raw: jvmtiCapabilities,
+}
+
+impl Capabilities {
+    pub fn new() -> Capabilities {
+        return Capabilities::default();
+    }
+
+    pub fn from_raw(raw: jvmtiCapabilities) -> Capabilities {
+        return Capabilities { raw: raw };
+    }
+
+    pub fn as_raw(&self) -> &jvmtiCapabilities {
+        return &self.raw;
+    }
+
+    pub fn with(mut self, cap: Capability) -> Capabilities {
+        let (word, bit) = cap.bit();
+        *self.word_mut(word) |= bit;
+        return self;
+    }
+
+    pub fn has(&self, cap: Capability) -> bool {
+        let (word, bit) = cap.bit();
+        return self.word(word) & bit != 0;
+    }
+
+    /// The known capabilities that are set in both
+    pub fn intersect(&self, other: &Capabilities) -> Capabilities {
+        return Capability::all().into_iter()
+            .filter(|cap| self.has(*cap) && other.has(*cap))
+            .fold(Capabilities::new(), |caps, cap| caps.with(cap));
+    }
+
+    /// The known capabilities set here but not in the other
+    pub fn missing_from(&self, other: &Capabilities) -> Vec<Capability> {
+        return Capability::all().into_iter().filter(|cap| self.has(*cap) && !other.has(*cap)).collect();
+    }
+
+    fn word(&self, word: usize) -> c_uint {
+        return match word {
+            0 => self.raw._bindgen_bitfield_1_,
+            1 => self.raw._bindgen_bitfield_2_,
+            2 => self.raw._bindgen_bitfield_3_,
+            _ => self.raw._bindgen_bitfield_4_,
+        };
+    }
+
+    fn word_mut(&mut self, word: usize) -> &mut c_uint {
+        return match word {
+            0 => &mut self.raw._bindgen_bitfield_1_,
+            1 => &mut self.raw._bindgen_bitfield_2_,
+            2 => &mut self.raw._bindgen_bitfield_3_,
+            _ => &mut self.raw._bindgen_bitfield_4_,
+        };
+    }
+}
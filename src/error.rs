@@ -0,0 +1,161 @@
+extern crate jni_sys;
+
+use jni_sys::{jint, JNI_ERR, JNI_EDETACHED, JNI_EVERSION, JNI_ENOMEM, JNI_EEXIST, JNI_EINVAL};
+use jvmti_sys::jvmtiError;
+use std::error;
+use std::fmt;
+use std::io;
+
+///
+/// An error from the agent. JVMTI, JNI and Java exception errors carry the name of the operation that
+/// failed.
+#[derive(Debug)]
+pub enum AgentError {
+    /// A JVMTI function returned an error code
+    Jvmti { op: &'static str, code: jvmtiError },
+    /// A JNI function returned an error code
+    Jni { op: &'static str, code: jint },
+    /// A JNI function left a Java exception pending. The exception has been cleared.
+    JavaException { op: &'static str, exception: JavaThrowable },
+    /// A class could not be read, transformed or written
+    Transform(io::Error),
+    /// Anything else that is not as we expect it
+    Other(String),
+}
+
+impl AgentError {
+    /// Whether this is the given JVMTI error, regardless of operation
+    pub fn is_jvmti_error(&self, expected: jvmtiError) -> bool {
+        return match self {
+            &AgentError::Jvmti { code, .. } => code as u32 == expected as u32,
+            _ => false
+        };
+    }
+}
+
+impl fmt::Display for AgentError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        return match self {
+            &AgentError::Jvmti { op, code } => write!(f, "{} failed with {:?}: {}", op, code, jvmti_error_message(code)),
+            &AgentError::Jni { op, code } => write!(f, "{} failed with JNI code {}: {}", op, code, jni_error_message(code)),
+            &AgentError::JavaException { op, ref exception } => write!(f, "{} threw {}", op, exception),
+            &AgentError::Transform(ref err) => write!(f, "Unable to transform class: {}", err),
+            &AgentError::Other(ref msg) => write!(f, "{}", msg),
+        };
+    }
+}
+
+impl error::Error for AgentError {
+    fn description(&self) -> &str {
+        return match self {
+            &AgentError::Jvmti { code, .. } => jvmti_error_message(code),
+            &AgentError::Jni { code, .. } => jni_error_message(code),
+            &AgentError::JavaException { .. } => "Java exception thrown",
+            &AgentError::Transform(_) => "unable to transform class",
+            &AgentError::Other(ref msg) => msg,
+        };
+    }
+}
+
+impl From<io::Error> for AgentError {
+    fn from(err: io::Error) -> AgentError {
+        return AgentError::Transform(err);
+    }
+}
+
+impl From<String> for AgentError {
+    fn from(msg: String) -> AgentError {
+        return AgentError::Other(msg);
+    }
+}
+
+///
+/// What could be gathered from a Java exception before it was cleared. Anything that could not be
+/// gathered, e.g. because getting it threw another exception, is left empty.
+#[derive(Debug)]
+pub struct JavaThrowable {
+    /// The binary class name, e.g. java.lang.IllegalStateException
+    pub class_name: String,
+    pub message: Option<String>,
+    /// The top frames, formatted like in a Java stack trace
+    pub frames: Vec<String>,
+}
+
+impl fmt::Display for JavaThrowable {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.class_name)?;
+        if let Some(ref message) = self.message {
+            write!(f, ": {}", message)?;
+        }
+        for frame in self.frames.iter() {
+            write!(f, "\n\tat {}", frame)?;
+        }
+        return Result::Ok(());
+    }
+}
+
+fn jvmti_error_message(code: jvmtiError) -> &'static str {
+    return match code {
+        jvmtiError::JVMTI_ERROR_NONE => "no error",
+        jvmtiError::JVMTI_ERROR_INVALID_THREAD => "not a valid thread",
+        jvmtiError::JVMTI_ERROR_INVALID_THREAD_GROUP => "not a valid thread group",
+        jvmtiError::JVMTI_ERROR_INVALID_PRIORITY => "not a valid priority",
+        jvmtiError::JVMTI_ERROR_THREAD_NOT_SUSPENDED => "thread is not suspended",
+        jvmtiError::JVMTI_ERROR_THREAD_SUSPENDED => "thread is already suspended",
+        jvmtiError::JVMTI_ERROR_THREAD_NOT_ALIVE => "thread is not alive",
+        jvmtiError::JVMTI_ERROR_INVALID_OBJECT => "not a valid object",
+        jvmtiError::JVMTI_ERROR_INVALID_CLASS => "not a valid class",
+        jvmtiError::JVMTI_ERROR_CLASS_NOT_PREPARED => "class is not prepared yet",
+        jvmtiError::JVMTI_ERROR_INVALID_METHODID => "not a valid method",
+        jvmtiError::JVMTI_ERROR_INVALID_LOCATION => "not a valid location",
+        jvmtiError::JVMTI_ERROR_INVALID_FIELDID => "not a valid field",
+        jvmtiError::JVMTI_ERROR_NO_MORE_FRAMES => "no frame at the given depth",
+        jvmtiError::JVMTI_ERROR_OPAQUE_FRAME => "no information for the frame, e.g. a native frame",
+        jvmtiError::JVMTI_ERROR_TYPE_MISMATCH => "variable is not of the requested type",
+        jvmtiError::JVMTI_ERROR_INVALID_SLOT => "not a valid slot",
+        jvmtiError::JVMTI_ERROR_DUPLICATE => "already set",
+        jvmtiError::JVMTI_ERROR_NOT_FOUND => "not found",
+        jvmtiError::JVMTI_ERROR_INVALID_MONITOR => "not a valid raw monitor",
+        jvmtiError::JVMTI_ERROR_NOT_MONITOR_OWNER => "raw monitor is not owned",
+        jvmtiError::JVMTI_ERROR_INTERRUPT => "call was interrupted",
+        jvmtiError::JVMTI_ERROR_INVALID_CLASS_FORMAT => "class file is malformed",
+        jvmtiError::JVMTI_ERROR_CIRCULAR_CLASS_DEFINITION => "class would be its own superclass",
+        jvmtiError::JVMTI_ERROR_FAILS_VERIFICATION => "class fails verification",
+        jvmtiError::JVMTI_ERROR_UNSUPPORTED_REDEFINITION_METHOD_ADDED => "redefinition adds a method",
+        jvmtiError::JVMTI_ERROR_UNSUPPORTED_REDEFINITION_SCHEMA_CHANGED => "redefinition changes fields",
+        jvmtiError::JVMTI_ERROR_INVALID_TYPESTATE => "thread state was modified",
+        jvmtiError::JVMTI_ERROR_UNSUPPORTED_REDEFINITION_HIERARCHY_CHANGED => "redefinition changes the class hierarchy",
+        jvmtiError::JVMTI_ERROR_UNSUPPORTED_REDEFINITION_METHOD_DELETED => "redefinition deletes a method",
+        jvmtiError::JVMTI_ERROR_UNSUPPORTED_VERSION => "class file version is not supported",
+        jvmtiError::JVMTI_ERROR_NAMES_DONT_MATCH => "class name does not match",
+        jvmtiError::JVMTI_ERROR_UNSUPPORTED_REDEFINITION_CLASS_MODIFIERS_CHANGED => "redefinition changes class modifiers",
+        jvmtiError::JVMTI_ERROR_UNSUPPORTED_REDEFINITION_METHOD_MODIFIERS_CHANGED => "redefinition changes method modifiers",
+        jvmtiError::JVMTI_ERROR_UNMODIFIABLE_CLASS => "class cannot be modified",
+        jvmtiError::JVMTI_ERROR_NOT_AVAILABLE => "not available in this VM",
+        jvmtiError::JVMTI_ERROR_MUST_POSSESS_CAPABILITY => "environment lacks the needed capability",
+        jvmtiError::JVMTI_ERROR_NULL_POINTER => "unexpected null pointer",
+        jvmtiError::JVMTI_ERROR_ABSENT_INFORMATION => "information is absent, e.g. compiled without debug info",
+        jvmtiError::JVMTI_ERROR_INVALID_EVENT_TYPE => "not a valid event type",
+        jvmtiError::JVMTI_ERROR_ILLEGAL_ARGUMENT => "illegal argument",
+        jvmtiError::JVMTI_ERROR_NATIVE_METHOD => "not available for native methods",
+        jvmtiError::JVMTI_ERROR_CLASS_LOADER_UNSUPPORTED => "not supported by the class loader",
+        jvmtiError::JVMTI_ERROR_OUT_OF_MEMORY => "out of memory",
+        jvmtiError::JVMTI_ERROR_ACCESS_DENIED => "access denied",
+        jvmtiError::JVMTI_ERROR_WRONG_PHASE => "not available in the current VM phase",
+        jvmtiError::JVMTI_ERROR_INTERNAL => "internal error",
+        jvmtiError::JVMTI_ERROR_UNATTACHED_THREAD => "thread is not attached to the VM",
+        jvmtiError::JVMTI_ERROR_INVALID_ENVIRONMENT => "environment is no longer valid",
+    };
+}
+
+fn jni_error_message(code: jint) -> &'static str {
+    return match code {
+        JNI_ERR => "unknown error",
+        JNI_EDETACHED => "thread is detached from the VM",
+        JNI_EVERSION => "version is not supported",
+        JNI_ENOMEM => "out of memory",
+        JNI_EEXIST => "VM already created",
+        JNI_EINVAL => "invalid argument",
+        _ => "unrecognized error",
+    };
+}
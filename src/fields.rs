@@ -0,0 +1,156 @@
+extern crate jni_sys;
+
+use error::AgentError;
+use jni_sys::{JNIEnv, jfieldID, jobject};
+use state::{AgentState, MethodRef};
+use std::collections::HashMap;
+use std::os::raw::{c_double, c_int, c_uint};
+use std::sync::atomic::{AtomicPtr, Ordering};
+use util;
+
+// Only set when snapshots are wanted, never changed after load
+static SNAPSHOT_CONFIG: AtomicPtr<SnapshotConfig> = AtomicPtr::new(0 as *mut SnapshotConfig);
+
+const DEFAULT_MAX_FIELDS: usize = 10;
+
+const DEFAULT_MAX_DEPTH: usize = 1;
+
+// Field values of these are shown as is instead of having their own fields expanded
+pub const LEAF_TYPES: &'static [&'static str] = &["Ljava/lang/String;", "Ljava/lang/Boolean;", "Ljava/lang/Byte;",
+                                              "Ljava/lang/Character;", "Ljava/lang/Short;", "Ljava/lang/Integer;",
+                                              "Ljava/lang/Long;", "Ljava/lang/Float;", "Ljava/lang/Double;"];
+
+///
+/// How much of "this" to capture for instance frames. Set from the agent options.
+pub struct SnapshotConfig {
+    /// The most fields per frame, nested ones included
+    pub max_fields: usize,
+    /// 1 for only the fields of "this", 2 to also expand the objects in those fields, etc
+    pub max_depth: usize,
+    // Keyed by class signature, e.g. "Lcom/foo/Service;"
+    includes: HashMap<String, Vec<String>>,
+}
+
+impl SnapshotConfig {
+    pub fn new() -> SnapshotConfig {
+        return SnapshotConfig {
+            max_fields: DEFAULT_MAX_FIELDS,
+            max_depth: DEFAULT_MAX_DEPTH,
+            includes: HashMap::new(),
+        };
+    }
+
+    /// Limits the fields of the class to the given ones. The value is the Java class name, a colon,
+    /// and the field names separated by pipes, e.g. "com.foo.Service:name|id".
+    pub fn add_include(&mut self, value: &str) -> Result<(), AgentError> {
+        let mut pieces = value.splitn(2, ':');
+        let class_name = pieces.next().unwrap_or("").trim();
+        let fields = pieces.next().ok_or_else(|| {
+            AgentError::Other(format!("Field include {} missing colon after class name", value))
+        })?;
+        if class_name.is_empty() {
+            return Result::Err(AgentError::Other(format!("Field include {} missing class name", value)));
+        }
+        let class_sig = format!("L{};", class_name.replace('.', "/"));
+        let names = self.includes.entry(class_sig).or_insert_with(Vec::new);
+        names.extend(fields.split('|').map(|name| name.trim().to_string()).filter(|name| !name.is_empty()));
+        return Result::Ok(());
+    }
+
+    fn includes(&self, class_sig: &str, field_name: &str) -> bool {
+        return match self.includes.get(class_sig) {
+            Some(names) => names.iter().any(|name| name == field_name),
+            None => true,
+        };
+    }
+
+    /// The config set at load or `None` if snapshots are off
+    pub fn global() -> Option<&'static SnapshotConfig> {
+        let config = SNAPSHOT_CONFIG.load(Ordering::SeqCst);
+        return if config.is_null() { None } else { Some(unsafe { &*config }) };
+    }
+
+    /// Turns snapshots on with this config for the rest of the VM's life
+    pub fn set_global(self) {
+        SNAPSHOT_CONFIG.store(Box::into_raw(Box::new(self)), Ordering::SeqCst);
+    }
+}
+
+/// A field value from a snapshot, named by its path from "this", e.g. "this.config.url"
+pub struct FieldValue {
+    pub name: String,
+    pub typ: String,
+    pub val: jobject,
+}
+
+/// The instance fields of the object and its superclasses, up to the configured count and depth.
+/// Each value is a new local ref.
+pub unsafe fn snapshot(state: &AgentState,
+                       jni_env: *mut JNIEnv,
+                       config: &SnapshotConfig,
+                       obj: jobject,
+                       prefix: &str) -> Result<Vec<FieldValue>, AgentError> {
+    let mut ret = Vec::new();
+    add_fields(state, jni_env, config, obj, prefix, 1, &mut ret)?;
+    return Result::Ok(ret);
+}
+
+unsafe fn add_fields(state: &AgentState,
+                     jni_env: *mut JNIEnv,
+                     config: &SnapshotConfig,
+                     obj: jobject,
+                     prefix: &str,
+                     depth: usize,
+                     ret: &mut Vec<FieldValue>) -> Result<(), AgentError> {
+    // Includes are by the runtime class, not the one declaring the field
+    let obj_class = util::result_or_jni_ex((**jni_env).GetObjectClass.unwrap()(jni_env, obj), jni_env, "GetObjectClass")?;
+    let class_sig = state.jvmti.class_signature(obj_class);
+    let mut class = obj_class;
+    let res = class_sig.and_then(|class_sig| {
+        while !class.is_null() && ret.len() < config.max_fields {
+            for field in state.jvmti.class_fields(class)? {
+                if ret.len() >= config.max_fields { break; }
+                // Statics aren't part of the instance
+                if state.jvmti.field_modifiers(class, field)? & 0x0008 != 0 { continue; }
+                let (name, typ) = state.jvmti.field_name(class, field)?;
+                if !config.includes(&class_sig, &name) { continue; }
+                let val = field_value(state, jni_env, obj, field, &typ)?;
+                let path = format!("{}.{}", prefix, name);
+                if depth < config.max_depth && !val.is_null() && typ.starts_with('L') && !LEAF_TYPES.contains(&typ.as_ref()) {
+                    let nested = add_fields(state, jni_env, config, val, &path, depth + 1, ret);
+                    (**jni_env).DeleteLocalRef.unwrap()(jni_env, val);
+                    nested?;
+                } else {
+                    ret.push(FieldValue { name: path, typ: typ, val: val });
+                }
+            }
+            let super_class = (**jni_env).GetSuperclass.unwrap()(jni_env, class);
+            (**jni_env).DeleteLocalRef.unwrap()(jni_env, class);
+            class = super_class;
+        }
+        return Result::Ok(());
+    });
+    if !class.is_null() { (**jni_env).DeleteLocalRef.unwrap()(jni_env, class); }
+    return res;
+}
+
+unsafe fn field_value(state: &AgentState, jni_env: *mut JNIEnv, obj: jobject, field: jfieldID, typ: &str) -> Result<jobject, AgentError> {
+    return match typ {
+        "Z" => box_value(jni_env, state.box_meths.boolean, (**jni_env).GetBooleanField.unwrap()(jni_env, obj, field) as c_uint),
+        "B" => box_value(jni_env, state.box_meths.byte, (**jni_env).GetByteField.unwrap()(jni_env, obj, field) as c_int),
+        "C" => box_value(jni_env, state.box_meths.char, (**jni_env).GetCharField.unwrap()(jni_env, obj, field) as c_uint),
+        "S" => box_value(jni_env, state.box_meths.short, (**jni_env).GetShortField.unwrap()(jni_env, obj, field) as c_int),
+        "I" => box_value(jni_env, state.box_meths.int, (**jni_env).GetIntField.unwrap()(jni_env, obj, field)),
+        "J" => box_value(jni_env, state.box_meths.long, (**jni_env).GetLongField.unwrap()(jni_env, obj, field)),
+        "F" => box_value(jni_env, state.box_meths.float, (**jni_env).GetFloatField.unwrap()(jni_env, obj, field) as c_double),
+        "D" => box_value(jni_env, state.box_meths.double, (**jni_env).GetDoubleField.unwrap()(jni_env, obj, field) as c_double),
+        typ if typ.starts_with("[") || typ.starts_with("L") =>
+            util::result_or_jni_ex((**jni_env).GetObjectField.unwrap()(jni_env, obj, field), jni_env, "GetObjectField"),
+        _ => Result::Err(AgentError::Other(format!("Unrecognized type: {}", typ)))
+    };
+}
+
+unsafe fn box_value<T>(jni_env: *mut JNIEnv, (box_class, box_meth): MethodRef, val: T) -> Result<jobject, AgentError> {
+    let boxed = (**jni_env).CallStaticObjectMethod.unwrap()(jni_env, box_class, box_meth, val);
+    return util::result_or_jni_ex(boxed, jni_env, "CallStaticObjectMethod");
+}
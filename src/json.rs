@@ -0,0 +1,241 @@
+extern crate jni_sys;
+
+use error::AgentError;
+use jni_sys::{JNIEnv, JNI_FALSE, jint, jmethodID, jobject, jobjectArray, jsize, jstring};
+use render::{self, RenderedVar, VarRenderer};
+use state::AgentState;
+use std::ptr;
+use util;
+
+// Refs alive at once for a single throwable or frame, rendering has its own frames
+const JSON_LOCAL_REFS: jint = 16;
+
+///
+/// Writes a throwable, its causes and its suppressed throwables as JSON. Each throwable is:
+///
+/// ```text
+/// {"class": "java.lang.IllegalStateException", "message": "Oops" or null,
+///  "frames": [{"class": "com.foo.Bar", "method": "baz", "file": "Bar.java" or null, "line": 12,
+///              "params": [{"name": "this", "type": "Lcom/foo/Bar;", "value": "com.foo.Bar@6d06d69c"}] or null,
+///              "locals": [...] only if captured}],
+///  "cause": {...} or null,
+///  "suppressed": [{...}]}
+/// ```
+///
+/// A throwable already being written further up the chain is just the class and message along
+/// with `"circular": true`.
+pub struct JsonWriter<'a> {
+    state: &'a AgentState,
+    jni_env: *mut JNIEnv,
+    vars: VarRenderer<'a>,
+    /// Whether frames get their params, otherwise they're all null
+    capturing: bool,
+    /// The throwables being written, to catch cycles
+    ancestors: Vec<jobject>,
+    out: String,
+}
+
+impl<'a> JsonWriter<'a> {
+    pub unsafe fn new(state: &'a AgentState, jni_env: *mut JNIEnv, capturing: bool) -> Result<JsonWriter<'a>, AgentError> {
+        return Result::Ok(JsonWriter {
+            state: state,
+            jni_env: jni_env,
+            vars: VarRenderer::new(state, jni_env)?,
+            capturing: capturing,
+            ancestors: Vec::new(),
+            out: String::new(),
+        });
+    }
+
+    /// Writes the throwable and everything it refers to, giving back the whole JSON string
+    pub unsafe fn write(mut self, throwable: jobject) -> Result<jstring, AgentError> {
+        self.write_throwable(throwable)?;
+        return render::new_string(self.jni_env, &self.out);
+    }
+
+    unsafe fn write_throwable(&mut self, throwable: jobject) -> Result<(), AgentError> {
+        let jni_env = self.jni_env;
+        util::with_local_frame(jni_env, JSON_LOCAL_REFS, || {
+            let class = util::result_or_jni_ex((**jni_env).GetObjectClass.unwrap()(jni_env, throwable),
+                                               jni_env,
+                                               "GetObjectClass")?;
+            let class_name = render::java_class_name(&self.state.jvmti.class_signature(class)?);
+            self.out.push_str("{\"class\": ");
+            push_json_string(&mut self.out, &class_name);
+            self.out.push_str(", \"message\": ");
+            let message = self.call_string(throwable, self.state.json_meths.get_message)?;
+            self.push_string_or_null(message);
+            let circular = self.ancestors.iter().any(|ancestor| {
+                (**jni_env).IsSameObject.unwrap()(jni_env, *ancestor, throwable) != JNI_FALSE
+            });
+            if circular {
+                self.out.push_str(", \"circular\": true}");
+                return Result::Ok(ptr::null_mut());
+            }
+            self.ancestors.push(throwable);
+            let res = self.write_throwable_rest(throwable);
+            self.ancestors.pop();
+            res?;
+            self.out.push('}');
+            return Result::Ok(ptr::null_mut());
+        })?;
+        return Result::Ok(());
+    }
+
+    unsafe fn write_throwable_rest(&mut self, throwable: jobject) -> Result<(), AgentError> {
+        let jni_env = self.jni_env;
+        self.out.push_str(", \"frames\": [");
+        let trace = util::result_or_jni_ex((**jni_env).CallObjectMethod.unwrap()(jni_env,
+                                                                                 throwable,
+                                                                                 self.state.json_meths.get_stack_trace),
+                                           jni_env,
+                                           "CallObjectMethod")?;
+        for index in 0..self.array_len(trace)? {
+            if index > 0 { self.out.push_str(", "); }
+            util::with_local_frame(jni_env, JSON_LOCAL_REFS, || {
+                let elem = util::result_or_jni_ex((**jni_env).GetObjectArrayElement.unwrap()(jni_env, trace, index),
+                                                  jni_env,
+                                                  "GetObjectArrayElement")?;
+                self.write_frame(elem)?;
+                return Result::Ok(ptr::null_mut());
+            })?;
+        }
+        self.out.push_str("], \"cause\": ");
+        let cause = util::result_or_jni_ex((**jni_env).CallObjectMethod.unwrap()(jni_env, throwable, self.state.json_meths.get_cause),
+                                           jni_env,
+                                           "CallObjectMethod")?;
+        if cause.is_null() {
+            self.out.push_str("null");
+        } else {
+            self.write_throwable(cause)?;
+        }
+        self.out.push_str(", \"suppressed\": [");
+        let suppressed = util::result_or_jni_ex((**jni_env).CallObjectMethod.unwrap()(jni_env,
+                                                                                      throwable,
+                                                                                      self.state.json_meths.get_suppressed),
+                                                jni_env,
+                                                "CallObjectMethod")?;
+        for index in 0..self.array_len(suppressed)? {
+            if index > 0 { self.out.push_str(", "); }
+            let other = util::result_or_jni_ex((**jni_env).GetObjectArrayElement.unwrap()(jni_env, suppressed, index),
+                                               jni_env,
+                                               "GetObjectArrayElement")?;
+            let res = self.write_throwable(other);
+            (**jni_env).DeleteLocalRef.unwrap()(jni_env, other);
+            res?;
+        }
+        self.out.push(']');
+        return Result::Ok(());
+    }
+
+    unsafe fn write_frame(&mut self, elem: jobject) -> Result<(), AgentError> {
+        let jni_env = self.jni_env;
+        self.out.push_str("{\"class\": ");
+        let class_name = self.call_string(elem, self.state.json_meths.elem_class_name)?;
+        self.push_string_or_null(class_name);
+        self.out.push_str(", \"method\": ");
+        let method_name = self.call_string(elem, self.state.json_meths.elem_method_name)?;
+        self.push_string_or_null(method_name);
+        self.out.push_str(", \"file\": ");
+        let file_name = self.call_string(elem, self.state.json_meths.elem_file_name)?;
+        self.push_string_or_null(file_name);
+        let line = util::result_or_jni_ex((**jni_env).CallIntMethod.unwrap()(jni_env, elem, self.state.json_meths.elem_line_number),
+                                          jni_env,
+                                          "CallIntMethod")?;
+        self.out.push_str(&format!(", \"line\": {}, \"params\": ", line));
+        let params = self.elem_params(elem)?;
+        if params.is_null() {
+            self.out.push_str("null");
+        } else {
+            let (vars, locals) = self.vars.render_frame(params)?;
+            push_json_vars(&mut self.out, &vars);
+            if let Some(locals) = locals {
+                self.out.push_str(", \"locals\": ");
+                push_json_vars(&mut self.out, &locals);
+            }
+        }
+        self.out.push('}');
+        return Result::Ok(());
+    }
+
+    /// The params the element was given, or null if none or not capturing
+    unsafe fn elem_params(&self, elem: jobject) -> Result<jobjectArray, AgentError> {
+        let jni_env = self.jni_env;
+        if !self.capturing {
+            return Result::Ok(ptr::null_mut());
+        }
+        if self.state.attached {
+            return util::result_or_jni_ex((**jni_env).CallStaticObjectMethod.unwrap()(jni_env,
+                                                                                      self.state.native_class,
+                                                                                      self.state.json_meths.get_attached,
+                                                                                      elem),
+                                          jni_env,
+                                          "CallStaticObjectMethod");
+        }
+        return util::result_or_jni_ex((**jni_env).GetObjectField.unwrap()(jni_env,
+                                                                          elem,
+                                                                          self.state.replaced()?.param_info_field),
+                                      jni_env,
+                                      "GetObjectField");
+    }
+
+    unsafe fn call_string(&self, obj: jobject, meth: jmethodID) -> Result<Option<String>, AgentError> {
+        let jni_env = self.jni_env;
+        let str = util::result_or_jni_ex((**jni_env).CallObjectMethod.unwrap()(jni_env, obj, meth),
+                                         jni_env,
+                                         "CallObjectMethod")?;
+        if str.is_null() {
+            return Result::Ok(None);
+        }
+        let ret = render::java_string(jni_env, str, usize::max_value());
+        (**jni_env).DeleteLocalRef.unwrap()(jni_env, str);
+        return ret.map(Some);
+    }
+
+    unsafe fn array_len(&self, arr: jobjectArray) -> Result<jsize, AgentError> {
+        if arr.is_null() {
+            return Result::Ok(0);
+        }
+        let jni_env = self.jni_env;
+        return util::result_or_jni_ex((**jni_env).GetArrayLength.unwrap()(jni_env, arr), jni_env, "GetArrayLength");
+    }
+
+    fn push_string_or_null(&mut self, str: Option<String>) {
+        match str {
+            Some(ref str) => push_json_string(&mut self.out, str),
+            None => self.out.push_str("null"),
+        }
+    }
+}
+
+fn push_json_vars(out: &mut String, vars: &[RenderedVar]) {
+    out.push('[');
+    for (index, var) in vars.iter().enumerate() {
+        if index > 0 { out.push_str(", "); }
+        out.push_str("{\"name\": ");
+        push_json_string(out, &var.name);
+        out.push_str(", \"type\": ");
+        push_json_string(out, &var.sig);
+        out.push_str(", \"value\": ");
+        push_json_string(out, &var.value);
+        out.push('}');
+    }
+    out.push(']');
+}
+
+/// Pushes the string quoted, escaping what JSON requires
+fn push_json_string(out: &mut String, str: &str) {
+    out.push('"');
+    for c in str.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
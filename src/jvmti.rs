@@ -0,0 +1,322 @@
+extern crate jni_sys;
+
+use jni_sys::{JavaVM, JNI_OK, jclass, jdouble, jfieldID, jfloat, jint, jlong, jmethodID, jobject};
+use jvmti_sys::{jvmtiEnv, jvmtiError, jvmtiEvent, jvmtiEventCallbacks, jvmtiEventMode, jvmtiCapabilities, jvmtiFrameInfo,
+                jvmtiLocalVariableEntry, jthread, jlocation, JVMTI_VERSION};
+use std::ffi::CStr;
+use std::mem;
+use std::mem::size_of;
+use std::os::raw::{c_char, c_uchar, c_void};
+use std::ptr;
+use std::slice;
+use capabilities::Capabilities;
+use error::AgentError;
+use util;
+
+///
+/// A handle to a JVMTI environment. Calls return a `Result` instead of an error code, and memory
+/// allocated by JVMTI is handed back in a `JvmtiAlloc` that deallocates it when dropped.
+#[derive(Clone, Copy)]
+pub struct Jvmti {
+    env: *mut jvmtiEnv,
+}
+
+// JVMTI environments can be used from any thread
+unsafe impl Send for Jvmti {}
+unsafe impl Sync for Jvmti {}
+
+impl Jvmti {
+    /// Wraps an environment given to us by the JVM
+    pub unsafe fn new(env: *mut jvmtiEnv) -> Jvmti {
+        return Jvmti { env: env };
+    }
+
+    pub unsafe fn from_vm(vm: *mut JavaVM) -> Result<Jvmti, AgentError> {
+        let mut ptr: *mut c_void = ptr::null_mut() as *mut c_void;
+        let env_res = (**vm).GetEnv.unwrap()(vm, &mut ptr, JVMTI_VERSION);
+        if env_res != JNI_OK {
+            return Result::Err(AgentError::Jni { op: "GetEnv", code: env_res });
+        }
+        return Result::Ok(Jvmti::new(ptr as *mut jvmtiEnv));
+    }
+
+    /// The capabilities this environment could possess right now
+    pub fn potential_capabilities(&self) -> Result<Capabilities, AgentError> {
+        let mut caps = jvmtiCapabilities::default();
+        let caps_res = unsafe { (**self.env).GetPotentialCapabilities.unwrap()(self.env, &mut caps) };
+        return util::result_or_jvmti_err(Capabilities::from_raw(caps), caps_res, "GetPotentialCapabilities");
+    }
+
+    pub fn add_capabilities(&self, caps: &Capabilities) -> Result<(), AgentError> {
+        return unsafe { util::unit_or_jvmti_err((**self.env).AddCapabilities.unwrap()(self.env, caps.as_raw()), "AddCapabilities") };
+    }
+
+    pub fn set_event_callbacks(&self, callbacks: &jvmtiEventCallbacks) -> Result<(), AgentError> {
+        return unsafe {
+            util::unit_or_jvmti_err((**self.env).SetEventCallbacks.unwrap()(self.env,
+                                                                            callbacks,
+                                                                            size_of::<jvmtiEventCallbacks>() as jint), "SetEventCallbacks")
+        };
+    }
+
+    pub fn enable_event(&self, event: jvmtiEvent) -> Result<(), AgentError> {
+        return unsafe {
+            util::unit_or_jvmti_err((**self.env).SetEventNotificationMode.unwrap()(self.env,
+                                                                                   jvmtiEventMode::JVMTI_ENABLE,
+                                                                                   event,
+                                                                                   ptr::null_mut()), "SetEventNotificationMode")
+        };
+    }
+
+    pub fn disable_event(&self, event: jvmtiEvent) -> Result<(), AgentError> {
+        return unsafe {
+            util::unit_or_jvmti_err((**self.env).SetEventNotificationMode.unwrap()(self.env,
+                                                                                   jvmtiEventMode::JVMTI_DISABLE,
+                                                                                   event,
+                                                                                   ptr::null_mut()), "SetEventNotificationMode")
+        };
+    }
+
+    /// Runs the class file load hooks of retransformation capable environments on loaded classes
+    pub fn retransform_classes(&self, classes: &[jclass]) -> Result<(), AgentError> {
+        return unsafe {
+            util::unit_or_jvmti_err((**self.env).RetransformClasses.unwrap()(self.env,
+                                                                             classes.len() as jint,
+                                                                             classes.as_ptr()), "RetransformClasses")
+        };
+    }
+
+    pub fn allocate(&self, size: usize) -> Result<JvmtiAlloc<c_uchar>, AgentError> {
+        let mut mem: *mut c_uchar = ptr::null_mut();
+        unsafe { util::unit_or_jvmti_err((**self.env).Allocate.unwrap()(self.env, size as jlong, &mut mem), "Allocate")?; }
+        return Result::Ok(JvmtiAlloc::new(*self, mem));
+    }
+
+    pub fn stack_trace(&self, thread: jthread, max_depth: jint) -> Result<Vec<jvmtiFrameInfo>, AgentError> {
+        let mut frames: Vec<jvmtiFrameInfo> = Vec::with_capacity(max_depth as usize);
+        let mut frame_count: jint = 0;
+        unsafe {
+            let trace_res = (**self.env).GetStackTrace.unwrap()(self.env,
+                                                                thread,
+                                                                0,
+                                                                max_depth,
+                                                                frames.as_mut_ptr(), &mut frame_count);
+            util::unit_or_jvmti_err(trace_res, "GetStackTrace")?;
+            frames.set_len(frame_count as usize);
+        }
+        frames.shrink_to_fit();
+        return Result::Ok(frames);
+    }
+
+    pub fn local_instance(&self, thread: jthread, depth: jint) -> Result<jobject, AgentError> {
+        let mut obj: jobject = ptr::null_mut();
+        let inst_res = unsafe { (**self.env).GetLocalInstance.unwrap()(self.env, thread, depth, &mut obj) };
+        return util::result_or_jvmti_err(obj, inst_res, "GetLocalInstance");
+    }
+
+    pub fn local_int(&self, thread: jthread, depth: jint, slot: jint) -> Result<jint, AgentError> {
+        let mut val: jint = 0;
+        let local_res = unsafe { (**self.env).GetLocalInt.unwrap()(self.env, thread, depth, slot, &mut val) };
+        return util::result_or_jvmti_err(val, local_res, "GetLocalInt");
+    }
+
+    pub fn local_long(&self, thread: jthread, depth: jint, slot: jint) -> Result<jlong, AgentError> {
+        let mut val: jlong = 0;
+        let local_res = unsafe { (**self.env).GetLocalLong.unwrap()(self.env, thread, depth, slot, &mut val) };
+        return util::result_or_jvmti_err(val, local_res, "GetLocalLong");
+    }
+
+    pub fn local_float(&self, thread: jthread, depth: jint, slot: jint) -> Result<jfloat, AgentError> {
+        let mut val: jfloat = 0.0;
+        let local_res = unsafe { (**self.env).GetLocalFloat.unwrap()(self.env, thread, depth, slot, &mut val) };
+        return util::result_or_jvmti_err(val, local_res, "GetLocalFloat");
+    }
+
+    pub fn local_double(&self, thread: jthread, depth: jint, slot: jint) -> Result<jdouble, AgentError> {
+        let mut val: jdouble = 0.0;
+        let local_res = unsafe { (**self.env).GetLocalDouble.unwrap()(self.env, thread, depth, slot, &mut val) };
+        return util::result_or_jvmti_err(val, local_res, "GetLocalDouble");
+    }
+
+    pub fn local_object(&self, thread: jthread, depth: jint, slot: jint) -> Result<jobject, AgentError> {
+        let mut val: jobject = ptr::null_mut();
+        let local_res = unsafe { (**self.env).GetLocalObject.unwrap()(self.env, thread, depth, slot, &mut val) };
+        return util::result_or_jvmti_err(val, local_res, "GetLocalObject");
+    }
+
+    pub fn class_signature(&self, class: jclass) -> Result<String, AgentError> {
+        let mut sig: *mut c_char = ptr::null_mut();
+        let sig_res = unsafe { (**self.env).GetClassSignature.unwrap()(self.env, class, &mut sig, ptr::null_mut()) };
+        util::unit_or_jvmti_err(sig_res, "GetClassSignature")?;
+        return Result::Ok(JvmtiAlloc::new(*self, sig).to_string_lossy());
+    }
+
+    /// The class's defining loader, null for the bootstrap loader
+    pub fn class_loader(&self, class: jclass) -> Result<jobject, AgentError> {
+        let mut ret: jobject = ptr::null_mut();
+        let loader_res = unsafe { (**self.env).GetClassLoader.unwrap()(self.env, class, &mut ret) };
+        return util::result_or_jvmti_err(ret, loader_res, "GetClassLoader");
+    }
+
+    pub fn method_name(&self, method: jmethodID) -> Result<String, AgentError> {
+        let mut name: *mut c_char = ptr::null_mut();
+        let name_res = unsafe {
+            (**self.env).GetMethodName.unwrap()(self.env, method, &mut name, ptr::null_mut(), ptr::null_mut())
+        };
+        util::unit_or_jvmti_err(name_res, "GetMethodName")?;
+        return Result::Ok(JvmtiAlloc::new(*self, name).to_string_lossy());
+    }
+
+    pub fn method_signature(&self, method: jmethodID) -> Result<String, AgentError> {
+        let mut sig: *mut c_char = ptr::null_mut();
+        let sig_res = unsafe {
+            (**self.env).GetMethodName.unwrap()(self.env, method, ptr::null_mut(), &mut sig, ptr::null_mut())
+        };
+        util::unit_or_jvmti_err(sig_res, "GetMethodName")?;
+        return Result::Ok(JvmtiAlloc::new(*self, sig).to_string_lossy());
+    }
+
+    pub fn method_declaring_class(&self, method: jmethodID) -> Result<jclass, AgentError> {
+        let mut ret: jclass = ptr::null_mut();
+        let cls_res = unsafe { (**self.env).GetMethodDeclaringClass.unwrap()(self.env, method, &mut ret) };
+        return util::result_or_jvmti_err(ret, cls_res, "GetMethodDeclaringClass");
+    }
+
+    pub fn method_modifiers(&self, method: jmethodID) -> Result<jint, AgentError> {
+        let mut mods: jint = 0;
+        let mod_res = unsafe { (**self.env).GetMethodModifiers.unwrap()(self.env, method, &mut mods) };
+        return util::result_or_jvmti_err(mods, mod_res, "GetMethodModifiers");
+    }
+
+    /// The fields declared by the class itself, not its superclasses
+    pub fn class_fields(&self, class: jclass) -> Result<Vec<jfieldID>, AgentError> {
+        let mut fields: *mut jfieldID = ptr::null_mut();
+        let mut field_count: jint = 0;
+        let fields_res = unsafe { (**self.env).GetClassFields.unwrap()(self.env, class, &mut field_count, &mut fields) };
+        util::unit_or_jvmti_err(fields_res, "GetClassFields")?;
+        // Nothing is allocated when there are no fields
+        if fields.is_null() {
+            return Result::Ok(Vec::new());
+        }
+        let fields = JvmtiAlloc::new(*self, fields);
+        return Result::Ok(unsafe { slice::from_raw_parts(fields.as_ptr(), field_count as usize) }.to_vec());
+    }
+
+    /// The name and signature of the field
+    pub fn field_name(&self, class: jclass, field: jfieldID) -> Result<(String, String), AgentError> {
+        let mut name: *mut c_char = ptr::null_mut();
+        let mut sig: *mut c_char = ptr::null_mut();
+        let name_res = unsafe {
+            (**self.env).GetFieldName.unwrap()(self.env, class, field, &mut name, &mut sig, ptr::null_mut())
+        };
+        util::unit_or_jvmti_err(name_res, "GetFieldName")?;
+        return Result::Ok((JvmtiAlloc::new(*self, name).to_string_lossy(), JvmtiAlloc::new(*self, sig).to_string_lossy()));
+    }
+
+    pub fn field_modifiers(&self, class: jclass, field: jfieldID) -> Result<jint, AgentError> {
+        let mut mods: jint = 0;
+        let mod_res = unsafe { (**self.env).GetFieldModifiers.unwrap()(self.env, class, field, &mut mods) };
+        return util::result_or_jvmti_err(mods, mod_res, "GetFieldModifiers");
+    }
+
+    /// The identity hash code, without running any of the object's code
+    pub fn object_hash_code(&self, obj: jobject) -> Result<jint, AgentError> {
+        let mut hash: jint = 0;
+        let hash_res = unsafe { (**self.env).GetObjectHashCode.unwrap()(self.env, obj, &mut hash) };
+        return util::result_or_jvmti_err(hash, hash_res, "GetObjectHashCode");
+    }
+
+    /// The shallow size of the object in bytes
+    pub fn object_size(&self, obj: jobject) -> Result<jlong, AgentError> {
+        let mut size: jlong = 0;
+        let size_res = unsafe { (**self.env).GetObjectSize.unwrap()(self.env, obj, &mut size) };
+        return util::result_or_jvmti_err(size, size_res, "GetObjectSize");
+    }
+
+    /// Returns `None` if the method has no local variable table, e.g. it wasn't compiled with one
+    pub fn local_variable_table(&self, method: jmethodID) -> Result<Option<Vec<LocalVariable>>, AgentError> {
+        let mut entries: *mut jvmtiLocalVariableEntry = ptr::null_mut();
+        let mut entry_count: jint = 0;
+        let table_res = unsafe {
+            (**self.env).GetLocalVariableTable.unwrap()(self.env, method, &mut entry_count, &mut entries)
+        };
+        match util::unit_or_jvmti_err(table_res, "GetLocalVariableTable") {
+            Err(ref err) if err.is_jvmti_error(jvmtiError::JVMTI_ERROR_ABSENT_INFORMATION) => return Result::Ok(None),
+            other => other?,
+        }
+        // Nothing is allocated when the table is empty
+        if entries.is_null() {
+            return Result::Ok(Some(Vec::new()));
+        }
+        let entries = JvmtiAlloc::new(*self, entries);
+        if entry_count <= 0 {
+            return Result::Ok(Some(Vec::new()));
+        }
+        let entry_slice = unsafe { slice::from_raw_parts(entries.as_ptr(), entry_count as usize) };
+        // Take all the strings before reading any so they are all deallocated
+        let strs: Vec<(JvmtiAlloc<c_char>, JvmtiAlloc<c_char>, JvmtiAlloc<c_char>)> = entry_slice.iter().map(|entry| {
+            (JvmtiAlloc::new(*self, entry.name),
+             JvmtiAlloc::new(*self, entry.signature),
+             JvmtiAlloc::new(*self, entry.generic_signature))
+        }).collect();
+        return Result::Ok(Some(entry_slice.iter().zip(strs.iter()).map(|(entry, &(ref name, ref sig, _))| {
+            LocalVariable {
+                start_location: entry.start_location,
+                length: entry.length,
+                name: name.to_string_lossy(),
+                signature: sig.to_string_lossy(),
+                slot: entry.slot,
+            }
+        }).collect()));
+    }
+}
+
+/// An entry of a method's local variable table
+pub struct LocalVariable {
+    pub start_location: jlocation,
+    pub length: jint,
+    pub name: String,
+    pub signature: String,
+    pub slot: jint,
+}
+
+///
+/// Memory allocated by JVMTI. It is deallocated when dropped unless given up with `into_raw`.
+pub struct JvmtiAlloc<T> {
+    jvmti: Jvmti,
+    ptr: *mut T,
+}
+
+impl<T> JvmtiAlloc<T> {
+    fn new(jvmti: Jvmti, ptr: *mut T) -> JvmtiAlloc<T> {
+        return JvmtiAlloc { jvmti: jvmti, ptr: ptr };
+    }
+
+    pub fn as_ptr(&self) -> *mut T {
+        return self.ptr;
+    }
+
+    /// Gives up the memory without deallocating it, e.g. to hand it to the JVM
+    pub fn into_raw(self) -> *mut T {
+        let ptr = self.ptr;
+        mem::forget(self);
+        return ptr;
+    }
+}
+
+impl JvmtiAlloc<c_char> {
+    fn to_string_lossy(&self) -> String {
+        return unsafe { CStr::from_ptr(self.ptr).to_string_lossy().into_owned() };
+    }
+}
+
+impl<T> Drop for JvmtiAlloc<T> {
+    fn drop(&mut self) {
+        if !self.ptr.is_null() {
+            let de_res = unsafe { (**self.jvmti.env).Deallocate.unwrap()(self.jvmti.env, self.ptr as *mut c_uchar) };
+            if let Err(err) = util::unit_or_jvmti_err(de_res, "Deallocate") {
+                debug!("Unable to deallocate: {}", err);
+            }
+        }
+    }
+}
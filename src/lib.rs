@@ -2,19 +2,39 @@ extern crate jni_sys;
 #[macro_use]
 extern crate log;
 extern crate env_logger;
+extern crate regex;
+#[cfg(feature = "serde")]
+extern crate serde;
 
+mod annotations;
+mod capabilities;
+mod error;
+mod fields;
+mod json;
+mod jvmti;
 mod jvmti_sys;
 mod manip;
+mod redact;
+mod render;
+mod state;
 mod util;
 pub mod bytecode;
 pub mod native;
 
-use jni_sys::{JavaVM, jint, jclass, jobject, JNIEnv, JNI_OK};
-use jvmti_sys::{jvmtiEnv, JVMTI_VERSION, jvmtiEventCallbacks, jvmtiCapabilities, jvmtiEventMode, jvmtiEvent, jthread};
+use annotations::AnnotationIndex;
+use capabilities::{Capabilities, Capability};
+use error::AgentError;
+use fields::SnapshotConfig;
+use redact::RedactConfig;
+use render::RenderConfig;
+use jni_sys::{JavaVM, jint, jclass, jobject, JNIEnv, JNI_ERR, JNI_OK, JNI_VERSION_1_6};
+use jvmti::Jvmti;
+use state::AgentState;
+use jvmti_sys::{jvmtiEnv, jvmtiEventCallbacks, jvmtiEvent, jthread};
 use std::os::raw::{c_char, c_void, c_uchar};
 use std::ffi::CStr;
-use std::mem::size_of;
 use std::ptr;
+use std::slice;
 
 #[no_mangle]
 #[allow(non_snake_case)]
@@ -22,103 +42,316 @@ pub unsafe extern "C" fn Agent_OnLoad(vm: *mut JavaVM,
                                       options: *mut c_char,
                                       _reserved: *mut c_void)
                                       -> jint {
-    debug!("Agent loading");
-    match run(vm, options) {
-        Ok(()) => debug!("Agent loaded"),
-        Err(errStr) => info!("Agent unable to load: {}", errStr),
-    }
-    return 0;
+    return util::guard_ffi(ptr::null_mut(), "Agent_OnLoad", 0, || {
+        debug!("Agent loading");
+        match run(vm, options) {
+            Ok(()) => debug!("Agent loaded"),
+            Err(errStr) => info!("Agent unable to load: {}", errStr),
+        }
+        return 0;
+    });
+}
+
+#[no_mangle]
+#[allow(non_snake_case)]
+pub unsafe extern "C" fn Agent_OnAttach(vm: *mut JavaVM,
+                                        options: *mut c_char,
+                                        _reserved: *mut c_void)
+                                        -> jint {
+    return util::guard_ffi(ptr::null_mut(), "Agent_OnAttach", JNI_ERR, || {
+        debug!("Agent attaching");
+        return match attach(vm, options) {
+            Ok(()) => {
+                debug!("Agent attached");
+                0
+            },
+            Err(err_str) => {
+                info!("Agent unable to attach: {}", err_str);
+                JNI_ERR
+            },
+        };
+    });
 }
 
 #[no_mangle]
 #[allow(non_snake_case)]
-pub extern "C" fn Agent_OnUnload(_vm: *mut JavaVM) {
-    debug!("Agent unloaded");
+pub unsafe extern "C" fn Agent_OnUnload(vm: *mut JavaVM) {
+    util::guard_ffi(ptr::null_mut(), "Agent_OnUnload", (), || {
+        native::set_capture_enabled(false);
+        // The VM may be too far gone to hand out a JNI env, then the global refs go with it
+        let mut jni_env: *mut c_void = ptr::null_mut();
+        if (**vm).GetEnv.unwrap()(vm, &mut jni_env, JNI_VERSION_1_6) != JNI_OK {
+            jni_env = ptr::null_mut();
+        }
+        match AgentState::release(jni_env as *mut JNIEnv) {
+            Ok(true) => debug!("Agent unloaded"),
+            Ok(false) => info!("Agent unloaded, but its state was left allocated"),
+            Err(err_str) => info!("Agent unloaded with error: {}", err_str),
+        }
+    });
 }
 
-unsafe fn run(vm: *mut JavaVM, options: *mut c_char) -> Result<(), String> {
+unsafe fn run(vm: *mut JavaVM, options: *mut c_char) -> Result<(), AgentError> {
     // Init things like logging
     init(options);
 
     // Get the environment
-    let jvmti_env = get_env(vm)?;
+    let jvmti = Jvmti::from_vm(vm)?;
 
     // Add needed capabilities
-    add_capabilities(jvmti_env)?;
+    add_capabilities(jvmti, false)?;
 
     // Set the callbacks
-    set_event_callbacks(jvmti_env)?;
+    set_event_callbacks(jvmti)?;
 
     // Enable the notifications
-    return enable_notifications(jvmti_env);
+    return enable_notifications(jvmti);
+}
+
+unsafe fn attach(vm: *mut JavaVM, options: *mut c_char) -> Result<(), AgentError> {
+    init(options);
+    // Classes loaded before now were never scanned, and their retransform bytes lack CLASS
+    // retention annotations, so @Sensitive params would be shown as is
+    if AnnotationIndex::global().is_some() {
+        return Result::Err(AgentError::Other("The annotations option can't be used when attaching".to_string()));
+    }
+    let jvmti = Jvmti::from_vm(vm)?;
+    add_capabilities(jvmti, true)?;
+
+    // The VM is already live so there is no init event, do what it would do here
+    let mut jni_env: *mut c_void = ptr::null_mut();
+    let env_res = (**vm).GetEnv.unwrap()(vm, &mut jni_env, JNI_VERSION_1_6);
+    if env_res != JNI_OK {
+        return Result::Err(AgentError::Jni { op: "GetEnv", code: env_res });
+    }
+    let jni_env = jni_env as *mut JNIEnv;
+    manip::define_manip_class(jni_env)?;
+    AgentState::init(jvmti, jni_env, true)?.set_global();
+
+    // Only the load hook is needed, then have it run over the classes already loaded
+    set_event_callbacks(jvmti)?;
+    jvmti.enable_event(jvmtiEvent::JVMTI_EVENT_CLASS_FILE_LOAD_HOOK)?;
+    jvmti.enable_event(jvmtiEvent::JVMTI_EVENT_THREAD_END)?;
+    let state = AgentState::global().unwrap();
+    return jvmti.retransform_classes(&[state.throwable_class, state.elem_class]);
 }
 
-fn init(_options: *mut c_char) {
+unsafe fn init(options: *mut c_char) {
     let _ = env_logger::init();
+    if options.is_null() {
+        return;
+    }
+    // Options are comma separated, some with values, e.g. -agentpath:/path/to/lib=verify,this_fields_max=5
+    let mut snapshot: Option<SnapshotConfig> = None;
+    let mut render = RenderConfig::new();
+    let mut redact: Option<RedactConfig> = None;
+    for option in CStr::from_ptr(options).to_string_lossy().split(',') {
+        let option = option.trim();
+        let (key, value) = match option.find('=') {
+            Some(index) => (&option[..index], Some(&option[index + 1..])),
+            None => (option, None),
+        };
+        let res = match (key, value) {
+            ("", None) => Result::Ok(()),
+            ("verify", None) => {
+                manip::set_verify_transforms(true);
+                Result::Ok(())
+            },
+            ("locals", None) => {
+                native::set_capture_locals(true);
+                Result::Ok(())
+            },
+            ("annotations", None) => {
+                if AnnotationIndex::global().is_none() { AnnotationIndex::new().set_global(); }
+                Result::Ok(())
+            },
+            // Any of the field options turns on snapshots
+            ("this_fields", None) => {
+                snapshot.get_or_insert_with(SnapshotConfig::new);
+                Result::Ok(())
+            },
+            ("this_fields_max", Some(value)) => parse_count(value).map(|count| {
+                snapshot.get_or_insert_with(SnapshotConfig::new).max_fields = count;
+            }),
+            ("this_fields_depth", Some(value)) => parse_count(value).map(|count| {
+                snapshot.get_or_insert_with(SnapshotConfig::new).max_depth = count;
+            }),
+            ("this_fields_include", Some(value)) => snapshot.get_or_insert_with(SnapshotConfig::new).add_include(value),
+            ("render_depth", Some(value)) => parse_count(value).map(|count| render.max_depth = count),
+            ("render_elements", Some(value)) => parse_count(value).map(|count| render.max_elements = count),
+            ("render_budget", Some(value)) => parse_count(value).map(|count| render.trace_budget = count),
+            ("render_bytes", Some(value)) => render.set_byte_mode(value),
+            ("render_safe", None) => {
+                render.safe = true;
+                Result::Ok(())
+            },
+            ("render_trusted", Some(value)) => render.add_trusted(value),
+            ("eager", None) => {
+                render.eager = true;
+                Result::Ok(())
+            },
+            ("eager_max_len", Some(value)) => parse_count(value).map(|count| {
+                render.eager = true;
+                render.eager_max_len = count;
+            }),
+            ("retention", Some(value)) => render.set_retention(value),
+            ("retention_max_size", Some(value)) => parse_count(value).map(|size| render.max_retained_size = Some(size)),
+            ("frame_format", Some(value)) => percent_decode(value).and_then(|value| render.frame_format.set_frame(&value)),
+            ("frame_var_format", Some(value)) => percent_decode(value).map(|value| render.frame_format.var = value),
+            ("frame_separator", Some(value)) => percent_decode(value).map(|value| render.frame_format.separator = value),
+            ("frame_hide_this", None) => {
+                render.frame_format.hide_this = true;
+                Result::Ok(())
+            },
+            // Any of the redact options turns on redaction
+            ("redact", None) => {
+                redact.get_or_insert_with(RedactConfig::new).add_defaults();
+                Result::Ok(())
+            },
+            ("redact_name", Some(value)) => percent_decode(value).and_then(|value| {
+                redact.get_or_insert_with(RedactConfig::new).add_name(&value)
+            }),
+            ("redact_type", Some(value)) => percent_decode(value).and_then(|value| {
+                redact.get_or_insert_with(RedactConfig::new).add_type(&value)
+            }),
+            ("redact_value", Some(value)) => percent_decode(value).and_then(|value| {
+                redact.get_or_insert_with(RedactConfig::new).add_value(&value)
+            }),
+            ("redact_mask", Some(value)) => percent_decode(value).map(|value| {
+                redact.get_or_insert_with(RedactConfig::new).mask = value
+            }),
+            _ => Result::Err(AgentError::Other("Unknown agent option".to_string())),
+        };
+        if let Err(err_str) = res {
+            warn!("Ignoring agent option {}: {}", option, err_str);
+        }
+    }
+    if let Some(snapshot) = snapshot {
+        snapshot.set_global();
+    }
+    if let Some(redact) = redact {
+        redact.set_global();
+    }
+    render.set_global();
 }
 
-unsafe fn get_env(vm: *mut JavaVM) -> Result<*mut jvmtiEnv, String> {
-    let mut ptr: *mut c_void = ptr::null_mut() as *mut c_void;
-    let env_res = (**vm).GetEnv.unwrap()(vm, &mut ptr, JVMTI_VERSION);
-    if env_res != JNI_OK {
-        return Result::Err(format!("No environment, err: {}", env_res));
+fn parse_count(value: &str) -> Result<usize, AgentError> {
+    return value.trim().parse::<usize>().map_err(|err| AgentError::Other(format!("Invalid count: {}", err)));
+}
+
+/// Decodes "%XX" hex escapes so option values can have commas, spaces and the like, e.g. "%2C%20"
+fn percent_decode(value: &str) -> Result<String, AgentError> {
+    let bytes = value.as_bytes();
+    let mut ret = Vec::with_capacity(bytes.len());
+    let mut index = 0;
+    while index < bytes.len() {
+        if bytes[index] != b'%' {
+            ret.push(bytes[index]);
+            index += 1;
+            continue;
+        }
+        let hex = value.get(index + 1..index + 3)
+            .ok_or_else(|| AgentError::Other("Incomplete % escape".to_string()))?;
+        let byte = u8::from_str_radix(hex, 16)
+            .map_err(|_| AgentError::Other(format!("Invalid % escape %{}", hex)))?;
+        ret.push(byte);
+        index += 3;
     }
-    return Result::Ok(ptr as *mut jvmtiEnv);
+    return String::from_utf8(ret).map_err(|err| AgentError::Other(format!("Invalid escaped text: {}", err)));
 }
 
-unsafe fn add_capabilities(jvmti_env: *mut jvmtiEnv) -> Result<(), String> {
-    let caps = jvmtiCapabilities {
-        // can_access_local_variables | can_generate_all_class_hook_events
-        _bindgen_bitfield_1_: 0x00004000 | 0x04000000,
-        ..Default::default()
+unsafe fn add_capabilities(jvmti: Jvmti, attaching: bool) -> Result<(), AgentError> {
+    // Ask only for what the VM can give us and run without the rest
+    let mut wanted = Capabilities::new()
+        .with(Capability::AccessLocalVariables)
+        .with(Capability::GenerateAllClassHookEvents);
+    if attaching {
+        wanted = wanted.with(Capability::RetransformClasses);
+    }
+    let caps = wanted.intersect(&jvmti.potential_capabilities()?);
+    // Without it there is nothing to do when attached
+    if attaching && !caps.has(Capability::RetransformClasses) {
+        return Result::Err(AgentError::Other(format!("Capability {} is required to attach",
+                                                     Capability::RetransformClasses)));
+    }
+    for missing in wanted.missing_from(&caps) {
+        warn!("Capability {} is unavailable, {}", missing, disabled_feature(missing));
+    }
+    jvmti.add_capabilities(&caps)?;
+    native::set_access_locals(caps.has(Capability::AccessLocalVariables));
+    return Result::Ok(());
+}
+
+fn disabled_feature(cap: Capability) -> &'static str {
+    return match cap {
+        Capability::AccessLocalVariables =>
+            "parameter names and values will not be captured",
+        Capability::GenerateAllClassHookEvents =>
+            "Throwable and StackTraceElement may not be transformed if loaded from shared class data",
+        Capability::RetransformClasses =>
+            "already loaded classes can't be transformed",
     };
-    return util::unit_or_jvmti_err((**jvmti_env).AddCapabilities.unwrap()(jvmti_env, &caps));
 }
 
-unsafe fn set_event_callbacks(jvmti_env: *mut jvmtiEnv) -> Result<(), String> {
-    // We only need init and load hook
+fn set_event_callbacks(jvmti: Jvmti) -> Result<(), AgentError> {
+    // We only need init, load hook and thread end
     let cb = jvmtiEventCallbacks {
         ClassFileLoadHook: Some(class_file_load_hook),
         VMInit: Some(vm_init),
+        ThreadEnd: Some(thread_end),
         ..Default::default()
     };
-    let cb_res = (**jvmti_env).SetEventCallbacks.unwrap()(jvmti_env,
-                                                          &cb,
-                                                          size_of::<jvmtiEventCallbacks>() as i32);
-    return util::unit_or_jvmti_err(cb_res);
+    return jvmti.set_event_callbacks(&cb);
 }
 
-unsafe fn enable_notifications(jvmti_env: *mut jvmtiEnv) -> Result<(), String> {
-    enable_notification(jvmti_env, jvmtiEvent::JVMTI_EVENT_VM_INIT)?;
-    return enable_notification(jvmti_env, jvmtiEvent::JVMTI_EVENT_CLASS_FILE_LOAD_HOOK);
+fn enable_notifications(jvmti: Jvmti) -> Result<(), AgentError> {
+    jvmti.enable_event(jvmtiEvent::JVMTI_EVENT_VM_INIT)?;
+    jvmti.enable_event(jvmtiEvent::JVMTI_EVENT_THREAD_END)?;
+    return jvmti.enable_event(jvmtiEvent::JVMTI_EVENT_CLASS_FILE_LOAD_HOOK);
 }
 
-unsafe fn enable_notification(jvmti_env: *mut jvmtiEnv, event: jvmtiEvent) -> Result<(), String> {
-    let mode_res = (**jvmti_env).SetEventNotificationMode.unwrap()(jvmti_env,
-                                                                   jvmtiEventMode::JVMTI_ENABLE,
-                                                                   event,
-                                                                   ptr::null_mut());
-    return util::unit_or_jvmti_err(mode_res);
-}
-
-unsafe fn transform_class_file(jvmti_env: *mut jvmtiEnv,
+unsafe fn transform_class_file(jvmti: Jvmti,
                                jni_env: *mut JNIEnv,
                                class_being_redefined: jclass,
+                               loader: jobject,
                                name: *const c_char,
                                class_data_len: jint,
                                class_data: *const c_uchar,
                                new_class_data_len: *mut jint,
                                new_class_data: *mut *mut c_uchar)
-                               -> Result<(), String> {
-    // Must have name and must be being first class definition
-    if name.is_null() || !class_being_redefined.is_null() {
+                               -> Result<(), AgentError> {
+    if name.is_null() {
         return Result::Ok(());
     }
+    // Classes already loaded when attached are retransformed in a way that adds no members
+    if !class_being_redefined.is_null() {
+        if !AgentState::global().map_or(false, |state| state.attached) {
+            return Result::Ok(());
+        }
+        return match CStr::from_ptr(name).to_str() {
+            Ok("java/lang/Throwable") =>
+                manip::retransform_throwable_class(jvmti, jni_env, class_data_len, class_data, new_class_data_len, new_class_data),
+            Ok("java/lang/StackTraceElement") =>
+                manip::retransform_element_class(jvmti, jni_env, class_data_len, class_data, new_class_data_len, new_class_data),
+            _ =>
+                Result::Ok(())
+        };
+    }
     return match CStr::from_ptr(name).to_str() {
         Ok("java/lang/Throwable") =>
-            manip::manip_throwable_class(jvmti_env, jni_env, class_data_len, class_data, new_class_data_len, new_class_data),
+            manip::manip_throwable_class(jvmti, jni_env, class_data_len, class_data, new_class_data_len, new_class_data),
         Ok("java/lang/StackTraceElement") =>
-            manip::manip_element_class(jvmti_env, jni_env, class_data_len, class_data, new_class_data_len, new_class_data),
+            manip::manip_element_class(jvmti, jni_env, class_data_len, class_data, new_class_data_len, new_class_data),
+        Ok(class_name) => {
+            if let Some(index) = AnnotationIndex::global() {
+                // A class we can't read just has no annotations as far as we're concerned
+                let class_bytes = slice::from_raw_parts(class_data, class_data_len as usize);
+                if let Err(err_str) = index.scan(jni_env, loader, class_name, class_bytes) {
+                    debug!("Unable to scan annotations of {}: {}", class_name, err_str);
+                }
+            }
+            Result::Ok(())
+        },
         _ =>
             Result::Ok(())
     }
@@ -127,7 +360,7 @@ unsafe fn transform_class_file(jvmti_env: *mut jvmtiEnv,
 unsafe extern "C" fn class_file_load_hook(jvmti_env: *mut jvmtiEnv,
                                           jni_env: *mut JNIEnv,
                                           class_being_redefined: jclass,
-                                          _loader: jobject,
+                                          loader: jobject,
                                           name: *const c_char,
                                           _protection_domain: jobject,
                                           class_data_len: jint,
@@ -135,25 +368,42 @@ unsafe extern "C" fn class_file_load_hook(jvmti_env: *mut jvmtiEnv,
                                           new_class_data_len: *mut jint,
                                           new_class_data: *mut *mut c_uchar)
                                           -> () {
-    match transform_class_file(jvmti_env,
-                               jni_env,
-                               class_being_redefined,
-                               name,
-                               class_data_len,
-                               class_data,
-                               new_class_data_len,
-                               new_class_data) {
-        Ok(()) => (),
-        Err(err_str) => info!("Failed to hook class: {}", err_str)
-    }
+    util::guard_ffi(jni_env, "ClassFileLoadHook", (), || {
+        match transform_class_file(Jvmti::new(jvmti_env),
+                                   jni_env,
+                                   class_being_redefined,
+                                   loader,
+                                   name,
+                                   class_data_len,
+                                   class_data,
+                                   new_class_data_len,
+                                   new_class_data) {
+            Ok(()) => (),
+            Err(err_str) => info!("Failed to hook class: {}", err_str)
+        }
+    });
 }
 
 unsafe extern "C" fn vm_init(jvmti_env: *mut jvmtiEnv, jni_env: *mut JNIEnv, _thread: jthread) -> () {
-    info!("Agent initializing");
-    // Set the global jvmti env for later jni use
-    native::init(jvmti_env);
-    match manip::define_manip_class(jni_env) {
-        Ok(()) => info!("Agent initialized"),
-        Err(err_str) => info!("Unable to initialize agent: {}", err_str),
-    }
-}
\ No newline at end of file
+    util::guard_ffi(jni_env, "VMInit", (), || {
+        info!("Agent initializing");
+        // Our class has to be there before the state can look it up
+        let init_res = manip::define_manip_class(jni_env)
+            .and_then(|()| AgentState::init(Jvmti::new(jvmti_env), jni_env, false));
+        // Set the global state for later jni use
+        match init_res {
+            Ok(state) => {
+                state.set_global();
+                info!("Agent initialized")
+            },
+            Err(err_str) => info!("Unable to initialize agent: {}", err_str),
+        }
+    });
+}
+
+unsafe extern "C" fn thread_end(_jvmti_env: *mut jvmtiEnv, jni_env: *mut JNIEnv, _thread: jthread) -> () {
+    util::guard_ffi(jni_env, "ThreadEnd", (), || {
+        // The thread's last trace budget would otherwise keep its weak ref for good
+        render::end_thread(jni_env);
+    });
+}
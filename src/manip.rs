@@ -1,19 +1,33 @@
 extern crate jni_sys;
 extern crate env_logger;
 
+use error::AgentError;
 use util;
-use jni_sys::{JNIEnv, jbyte, jclass, jint, jlong};
-use jvmti_sys::jvmtiEnv;
+use jni_sys::{JNIEnv, jbyte, jclass, jint};
+use jvmti::Jvmti;
 use std::ffi::CString;
 use std::ptr;
 use std::os::raw::c_uchar;
-use std::io::{Cursor, Error};
+use std::borrow::Cow;
+use std::io::{Error, ErrorKind};
+use std::mem;
 use std::slice;
-use bytecode::classfile::{AccessFlags, Attribute, Classfile, Constant, ConstantPoolIndex, Field, FieldAccessFlags, Instruction, Method, MethodAccessFlags};
+use std::sync::atomic::{AtomicBool, Ordering};
+use bytecode::classfile::{AccessFlags, Attribute, Constant, ConstantPool, ConstantPoolIndex, Field, FieldAccessFlags, Instruction, Method,
+                          MethodAccessFlags, StackMapFrame, VerificationType};
+use bytecode::verifier::ClassVerifier;
+use bytecode::visitor::{ClassFilter, FieldVisitor, MethodVisitor};
 use bytecode::io::reader::ClassReader;
 use bytecode::io::writer::ClassWriter;
 
-pub unsafe fn define_manip_class(jni_env: *mut JNIEnv) -> Result<(), String> {
+static VERIFY_TRANSFORMS: AtomicBool = AtomicBool::new(false);
+
+/// Sets whether transformed classes are verified before being handed to the JVM
+pub fn set_verify_transforms(verify: bool) {
+    VERIFY_TRANSFORMS.store(verify, Ordering::SeqCst);
+}
+
+pub unsafe fn define_manip_class(jni_env: *mut JNIEnv) -> Result<(), AgentError> {
     debug!("Defining class");
     // Get the bytes from file
     let class_bytes = include_bytes!("../javalib/native/build/classes/main/stackparam/StackParamNative.class");
@@ -26,234 +40,598 @@ pub unsafe fn define_manip_class(jni_env: *mut JNIEnv) -> Result<(), String> {
                                              class_bytes.as_ptr() as *const jbyte,
                                              class_bytes.len() as i32);
     // Confirm no exception
-    util::result_or_jni_ex((), jni_env)?;
+    util::result_or_jni_ex((), jni_env, "DefineClass")?;
     return Result::Ok(());
 }
 
-pub unsafe fn manip_throwable_class(jvmti_env: *mut jvmtiEnv,
+pub unsafe fn manip_throwable_class(jvmti: Jvmti,
                                     _jni_env: *mut JNIEnv,
                                     class_data_len: jint,
                                     class_data: *const c_uchar,
                                     new_class_data_len: *mut jint,
                                     new_class_data: *mut *mut c_uchar)
-                                    -> Result<(), String> {
-    // Read the class
-    let mut class_file = read_class(class_data_len, class_data)?;
-
-    // Do transforms
-    add_stack_params_field(&mut class_file);
-    add_native_stack_params_method(&mut class_file);
-    update_fill_method(&mut class_file)?;
-    replace_our_trace_method(&mut class_file)?;
-
-    // Write the class
-    return write_class(jvmti_env, &class_file, new_class_data_len, new_class_data);
+                                    -> Result<(), AgentError> {
+    return transform_class(jvmti, class_data_len, class_data, new_class_data_len, new_class_data, |filter| {
+        // Add "private transient Object[][] stackParams" field. Note, we choose not to explicitly set
+        // the stackParams field to null in Throwable constructors because we do it in fillInStackTrace
+        // one way or another.
+        filter.with_field_visitor(NewField {
+            access_flags: FieldAccessFlags::Private as u16 + FieldAccessFlags::Transient as u16,
+            name: "stackParams",
+            desc: "[[Ljava/lang/Object;",
+        })
+        // Create native stackParamFillInStackTrace(Thread)
+        .with_method_visitor(NewMethod {
+            access_flags: MethodAccessFlags::Private as u16 + MethodAccessFlags::Native as u16,
+            name: "stackParamFillInStackTrace",
+            desc: "(Ljava/lang/Thread;)Ljava/lang/Throwable;",
+        })
+        .with_method_visitor(FillMethodUpdate {
+            fill_class: "java/lang/Throwable",
+            fill_name: "stackParamFillInStackTrace",
+            fill_desc: "(Ljava/lang/Thread;)Ljava/lang/Throwable;",
+            static_fill: false,
+            found: false,
+        })
+        // Rename getOurStackTrace to $$stack_param$$getOurStackTrace, then create a new
+        // (synchronized) version that is our native one.
+        .with_method_visitor(NativeReplacement {
+            access_flags: MethodAccessFlags::Private as u16 + MethodAccessFlags::Synchronized as u16 +
+                MethodAccessFlags::Native as u16,
+            name: "getOurStackTrace",
+            desc: "()[Ljava/lang/StackTraceElement;",
+            found: false,
+        })
+    });
 }
 
-pub unsafe fn manip_element_class(jvmti_env: *mut jvmtiEnv,
+pub unsafe fn manip_element_class(jvmti: Jvmti,
                                   _jni_env: *mut JNIEnv,
                                   class_data_len: jint,
                                   class_data: *const c_uchar,
                                   new_class_data_len: *mut jint,
                                   new_class_data: *mut *mut c_uchar)
-                                  -> Result<(), String> {
-    // Read the class
-    let mut class_file = read_class(class_data_len, class_data)?;
+                                  -> Result<(), AgentError> {
+    return transform_class(jvmti, class_data_len, class_data, new_class_data_len, new_class_data, |filter| {
+        // Add "transient Object[] paramInfo" field. Note, even if we had code to manip <init> to set
+        // our field as null here, it doesn't help as who knows how the StackTraceElement is inited.
+        filter.with_field_visitor(NewField {
+            access_flags: FieldAccessFlags::Transient as u16,
+            name: "paramInfo",
+            desc: "[Ljava/lang/Object;",
+        })
+        // Add "private String paramStr" field. It isn't transient, so the params rendered into it by
+        // writeObject go with the element when serialized instead of the raw values.
+        .with_field_visitor(NewField {
+            access_flags: FieldAccessFlags::Private as u16,
+            name: "paramStr",
+            desc: "Ljava/lang/String;",
+        })
+        // Change current toString to $$stack_param$$toString and make a new native one
+        .with_method_visitor(NativeReplacement {
+            access_flags: MethodAccessFlags::Public as u16 + MethodAccessFlags::Native as u16,
+            name: "toString",
+            desc: "()Ljava/lang/String;",
+            found: false,
+        })
+        // Create native writeObject(ObjectOutputStream), which Java 8's element doesn't have
+        .with_method_visitor(NewMethod {
+            access_flags: MethodAccessFlags::Private as u16 + MethodAccessFlags::Native as u16,
+            name: "writeObject",
+            desc: "(Ljava/io/ObjectOutputStream;)V",
+        })
+    });
+}
 
-    // Do transforms
-    add_param_info_field(&mut class_file);
-    replace_elem_to_string(&mut class_file)?;
+/// The retransform compatible version of `manip_throwable_class`. No members can be added, so the
+/// fill and the trace are handed to static hooks on our class which keep the params on the side.
+pub unsafe fn retransform_throwable_class(jvmti: Jvmti,
+                                          _jni_env: *mut JNIEnv,
+                                          class_data_len: jint,
+                                          class_data: *const c_uchar,
+                                          new_class_data_len: *mut jint,
+                                          new_class_data: *mut *mut c_uchar)
+                                          -> Result<(), AgentError> {
+    return transform_class(jvmti, class_data_len, class_data, new_class_data_len, new_class_data, |filter| {
+        filter.with_method_visitor(FillMethodUpdate {
+            fill_class: "stackparam/StackParamNative",
+            fill_name: "fillInStackTraceHook",
+            fill_desc: "(Ljava/lang/Throwable;Ljava/lang/Thread;)Ljava/lang/Throwable;",
+            static_fill: true,
+            found: false,
+        })
+        .with_method_visitor(ReturnHook {
+            name: "getOurStackTrace",
+            desc: "()[Ljava/lang/StackTraceElement;",
+            hook_name: "ourStackTraceHook",
+            hook_desc: "([Ljava/lang/StackTraceElement;Ljava/lang/Throwable;)[Ljava/lang/StackTraceElement;",
+            found: false,
+        })
+    });
+}
 
-    // Write the class
-    return write_class(jvmti_env, &class_file, new_class_data_len, new_class_data);
+/// The retransform compatible version of `manip_element_class`
+pub unsafe fn retransform_element_class(jvmti: Jvmti,
+                                        _jni_env: *mut JNIEnv,
+                                        class_data_len: jint,
+                                        class_data: *const c_uchar,
+                                        new_class_data_len: *mut jint,
+                                        new_class_data: *mut *mut c_uchar)
+                                        -> Result<(), AgentError> {
+    return transform_class(jvmti, class_data_len, class_data, new_class_data_len, new_class_data, |filter| {
+        filter.with_method_visitor(ReturnHook {
+            name: "toString",
+            desc: "()Ljava/lang/String;",
+            hook_name: "elementToStringHook",
+            hook_desc: "(Ljava/lang/String;Ljava/lang/StackTraceElement;)Ljava/lang/String;",
+            found: false,
+        })
+    });
 }
 
-unsafe fn read_class(class_data_len: jint, class_data: *const c_uchar) -> Result<Classfile, String> {
+unsafe fn transform_class<F>(jvmti: Jvmti,
+                             class_data_len: jint,
+                             class_data: *const c_uchar,
+                             new_class_data_len: *mut jint,
+                             new_class_data: *mut *mut c_uchar,
+                             add_transforms: F) -> Result<(), AgentError>
+    where F: for<'v, 'a> FnOnce(ClassFilter<'v, 'a>) -> ClassFilter<'v, 'a> {
+    // The class is read in place, only new or changed parts are allocated
     let class_data_bytes = slice::from_raw_parts(class_data, class_data_len as usize);
-    let mut rdr = Cursor::new(class_data_bytes);
-    return str_err(ClassReader::read_class(&mut rdr));
+    // Stream the class through the transforms into a buffer, only handing it to the JVM if they
+    // all succeeded
+    let mut new_class_bytes: Vec<u8> = Vec::new();
+    {
+        let mut writer = ClassWriter::new(&mut new_class_bytes);
+        let mut filter = add_transforms(ClassFilter::new(&mut writer));
+        ClassReader::accept(class_data_bytes, &mut filter)?;
+    }
+    if VERIFY_TRANSFORMS.load(Ordering::SeqCst) {
+        // The JVM gives little detail on a class it can't verify and the original is still usable
+        let verify_res = ClassReader::read_class_bytes(&new_class_bytes)
+            .map_err(|err| format!("{}", err))
+            .and_then(|class| ClassVerifier::verify(&class).map_err(|err| format!("{}", err)));
+        if let Err(err_str) = verify_res {
+            error!("Transformed class failed verification, keeping the original: {}", err_str);
+            return Result::Ok(());
+        }
+    }
+    return write_class(jvmti, &new_class_bytes, new_class_data_len, new_class_data);
 }
 
-unsafe fn write_class(jvmti_env: *mut jvmtiEnv,
-                      class_file: &Classfile,
+unsafe fn write_class(jvmti: Jvmti,
+                      class_bytes: &Vec<u8>,
                       new_class_data_len: *mut jint,
-                      new_class_data: *mut *mut c_uchar) -> Result<(), String> {
-    let mut new_class_curs = Cursor::new(Vec::new());
-    str_err(ClassWriter::new(&mut new_class_curs).write_class(&class_file))?;
-    let new_class_vec: &Vec<u8> = new_class_curs.get_ref();
-    ptr::write(new_class_data_len, new_class_vec.len() as jint);
-    let alloc_res = (**jvmti_env).Allocate.unwrap()(jvmti_env, new_class_vec.len() as jlong, new_class_data);
-    util::unit_or_jvmti_err(alloc_res)?;
-    ptr::copy_nonoverlapping(new_class_vec.as_ptr(), *new_class_data, new_class_vec.len());
+                      new_class_data: *mut *mut c_uchar) -> Result<(), AgentError> {
+    let mem = jvmti.allocate(class_bytes.len())?;
+    ptr::copy_nonoverlapping(class_bytes.as_ptr(), mem.as_ptr(), class_bytes.len());
+    // The JVM owns the new class data from here
+    ptr::write(new_class_data_len, class_bytes.len() as jint);
+    ptr::write(new_class_data, mem.into_raw());
     return Result::Ok(());
 }
 
-unsafe fn add_param_info_field(class_file: &mut Classfile) {
-    let field_name_idx = ConstantPoolIndex { idx: utf8_const(class_file, "paramInfo") };
-    let field_desc_idx = ConstantPoolIndex { idx: utf8_const(class_file, "[Ljava/lang/Object;") };
-    class_file.fields.push(Field {
-        access_flags: AccessFlags { flags: FieldAccessFlags::Transient as u16 },
-        name_index: field_name_idx,
-        descriptor_index: field_desc_idx,
-        attributes: Vec::new(),
-    });
+/// Adds a field with no attributes after the existing ones
+struct NewField {
+    access_flags: u16,
+    name: &'static str,
+    desc: &'static str,
+}
 
-    // Note, even if we had code to manip <init> to set our field as null here, it doesn't
-    // help as who knows how the StackTraceElement is inited.
+impl<'a> FieldVisitor<'a> for NewField {
+    fn visit_end(&mut self, cp: &mut ConstantPool<'a>) -> Result<Vec<Field<'a>>, Error> {
+        return Result::Ok(vec![Field {
+            access_flags: AccessFlags { flags: self.access_flags },
+            name_index: ConstantPoolIndex { idx: utf8_const(cp, self.name) },
+            descriptor_index: ConstantPoolIndex { idx: utf8_const(cp, self.desc) },
+            attributes: Vec::new(),
+        }]);
+    }
 }
 
-unsafe fn replace_elem_to_string(class_file: &mut Classfile) -> Result<(), String> {
-    // Change current toString to $$stack_param$$toString and make a new native one
+/// Adds a method with no attributes (so it has to be native or abstract) after the existing ones
+struct NewMethod {
+    access_flags: u16,
+    name: &'static str,
+    desc: &'static str,
+}
 
-    // Rename
-    let mut found = false;
-    let meth_to_str_name_idx = utf8_const(class_file, "toString");
-    let meth_ret_str_desc_idx = utf8_const(class_file, "()Ljava/lang/String;");
-    let new_meth_to_str_name_idx = utf8_const(class_file, "$$stack_param$$toString");
-    for method in class_file.methods.iter_mut() {
-        if method.name_index.idx == meth_to_str_name_idx && method.descriptor_index.idx == meth_ret_str_desc_idx {
-            found = true;
-            method.name_index = ConstantPoolIndex { idx: new_meth_to_str_name_idx };
+impl<'a> MethodVisitor<'a> for NewMethod {
+    fn visit_end(&mut self, cp: &mut ConstantPool<'a>) -> Result<Vec<Method<'a>>, Error> {
+        return Result::Ok(vec![Method {
+            access_flags: AccessFlags { flags: self.access_flags },
+            name_index: ConstantPoolIndex { idx: utf8_const(cp, self.name) },
+            descriptor_index: ConstantPoolIndex { idx: utf8_const(cp, self.desc) },
+            attributes: Vec::new(),
+        }]);
+    }
+}
+
+/// Renames an existing method to $$stack_param$$ + name and adds a native one in its place
+struct NativeReplacement {
+    access_flags: u16,
+    name: &'static str,
+    desc: &'static str,
+    found: bool,
+}
+
+impl<'a> MethodVisitor<'a> for NativeReplacement {
+    fn visit_method(&mut self, cp: &mut ConstantPool<'a>, mut method: Method<'a>) -> Result<Option<Method<'a>>, Error> {
+        if method.name_index.idx == utf8_const(cp, self.name) && method.descriptor_index.idx == utf8_const(cp, self.desc) {
+            self.found = true;
+            method.name_index = ConstantPoolIndex { idx: utf8_const(cp, &format!("$$stack_param$${}", self.name)) };
         }
+        return Result::Ok(Some(method));
+    }
+
+    fn visit_end(&mut self, cp: &mut ConstantPool<'a>) -> Result<Vec<Method<'a>>, Error> {
+        if !self.found { return Result::Err(manip_err(&format!("Unable to find {}", self.name))); }
+        return Result::Ok(vec![Method {
+            access_flags: AccessFlags { flags: self.access_flags },
+            name_index: ConstantPoolIndex { idx: utf8_const(cp, self.name) },
+            descriptor_index: ConstantPoolIndex { idx: utf8_const(cp, self.desc) },
+            attributes: Vec::new(),
+        }]);
     }
-    if !found { return Result::Err("Unable to find toString".to_string()); }
-
-    // Make new native method
-    class_file.methods.push(Method {
-        access_flags: AccessFlags { flags: MethodAccessFlags::Public as u16 + MethodAccessFlags::Native as u16 },
-        name_index: ConstantPoolIndex { idx: meth_to_str_name_idx },
-        descriptor_index: ConstantPoolIndex { idx: meth_ret_str_desc_idx },
-        attributes: Vec::new()
-    });
-    return Result::Ok(());
 }
 
-unsafe fn add_stack_params_field(class_file: &mut Classfile) {
-    // Add "private transient Object[][] stackParams" field
-    let field_name_idx = ConstantPoolIndex { idx: utf8_const(class_file, "stackParams") };
-    let field_desc_idx = ConstantPoolIndex { idx: utf8_const(class_file, "[[Ljava/lang/Object;") };
-    class_file.fields.push(Field {
-        access_flags: AccessFlags { flags: FieldAccessFlags::Private as u16 + FieldAccessFlags::Transient as u16 },
-        name_index: field_name_idx,
-        descriptor_index: field_desc_idx,
-        attributes: Vec::new(),
-    });
+/// Changes existing fillInStackTrace to call the given fill taking "this" and the current thread right
+/// after fillInStackTrace(0). The fill returns the throwable so the stack is left the same.
+struct FillMethodUpdate {
+    fill_class: &'static str,
+    fill_name: &'static str,
+    fill_desc: &'static str,
+    static_fill: bool,
+    found: bool,
+}
 
-    // Note, we choose not to explicitly set the stackParams field to null in Throwable
-    // constructors because we do it in fillInStackTrace one way or another
+impl<'a> MethodVisitor<'a> for FillMethodUpdate {
+    fn visit_method(&mut self, cp: &mut ConstantPool<'a>, mut method: Method<'a>) -> Result<Option<Method<'a>>, Error> {
+        if method.name_index.idx != utf8_const(cp, "fillInStackTrace") ||
+            method.descriptor_index.idx != utf8_const(cp, "()Ljava/lang/Throwable;") {
+            return Result::Ok(Some(method));
+        }
+        self.found = true;
+        let curr_thread_ref_idx = method_ref_const(cp, "java/lang/Thread", "currentThread", "()Ljava/lang/Thread;");
+        let native_fill_meth_ref_idx = method_ref_const(cp, "java/lang/Throwable", "fillInStackTrace", "(I)Ljava/lang/Throwable;");
+        let new_fill_meth_ref_idx = method_ref_const(cp, self.fill_class, self.fill_name, self.fill_desc);
+        let code_attr = method.attributes.iter_mut().find(|attr| match attr {
+            &&mut Attribute::Code { .. } => true,
+            _ => false
+        }).ok_or(manip_err("Fill method has no code"))?;
+        // Find the index of the invoke special
+        let fill_invoke_idx = match code_attr {
+            &mut Attribute::Code { ref mut code, .. } => code.instructions_mut().iter().position(|i| {
+                match i {
+                    &Instruction::INVOKESPECIAL(ref idx) if *idx == native_fill_meth_ref_idx as u16 => true,
+                    _ => false
+                }
+            }),
+            _ => None
+        }.ok_or(manip_err("Cannot find invoke of native fill"))?;
+        // Call mine afterwards. "this" is currently on the stack already. It takes the current thread,
+        // so we grab that statically before calling so it is on the stack (current max stack of >= 2 is
+        // still ok for us). Result is a throwable so the stack is left how we got it.
+        insert_instructions(code_attr, fill_invoke_idx + 1, vec![
+            Instruction::INVOKESTATIC(curr_thread_ref_idx as u16),
+            if self.static_fill {
+                Instruction::INVOKESTATIC(new_fill_meth_ref_idx as u16)
+            } else {
+                Instruction::INVOKESPECIAL(new_fill_meth_ref_idx as u16)
+            }
+        ])?;
+        return Result::Ok(Some(method));
+    }
+
+    fn visit_end(&mut self, _cp: &mut ConstantPool<'a>) -> Result<Vec<Method<'a>>, Error> {
+        if !self.found { return Result::Err(manip_err("Cannot find fill method")); }
+        return Result::Ok(Vec::new());
+    }
 }
 
-unsafe fn add_native_stack_params_method(class_file: &mut Classfile) {
-    // Create native stackParamFillInStackTrace(Thread)
-    let sp_fill_meth_name_idx = utf8_const(class_file, "stackParamFillInStackTrace");
-    let meth_thread_ret_throwable_idx = utf8_const(class_file, "(Ljava/lang/Thread;)Ljava/lang/Throwable;");
-    class_file.methods.push(Method {
-        access_flags: AccessFlags { flags: MethodAccessFlags::Private as u16 + MethodAccessFlags::Native as u16 },
-        name_index: ConstantPoolIndex { idx: sp_fill_meth_name_idx },
-        descriptor_index: ConstantPoolIndex { idx: meth_thread_ret_throwable_idx },
-        attributes: Vec::new()
-    });
+/// Passes what an instance method returns, along with "this", through a static hook on our class
+/// whose result is returned instead
+struct ReturnHook {
+    name: &'static str,
+    desc: &'static str,
+    hook_name: &'static str,
+    hook_desc: &'static str,
+    found: bool,
 }
 
-unsafe fn update_fill_method(class_file: &mut Classfile) -> Result<(), String> {
-    // Change existing fillInStackTrace to call stackParamFillInStackTrace(Thread) right after fillInStackTrace(0)
-    let fill_meth_name_idx = utf8_const(class_file, "fillInStackTrace");
-    // Get the code
-    let curr_thread_ref_idx = method_ref_const(class_file, "java/lang/Thread", "currentThread", "()Ljava/lang/Thread;");
-    let meth_ret_throwable_idx = utf8_const(class_file, "()Ljava/lang/Throwable;");
-    let native_fill_meth_ref_idx = method_ref_const(class_file, "java/lang/Throwable", "fillInStackTrace", "(I)Ljava/lang/Throwable;");
-    let new_native_fill_meth_ref_idx = method_ref_const(class_file,
-                                                        "java/lang/Throwable",
-                                                        "stackParamFillInStackTrace",
-                                                        "(Ljava/lang/Thread;)Ljava/lang/Throwable;");
-    let mut fill_meth = class_file.methods.iter_mut().find(|m| {
-        m.name_index.idx == fill_meth_name_idx && m.descriptor_index.idx == meth_ret_throwable_idx
-    }).ok_or("Cannot find fill method".to_string())?;
-    let mut fill_meth_code = get_method_code_mut(&mut fill_meth)?;
-    // Find the index of the invoke special
-    let fill_invoke_idx = fill_meth_code.iter().position(|i| {
-        match i {
-            &Instruction::INVOKESPECIAL(ref idx) if *idx == native_fill_meth_ref_idx as u16 => true,
+impl<'a> MethodVisitor<'a> for ReturnHook {
+    fn visit_method(&mut self, cp: &mut ConstantPool<'a>, mut method: Method<'a>) -> Result<Option<Method<'a>>, Error> {
+        if method.name_index.idx != utf8_const(cp, self.name) || method.descriptor_index.idx != utf8_const(cp, self.desc) {
+            return Result::Ok(Some(method));
+        }
+        self.found = true;
+        let hook_ref_idx = method_ref_const(cp, "stackparam/StackParamNative", self.hook_name, self.hook_desc);
+        let code_attr = method.attributes.iter_mut().find(|attr| match attr {
+            &&mut Attribute::Code { .. } => true,
             _ => false
+        }).ok_or(manip_err("Hooked method has no code"))?;
+        let return_idxs: Vec<usize> = match code_attr {
+            &mut Attribute::Code { ref mut code, .. } => {
+                let code = code.instructions_mut();
+                // A jump straight to the return would skip the hook
+                let offsets = instruction_offsets(code);
+                let targets = branch_targets(code);
+                let return_idxs: Vec<usize> = code.iter().enumerate().filter(|&(_, i)| match i {
+                    &Instruction::ARETURN => true,
+                    _ => false
+                }).map(|(idx, _)| idx).collect();
+                if return_idxs.iter().any(|idx| targets.contains(&offsets[*idx])) {
+                    return Result::Err(manip_err(&format!("A return of {} is a branch target", self.name)));
+                }
+                return_idxs
+            },
+            _ => Vec::new()
+        };
+        if return_idxs.is_empty() { return Result::Err(manip_err(&format!("Cannot find return of {}", self.name))); }
+        // Last first so the earlier indices stay put
+        for idx in return_idxs.iter().rev() {
+            insert_instructions(code_attr, *idx, vec![
+                Instruction::ALOAD_0,
+                Instruction::INVOKESTATIC(hook_ref_idx as u16)
+            ])?;
         }
-    }).ok_or("Cannot find invoke of native fill".to_string())?;
-    // Call mine afterwards. "this" is currently on the stack already. It takes the current thread,
-    // so we grab that statically before calling so it is on the stack (current max stack of >= 2 is
-    // still ok for us). Result is a throwable so the stack is left how we got it.
-    fill_meth_code.insert(fill_invoke_idx + 1, Instruction::INVOKESTATIC(curr_thread_ref_idx as u16));
-    fill_meth_code.insert(fill_invoke_idx + 2, Instruction::INVOKESPECIAL(new_native_fill_meth_ref_idx as u16));
-    return Result::Ok(());
+        // "this" goes on top of the returned value
+        if let &mut Attribute::Code { ref mut max_stack, .. } = code_attr {
+            *max_stack += 1;
+        }
+        return Result::Ok(Some(method));
+    }
+
+    fn visit_end(&mut self, _cp: &mut ConstantPool<'a>) -> Result<Vec<Method<'a>>, Error> {
+        if !self.found { return Result::Err(manip_err(&format!("Unable to find {}", self.name))); }
+        return Result::Ok(Vec::new());
+    }
 }
 
-unsafe fn replace_our_trace_method(class_file: &mut Classfile) -> Result<(), String> {
-    // Rename getOurStackTrace to $$stack_param$$getOurStackTrace, then create a new
-    // (synchronized) version that is our native one.
+/// Inserts instructions before the one at the given index of a code attribute. Branches, exception
+/// handlers, stack map frames and debug tables are moved along so they still refer to the same
+/// instructions as before.
+fn insert_instructions(code_attr: &mut Attribute, index: usize, new_instructions: Vec<Instruction>) -> Result<(), Error> {
+    let (code, exception_table, attributes) = match code_attr {
+        &mut Attribute::Code { ref mut code, ref mut exception_table, ref mut attributes, .. } =>
+            (code.instructions_mut(), exception_table, attributes),
+        _ => return Result::Err(manip_err("Expected code attribute"))
+    };
+    let old_offsets = instruction_offsets(code);
+    let inserted = new_instructions.len();
+    let tail = code.split_off(index);
+    code.extend(new_instructions);
+    code.extend(tail);
+    let new_offsets = instruction_offsets(code);
+    // Old offsets to new ones, the offset just past the code is allowed for exception handler ends
+    let map_offset = |old_offset: usize| -> Result<usize, Error> {
+        match old_offsets.binary_search(&old_offset) {
+            Ok(i) if i < index => Result::Ok(new_offsets[i]),
+            Ok(i) => Result::Ok(new_offsets[i + inserted]),
+            Err(_) => Result::Err(manip_err(&format!("Offset {} is not at an instruction", old_offset)))
+        }
+    };
+    let map_u16 = |old_offset: u16| -> Result<u16, Error> {
+        let new_offset = map_offset(old_offset as usize)?;
+        if new_offset > u16::max_value() as usize { return Result::Err(manip_err("Code too large")); }
+        return Result::Ok(new_offset as u16);
+    };
 
-    // Rename
-    let mut found = false;
-    let meth_get_our_name_idx = utf8_const(class_file, "getOurStackTrace");
-    let meth_ret_elems_desc_idx = utf8_const(class_file, "()[Ljava/lang/StackTraceElement;");
-    let new_meth_get_our_name_idx = utf8_const(class_file, "$$stack_param$$getOurStackTrace");
-    for method in class_file.methods.iter_mut() {
-        if method.name_index.idx == meth_get_our_name_idx && method.descriptor_index.idx == meth_ret_elems_desc_idx {
-            found = true;
-            method.name_index = ConstantPoolIndex { idx: new_meth_get_our_name_idx };
+    // Branches are relative to the instruction doing the branching
+    for (i, instruction) in code.iter_mut().enumerate() {
+        if i >= index && i < index + inserted { continue; }
+        let old_idx = if i < index { i } else { i - inserted };
+        let (old_at, new_at) = (old_offsets[old_idx] as i64, new_offsets[i] as i64);
+        let map_branch = |rel: i32| -> Result<i32, Error> {
+            return Result::Ok((map_offset((old_at + rel as i64) as usize)? as i64 - new_at) as i32);
+        };
+        let map_short_branch = |rel: i16| -> Result<i16, Error> {
+            let new_rel = map_branch(rel as i32)?;
+            if new_rel < i16::min_value() as i32 || new_rel > i16::max_value() as i32 {
+                return Result::Err(manip_err("Branch too far after insert"));
+            }
+            return Result::Ok(new_rel as i16);
+        };
+        match instruction {
+            &mut Instruction::IFEQ(ref mut rel) | &mut Instruction::IFNE(ref mut rel) | &mut Instruction::IFLT(ref mut rel) |
+            &mut Instruction::IFGE(ref mut rel) | &mut Instruction::IFGT(ref mut rel) | &mut Instruction::IFLE(ref mut rel) |
+            &mut Instruction::IF_ICMPEQ(ref mut rel) | &mut Instruction::IF_ICMPNE(ref mut rel) |
+            &mut Instruction::IF_ICMPLT(ref mut rel) | &mut Instruction::IF_ICMPGE(ref mut rel) |
+            &mut Instruction::IF_ICMPGT(ref mut rel) | &mut Instruction::IF_ICMPLE(ref mut rel) |
+            &mut Instruction::IF_ACMPEQ(ref mut rel) | &mut Instruction::IF_ACMPNE(ref mut rel) |
+            &mut Instruction::IFNULL(ref mut rel) | &mut Instruction::IFNONNULL(ref mut rel) |
+            &mut Instruction::GOTO(ref mut rel) | &mut Instruction::JSR(ref mut rel) => *rel = map_short_branch(*rel)?,
+            &mut Instruction::GOTO_W(ref mut rel) | &mut Instruction::JSR_W(ref mut rel) => *rel = map_branch(*rel)?,
+            &mut Instruction::TABLESWITCH(ref mut default, _, _, ref mut rels) => {
+                *default = map_branch(*default)?;
+                for rel in rels.iter_mut() { *rel = map_branch(*rel)?; }
+            },
+            &mut Instruction::LOOKUPSWITCH(ref mut default, ref mut pairs) => {
+                *default = map_branch(*default)?;
+                for pair in pairs.iter_mut() { pair.1 = map_branch(pair.1)?; }
+            },
+            _ => ()
+        }
+    }
+
+    for handler in exception_table.iter_mut() {
+        handler.start_pc = map_u16(handler.start_pc)?;
+        handler.end_pc = map_u16(handler.end_pc)?;
+        handler.handler_pc = map_u16(handler.handler_pc)?;
+    }
+
+    for attr in attributes.iter_mut() {
+        match attr {
+            &mut Attribute::StackMapTable(ref mut frames) => {
+                // Deltas are from the previous frame plus one, except for the first frame
+                let mut prev_old: Option<usize> = None;
+                let mut prev_new: Option<usize> = None;
+                for frame in frames.iter_mut() {
+                    let delta = stack_map_frame_delta(frame);
+                    let old_offset = prev_old.map(|prev| prev + delta + 1).unwrap_or(delta);
+                    let new_offset = map_offset(old_offset)?;
+                    let new_delta = new_offset - prev_new.map(|prev| prev + 1).unwrap_or(0);
+                    set_stack_map_frame_delta(frame, new_delta)?;
+                    for vtype in stack_map_frame_types(frame) {
+                        if let &mut VerificationType::Uninitialized { ref mut offset } = vtype {
+                            *offset = map_u16(*offset)?;
+                        }
+                    }
+                    prev_old = Some(old_offset);
+                    prev_new = Some(new_offset);
+                }
+            },
+            &mut Attribute::LineNumberTable(ref mut lines) => {
+                for line in lines.iter_mut() { line.start_pc = map_u16(line.start_pc)?; }
+            },
+            &mut Attribute::LocalVariableTable(ref mut vars) => {
+                for var in vars.iter_mut() {
+                    let end_pc = map_u16(var.start_pc + var.length)?;
+                    var.start_pc = map_u16(var.start_pc)?;
+                    var.length = end_pc - var.start_pc;
+                }
+            },
+            &mut Attribute::LocalVariableTypeTable(ref mut vars) => {
+                for var in vars.iter_mut() {
+                    let end_pc = map_u16(var.start_pc + var.length)?;
+                    var.start_pc = map_u16(var.start_pc)?;
+                    var.length = end_pc - var.start_pc;
+                }
+            },
+            _ => ()
         }
     }
-    if !found { return Result::Err("Unable to find getOurStackTrace".to_string()); }
-
-    // Make new native method
-    class_file.methods.push(Method {
-        access_flags: AccessFlags { flags: MethodAccessFlags::Private as u16 + MethodAccessFlags::Synchronized as u16 + MethodAccessFlags::Native as u16 },
-        name_index: ConstantPoolIndex { idx: meth_get_our_name_idx },
-        descriptor_index: ConstantPoolIndex { idx: meth_ret_elems_desc_idx },
-        attributes: Vec::new()
-    });
     return Result::Ok(());
 }
 
-unsafe fn get_method_code_mut(method: &mut Method) -> Result<&mut Vec<Instruction>, String> {
-    for attr in method.attributes.iter_mut() {
-        match attr {
-            &mut Attribute::Code { ref mut code, .. } => return Result::Ok(code),
+/// The offsets any instruction can branch to
+fn branch_targets(code: &Vec<Instruction>) -> Vec<usize> {
+    let offsets = instruction_offsets(code);
+    let mut targets = Vec::new();
+    for (i, instruction) in code.iter().enumerate() {
+        let at = offsets[i] as i64;
+        match instruction {
+            &Instruction::IFEQ(rel) | &Instruction::IFNE(rel) | &Instruction::IFLT(rel) |
+            &Instruction::IFGE(rel) | &Instruction::IFGT(rel) | &Instruction::IFLE(rel) |
+            &Instruction::IF_ICMPEQ(rel) | &Instruction::IF_ICMPNE(rel) |
+            &Instruction::IF_ICMPLT(rel) | &Instruction::IF_ICMPGE(rel) |
+            &Instruction::IF_ICMPGT(rel) | &Instruction::IF_ICMPLE(rel) |
+            &Instruction::IF_ACMPEQ(rel) | &Instruction::IF_ACMPNE(rel) |
+            &Instruction::IFNULL(rel) | &Instruction::IFNONNULL(rel) |
+            &Instruction::GOTO(rel) | &Instruction::JSR(rel) => targets.push((at + rel as i64) as usize),
+            &Instruction::GOTO_W(rel) | &Instruction::JSR_W(rel) => targets.push((at + rel as i64) as usize),
+            &Instruction::TABLESWITCH(default, _, _, ref rels) => {
+                targets.push((at + default as i64) as usize);
+                targets.extend(rels.iter().map(|rel| (at + *rel as i64) as usize));
+            },
+            &Instruction::LOOKUPSWITCH(default, ref pairs) => {
+                targets.push((at + default as i64) as usize);
+                targets.extend(pairs.iter().map(|pair| (at + pair.1 as i64) as usize));
+            },
             _ => ()
         }
     }
-    return Result::Err("Unable to find code for method".to_string());
+    return targets;
+}
+
+/// The offset of each instruction followed by the offset just past the code
+fn instruction_offsets(code: &Vec<Instruction>) -> Vec<usize> {
+    let mut offsets = Vec::with_capacity(code.len() + 1);
+    let mut offset = 0;
+    for instruction in code.iter() {
+        offsets.push(offset);
+        offset += instruction.len_at(offset);
+    }
+    offsets.push(offset);
+    return offsets;
+}
+
+fn stack_map_frame_delta(frame: &StackMapFrame) -> usize {
+    return match frame {
+        &StackMapFrame::SameFrame { tag } => tag as usize,
+        &StackMapFrame::SameLocals1StackItemFrame { tag, .. } => tag as usize - 64,
+        &StackMapFrame::SameLocals1StackItemFrameExtended { offset_delta, .. } |
+        &StackMapFrame::ChopFrame { offset_delta, .. } |
+        &StackMapFrame::SameFrameExtended { offset_delta } |
+        &StackMapFrame::AppendFrame { offset_delta, .. } |
+        &StackMapFrame::FullFrame { offset_delta, .. } => offset_delta as usize,
+        &StackMapFrame::FutureUse { .. } => 0
+    };
+}
+
+/// Sets the offset delta of the frame, switching to the extended frame type if it no longer fits
+/// in the tag
+fn set_stack_map_frame_delta(frame: &mut StackMapFrame, delta: usize) -> Result<(), Error> {
+    if delta > u16::max_value() as usize { return Result::Err(manip_err("Stack map frame delta too large")); }
+    let delta16 = delta as u16;
+    let replacement = match frame {
+        &mut StackMapFrame::SameFrame { ref mut tag } => {
+            if delta < 64 { *tag = delta as u8; None } else { Some(StackMapFrame::SameFrameExtended { offset_delta: delta16 }) }
+        },
+        &mut StackMapFrame::SameLocals1StackItemFrame { ref mut tag, ref mut stack } => {
+            if delta < 64 {
+                *tag = 64 + delta as u8;
+                None
+            } else {
+                let stack = mem::replace(stack, VerificationType::Top);
+                Some(StackMapFrame::SameLocals1StackItemFrameExtended { offset_delta: delta16, stack: stack })
+            }
+        },
+        &mut StackMapFrame::SameLocals1StackItemFrameExtended { ref mut offset_delta, .. } |
+        &mut StackMapFrame::ChopFrame { ref mut offset_delta, .. } |
+        &mut StackMapFrame::SameFrameExtended { ref mut offset_delta } |
+        &mut StackMapFrame::AppendFrame { ref mut offset_delta, .. } |
+        &mut StackMapFrame::FullFrame { ref mut offset_delta, .. } => { *offset_delta = delta16; None },
+        &mut StackMapFrame::FutureUse { .. } => None
+    };
+    if let Some(replacement) = replacement {
+        *frame = replacement;
+    }
+    return Result::Ok(());
+}
+
+fn stack_map_frame_types(frame: &mut StackMapFrame) -> Vec<&mut VerificationType> {
+    return match frame {
+        &mut StackMapFrame::SameLocals1StackItemFrame { ref mut stack, .. } |
+        &mut StackMapFrame::SameLocals1StackItemFrameExtended { ref mut stack, .. } => vec![stack],
+        &mut StackMapFrame::AppendFrame { ref mut locals, .. } => locals.iter_mut().collect(),
+        &mut StackMapFrame::FullFrame { ref mut locals, ref mut stack, .. } => locals.iter_mut().chain(stack.iter_mut()).collect(),
+        _ => Vec::new()
+    };
 }
 
 #[allow(dead_code)]
-unsafe fn get_manip_class(jni_env: *mut JNIEnv) -> Result<jclass, String> {
+unsafe fn get_manip_class(jni_env: *mut JNIEnv) -> Result<jclass, AgentError> {
     let class_name = CString::new("stackparam/StackParamNative").unwrap();
     let class = (**jni_env).FindClass.unwrap()(jni_env, class_name.as_ref().as_ptr());
-    return util::result_or_jni_ex(class, jni_env);
+    return util::result_or_jni_ex(class, jni_env, "FindClass");
 }
 
-fn str_err<T>(res: Result<T, Error>) -> Result<T, String> {
-    return res.map_err(|err| format!("{}", err))
+fn manip_err(msg: &str) -> Error {
+    return Error::new(ErrorKind::Other, msg);
 }
 
-fn utf8_const(class_file: &mut Classfile, str: &str) -> usize {
-    for i in 0..class_file.constant_pool.constants.len() {
-        match class_file.constant_pool.constants[i] {
+fn utf8_const(cp: &mut ConstantPool, str: &str) -> usize {
+    for i in 0..cp.constants.len() {
+        match cp.constants[i] {
             Constant::Utf8(ref bytes) => {
-                if bytes.as_slice() == str.as_bytes() {
+                if bytes.as_ref() == str.as_bytes() {
                     return i;
                 }
             },
             _ => ()
         }
     }
-    let ret = class_file.constant_pool.constants.len();
-    class_file.constant_pool.constants.push(Constant::Utf8(str.as_bytes().to_vec()));
+    let ret = cp.constants.len();
+    cp.constants.push(Constant::Utf8(Cow::Owned(str.as_bytes().to_vec())));
     return ret;
 }
 
 #[allow(dead_code)]
-fn str_const(class_file: &mut Classfile, str: &str) -> usize {
-    let utf8_idx = utf8_const(class_file, str);
-    for i in 0..class_file.constant_pool.constants.len() {
-        match class_file.constant_pool.constants[i] {
+fn str_const(cp: &mut ConstantPool, str: &str) -> usize {
+    let utf8_idx = utf8_const(cp, str);
+    for i in 0..cp.constants.len() {
+        match cp.constants[i] {
             Constant::String(ref idx) => {
                 if idx.idx == utf8_idx {
                     return i;
@@ -262,15 +640,15 @@ fn str_const(class_file: &mut Classfile, str: &str) -> usize {
             _ => ()
         }
     }
-    let ret = class_file.constant_pool.constants.len();
-    class_file.constant_pool.constants.push(Constant::String(ConstantPoolIndex { idx: utf8_idx }));
+    let ret = cp.constants.len();
+    cp.constants.push(Constant::String(ConstantPoolIndex { idx: utf8_idx }));
     return ret;
 }
 
-fn class_const(class_file: &mut Classfile, class_name: &str) -> usize {
-    let utf8_idx = utf8_const(class_file, class_name);
-    for i in 0..class_file.constant_pool.constants.len() {
-        match class_file.constant_pool.constants[i] {
+fn class_const(cp: &mut ConstantPool, class_name: &str) -> usize {
+    let utf8_idx = utf8_const(cp, class_name);
+    for i in 0..cp.constants.len() {
+        match cp.constants[i] {
             Constant::Class(ref idx) => {
                 if idx.idx == utf8_idx {
                     return i;
@@ -279,17 +657,17 @@ fn class_const(class_file: &mut Classfile, class_name: &str) -> usize {
             _ => ()
         }
     }
-    let ret = class_file.constant_pool.constants.len();
-    class_file.constant_pool.constants.push(Constant::Class(ConstantPoolIndex { idx: utf8_idx }));
+    let ret = cp.constants.len();
+    cp.constants.push(Constant::Class(ConstantPoolIndex { idx: utf8_idx }));
     return ret;
 }
 
 #[allow(dead_code)]
-fn name_and_type_const(class_file: &mut Classfile, name: &str, desc: &str) -> usize {
-    let name_idx = utf8_const(class_file, name);
-    let desc_idx = utf8_const(class_file, desc);
-    for i in 0..class_file.constant_pool.constants.len() {
-        match class_file.constant_pool.constants[i] {
+fn name_and_type_const(cp: &mut ConstantPool, name: &str, desc: &str) -> usize {
+    let name_idx = utf8_const(cp, name);
+    let desc_idx = utf8_const(cp, desc);
+    for i in 0..cp.constants.len() {
+        match cp.constants[i] {
             Constant::NameAndType { ref name_index, ref descriptor_index } => {
                 if name_index.idx == name_idx && descriptor_index.idx == desc_idx {
                     return i;
@@ -298,8 +676,8 @@ fn name_and_type_const(class_file: &mut Classfile, name: &str, desc: &str) -> us
             _ => ()
         }
     }
-    let ret = class_file.constant_pool.constants.len();
-    class_file.constant_pool.constants.push(Constant::NameAndType {
+    let ret = cp.constants.len();
+    cp.constants.push(Constant::NameAndType {
         name_index: ConstantPoolIndex { idx: name_idx },
         descriptor_index: ConstantPoolIndex { idx: desc_idx },
     });
@@ -307,11 +685,11 @@ fn name_and_type_const(class_file: &mut Classfile, name: &str, desc: &str) -> us
 }
 
 #[allow(dead_code)]
-fn field_ref_const(class_file: &mut Classfile, class_name: &str, field_name: &str, desc: &str) -> usize {
-    let class_idx = class_const(class_file, class_name);
-    let name_and_type_idx = name_and_type_const(class_file, field_name, desc);
-    for i in 0..class_file.constant_pool.constants.len() {
-        match class_file.constant_pool.constants[i] {
+fn field_ref_const(cp: &mut ConstantPool, class_name: &str, field_name: &str, desc: &str) -> usize {
+    let class_idx = class_const(cp, class_name);
+    let name_and_type_idx = name_and_type_const(cp, field_name, desc);
+    for i in 0..cp.constants.len() {
+        match cp.constants[i] {
             Constant::FieldRef { ref class_index, ref name_and_type_index } => {
                 if class_index.idx == class_idx && name_and_type_index.idx == name_and_type_idx {
                     return i;
@@ -320,19 +698,19 @@ fn field_ref_const(class_file: &mut Classfile, class_name: &str, field_name: &st
             _ => ()
         }
     }
-    let ret = class_file.constant_pool.constants.len();
-    class_file.constant_pool.constants.push(Constant::FieldRef {
+    let ret = cp.constants.len();
+    cp.constants.push(Constant::FieldRef {
         class_index: ConstantPoolIndex { idx: class_idx },
         name_and_type_index: ConstantPoolIndex { idx: name_and_type_idx },
     });
     return ret;
 }
 
-fn method_ref_const(class_file: &mut Classfile, class_name: &str, method_name: &str, desc: &str) -> usize {
-    let class_idx = class_const(class_file, class_name);
-    let name_and_type_idx = name_and_type_const(class_file, method_name, desc);
-    for i in 0..class_file.constant_pool.constants.len() {
-        match class_file.constant_pool.constants[i] {
+fn method_ref_const(cp: &mut ConstantPool, class_name: &str, method_name: &str, desc: &str) -> usize {
+    let class_idx = class_const(cp, class_name);
+    let name_and_type_idx = name_and_type_const(cp, method_name, desc);
+    for i in 0..cp.constants.len() {
+        match cp.constants[i] {
             Constant::MethodRef { ref class_index, ref name_and_type_index } => {
                 if class_index.idx == class_idx && name_and_type_index.idx == name_and_type_idx {
                     return i;
@@ -341,8 +719,8 @@ fn method_ref_const(class_file: &mut Classfile, class_name: &str, method_name: &
             _ => ()
         }
     }
-    let ret = class_file.constant_pool.constants.len();
-    class_file.constant_pool.constants.push(Constant::MethodRef {
+    let ret = cp.constants.len();
+    cp.constants.push(Constant::MethodRef {
         class_index: ConstantPoolIndex { idx: class_idx },
         name_and_type_index: ConstantPoolIndex { idx: name_and_type_idx },
     });
@@ -1,51 +1,116 @@
 extern crate jni_sys;
 
 use log::LogLevel::{Debug, Trace};
-use jni_sys::{JNIEnv, jclass, jint, jlong, jfloat, jdouble, jobject, jmethodID, jfieldID, jstring, jobjectArray, jsize};
-use jvmti_sys::{jvmtiEnv, jthread, jvmtiFrameInfo, jvmtiLocalVariableEntry, jvmtiError};
+use annotations::AnnotationIndex;
+use error::AgentError;
+use fields::{self, SnapshotConfig};
+use json::JsonWriter;
+use redact::{self, RedactConfig};
+use render;
+use jni_sys::{JNIEnv, JNI_FALSE, JNI_TRUE, jboolean, jclass, jint, jobject, jmethodID, jstring, jobjectArray, jsize};
+use jvmti::{Jvmti, LocalVariable};
+use jvmti_sys::{jlocation, jthread, jvmtiFrameInfo};
+use state::{self, AgentState, StateRef};
 use std::ptr;
 use util;
-use std::os::raw::{c_char, c_uchar, c_uint, c_int, c_double};
-use std::slice;
-use std::ffi::{CStr, CString};
-use std::sync::{Once, ONCE_INIT};
-use std::mem;
+use std::os::raw::{c_uint, c_int, c_double};
+use std::ffi::CString;
+use std::sync::atomic::{AtomicBool, Ordering};
 
 const DEFAULT_MAX_STACK_DEPTH: jint = 3000;
 
-// Not set until after init on purpose
-static mut JVMTI_ENV: *mut jvmtiEnv = 0 as *mut jvmtiEnv;
+// Local refs each stack frame needs besides the 3 per param: the param array and the shared
+// unknown value
+const FRAME_LOCAL_REFS: jint = 2;
 
-pub unsafe fn init(jvmti_env: *mut jvmtiEnv) {
-    JVMTI_ENV = jvmti_env;
+// Written when capabilities are added, which can be from an attach while other threads run
+static ACCESS_LOCALS: AtomicBool = AtomicBool::new(true);
+
+static CAPTURE_LOCALS: AtomicBool = AtomicBool::new(false);
+
+// Can be flipped from any thread at runtime, hence not a plain static
+static CAPTURE_ENABLED: AtomicBool = AtomicBool::new(true);
+
+/// Sets whether locals can be read, without which only the method signatures are captured
+pub fn set_access_locals(access: bool) {
+    ACCESS_LOCALS.store(access, Ordering::SeqCst);
+}
+
+/// Sets whether the other locals in scope are captured along with the params
+pub fn set_capture_locals(capture: bool) {
+    CAPTURE_LOCALS.store(capture, Ordering::SeqCst);
+}
+
+/// Sets whether throwables get params. When off, the replaced methods act as the originals.
+pub fn set_capture_enabled(enabled: bool) {
+    CAPTURE_ENABLED.store(enabled, Ordering::SeqCst);
+}
+
+/// The global state if params are being captured
+fn capturing_state() -> Option<StateRef> {
+    if !CAPTURE_ENABLED.load(Ordering::SeqCst) { return None; }
+    return AgentState::global();
 }
 
 #[no_mangle]
 #[allow(non_snake_case)]
 pub unsafe extern "C" fn Java_java_lang_Throwable_getOurStackTrace(jni_env: *mut JNIEnv,
                                                                    this: jobject) -> jobject {
-    if log_enabled!(Debug) {
-        debug!("Asking for trace from {}", class_sig_from_obj(jni_env, this).unwrap_or("<unknown>".to_string()));
-    }
-    return match populate_trace_elements(jni_env, this) {
-        Result::Err(err_str) => {
-            debug!("Stack elem populate err: {}", err_str);
-            ptr::null_mut()
-        },
-        Result::Ok(ret) => ret
-    };
+    return util::guard_ffi(jni_env, "Throwable.getOurStackTrace", ptr::null_mut(), || {
+        let res = match capturing_state() {
+            Some(state) => {
+                if log_enabled!(Debug) {
+                    debug!("Asking for trace from {}",
+                           class_sig_from_obj(state.jvmti, jni_env, this).unwrap_or("<unknown>".to_string()));
+                }
+                populate_trace_elements(&state, jni_env, this)
+            },
+            // Nothing has params before VM init or when disabled, so just defer to the original
+            None => call_orig(jni_env, this, &state::ORIG_TRACE_METH),
+        };
+        return match res {
+            Result::Err(err_str) => {
+                debug!("Stack elem populate err: {}", err_str);
+                ptr::null_mut()
+            },
+            Result::Ok(ret) => ret
+        };
+    });
 }
 
 #[no_mangle]
 #[allow(non_snake_case)]
 pub unsafe extern "C" fn Java_java_lang_StackTraceElement_toString(jni_env: *mut JNIEnv, this: jobject) -> jobject {
-    return match append_param_to_string(jni_env, this) {
-        Result::Err(err_str) => {
-            debug!("Stack elem toString err: {}", err_str);
-            ptr::null_mut()
-        },
-        Result::Ok(ret) => ret
-    };
+    return util::guard_ffi(jni_env, "StackTraceElement.toString", ptr::null_mut(), || {
+        let res = match capturing_state() {
+            Some(state) => append_param_to_string(&state, jni_env, this),
+            None => call_orig(jni_env, this, &state::ORIG_TO_STRING_METH),
+        };
+        return match res {
+            Result::Err(err_str) => {
+                debug!("Stack elem toString err: {}", err_str);
+                ptr::null_mut()
+            },
+            Result::Ok(ret) => ret
+        };
+    });
+}
+
+#[no_mangle]
+#[allow(non_snake_case)]
+pub unsafe extern "C" fn Java_java_lang_StackTraceElement_writeObject(jni_env: *mut JNIEnv, this: jobject, out: jobject) {
+    util::guard_ffi(jni_env, "StackTraceElement.writeObject", (), || {
+        if let Some(state) = capturing_state() {
+            if let Result::Err(err_str) = store_param_str(&state, jni_env, this) {
+                debug!("Stack elem param string err: {}", err_str);
+            }
+        }
+        // Any exception writing the fields is left for the caller to get
+        match state::DEFAULT_WRITE_OBJECT_METH.get(jni_env) {
+            Result::Ok(meth) => (**jni_env).CallVoidMethod.unwrap()(jni_env, out, meth),
+            Result::Err(err_str) => debug!("Stack elem write err: {}", err_str),
+        }
+    });
 }
 
 #[no_mangle]
@@ -53,119 +118,100 @@ pub unsafe extern "C" fn Java_java_lang_StackTraceElement_toString(jni_env: *mut
 pub unsafe extern "C" fn Java_java_lang_Throwable_stackParamFillInStackTrace(jni_env: *mut JNIEnv,
                                                                              this: jobject,
                                                                              thread: jthread) -> jobject {
-    // This is needed in case of failure, we need to at least set the field to something
-    unsafe fn set_param_to_null_ignore_err(jni_env: *mut JNIEnv, this: jobject) {
-        let field = get_stack_params_field(jni_env).unwrap_or(ptr::null_mut());
-        if !field.is_null() {
-            (**jni_env).SetObjectField.unwrap()(jni_env, this, field, ptr::null_mut());
-            let _ = util::result_or_jni_ex((), jni_env);
+    return util::guard_ffi(jni_env, "Throwable.stackParamFillInStackTrace", this, || {
+        // Do nothing before vm init (i.e. before our global state is set), the field can only be null then.
+        // Also do nothing when disabled, it won't be read.
+        let state = match capturing_state() {
+            Some(state) => state,
+            None => return this,
+        };
+
+        // TODO: there are a ton of exception fills happening on startup that are slowing things down and
+        // are not relayed to the user. We should either skip filling those, or find a way to make the fill
+        // cheaper (bunch of string allocs)
+        if log_enabled!(Debug) {
+            let class_name = class_sig_from_obj(state.jvmti, jni_env, this).unwrap_or("<unknown>".to_string());
+            debug!("Asking to fill for {}", class_name);
         }
-    }
 
-    // Do nothing before vm init (i.e. before our static is set)
-    if JVMTI_ENV.is_null() {
-        set_param_to_null_ignore_err(jni_env, this);
+        // Populate the field, swallow the err
+        match populate_stack_params(&state, jni_env, this, thread) {
+            Result::Err(err_str) => {
+                debug!("Stack param fill err: {}", err_str);
+                // We need to at least set the field to something
+                if let Ok(members) = state.replaced() {
+                    (**jni_env).SetObjectField.unwrap()(jni_env, this, members.stack_params_field, ptr::null_mut());
+                    let _ = util::result_or_jni_ex((), jni_env, "SetObjectField");
+                }
+            },
+            Result::Ok(()) => ()
+        };
         return this;
-    }
-
-    // TODO: there are a ton of exception fills happening on startup that are slowing things down and
-    // are not relayed to the user. We should either skip filling those, or find a way to make the fill
-    // cheaper (bunch of string allocs)
-    if log_enabled!(Debug) {
-        let class_name = class_sig_from_obj(jni_env, this).unwrap_or("<unknown>".to_string());
-        debug!("Asking to fill for {}", class_name);
-    }
+    });
+}
 
-    // Populate the field, swallow the err
-    match populate_stack_params(jni_env, this, thread) {
-        Result::Err(err_str) => {
-            debug!("Stack param fill err: {}", err_str);
-            set_param_to_null_ignore_err(jni_env, this);
-        },
-        Result::Ok(()) => ()
-    };
-    return this;
+/// Calls one of the renamed original methods without the global state
+unsafe fn call_orig(jni_env: *mut JNIEnv, this: jobject, orig: &state::CachedMethod) -> Result<jobject, AgentError> {
+    let meth = orig.get(jni_env)?;
+    return util::result_or_jni_ex((**jni_env).CallObjectMethod.unwrap()(jni_env, this, meth), jni_env, "CallObjectMethod");
 }
 
-unsafe fn append_param_to_string(jni_env: *mut JNIEnv, this: jobject) -> Result<jobject, String> {
-    // First call the original one, then take the result and append our stuff via static call
-    let str = get_elem_str_orig(jni_env, this)?;
-    // Only if JVMTI is inited (because we need our manip class loaded)
-    if JVMTI_ENV.is_null() {
-        return Result::Ok(str);
-    }
+unsafe fn append_param_to_string(state: &AgentState, jni_env: *mut JNIEnv, this: jobject) -> Result<jobject, AgentError> {
+    let members = state.replaced()?;
+    // First call the original one, then take the result and append our stuff
+    let str = util::result_or_jni_ex((**jni_env).CallObjectMethod.unwrap()(jni_env, this, members.str_orig_meth),
+                                     jni_env,
+                                     "CallObjectMethod")?;
     // Get the param info
-    let param_info_field = get_elem_param_info_field(jni_env)?;
-    let param_info = util::result_or_jni_ex((**jni_env).GetObjectField.unwrap()(jni_env, this, param_info_field), jni_env)?;
-    if param_info.is_null() {
+    let param_info = util::result_or_jni_ex((**jni_env).GetObjectField.unwrap()(jni_env, this, members.param_info_field),
+                                            jni_env,
+                                            "GetObjectField")?;
+    if !param_info.is_null() {
+        return render::append_params(state, jni_env, str, param_info, this);
+    }
+    // Elements read back from a stream only have the params as rendered when written
+    let param_str = util::result_or_jni_ex((**jni_env).GetObjectField.unwrap()(jni_env, this, members.param_str_field),
+                                           jni_env,
+                                           "GetObjectField")?;
+    if param_str.is_null() {
         return Result::Ok(str);
     }
-    return append_param_info(jni_env, str, param_info);
-}
-
-unsafe fn get_elem_str_orig(jni_env: *mut JNIEnv, this: jobject) -> Result<jobject, String> {
-    static mut STR_ORIG_METH: jmethodID = 0 as jmethodID;
-    static ONCE: Once = ONCE_INIT;
-    ONCE.call_once(|| {
-        let elem_class = get_elem_class(jni_env).unwrap_or(ptr::null_mut());
-        if !elem_class.is_null() {
-            // We swallow exceptions in here on purpose
-            let meth_name_str = CString::new("$$stack_param$$toString").unwrap();
-            let meth_sig_str = CString::new("()Ljava/lang/String;").unwrap();
-            STR_ORIG_METH = (**jni_env).GetMethodID.unwrap()(jni_env,
-                                                             elem_class,
-                                                             meth_name_str.as_ptr(),
-                                                             meth_sig_str.as_ptr());
-            let _ = util::result_or_jni_ex((), jni_env);
-        }
-    });
-    if STR_ORIG_METH.is_null() { return Result::Err("No $$stack_param$$toString method".to_string()); }
-    return util::result_or_jni_ex((**jni_env).CallObjectMethod.unwrap()(jni_env, this, STR_ORIG_METH), jni_env);
-}
-
-unsafe fn append_param_info(jni_env: *mut JNIEnv, str: jobject, param_info: jobject) -> Result<jobject, String> {
-    let manip_class = get_manip_class(jni_env)?;
-    static mut APPEND_METH: jmethodID = 0 as jmethodID;
-    static ONCE: Once = ONCE_INIT;
-    ONCE.call_once(|| {
-        // We swallow exceptions in here on purpose
-        let meth_name_str = CString::new("appendParamsToFrameString").unwrap();
-        let meth_sig_str = CString::new("(Ljava/lang/String;[Ljava/lang/Object;)Ljava/lang/String;").unwrap();
-        APPEND_METH = (**jni_env).GetStaticMethodID.unwrap()(jni_env,
-                                                             manip_class,
-                                                             meth_name_str.as_ptr(),
-                                                             meth_sig_str.as_ptr());
-        let _ = util::result_or_jni_ex((), jni_env);
-    });
-    if APPEND_METH.is_null() { return Result::Err("No append method".to_string()); }
-    let ret = util::result_or_jni_ex((**jni_env).CallStaticObjectMethod.unwrap()(jni_env,
-                                                                              manip_class,
-                                                                              APPEND_METH,
-                                                                              str,
-                                                                              param_info), jni_env);
-    return ret;
+    return render::append_params_string(jni_env, str, param_str);
 }
 
-unsafe fn get_manip_class(jni_env: *mut JNIEnv) -> Result<jclass, String> {
-    let class_name_str = CString::new("stackparam/StackParamNative").unwrap();
-    return util::result_or_jni_ex((**jni_env).FindClass.unwrap()(jni_env, class_name_str.as_ptr()), jni_env);
-}
-
-unsafe fn get_elem_class(jni_env: *mut JNIEnv) -> Result<jclass, String> {
-    let class_name_str = CString::new("java/lang/StackTraceElement").unwrap();
-    return util::result_or_jni_ex((**jni_env).FindClass.unwrap()(jni_env, class_name_str.as_ptr()), jni_env);
+/// Renders the element's params into its serialized string field. Elements without params keep
+/// what they have, which is what they were read with if they came from a stream.
+unsafe fn store_param_str(state: &AgentState, jni_env: *mut JNIEnv, this: jobject) -> Result<(), AgentError> {
+    let members = state.replaced()?;
+    let param_info = util::result_or_jni_ex((**jni_env).GetObjectField.unwrap()(jni_env, this, members.param_info_field),
+                                            jni_env,
+                                            "GetObjectField")?;
+    if param_info.is_null() {
+        return Result::Ok(());
+    }
+    let param_str = render::params_string(state, jni_env, param_info, this)?;
+    (**jni_env).SetObjectField.unwrap()(jni_env, this, members.param_str_field, param_str);
+    return util::result_or_jni_ex((), jni_env, "SetObjectField");
 }
 
-unsafe fn populate_trace_elements(jni_env: *mut JNIEnv, this: jobject) -> Result<jobject, String> {
+unsafe fn populate_trace_elements(state: &AgentState, jni_env: *mut JNIEnv, this: jobject) -> Result<jobject, AgentError> {
     // We will fill the stack trace field if it has changed and it's
     // a non-null array with length greater than 0.
+    let members = state.replaced()?;
 
     // Grab the field value
     let field_val = util::result_or_jni_ex((**jni_env).GetObjectField.unwrap()(jni_env,
                                                                                this,
-                                                                               get_stack_trace_field(jni_env)?), jni_env)?;
+                                                                               state.stack_trace_field), jni_env, "GetObjectField")?;
     // Defer to original method
-    let ret = get_our_trace_orig(jni_env, this)?;
+    let ret = util::result_or_jni_ex((**jni_env).CallObjectMethod.unwrap()(jni_env, this, members.trace_orig_meth),
+                                     jni_env,
+                                     "CallObjectMethod")?;
+
+    // Whoever asked is likely about to render it
+    if !ret.is_null() {
+        render::begin_trace(jni_env, ret);
+    }
 
     // Is the field value the same or did we get null back?
     if ret.is_null() || ret == field_val {
@@ -173,172 +219,90 @@ unsafe fn populate_trace_elements(jni_env: *mut JNIEnv, this: jobject) -> Result
     }
 
     // Is the result array size over 0?
-    let ret_len = util::result_or_jni_ex((**jni_env).GetArrayLength.unwrap()(jni_env, ret), jni_env)?;
+    let ret_len = util::result_or_jni_ex((**jni_env).GetArrayLength.unwrap()(jni_env, ret), jni_env, "GetArrayLength")?;
     if ret_len == 0 {
         return Result::Ok(ret);
     }
 
-    add_element_params(jni_env, this, ret, ret_len)?;
+    add_element_params(state, jni_env, this, ret, ret_len)?;
     return Result::Ok(ret);
 }
 
-unsafe fn add_element_params(jni_env: *mut JNIEnv, this: jobject, elems: jobjectArray, elems_len: jsize) -> Result<(), String> {
+unsafe fn add_element_params(state: &AgentState,
+                             jni_env: *mut JNIEnv,
+                             this: jobject,
+                             elems: jobjectArray,
+                             elems_len: jsize) -> Result<(), AgentError> {
+    let members = state.replaced()?;
     let params = util::result_or_jni_ex((**jni_env).GetObjectField.unwrap()(jni_env,
                                                                             this,
-                                                                            get_stack_params_field(jni_env)?), jni_env)?;
+                                                                            members.stack_params_field), jni_env, "GetObjectField")?;
     // If it's null we just treat it as empty
     let params_len = if params.is_null() {
         0
     } else {
-        util::result_or_jni_ex((**jni_env).GetArrayLength.unwrap()(jni_env, params), jni_env)?
+        util::result_or_jni_ex((**jni_env).GetArrayLength.unwrap()(jni_env, params), jni_env, "GetArrayLength")?
     };
 
     for index in 0..elems_len {
-        let elem = util::result_or_jni_ex((**jni_env).GetObjectArrayElement.unwrap()(jni_env, elems, index), jni_env)?;
+        let elem = util::result_or_jni_ex((**jni_env).GetObjectArrayElement.unwrap()(jni_env, elems, index), jni_env, "GetObjectArrayElement")?;
         if !elem.is_null() {
             let param = if index >= params_len {
                 ptr::null_mut()
             } else {
-                util::result_or_jni_ex((**jni_env).GetObjectArrayElement.unwrap()(jni_env, params, index), jni_env)?
+                util::result_or_jni_ex((**jni_env).GetObjectArrayElement.unwrap()(jni_env, params, index), jni_env, "GetObjectArrayElement")?
             };
-            set_elem_param_info(jni_env, param, elem)?;
+            (**jni_env).SetObjectField.unwrap()(jni_env, elem, members.param_info_field, param);
+            util::result_or_jni_ex((), jni_env, "SetObjectField")?;
         }
     }
     return Result::Ok(());
 }
 
-unsafe fn get_elem_param_info_field(jni_env: *mut JNIEnv) -> Result<jfieldID, String> {
-    static mut PARAM_INFO_FIELD: jfieldID = 0 as jfieldID;
-    static ONCE: Once = ONCE_INIT;
-    ONCE.call_once(|| {
-        let elem_class = get_elem_class(jni_env).unwrap_or(ptr::null_mut());
-        if !elem_class.is_null() {
-            // We swallow exceptions in here on purpose
-            let field_name_str = CString::new("paramInfo").unwrap();
-            let field_sig_str = CString::new("[Ljava/lang/Object;").unwrap();
-            PARAM_INFO_FIELD = (**jni_env).GetFieldID.unwrap()(jni_env,
-                                                               elem_class,
-                                                               field_name_str.as_ptr(),
-                                                               field_sig_str.as_ptr());
-            let _ = util::result_or_jni_ex((), jni_env);
-        }
-    });
-    if PARAM_INFO_FIELD.is_null() { return Result::Err("No paramInfo field".to_string()); }
-    return Result::Ok(PARAM_INFO_FIELD);
-}
-
-unsafe fn set_elem_param_info(jni_env: *mut JNIEnv, param_info: jobject, on: jobject) -> Result<(), String> {
-    let param_info_field = get_elem_param_info_field(jni_env)?;
-    (**jni_env).SetObjectField.unwrap()(jni_env, on, param_info_field, param_info);
-    return util::result_or_jni_ex((), jni_env);
-}
-
-unsafe fn get_our_trace_orig(jni_env: *mut JNIEnv, this: jobject) -> Result<jobject, String> {
-    static mut TRACE_ORIG_METH: jmethodID = 0 as jmethodID;
-    static ONCE: Once = ONCE_INIT;
-    ONCE.call_once(|| {
-        let throwable_class = get_throwable_class(jni_env).unwrap_or(ptr::null_mut());
-        if !throwable_class.is_null() {
-            // We swallow exceptions in here on purpose
-            let meth_name_str = CString::new("$$stack_param$$getOurStackTrace").unwrap();
-            let meth_sig_str = CString::new("()[Ljava/lang/StackTraceElement;").unwrap();
-            TRACE_ORIG_METH = (**jni_env).GetMethodID.unwrap()(jni_env,
-                                                               throwable_class,
-                                                               meth_name_str.as_ptr(),
-                                                               meth_sig_str.as_ptr());
-            let _ = util::result_or_jni_ex((), jni_env);
-        }
-    });
-    if TRACE_ORIG_METH.is_null() { return Result::Err("No $$stack_param$$getOurStackTrace method".to_string()); }
-    return util::result_or_jni_ex((**jni_env).CallObjectMethod.unwrap()(jni_env, this, TRACE_ORIG_METH), jni_env);
-}
-
-unsafe fn get_stack_trace_field(jni_env: *mut JNIEnv) -> Result<jfieldID, String> {
-    static mut STACK_TRACE_FIELD: jfieldID = 0 as jfieldID;
-    static ONCE: Once = ONCE_INIT;
-    ONCE.call_once(|| {
-        let throwable_class = get_throwable_class(jni_env).unwrap_or(ptr::null_mut());
-        if !throwable_class.is_null() {
-            // We swallow exceptions in here on purpose
-            let field_name_str = CString::new("stackTrace").unwrap();
-            let field_sig_str = CString::new("[Ljava/lang/StackTraceElement;").unwrap();
-            STACK_TRACE_FIELD = (**jni_env).GetFieldID.unwrap()(jni_env,
-                                                                throwable_class,
-                                                                field_name_str.as_ptr(),
-                                                                field_sig_str.as_ptr());
-            let _ = util::result_or_jni_ex((), jni_env);
-        }
-    });
-    if STACK_TRACE_FIELD.is_null() { return Result::Err("No stackTrace field".to_string()); }
-    return Result::Ok(STACK_TRACE_FIELD);
-}
-
-unsafe fn populate_stack_params(jni_env: *mut JNIEnv, this: jobject, thread: jthread) -> Result<(), String> {
+unsafe fn populate_stack_params(state: &AgentState, jni_env: *mut JNIEnv, this: jobject, thread: jthread) -> Result<(), AgentError> {
+    let members = state.replaced()?;
+    // Skip the first 2 by default which we know are not the caller
+    let params_arr = throwable_stack_params(state, jni_env, this, thread, 2, false)?;
+    // Store in local field...
+    (**jni_env).SetObjectField.unwrap()(jni_env, this, members.stack_params_field, params_arr);
+    return util::result_or_jni_ex((), jni_env, "SetObjectField");
+}
+
+/// The params for the frames the throwable's trace will have, skipping the given number of our own.
+/// Those for the attached side table never hold values strongly, and are null if they can't be
+/// made so.
+unsafe fn throwable_stack_params(state: &AgentState,
+                                 jni_env: *mut JNIEnv,
+                                 this: jobject,
+                                 thread: jthread,
+                                 index_until_start: usize,
+                                 side_table: bool) -> Result<jobjectArray, AgentError> {
     // Grab the depth we want
-    let mut depth = get_stack_trace_depth(jni_env, this)?;
+    let mut depth = util::result_or_jni_ex((**jni_env).CallIntMethod.unwrap()(jni_env, this, state.stack_depth_meth),
+                                           jni_env,
+                                           "CallIntMethod")?;
     if depth == 0 {
         debug!("Unable to get stack trace depth, using {}", DEFAULT_MAX_STACK_DEPTH);
         depth = DEFAULT_MAX_STACK_DEPTH;
     }
 
-    // Load the stack params, skipping the first 2 by default which we know are not the caller
-    let mut params = get_params(jni_env, thread, depth + 10, 2)?;
+    // Load the stack frames
+    let mut frames = get_frames(state.jvmti, thread, depth + 10, index_until_start)?;
     // Only take the last so many to match the existing frame
-    if (depth as usize) < params.len() {
-        let to_remove_from_head = params.len() - (depth as usize);
-        params.drain(0..to_remove_from_head);
+    if (depth as usize) < frames.len() {
+        let to_remove_from_head = frames.len() - (depth as usize);
+        frames.drain(0..to_remove_from_head);
     }
 
-    // Convert to object array
-    let params_arr = params_to_object_array(jni_env, params)?;
-    // Store in local field...
-    (**jni_env).SetObjectField.unwrap()(jni_env, this, get_stack_params_field(jni_env)?, params_arr);
-    return util::result_or_jni_ex((), jni_env);
-}
-
-unsafe fn get_throwable_class(jni_env: *mut JNIEnv) -> Result<jclass, String> {
-    let class_name_str = CString::new("java/lang/Throwable").unwrap();
-    return util::result_or_jni_ex((**jni_env).FindClass.unwrap()(jni_env, class_name_str.as_ptr()), jni_env);
-}
-
-unsafe fn get_stack_params_field(jni_env: *mut JNIEnv) -> Result<jfieldID, String> {
-    static mut STACK_PARAMS_FIELD: jfieldID = 0 as jfieldID;
-    static ONCE: Once = ONCE_INIT;
-    ONCE.call_once(|| {
-        let throwable_class = get_throwable_class(jni_env).unwrap_or(ptr::null_mut());
-        if !throwable_class.is_null() {
-            // We swallow exceptions in here on purpose
-            let field_name_str = CString::new("stackParams").unwrap();
-            let field_sig_str = CString::new("[[Ljava/lang/Object;").unwrap();
-            STACK_PARAMS_FIELD = (**jni_env).GetFieldID.unwrap()(jni_env,
-                                                                 throwable_class,
-                                                                 field_name_str.as_ptr(),
-                                                                 field_sig_str.as_ptr());
-            let _ = util::result_or_jni_ex((), jni_env);
-        }
-    });
-    if STACK_PARAMS_FIELD.is_null() { return Result::Err("No stackParams field".to_string()); }
-    return Result::Ok(STACK_PARAMS_FIELD);
-}
-
-unsafe fn get_stack_trace_depth(jni_env: *mut JNIEnv, this: jobject) -> Result<jint, String> {
-    static mut STACK_DEPTH_METH: jmethodID = 0 as jmethodID;
-    static ONCE: Once = ONCE_INIT;
-    ONCE.call_once(|| {
-        let throwable_class = get_throwable_class(jni_env).unwrap_or(ptr::null_mut());
-        if !throwable_class.is_null() {
-            // We swallow exceptions in here on purpose
-            let meth_name_str = CString::new("getStackTraceDepth").unwrap();
-            let meth_sig_str = CString::new("()I").unwrap();
-            STACK_DEPTH_METH = (**jni_env).GetMethodID.unwrap()(jni_env,
-                                                                throwable_class,
-                                                                meth_name_str.as_ptr(),
-                                                                meth_sig_str.as_ptr());
-            let _ = util::result_or_jni_ex((), jni_env);
+    // Get the params as an object array
+    let params_arr = frames_to_object_array(state, jni_env, thread, frames)?;
+    if side_table || render::RenderConfig::global().changes_retained() {
+        if !render::retain_params(state, jni_env, params_arr, side_table)? && side_table {
+            (**jni_env).DeleteLocalRef.unwrap()(jni_env, params_arr);
+            return Result::Ok(ptr::null_mut());
         }
-    });
-    if STACK_DEPTH_METH.is_null() { return Result::Err("No getStackTraceDepth method".to_string()); }
-    return util::result_or_jni_ex((**jni_env).CallIntMethod.unwrap()(jni_env, this, STACK_DEPTH_METH), jni_env)
+    }
+    return Result::Ok(params_arr);
 }
 
 #[no_mangle]
@@ -347,248 +311,527 @@ pub unsafe extern "C" fn Java_stackparam_StackParamNative_loadStackParams(jni_en
                                                                           _cls: jclass,
                                                                           thread: jthread,
                                                                           max_depth: jint) -> jobject {
-    if thread.is_null() {
-        let _ = throw_ex_with_msg(jni_env, "java/lang/NullPointerException", "Thread is null");
-        return ptr::null_mut();
-    }
-    if max_depth < 0 {
-        let _ = throw_ex_with_msg(jni_env, "java/lang/IllegalArgumentException", "Max depth < 0");
-        return ptr::null_mut();
-    }
-    // TODO
-    return match get_params_as_object_array(jni_env, thread, max_depth, 0) {
-        Result::Err(err_str) => {
-            debug!("Stack param err: {}", err_str);
-            let _ = throw_ex_with_msg(jni_env,
-                                      "java/lang/RuntimeException",
-                                      format!("Unexpected stack param err: {}", err_str).as_ref());
-            ptr::null_mut()
-        },
-        Result::Ok(methods) => methods
-    };
+    return util::guard_ffi(jni_env, "StackParamNative.loadStackParams", ptr::null_mut(), || {
+        if thread.is_null() {
+            let _ = throw_ex_with_msg(jni_env, "java/lang/NullPointerException", "Thread is null");
+            return ptr::null_mut();
+        }
+        if max_depth < 0 {
+            let _ = throw_ex_with_msg(jni_env, "java/lang/IllegalArgumentException", "Max depth < 0");
+            return ptr::null_mut();
+        }
+        let state = match AgentState::global() {
+            Some(state) => state,
+            None => {
+                let _ = throw_ex_with_msg(jni_env, "java/lang/IllegalStateException", "Agent not initialized");
+                return ptr::null_mut();
+            }
+        };
+        return match get_params_as_object_array(&state, jni_env, thread, max_depth, 0) {
+            Result::Err(err_str) => {
+                debug!("Stack param err: {}", err_str);
+                let _ = throw_ex_with_msg(jni_env,
+                                          "java/lang/RuntimeException",
+                                          format!("Unexpected stack param err: {}", err_str).as_ref());
+                ptr::null_mut()
+            },
+            Result::Ok(methods) => methods
+        };
+    });
+}
+
+#[no_mangle]
+#[allow(non_snake_case)]
+pub unsafe extern "C" fn Java_stackparam_StackParamNative_loadThrowableStackParams(jni_env: *mut JNIEnv,
+                                                                                   _cls: jclass,
+                                                                                   throwable: jobject,
+                                                                                   thread: jthread) -> jobject {
+    return util::guard_ffi(jni_env, "StackParamNative.loadThrowableStackParams", ptr::null_mut(), || {
+        let state = match capturing_state() {
+            Some(state) => state,
+            None => return ptr::null_mut(),
+        };
+        // Skip ourselves and the hook calling us along with fillInStackTrace
+        return match throwable_stack_params(&state, jni_env, throwable, thread, 3, true) {
+            Result::Err(err_str) => {
+                debug!("Throwable stack param err: {}", err_str);
+                ptr::null_mut()
+            },
+            Result::Ok(params) => params
+        };
+    });
+}
+
+#[no_mangle]
+#[allow(non_snake_case)]
+pub unsafe extern "C" fn Java_stackparam_StackParamNative_setCaptureEnabled(jni_env: *mut JNIEnv,
+                                                                            _cls: jclass,
+                                                                            enabled: jboolean) {
+    util::guard_ffi(jni_env, "StackParamNative.setCaptureEnabled", (), || {
+        info!("Stack param capture {}", if enabled == JNI_FALSE { "disabled" } else { "enabled" });
+        set_capture_enabled(enabled != JNI_FALSE);
+    });
+}
+
+#[no_mangle]
+#[allow(non_snake_case)]
+pub unsafe extern "C" fn Java_stackparam_StackParamNative_isCaptureEnabled(jni_env: *mut JNIEnv,
+                                                                           _cls: jclass) -> jboolean {
+    return util::guard_ffi(jni_env, "StackParamNative.isCaptureEnabled", JNI_FALSE, || {
+        return if capturing_state().is_some() { JNI_TRUE } else { JNI_FALSE };
+    });
 }
 
-unsafe fn get_params_as_object_array(jni_env: *mut JNIEnv,
+#[no_mangle]
+#[allow(non_snake_case)]
+pub unsafe extern "C" fn Java_stackparam_StackParamNative_release(jni_env: *mut JNIEnv, _cls: jclass) -> jboolean {
+    return util::guard_ffi(jni_env, "StackParamNative.release", JNI_FALSE, || {
+        set_capture_enabled(false);
+        return match AgentState::release(jni_env) {
+            Ok(true) => {
+                info!("Agent released");
+                JNI_TRUE
+            },
+            Ok(false) => {
+                info!("Agent released, but its state was left allocated");
+                JNI_FALSE
+            },
+            Err(err_str) => {
+                info!("Agent released with error: {}", err_str);
+                JNI_FALSE
+            },
+        };
+    });
+}
+
+#[no_mangle]
+#[allow(non_snake_case)]
+pub unsafe extern "C" fn Java_stackparam_StackParamNative_renderParams(jni_env: *mut JNIEnv,
+                                                                       _cls: jclass,
+                                                                       frame_str: jstring,
+                                                                       params: jobjectArray,
+                                                                       elem: jobject) -> jstring {
+    return util::guard_ffi(jni_env, "StackParamNative.renderParams", ptr::null_mut(), || {
+        // Null has the caller render it the old way
+        let state = match AgentState::global() {
+            Some(state) => state,
+            None => return ptr::null_mut(),
+        };
+        return match render::append_params(&state, jni_env, frame_str, params, elem) {
+            Result::Err(err_str) => {
+                debug!("Render err: {}", err_str);
+                let _ = throw_ex_with_msg(jni_env, "java/lang/RuntimeException", &format!("Unable to render: {}", err_str));
+                ptr::null_mut()
+            },
+            Result::Ok(str) => str
+        };
+    });
+}
+
+#[no_mangle]
+#[allow(non_snake_case)]
+pub unsafe extern "C" fn Java_stackparam_StackParamNative_toJson(jni_env: *mut JNIEnv,
+                                                                 _cls: jclass,
+                                                                 throwable: jobject) -> jstring {
+    return util::guard_ffi(jni_env, "StackParamNative.toJson", ptr::null_mut(), || {
+        if throwable.is_null() {
+            let _ = throw_ex_with_msg(jni_env, "java/lang/NullPointerException", "Throwable is null");
+            return ptr::null_mut();
+        }
+        let state = match AgentState::global() {
+            Some(state) => state,
+            None => {
+                let _ = throw_ex_with_msg(jni_env, "java/lang/IllegalStateException", "Agent not initialized");
+                return ptr::null_mut();
+            }
+        };
+        // While disabled, frames are written without params like their strings are
+        let res = JsonWriter::new(&state, jni_env, CAPTURE_ENABLED.load(Ordering::SeqCst))
+            .and_then(|writer| writer.write(throwable));
+        return match res {
+            Result::Err(err_str) => {
+                debug!("JSON err: {}", err_str);
+                let _ = throw_ex_with_msg(jni_env, "java/lang/RuntimeException", &format!("Unable to write JSON: {}", err_str));
+                ptr::null_mut()
+            },
+            Result::Ok(str) => str
+        };
+    });
+}
+
+#[no_mangle]
+#[allow(non_snake_case)]
+pub unsafe extern "C" fn Java_stackparam_StackParamNative_getSerializedParams(jni_env: *mut JNIEnv,
+                                                                              _cls: jclass,
+                                                                              elem: jobject) -> jstring {
+    return util::guard_ffi(jni_env, "StackParamNative.getSerializedParams", ptr::null_mut(), || {
+        if elem.is_null() {
+            let _ = throw_ex_with_msg(jni_env, "java/lang/NullPointerException", "Element is null");
+            return ptr::null_mut();
+        }
+        let state = match AgentState::global() {
+            Some(state) => state,
+            None => {
+                let _ = throw_ex_with_msg(jni_env, "java/lang/IllegalStateException", "Agent not initialized");
+                return ptr::null_mut();
+            }
+        };
+        // Attached, the element has nowhere the string could have been read into
+        let members = match state.replaced() {
+            Result::Ok(members) => members,
+            Result::Err(_) => return ptr::null_mut(),
+        };
+        return match util::result_or_jni_ex((**jni_env).GetObjectField.unwrap()(jni_env, elem, members.param_str_field),
+                                            jni_env,
+                                            "GetObjectField") {
+            Result::Err(err_str) => {
+                debug!("Serialized params err: {}", err_str);
+                ptr::null_mut()
+            },
+            Result::Ok(str) => str
+        };
+    });
+}
+
+#[no_mangle]
+#[allow(non_snake_case)]
+pub unsafe extern "C" fn Java_stackparam_StackParamNative_beginTrace(jni_env: *mut JNIEnv,
+                                                                     _cls: jclass,
+                                                                     trace: jobjectArray) {
+    util::guard_ffi(jni_env, "StackParamNative.beginTrace", (), || {
+        if !trace.is_null() {
+            render::begin_trace(jni_env, trace);
+        }
+    });
+}
+
+unsafe fn get_params_as_object_array(state: &AgentState,
+                                     jni_env: *mut JNIEnv,
                                      thread: jthread,
                                      max_depth: jint,
-                                     index_until_start: usize) -> Result<jobjectArray, String> {
-    return params_to_object_array(jni_env, get_params(jni_env, thread, max_depth, index_until_start)?);
+                                     index_until_start: usize) -> Result<jobjectArray, AgentError> {
+    let frames = get_frames(state.jvmti, thread, max_depth, index_until_start)?;
+    return frames_to_object_array(state, jni_env, thread, frames);
 }
 
-unsafe fn params_to_object_array(jni_env: *mut JNIEnv, methods: Vec<MethodInfo>) -> Result<jobjectArray, String> {
-    let obj_str = CString::new("java/lang/Object").unwrap();
-    let obj_class = util::result_or_jni_ex((**jni_env).FindClass.unwrap()(jni_env, obj_str.as_ptr()), jni_env)?;
-    let obj_arr_str = CString::new("[Ljava/lang/Object;").unwrap();
-    let obj_arr_class = util::result_or_jni_ex((**jni_env).FindClass.unwrap()(jni_env, obj_arr_str.as_ptr()), jni_env)?;
+unsafe fn frames_to_object_array(state: &AgentState,
+                                 jni_env: *mut JNIEnv,
+                                 thread: jthread,
+                                 frames: Vec<(jint, jvmtiFrameInfo)>) -> Result<jobjectArray, AgentError> {
     let ret = util::result_or_jni_ex((**jni_env).NewObjectArray.unwrap()(jni_env,
-                                                                         methods.len() as jsize,
-                                                                         obj_arr_class,
-                                                                         ptr::null_mut()), jni_env)?;
-    let mut unknown_param: jstring = ptr::null_mut();
-    for (method_index, method) in methods.iter().enumerate() {
-        let param_arr = util::result_or_jni_ex((**jni_env).NewObjectArray.unwrap()(jni_env,
-                                                                                   (method.params.len() * 3) as jsize,
-                                                                                   obj_class,
-                                                                                   ptr::null_mut()), jni_env)?;
-        for (param_index, param) in method.params.iter().enumerate() {
-            // Goes: param name, param sig, val
-            (**jni_env).SetObjectArrayElement.unwrap()(jni_env,
-                                                       param_arr,
-                                                       (param_index * 3) as jsize,
-                                                       new_string(jni_env, param.name.as_ref())?);
-            util::result_or_jni_ex((), jni_env)?;
-            (**jni_env).SetObjectArrayElement.unwrap()(jni_env,
-                                                       param_arr,
-                                                       ((param_index * 3) + 1) as jsize,
-                                                       new_string(jni_env, param.typ.as_ref())?);
-            util::result_or_jni_ex((), jni_env)?;
-            let val = match param.val {
-                Some(val) => val,
-                None => {
-                    if unknown_param.is_null() {
-                        unknown_param = new_string(jni_env, "<unknown>")?;
-                    }
-                    unknown_param
-                }
+                                                                         frames.len() as jsize,
+                                                                         state.object_array_class,
+                                                                         ptr::null_mut()), jni_env, "NewObjectArray")?;
+    for (method_index, &(depth, ref frame)) in frames.iter().enumerate() {
+        // Every frame gets its own local frame so the refs don't pile up on deep stacks
+        let param_arr = util::with_local_frame(jni_env, FRAME_LOCAL_REFS, || {
+            return match get_frame_params(state, jni_env, thread, frame, depth)? {
+                Some(method) => method_to_object_array(state, jni_env, &method),
+                // Opted out frames have no params at all
+                None => Result::Ok(ptr::null_mut()),
             };
-            (**jni_env).SetObjectArrayElement.unwrap()(jni_env, param_arr, ((param_index * 3) + 2) as jsize, val);
-            util::result_or_jni_ex((), jni_env)?;
-        }
+        })?;
         (**jni_env).SetObjectArrayElement.unwrap()(jni_env, ret, method_index as jsize, param_arr);
-        util::result_or_jni_ex((), jni_env)?;
+        util::result_or_jni_ex((), jni_env, "SetObjectArrayElement")?;
+        (**jni_env).DeleteLocalRef.unwrap()(jni_env, param_arr);
     }
     return Result::Ok(ret);
 }
 
-unsafe fn throw_ex_with_msg(jni_env: *mut JNIEnv, ex_class: &str, ex_msg: &str) -> Result<(), String> {
+unsafe fn method_to_object_array(state: &AgentState, jni_env: *mut JNIEnv, method: &MethodInfo) -> Result<jobjectArray, AgentError> {
+    let mut unknown_param: jstring = ptr::null_mut();
+    // Locals, when captured, are an extra array after the param triplets
+    let extra = if method.locals.is_some() { 1 } else { 0 };
+    let param_arr = params_to_object_array(state, jni_env, &method.params, extra, &mut unknown_param)?;
+    if let Some(ref locals) = method.locals {
+        let local_arr = params_to_object_array(state, jni_env, locals, 0, &mut unknown_param)?;
+        (**jni_env).SetObjectArrayElement.unwrap()(jni_env, param_arr, (method.params.len() * 3) as jsize, local_arr);
+        util::result_or_jni_ex((), jni_env, "SetObjectArrayElement")?;
+    }
+    return Result::Ok(param_arr);
+}
+
+unsafe fn params_to_object_array(state: &AgentState,
+                                 jni_env: *mut JNIEnv,
+                                 params: &Vec<Param>,
+                                 extra: usize,
+                                 unknown_param: &mut jstring) -> Result<jobjectArray, AgentError> {
+    let param_arr = util::result_or_jni_ex((**jni_env).NewObjectArray.unwrap()(jni_env,
+                                                                               (params.len() * 3 + extra) as jsize,
+                                                                               state.object_class,
+                                                                               ptr::null_mut()), jni_env, "NewObjectArray")?;
+    for (param_index, param) in params.iter().enumerate() {
+        // Goes: param name, param sig, val
+        (**jni_env).SetObjectArrayElement.unwrap()(jni_env,
+                                                   param_arr,
+                                                   (param_index * 3) as jsize,
+                                                   new_string(jni_env, param.name.as_ref())?);
+        util::result_or_jni_ex((), jni_env, "SetObjectArrayElement")?;
+        (**jni_env).SetObjectArrayElement.unwrap()(jni_env,
+                                                   param_arr,
+                                                   ((param_index * 3) + 1) as jsize,
+                                                   new_string(jni_env, param.typ.as_ref())?);
+        util::result_or_jni_ex((), jni_env, "SetObjectArrayElement")?;
+        let val = match param.val {
+            Some(val) => val,
+            None => {
+                if unknown_param.is_null() {
+                    *unknown_param = new_string(jni_env, "<unknown>")?;
+                }
+                *unknown_param
+            }
+        };
+        (**jni_env).SetObjectArrayElement.unwrap()(jni_env, param_arr, ((param_index * 3) + 2) as jsize, val);
+        util::result_or_jni_ex((), jni_env, "SetObjectArrayElement")?;
+    }
+    return Result::Ok(param_arr);
+}
+
+unsafe fn throw_ex_with_msg(jni_env: *mut JNIEnv, ex_class: &str, ex_msg: &str) -> Result<(), AgentError> {
     let ex_class_str = CString::new(ex_class).unwrap();
     let class = util::result_or_jni_ex((**jni_env).FindClass.unwrap()(jni_env,
-                                                                      ex_class_str.as_ptr()), jni_env)?;
+                                                                      ex_class_str.as_ptr()), jni_env, "FindClass")?;
     let ex_msg_str = CString::new(ex_msg).unwrap();
-    if (**jni_env).ThrowNew.unwrap()(jni_env, class, ex_msg_str.as_ptr()) < 0 {
-        return util::result_or_jni_ex((), jni_env);
+    let throw_res = (**jni_env).ThrowNew.unwrap()(jni_env, class, ex_msg_str.as_ptr());
+    if throw_res < 0 {
+        return Result::Err(AgentError::Jni { op: "ThrowNew", code: throw_res });
     }
     return Result::Ok(());
 }
 
-unsafe fn get_params(jni_env: *mut JNIEnv,
-                     thread: jthread,
-                     max_depth: jint,
-                     index_until_start: usize) -> Result<Vec<MethodInfo>, String> {
-    // Grab the trace
-    let trace = get_stack_trace(thread, max_depth)?;
-    // Go over every frame getting the info
-    let mut ret: Vec<MethodInfo> = Vec::new();
-    for (index, frame) in trace.iter().enumerate() {
-        if index >= index_until_start {
-            ret.push(get_frame_params(jni_env, thread, frame, index as jint)?);
-        }
+/// The frames of the trace with their depth, skipping the first so many
+fn get_frames(jvmti: Jvmti,
+              thread: jthread,
+              max_depth: jint,
+              index_until_start: usize) -> Result<Vec<(jint, jvmtiFrameInfo)>, AgentError> {
+    let trace = jvmti.stack_trace(thread, max_depth)?;
+    return Result::Ok(trace.into_iter().enumerate()
+        .skip(index_until_start)
+        .map(|(index, frame)| (index as jint, frame))
+        .collect());
+}
+
+unsafe fn class_sig_from_obj(jvmti: Jvmti, jni_env: *mut JNIEnv, obj: jobject) -> Result<String, AgentError> {
+    let class = util::result_or_jni_ex((**jni_env).GetObjectClass.unwrap()(jni_env, obj), jni_env, "GetObjectClass")?;
+    return jvmti.class_signature(class);
+}
+
+unsafe fn get_frame_params(state: &AgentState,
+                           jni_env: *mut JNIEnv,
+                           thread: jthread,
+                           frame: &jvmtiFrameInfo,
+                           depth: jint) -> Result<Option<MethodInfo>, AgentError> {
+    let jvmti = state.jvmti;
+    if log_enabled!(Trace) { trace!("Getting info for {}", jvmti.method_name(frame.method)?); }
+    let annotations = match AnnotationIndex::global() {
+        Some(index) => index.for_method(jvmti, jni_env, frame.method)?,
+        None => None,
+    };
+    if annotations.as_ref().map_or(false, |annotations| annotations.no_params) {
+        trace!("Method or class marked to not capture params");
+        return Result::Ok(None);
     }
-    return Result::Ok(ret);
-}
-
-unsafe fn class_sig(class: jclass) -> Result<String, String> {
-    let mut sig: *mut c_char = 0 as *mut c_char;
-    let sig_res = (**JVMTI_ENV).GetClassSignature.unwrap()(JVMTI_ENV, class, &mut sig, ptr::null_mut());
-    util::unit_or_jvmti_err(sig_res)?;
-    let sig_str = CStr::from_ptr(sig).to_string_lossy().clone().into_owned();
-    dealloc(sig)?;
-    return Result::Ok(sig_str);
-}
-
-unsafe fn class_sig_from_obj(jni_env: *mut JNIEnv, obj: jobject) -> Result<String, String> {
-    let class = util::result_or_jni_ex((**jni_env).GetObjectClass.unwrap()(jni_env, obj), jni_env)?;
-    return class_sig(class);
-}
-
-unsafe fn method_name(method: jmethodID) -> Result<String, String> {
-    let mut name: *mut c_char = 0 as *mut c_char;
-    let name_res = (**JVMTI_ENV).GetMethodName.unwrap()(JVMTI_ENV, method, &mut name, ptr::null_mut(), ptr::null_mut());
-    util::unit_or_jvmti_err(name_res)?;
-    let name_str = CStr::from_ptr(name).to_string_lossy().clone().into_owned();
-    dealloc(name)?;
-    return Result::Ok(name_str);
-}
-
-unsafe fn get_stack_trace(thread: jthread, max_depth: jint) -> Result<Vec<jvmtiFrameInfo>, String> {
-    let mut frames: Vec<jvmtiFrameInfo> = Vec::with_capacity(max_depth as usize);
-    let mut frame_count: jint = 0;
-    let trace_res = (**JVMTI_ENV).GetStackTrace.unwrap()(JVMTI_ENV,
-                                                         thread,
-                                                         0,
-                                                         max_depth,
-                                                         frames.as_mut_ptr(), &mut frame_count);
-    util::unit_or_jvmti_err(trace_res)?;
-    frames.set_len(frame_count as usize);
-    frames.shrink_to_fit();
-    return Result::Ok(frames);
-}
-
-unsafe fn get_frame_params(jni_env: *mut JNIEnv, thread: jthread, frame: &jvmtiFrameInfo, depth: jint) -> Result<MethodInfo, String> {
-    if log_enabled!(Trace) { trace!("Getting info for {}", method_name(frame.method)?); }
-    let mut method = get_method_param_info(frame.method)?;
+    let mut method = get_method_param_info(jvmti, frame.method)?;
     let is_native = method.mods & 0x00000100 != 0;
+    if !ACCESS_LOCALS.load(Ordering::SeqCst) {
+        // Names and values are left unknown
+        trace!("No access to locals, not applying local table or getting values");
+        util::ensure_local_capacity(jni_env, (method.params.len() * 3) as jint + FRAME_LOCAL_REFS)?;
+        return Result::Ok(Some(method));
+    }
     if is_native {
         trace!("Native method, not applying local table or getting values");
-    } else {
+    } else if let Some(entries) = jvmti.local_variable_table(frame.method)? {
+        // When information is absent, we don't care
         trace!("Applying local table");
-        apply_local_var_table(frame.method, &mut method)?;
+        apply_local_var_table(&entries, &mut method)?;
+        if CAPTURE_LOCALS.load(Ordering::SeqCst) {
+            method.locals = Some(in_scope_locals(&entries, &method.params, frame.location));
+        }
     }
+    // The values here and the name and type strings made from them later, plus the locals array
+    let var_count = method.params.len() + method.locals.as_ref().map_or(0, |locals| locals.len());
+    util::ensure_local_capacity(jni_env, (var_count * 3) as jint + FRAME_LOCAL_REFS + 1)?;
     // Apply the param values if we can get them
     for param in method.params.iter_mut() {
         trace!("Var named {} at slot {} has type {}", param.name, param.slot, param.typ);
         // Now get the local var if we can
         if param.slot == 0 && param.name == "this" {
-            param.val = Some(get_this(thread, depth)?);
+            param.val = Some(jvmti.local_instance(thread, depth)?);
         } else if !is_native {
-            param.val = Some(get_local_var(jni_env, thread, depth, param.slot, param.typ.as_ref())?);
+            param.val = Some(get_local_var(state, jni_env, thread, depth, param.slot, param.typ.as_ref())?);
+        }
+    }
+    // Params marked sensitive are masked no matter the redaction rules, done before the fields of
+    // "this" shift them
+    let mut mask: jstring = ptr::null_mut();
+    if let Some(annotations) = annotations {
+        let first_index = if method.mods & 0x00000008 == 0 { 1 } else { 0 };
+        for index in annotations.sensitive_params.iter() {
+            if let Some(param) = method.params.get_mut(first_index + index) {
+                mask_var(jni_env, param, &mut mask)?;
+            }
+        }
+    }
+    // Fields of "this" go right after it
+    if let Some(config) = SnapshotConfig::global() {
+        let this = method.params.first().and_then(|param| if param.name == "this" { param.val } else { None });
+        if let Some(this) = this.filter(|this| !this.is_null()) {
+            util::ensure_local_capacity(jni_env, (var_count + config.max_fields * 3) as jint + FRAME_LOCAL_REFS + 1)?;
+            // The params are still worth having without the fields
+            match fields::snapshot(state, jni_env, config, this, "this") {
+                Ok(snapshot) => {
+                    let field_params: Vec<Param> = snapshot.into_iter().map(|field| {
+                        Param { name: field.name, typ: field.typ, slot: -1, val: Some(field.val) }
+                    }).collect();
+                    method.params.splice(1..1, field_params);
+                },
+                Err(err_str) => debug!("Unable to snapshot fields: {}", err_str),
+            }
+        }
+    }
+    if let Some(ref mut locals) = method.locals {
+        for local in locals.iter_mut() {
+            // The table can be off from what is really in the slot, so a failure just leaves it unknown
+            local.val = match get_local_var(state, jni_env, thread, depth, local.slot, local.typ.as_ref()) {
+                Ok(val) => Some(val),
+                Err(err_str) => {
+                    trace!("Unable to get local {}: {}", local.name, err_str);
+                    None
+                }
+            };
+        }
+    }
+    // Done last so nothing captured gets out unmasked
+    if let Some(config) = RedactConfig::global() {
+        redact_vars(state, jni_env, config, &mut method.params, &mut mask)?;
+        if let Some(ref mut locals) = method.locals {
+            redact_vars(state, jni_env, config, locals, &mut mask)?;
+        }
+    }
+    return Result::Ok(Some(method));
+}
+
+/// Replaces the values the rules match with the mask
+unsafe fn redact_vars(state: &AgentState,
+                      jni_env: *mut JNIEnv,
+                      config: &RedactConfig,
+                      vars: &mut Vec<Param>,
+                      mask: &mut jstring) -> Result<(), AgentError> {
+    for var in vars.iter_mut() {
+        let val = match var.val {
+            Some(val) => val,
+            None => continue,
+        };
+        if !config.matches_var(&var.name, &var.typ) && !config.matches_value(state.jvmti, jni_env, val)? {
+            continue;
         }
+        trace!("Redacting {}", var.name);
+        mask_var(jni_env, var, mask)?;
     }
-    return Result::Ok(method);
+    return Result::Ok(());
 }
 
-unsafe fn new_string(jni_env: *mut JNIEnv, str: &str) -> Result<jstring, String> {
-    let cstr = CString::new(str).unwrap();
-    return util::result_or_jni_ex((**jni_env).NewStringUTF.unwrap()(jni_env, cstr.as_ptr()), jni_env);
+/// Replaces the value with the mask, which is created on first use
+unsafe fn mask_var(jni_env: *mut JNIEnv, var: &mut Param, mask: &mut jstring) -> Result<(), AgentError> {
+    if let Some(val) = var.val {
+        if !val.is_null() && val != *mask {
+            (**jni_env).DeleteLocalRef.unwrap()(jni_env, val);
+        }
+    }
+    if mask.is_null() {
+        *mask = new_string(jni_env, redact::mask_text())?;
+    }
+    var.val = Some(*mask);
+    return Result::Ok(());
+}
+
+/// The non-param locals whose scope covers the given location, ordered by slot
+fn in_scope_locals(entries: &Vec<LocalVariable>, params: &Vec<Param>, location: jlocation) -> Vec<Param> {
+    let mut locals: Vec<Param> = entries.iter()
+        .filter(|entry| !params.iter().any(|param| param.slot == entry.slot))
+        .filter(|entry| entry.start_location <= location && location < entry.start_location + entry.length as jlocation)
+        .map(|entry| {
+            Param {
+                name: entry.name.clone(),
+                typ: entry.signature.clone(),
+                slot: entry.slot,
+                val: None,
+            }
+        })
+        .collect();
+    locals.sort_by_key(|local| local.slot);
+    return locals;
 }
 
-unsafe fn get_this(thread: jthread, depth: jint) -> Result<jobject, String> {
-    let mut obj: jobject = ptr::null_mut();
-    let inst_res = (**JVMTI_ENV).GetLocalInstance.unwrap()(JVMTI_ENV, thread, depth, &mut obj);
-    return util::result_or_jvmti_err(obj, inst_res);
+unsafe fn new_string(jni_env: *mut JNIEnv, str: &str) -> Result<jstring, AgentError> {
+    let cstr = CString::new(str).unwrap();
+    return util::result_or_jni_ex((**jni_env).NewStringUTF.unwrap()(jni_env, cstr.as_ptr()), jni_env, "NewStringUTF");
 }
 
-unsafe fn get_local_var(jni_env: *mut JNIEnv, thread: jthread, depth: jint, slot: jint, typ: &str) -> Result<jobject, String> {
+unsafe fn get_local_var(state: &AgentState,
+                        jni_env: *mut JNIEnv,
+                        thread: jthread, depth: jint, slot: jint, typ: &str) -> Result<jobject, AgentError> {
+    let jvmti = state.jvmti;
     return match typ {
         "Z" => {
-            let val = get_local_int(thread, depth, slot)?;
-            let (box_class, box_meth) = primitive_box_methods(jni_env)?.boolean;
+            let val = jvmti.local_int(thread, depth, slot)?;
+            let (box_class, box_meth) = state.box_meths.boolean;
             util::result_or_jni_ex(
-                (**jni_env).CallStaticObjectMethod.unwrap()(jni_env, box_class, box_meth, val as c_uint), jni_env)
+                (**jni_env).CallStaticObjectMethod.unwrap()(jni_env, box_class, box_meth, val as c_uint), jni_env, "CallStaticObjectMethod")
         },
         "B" => {
-            let val = get_local_int(thread, depth, slot)?;
-            let (box_class, box_meth) = primitive_box_methods(jni_env)?.byte;
+            let val = jvmti.local_int(thread, depth, slot)?;
+            let (box_class, box_meth) = state.box_meths.byte;
             util::result_or_jni_ex(
-                (**jni_env).CallStaticObjectMethod.unwrap()(jni_env, box_class, box_meth, val as c_int), jni_env)
+                (**jni_env).CallStaticObjectMethod.unwrap()(jni_env, box_class, box_meth, val as c_int), jni_env, "CallStaticObjectMethod")
         },
         "C" => {
-            let val = get_local_int(thread, depth, slot)?;
-            let (box_class, box_meth) = primitive_box_methods(jni_env)?.char;
+            let val = jvmti.local_int(thread, depth, slot)?;
+            let (box_class, box_meth) = state.box_meths.char;
             util::result_or_jni_ex(
-                (**jni_env).CallStaticObjectMethod.unwrap()(jni_env, box_class, box_meth, val as c_uint), jni_env)
+                (**jni_env).CallStaticObjectMethod.unwrap()(jni_env, box_class, box_meth, val as c_uint), jni_env, "CallStaticObjectMethod")
         },
         "S" => {
-            let val = get_local_int(thread, depth, slot)?;
-            let (box_class, box_meth) = primitive_box_methods(jni_env)?.short;
+            let val = jvmti.local_int(thread, depth, slot)?;
+            let (box_class, box_meth) = state.box_meths.short;
             util::result_or_jni_ex(
-                (**jni_env).CallStaticObjectMethod.unwrap()(jni_env, box_class, box_meth, val as c_int), jni_env)
+                (**jni_env).CallStaticObjectMethod.unwrap()(jni_env, box_class, box_meth, val as c_int), jni_env, "CallStaticObjectMethod")
         },
         "I" => {
-            let val = get_local_int(thread, depth, slot)?;
-            let (box_class, box_meth) = primitive_box_methods(jni_env)?.int;
+            let val = jvmti.local_int(thread, depth, slot)?;
+            let (box_class, box_meth) = state.box_meths.int;
             util::result_or_jni_ex(
-                (**jni_env).CallStaticObjectMethod.unwrap()(jni_env, box_class, box_meth, val), jni_env)
+                (**jni_env).CallStaticObjectMethod.unwrap()(jni_env, box_class, box_meth, val), jni_env, "CallStaticObjectMethod")
         },
         "J" => {
-            let mut val: jlong = 0;
-            util::unit_or_jvmti_err((**JVMTI_ENV).GetLocalLong.unwrap()(JVMTI_ENV, thread, depth, slot, &mut val))?;
-            let (box_class, box_meth) = primitive_box_methods(jni_env)?.long;
+            let val = jvmti.local_long(thread, depth, slot)?;
+            let (box_class, box_meth) = state.box_meths.long;
             util::result_or_jni_ex(
-                (**jni_env).CallStaticObjectMethod.unwrap()(jni_env, box_class, box_meth, val), jni_env)
+                (**jni_env).CallStaticObjectMethod.unwrap()(jni_env, box_class, box_meth, val), jni_env, "CallStaticObjectMethod")
         },
         "F" => {
-            let mut val: jfloat = 0.0;
-            util::unit_or_jvmti_err((**JVMTI_ENV).GetLocalFloat.unwrap()(JVMTI_ENV, thread, depth, slot, &mut val))?;
-            let (box_class, box_meth) = primitive_box_methods(jni_env)?.float;
+            let val = jvmti.local_float(thread, depth, slot)?;
+            let (box_class, box_meth) = state.box_meths.float;
             util::result_or_jni_ex(
-                (**jni_env).CallStaticObjectMethod.unwrap()(jni_env, box_class, box_meth, val as c_double), jni_env)
+                (**jni_env).CallStaticObjectMethod.unwrap()(jni_env, box_class, box_meth, val as c_double), jni_env, "CallStaticObjectMethod")
         },
         "D" => {
-            let mut val: jdouble = 0.0;
-            util::unit_or_jvmti_err((**JVMTI_ENV).GetLocalDouble.unwrap()(JVMTI_ENV, thread, depth, slot, &mut val))?;
-            let (box_class, box_meth) = primitive_box_methods(jni_env)?.double;
+            let val = jvmti.local_double(thread, depth, slot)?;
+            let (box_class, box_meth) = state.box_meths.double;
             util::result_or_jni_ex(
-                (**jni_env).CallStaticObjectMethod.unwrap()(jni_env, box_class, box_meth, val as c_double), jni_env)
-        },
-        typ if typ.starts_with("[") || typ.starts_with("L") => {
-            let mut val: jobject = ptr::null_mut();
-            let local_res = (**JVMTI_ENV).GetLocalObject.unwrap()(JVMTI_ENV, thread, depth, slot, &mut val);
-            util::result_or_jvmti_err(val, local_res)
+                (**jni_env).CallStaticObjectMethod.unwrap()(jni_env, box_class, box_meth, val as c_double), jni_env, "CallStaticObjectMethod")
         },
-        _ => Result::Err(format!("Unrecognized type: {}", typ))
+        typ if typ.starts_with("[") || typ.starts_with("L") => jvmti.local_object(thread, depth, slot),
+        _ => Result::Err(AgentError::Other(format!("Unrecognized type: {}", typ)))
     }
 }
 
-unsafe fn get_local_int(thread: jthread, depth: jint, slot: jint) -> Result<jint, String> {
-    let mut val: jint = 0;
-    let local_res = (**JVMTI_ENV).GetLocalInt.unwrap()(JVMTI_ENV, thread, depth, slot, &mut val);
-    return util::result_or_jvmti_err(val, local_res);
-}
-
 struct MethodInfo {
     mods: jint,
     params: Vec<Param>,
+    /// Only set when locals are captured
+    locals: Option<Vec<Param>>,
 }
 
 struct Param {
@@ -598,26 +841,24 @@ struct Param {
     val: Option<jobject>,
 }
 
-unsafe fn get_method_param_info(method: jmethodID) -> Result<MethodInfo, String> {
+fn get_method_param_info(jvmti: Jvmti, method: jmethodID) -> Result<MethodInfo, AgentError> {
     let mut ret = MethodInfo {
-        mods: get_method_modifiers(method)?,
+        mods: jvmti.method_modifiers(method)?,
         params: Vec::new(),
+        locals: None,
     };
     let is_static = ret.mods & 0x00000008 != 0;
-    let mut sig: *mut c_char = 0 as *mut c_char;
-    let name_res = (**JVMTI_ENV).GetMethodName.unwrap()(JVMTI_ENV, method, ptr::null_mut(), &mut sig, ptr::null_mut());
-    util::unit_or_jvmti_err(name_res)?;
     // Parse the sig
-    let sig_str = CStr::from_ptr(sig).to_str().map_err(|_| "Error parsing method sig")?;
+    let sig_str = jvmti.method_signature(method)?;
     let mut sig_chars = sig_str.chars();
-    if sig_chars.next() != Some('(') { return Result::Err(format!("Str {} missing opening param", sig_str)); }
+    if sig_chars.next() != Some('(') { return Result::Err(AgentError::Other(format!("Str {} missing opening param", sig_str))); }
     let mut working_str = "".to_string();
     let mut in_obj = false;
     let mut slot_counter = 0;
     if !is_static {
         ret.params.push(Param {
             name: "this".to_string(),
-            typ: get_class_signature(get_method_declaring_class(method)?)?,
+            typ: jvmti.class_signature(jvmti.method_declaring_class(method)?)?,
             slot: slot_counter,
             val: None,
         });
@@ -626,15 +867,9 @@ unsafe fn get_method_param_info(method: jmethodID) -> Result<MethodInfo, String>
     let mut param_counter = 0;
     loop {
         match sig_chars.next() {
-            None => {
-                let _ = dealloc(sig as *mut c_char);
-                return Result::Err("Unexpected end of desc".to_string());
-            },
+            None => return Result::Err(AgentError::Other("Unexpected end of desc".to_string())),
             Some(c) => match c {
-                ')' => {
-                    dealloc(sig)?;
-                    return Result::Ok(ret);
-                },
+                ')' => return Result::Ok(ret),
                 ';' if in_obj => {
                     working_str.push(';');
                     ret.params.push(Param {
@@ -670,145 +905,37 @@ unsafe fn get_method_param_info(method: jmethodID) -> Result<MethodInfo, String>
                     }
                     working_str.clear();
                 },
-                _ => {
-                    // Ignore dealloc err
-                    let _ = dealloc(sig as *mut c_char);
-                    return Result::Err(format!("Unrecognized char: {}", c));
-                },
+                _ => return Result::Err(AgentError::Other(format!("Unrecognized char: {}", c))),
             },
         }
     }
 }
 
-unsafe fn dealloc<T>(v: *mut T) -> Result<(), String> {
-    let de_res = (**JVMTI_ENV).Deallocate.unwrap()(JVMTI_ENV, v as *mut c_uchar);
-    return util::unit_or_jvmti_err(de_res);
-}
-
-unsafe fn get_class_signature(class: jclass) -> Result<String, String> {
-    let mut ret: *mut c_char = ptr::null_mut();
-    let sig_res = (**JVMTI_ENV).GetClassSignature.unwrap()(JVMTI_ENV, class, &mut ret, ptr::null_mut());
-    util::unit_or_jvmti_err(sig_res)?;
-    let sig_str = CStr::from_ptr(ret).to_string_lossy().clone().into_owned();
-    dealloc(ret)?;
-    return Result::Ok(sig_str);
-}
-
-unsafe fn get_method_declaring_class(method: jmethodID) -> Result<jclass, String> {
-    let mut ret: jclass = ptr::null_mut();
-    let cls_res = (**JVMTI_ENV).GetMethodDeclaringClass.unwrap()(JVMTI_ENV, method, &mut ret);
-    return util::result_or_jvmti_err(ret, cls_res);
-}
-
-unsafe fn get_method_modifiers(method: jmethodID) -> Result<jint, String> {
-    let mut mods: jint = 0;
-    let mod_res = (**JVMTI_ENV).GetMethodModifiers.unwrap()(JVMTI_ENV, method, &mut mods);
-    return util::result_or_jvmti_err(mods, mod_res);
-}
-
-unsafe fn apply_local_var_table(method: jmethodID, info: &mut MethodInfo) -> Result<(), String> {
-    let mut entries: *mut jvmtiLocalVariableEntry = ptr::null_mut();
-    let mut entry_count: jint = 0;
-    let table_res = (**JVMTI_ENV).GetLocalVariableTable.unwrap()(JVMTI_ENV, method, &mut entry_count, &mut entries);
-    if table_res as u32 == jvmtiError::JVMTI_ERROR_ABSENT_INFORMATION as u32 {
-        // When information is absent, we don't care
-        return Result::Ok(());
-    }
-    util::unit_or_jvmti_err(table_res)?;
-    let entry_slice = slice::from_raw_parts(entries, entry_count as usize);
+fn apply_local_var_table(entries: &Vec<LocalVariable>, info: &mut MethodInfo) -> Result<(), AgentError> {
     if log_enabled!(Trace) {
-        for entry in entry_slice {
-            trace!("Var table entry named {} at slot {} has type {}",
-            CStr::from_ptr(entry.name).to_string_lossy(),
-            entry.slot, CStr::from_ptr(entry.signature).to_string_lossy());
+        for entry in entries.iter() {
+            trace!("Var table entry named {} at slot {} has type {}", entry.name, entry.slot, entry.signature);
         }
     }
-    let mut err: Option<String> = None;
-    'param_loop: for param in info.params.iter_mut() {
-        // Find the entry at the expected slot and start location 0, but break
+    for param in info.params.iter_mut() {
+        // Find the entry at the expected slot and start location 0, but fail
         // if there is something else at that slot but not at location 0
-        let mut maybe_entry: Option<&jvmtiLocalVariableEntry> = None;
-        for entry in entry_slice {
+        let mut maybe_entry = None;
+        for entry in entries.iter() {
             if entry.slot == param.slot {
                 if entry.start_location != 0 {
-                    err = Some(format!("Var at slot {} should be location 0, but is {}", entry.slot, entry.start_location));
-                    break 'param_loop;
+                    return Result::Err(AgentError::Other(format!("Var at slot {} should be location 0, but is {}", entry.slot, entry.start_location)));
                 }
                 maybe_entry = Some(entry);
             }
         }
-        let entry = match maybe_entry {
-            Some(entry) => entry,
-            None => {
-                err = Some(format!("Can't find var entry for slot {} and location 0", param.slot));
-                break;
-            },
-        };
-        param.name = CStr::from_ptr(entry.name).to_string_lossy().clone().into_owned();
-        // Don't need to own this
-        let type_str = CStr::from_ptr(entry.signature).to_string_lossy();
-        if type_str != param.typ {
-            err = Some(format!("Var {} expected type {}, got {}", param.name, param.typ, type_str.clone()));
-            break;
+        let entry = maybe_entry.ok_or_else(|| {
+            AgentError::Other(format!("Can't find var entry for slot {} and location 0", param.slot))
+        })?;
+        param.name = entry.name.clone();
+        if entry.signature != param.typ {
+            return Result::Err(AgentError::Other(format!("Var {} expected type {}, got {}", param.name, param.typ, entry.signature)));
         }
     }
-    // Dealloc everything, ignoring errors
-    for entry in entry_slice {
-        let _ = dealloc(entry.name);
-        let _ = dealloc(entry.signature);
-        if !entry.generic_signature.is_null() {
-            let _ = dealloc(entry.generic_signature);
-        }
-    }
-    let _ = dealloc(entries);
-    return match err {
-        Some(err_str) => Result::Err(err_str),
-        None => Result::Ok(())
-    };
+    return Result::Ok(());
 }
-
-type MethodRef = (jclass, jmethodID);
-
-struct PrimitiveBoxMethods {
-    boolean: MethodRef,
-    byte: MethodRef,
-    char: MethodRef,
-    short: MethodRef,
-    int: MethodRef,
-    long: MethodRef,
-    float: MethodRef,
-    double: MethodRef
-}
-
-unsafe fn primitive_box_methods(jni_env: *mut JNIEnv) -> Result<PrimitiveBoxMethods, String> {
-    static mut PRIM_BOX_METHS: *const Result<PrimitiveBoxMethods, String> = 0 as *const Result<PrimitiveBoxMethods, String>;
-    static ONCE: Once = ONCE_INIT;
-    ONCE.call_once(|| {
-        unsafe fn method_ref(jni_env: *mut JNIEnv, class_name: &str, method_desc: &str) -> Result<MethodRef, String> {
-            let class_name_str = CString::new(class_name).unwrap();
-            let class = util::result_or_jni_ex((**jni_env).FindClass.unwrap()(jni_env,
-                                                                              class_name_str.as_ptr()), jni_env)?;
-            let meth_name_str = CString::new("valueOf").unwrap();
-            let desc_str = CString::new(method_desc).unwrap();
-            let method = util::result_or_jni_ex((**jni_env).GetStaticMethodID.unwrap()(jni_env,
-                                                                                       class,
-                                                                                       meth_name_str.as_ptr(),
-                                                                                       desc_str.as_ptr()), jni_env)?;
-            return Result::Ok((class, method));
-        }
-        unsafe fn prim_box_meths(jni_env: *mut JNIEnv) -> Result<PrimitiveBoxMethods, String> {
-            return Result::Ok(PrimitiveBoxMethods {
-                boolean: method_ref(jni_env, "java/lang/Boolean", "(Z)Ljava/lang/Boolean;")?,
-                byte: method_ref(jni_env, "java/lang/Byte", "(B)Ljava/lang/Byte;")?,
-                char: method_ref(jni_env, "java/lang/Character", "(C)Ljava/lang/Character;")?,
-                short: method_ref(jni_env, "java/lang/Short", "(S)Ljava/lang/Short;")?,
-                int: method_ref(jni_env, "java/lang/Integer", "(I)Ljava/lang/Integer;")?,
-                long: method_ref(jni_env, "java/lang/Long", "(J)Ljava/lang/Long;")?,
-                float: method_ref(jni_env, "java/lang/Float", "(F)Ljava/lang/Float;")?,
-                double: method_ref(jni_env, "java/lang/Double", "(D)Ljava/lang/Double;")?,
-            });
-        }
-        PRIM_BOX_METHS = mem::transmute(Box::new(prim_box_meths(jni_env)));
-    });
-    return ptr::read(PRIM_BOX_METHS);
-}
\ No newline at end of file
@@ -0,0 +1,149 @@
+extern crate jni_sys;
+
+use error::AgentError;
+use jni_sys::{JNIEnv, jchar, jobject, jsize};
+use jvmti::Jvmti;
+use regex::Regex;
+use std::sync::atomic::{AtomicPtr, Ordering};
+use util;
+
+// Only set when there are rules, never changed after load
+static REDACT_CONFIG: AtomicPtr<RedactConfig> = AtomicPtr::new(0 as *mut RedactConfig);
+
+const DEFAULT_MASK: &'static str = "<redacted>";
+
+// What the plain "redact" option matches
+const DEFAULT_NAME_PATTERN: &'static str = ".*(password|passwd|secret|token).*";
+
+// Longer values aren't checked against the value patterns
+const MAX_VALUE_CHARS: jsize = 4096;
+
+///
+/// The rules for which captured values are masked. Set from the agent options.
+pub struct RedactConfig {
+    /// What redacted values are replaced with
+    pub mask: String,
+    // Matched case insensitively against the whole var name and, for fields, the last part of it
+    names: Vec<Regex>,
+    // Declared type signatures, e.g. "[C"
+    types: Vec<String>,
+    // Matched anywhere in string and char array values
+    values: Vec<Regex>,
+}
+
+impl RedactConfig {
+    pub fn new() -> RedactConfig {
+        return RedactConfig {
+            mask: DEFAULT_MASK.to_string(),
+            names: Vec::new(),
+            types: Vec::new(),
+            values: Vec::new(),
+        };
+    }
+
+    /// Adds the rule for vars named like passwords, secrets and tokens
+    pub fn add_defaults(&mut self) {
+        self.add_name(DEFAULT_NAME_PATTERN).unwrap();
+    }
+
+    /// Redacts vars whose whole name matches the regex, ignoring case
+    pub fn add_name(&mut self, value: &str) -> Result<(), AgentError> {
+        let regex = Regex::new(&format!("(?i)^(?:{})$", value.trim()))
+            .map_err(|err| AgentError::Other(format!("Invalid name pattern: {}", err)))?;
+        self.names.push(regex);
+        return Result::Ok(());
+    }
+
+    /// Redacts vars declared as the Java type, e.g. "char[]" or "com.foo.Credentials"
+    pub fn add_type(&mut self, value: &str) -> Result<(), AgentError> {
+        let sig = java_type_sig(value.trim())?;
+        self.types.push(sig);
+        return Result::Ok(());
+    }
+
+    /// Redacts string and char array values with a match for the regex anywhere in them
+    pub fn add_value(&mut self, value: &str) -> Result<(), AgentError> {
+        let regex = Regex::new(value).map_err(|err| AgentError::Other(format!("Invalid value pattern: {}", err)))?;
+        self.values.push(regex);
+        return Result::Ok(());
+    }
+
+    /// Whether the var should be redacted by its name or declared type alone
+    pub fn matches_var(&self, name: &str, typ: &str) -> bool {
+        // "this.creds.password" is checked as a whole and as "password"
+        let last_name = name.rsplit('.').next().unwrap_or(name);
+        return self.types.iter().any(|sig| sig == typ) ||
+            self.names.iter().any(|regex| regex.is_match(name) || regex.is_match(last_name));
+    }
+
+    /// Whether the value is a string or char array matching a value pattern
+    pub unsafe fn matches_value(&self, jvmti: Jvmti, jni_env: *mut JNIEnv, val: jobject) -> Result<bool, AgentError> {
+        if self.values.is_empty() || val.is_null() {
+            return Result::Ok(false);
+        }
+        let class = util::result_or_jni_ex((**jni_env).GetObjectClass.unwrap()(jni_env, val), jni_env, "GetObjectClass")?;
+        let sig = jvmti.class_signature(class);
+        (**jni_env).DeleteLocalRef.unwrap()(jni_env, class);
+        let text = match sig?.as_ref() {
+            "Ljava/lang/String;" => {
+                let len = (**jni_env).GetStringLength.unwrap()(jni_env, val);
+                if len > MAX_VALUE_CHARS { return Result::Ok(false); }
+                let mut chars: Vec<jchar> = vec![0; len as usize];
+                (**jni_env).GetStringRegion.unwrap()(jni_env, val, 0, len, chars.as_mut_ptr());
+                util::result_or_jni_ex((), jni_env, "GetStringRegion")?;
+                String::from_utf16_lossy(&chars)
+            },
+            "[C" => {
+                let len = (**jni_env).GetArrayLength.unwrap()(jni_env, val);
+                if len > MAX_VALUE_CHARS { return Result::Ok(false); }
+                let mut chars: Vec<jchar> = vec![0; len as usize];
+                (**jni_env).GetCharArrayRegion.unwrap()(jni_env, val, 0, len, chars.as_mut_ptr());
+                util::result_or_jni_ex((), jni_env, "GetCharArrayRegion")?;
+                String::from_utf16_lossy(&chars)
+            },
+            _ => return Result::Ok(false),
+        };
+        return Result::Ok(self.values.iter().any(|regex| regex.is_match(&text)));
+    }
+
+    /// The config set at load or `None` if nothing is redacted
+    pub fn global() -> Option<&'static RedactConfig> {
+        let config = REDACT_CONFIG.load(Ordering::SeqCst);
+        return if config.is_null() { None } else { Some(unsafe { &*config }) };
+    }
+
+    /// Turns redaction on with this config for the rest of the VM's life
+    pub fn set_global(self) {
+        REDACT_CONFIG.store(Box::into_raw(Box::new(self)), Ordering::SeqCst);
+    }
+}
+
+/// What masked values are replaced with, even when there are no rules
+pub fn mask_text() -> &'static str {
+    return RedactConfig::global().map_or(DEFAULT_MASK, |config| config.mask.as_ref());
+}
+
+/// E.g. "int" to "I", "char[]" to "[C", and "com.foo.Bar[]" to "[Lcom/foo/Bar;"
+fn java_type_sig(java_type: &str) -> Result<String, AgentError> {
+    let mut base = java_type;
+    let mut sig = String::new();
+    while base.ends_with("[]") {
+        sig.push('[');
+        base = base[..base.len() - 2].trim();
+    }
+    if base.is_empty() {
+        return Result::Err(AgentError::Other(format!("Missing type name in {}", java_type)));
+    }
+    sig.push_str(&match base {
+        "boolean" => "Z".to_string(),
+        "byte" => "B".to_string(),
+        "char" => "C".to_string(),
+        "short" => "S".to_string(),
+        "int" => "I".to_string(),
+        "long" => "J".to_string(),
+        "float" => "F".to_string(),
+        "double" => "D".to_string(),
+        class_name => format!("L{};", class_name.replace('.', "/")),
+    });
+    return Result::Ok(sig);
+}
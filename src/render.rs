@@ -0,0 +1,1176 @@
+extern crate jni_sys;
+
+use error::AgentError;
+use fields;
+use jni_sys::{JNIEnv, JNI_FALSE, jbyte, jchar, jclass, jfieldID, jint, jmethodID, jobject, jobjectArray, jsize, jstring};
+use redact::{self, RedactConfig};
+use state::AgentState;
+use std::cell::{Cell, RefCell};
+use std::cmp;
+use std::os::raw::c_double;
+use std::ptr;
+use std::sync::atomic::{AtomicPtr, Ordering};
+use util;
+
+// Set at load if any render option is given, otherwise the defaults are used
+static RENDER_CONFIG: AtomicPtr<RenderConfig> = AtomicPtr::new(0 as *mut RenderConfig);
+
+const DEFAULT_MAX_DEPTH: usize = 3;
+
+const DEFAULT_MAX_ELEMENTS: usize = 100;
+
+const DEFAULT_TRACE_BUDGET: usize = 64 * 1024;
+
+const DEFAULT_EAGER_MAX_LEN: usize = 1000;
+
+const DEFAULT_FRAME_FORMAT: &'static str = "[{vars}]";
+
+const DEFAULT_VAR_FORMAT: &'static str = "{name}={value}";
+
+const DEFAULT_VAR_SEPARATOR: &'static str = ", ";
+
+// Where the vars go in the frame format
+const VARS_PLACEHOLDER: &'static str = "{vars}";
+
+// Each param is rendered in its own local frame, this covers the refs alive at once at the max depth
+const RENDER_LOCAL_REFS: jint = 16;
+
+// Whose toString is run even in safe mode. A trailing ".*" is every class in the package.
+const SAFE_TRUSTED_TYPES: &'static [&'static str] = &["java.lang.String", "java.lang.Boolean", "java.lang.Byte",
+                                                      "java.lang.Character", "java.lang.Short", "java.lang.Integer",
+                                                      "java.lang.Long", "java.lang.Float", "java.lang.Double",
+                                                      "java.lang.Class", "java.math.BigInteger", "java.math.BigDecimal",
+                                                      "java.util.UUID", "java.net.URI", "java.time.*",
+                                                      "java.util.ArrayList", "java.util.LinkedList", "java.util.ArrayDeque",
+                                                      "java.util.HashSet", "java.util.LinkedHashSet", "java.util.TreeSet",
+                                                      "java.util.HashMap", "java.util.LinkedHashMap", "java.util.TreeMap",
+                                                      "java.util.concurrent.ConcurrentHashMap"];
+
+const BASE64_CHARS: &'static [u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+thread_local! {
+    // The trace last asked for on this thread and what is left of its budget
+    static TRACE_BUDGET: RefCell<Option<TraceBudget>> = RefCell::new(None);
+    // Set while retaining params, since rendering runs toString which can fill in throwables
+    static RETAINING: Cell<bool> = Cell::new(false);
+}
+
+/// How `byte[]` values are shown
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ByteMode {
+    /// Like `Arrays.toString`, e.g. "[1, 2, 3]"
+    List,
+    /// Lowercase hex pairs, e.g. "010203"
+    Hex,
+    /// Standard base64 with padding, e.g. "AQID"
+    Base64,
+}
+
+/// How throwables hold onto the values of their params
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Retention {
+    /// The values themselves
+    Strong,
+    /// Weak references to the values, shown as "<collected>" once collected
+    Weak,
+    /// Only the rendered strings of the values, like eager rendering
+    Summary,
+}
+
+/// How the vars are laid out after the frame string
+#[derive(Clone, Debug)]
+pub struct FrameFormat {
+    /// The text around the vars, "{vars}" being where they go
+    pub frame: String,
+    /// Each var, with "{name}", "{type}" and "{value}" replaced by its own
+    pub var: String,
+    /// Between each var
+    pub separator: String,
+    /// Leave out the "this" param
+    pub hide_this: bool,
+}
+
+impl FrameFormat {
+    pub fn new() -> FrameFormat {
+        return FrameFormat {
+            frame: DEFAULT_FRAME_FORMAT.to_string(),
+            var: DEFAULT_VAR_FORMAT.to_string(),
+            separator: DEFAULT_VAR_SEPARATOR.to_string(),
+            hide_this: false,
+        };
+    }
+
+    pub fn set_frame(&mut self, value: &str) -> Result<(), AgentError> {
+        if !value.contains(VARS_PLACEHOLDER) {
+            return Result::Err(AgentError::Other(format!("Frame format has no {}", VARS_PLACEHOLDER)));
+        }
+        self.frame = value.to_string();
+        return Result::Ok(());
+    }
+
+    /// The format with the parts set at runtime, if any, in place of these
+    unsafe fn with_runtime(&self, state: &AgentState, jni_env: *mut JNIEnv) -> Result<FrameFormat, AgentError> {
+        let meths = &state.render_meths;
+        let parts = util::result_or_jni_ex((**jni_env).GetStaticObjectField.unwrap()(jni_env,
+                                                                                     state.native_class,
+                                                                                     meths.frame_format_field),
+                                           jni_env,
+                                           "GetStaticObjectField")?;
+        let mut ret = self.clone();
+        if parts.is_null() {
+            return Result::Ok(ret);
+        }
+        // Set by setFrameFormat as the frame, var and separator strings, any null, then the boolean
+        if let Some(frame) = array_string_or_none(jni_env, parts, 0)? { ret.frame = frame; }
+        if let Some(var) = array_string_or_none(jni_env, parts, 1)? { ret.var = var; }
+        if let Some(separator) = array_string_or_none(jni_env, parts, 2)? { ret.separator = separator; }
+        let hide_this = util::result_or_jni_ex((**jni_env).GetObjectArrayElement.unwrap()(jni_env, parts, 3),
+                                               jni_env,
+                                               "GetObjectArrayElement")?;
+        if !hide_this.is_null() {
+            ret.hide_this = util::result_or_jni_ex((**jni_env).CallBooleanMethod.unwrap()(jni_env,
+                                                                                          hide_this,
+                                                                                          meths.boolean_value),
+                                                   jni_env,
+                                                   "CallBooleanMethod")? != JNI_FALSE;
+            (**jni_env).DeleteLocalRef.unwrap()(jni_env, hide_this);
+        }
+        (**jni_env).DeleteLocalRef.unwrap()(jni_env, parts);
+        return Result::Ok(ret);
+    }
+}
+
+///
+/// The bounds on rendering param values. Set from the agent options.
+pub struct RenderConfig {
+    /// How many arrays, collections and maps deep to show the contents of
+    pub max_depth: usize,
+    /// The most elements shown for an array, collection or map
+    pub max_elements: usize,
+    /// The most bytes of param text for all the frames of one trace
+    pub trace_budget: usize,
+    pub byte_mode: ByteMode,
+    /// Only run the toString of trusted types, the rest are shown from their fields
+    pub safe: bool,
+    /// Render values to strings when the throwable is filled in instead of when it is printed
+    pub eager: bool,
+    /// The most chars kept of each eagerly rendered value
+    pub eager_max_len: usize,
+    pub retention: Retention,
+    /// Values with a shallow size in bytes over this are only kept as their rendered strings
+    pub max_retained_size: Option<usize>,
+    /// Unless changed at runtime
+    pub frame_format: FrameFormat,
+    // Java class names, with a trailing ".*" for a whole package
+    trusted: Vec<String>,
+}
+
+impl RenderConfig {
+    pub fn new() -> RenderConfig {
+        return RenderConfig {
+            max_depth: DEFAULT_MAX_DEPTH,
+            max_elements: DEFAULT_MAX_ELEMENTS,
+            trace_budget: DEFAULT_TRACE_BUDGET,
+            byte_mode: ByteMode::List,
+            safe: false,
+            eager: false,
+            eager_max_len: DEFAULT_EAGER_MAX_LEN,
+            retention: Retention::Strong,
+            max_retained_size: None,
+            frame_format: FrameFormat::new(),
+            trusted: SAFE_TRUSTED_TYPES.iter().map(|name| name.to_string()).collect(),
+        };
+    }
+
+    pub fn set_byte_mode(&mut self, value: &str) -> Result<(), AgentError> {
+        self.byte_mode = match value.trim() {
+            "list" => ByteMode::List,
+            "hex" => ByteMode::Hex,
+            "base64" => ByteMode::Base64,
+            other => return Result::Err(AgentError::Other(format!("Unknown byte mode {}, expected list, hex or base64", other))),
+        };
+        return Result::Ok(());
+    }
+
+    pub fn set_retention(&mut self, value: &str) -> Result<(), AgentError> {
+        self.retention = match value.trim() {
+            "strong" => Retention::Strong,
+            "weak" => Retention::Weak,
+            "summary" => Retention::Summary,
+            other => return Result::Err(AgentError::Other(format!("Unknown retention {}, expected strong, weak or summary", other))),
+        };
+        return Result::Ok(());
+    }
+
+    /// Whether throwables keep anything other than the values themselves
+    pub fn changes_retained(&self) -> bool {
+        return self.eager || self.retention != Retention::Strong || self.max_retained_size.is_some();
+    }
+
+    /// Trusts the toString of the given class, e.g. "com.foo.Money", or package, e.g. "com.foo.*", in safe mode
+    pub fn add_trusted(&mut self, value: &str) -> Result<(), AgentError> {
+        let value = value.trim();
+        if value.is_empty() {
+            return Result::Err(AgentError::Other("Missing class name".to_string()));
+        }
+        self.trusted.push(value.to_string());
+        return Result::Ok(());
+    }
+
+    fn is_trusted(&self, class_sig: &str) -> bool {
+        let class_name = java_class_name(class_sig);
+        return self.trusted.iter().any(|trusted| {
+            if trusted.ends_with(".*") {
+                let package = &trusted[..trusted.len() - 1];
+                class_name.starts_with(package) && !class_name[package.len()..].contains('.')
+            } else {
+                *trusted == class_name
+            }
+        });
+    }
+
+    /// The config set at load or the defaults
+    pub fn global() -> &'static RenderConfig {
+        let config = RENDER_CONFIG.load(Ordering::SeqCst);
+        if config.is_null() {
+            // Only set once at load, so this is only hit when there were no render options
+            RenderConfig::new().set_global();
+            return RenderConfig::global();
+        }
+        return unsafe { &*config };
+    }
+
+    /// Makes this the config for the rest of the VM's life
+    pub fn set_global(self) {
+        let prev = RENDER_CONFIG.swap(Box::into_raw(Box::new(self)), Ordering::SeqCst);
+        // Someone may still be using it, so it is left allocated
+        if !prev.is_null() { debug!("Render config replaced"); }
+    }
+}
+
+struct TraceBudget {
+    // Weak so the trace can still be collected
+    trace: jobject,
+    // Where the next element to render likely is
+    next_index: jsize,
+    remaining: usize,
+}
+
+/// Starts a new budget for the elements of the given trace on this thread
+pub unsafe fn begin_trace(jni_env: *mut JNIEnv, trace: jobjectArray) {
+    let weak = (**jni_env).NewWeakGlobalRef.unwrap()(jni_env, trace);
+    let budget = TraceBudget { trace: weak, next_index: 0, remaining: RenderConfig::global().trace_budget };
+    TRACE_BUDGET.with(|cell| {
+        if let Some(prev) = cell.borrow_mut().replace(budget) {
+            (**jni_env).DeleteWeakGlobalRef.unwrap()(jni_env, prev.trace);
+        }
+    });
+}
+
+/// Lets go of this thread's trace budget, called as the thread ends
+pub unsafe fn end_thread(jni_env: *mut JNIEnv) {
+    TRACE_BUDGET.with(|cell| {
+        if let Some(budget) = cell.borrow_mut().take() {
+            (**jni_env).DeleteWeakGlobalRef.unwrap()(jni_env, budget.trace);
+        }
+    });
+}
+
+/// Appends the rendered params, e.g. " [foo=bar, baz=null]", to the frame string. If the element is
+/// from the last trace asked for on this thread, the rendering counts against that trace's budget.
+pub unsafe fn append_params(state: &AgentState,
+                            jni_env: *mut JNIEnv,
+                            frame_str: jstring,
+                            params: jobjectArray,
+                            elem: jobject) -> Result<jstring, AgentError> {
+    if params.is_null() {
+        return Result::Ok(frame_str);
+    }
+    let mut ret = java_string(jni_env, frame_str, usize::max_value())?;
+    ret.push(' ');
+    ret.push_str(&render_params(state, jni_env, params, elem)?);
+    return new_string(jni_env, &ret);
+}
+
+/// Just the rendered params, e.g. "[foo=bar, baz=null]", as kept when the element is serialized
+pub unsafe fn params_string(state: &AgentState,
+                            jni_env: *mut JNIEnv,
+                            params: jobjectArray,
+                            elem: jobject) -> Result<jstring, AgentError> {
+    return new_string(jni_env, &render_params(state, jni_env, params, elem)?);
+}
+
+/// Appends params already rendered by `params_string` to the frame string
+pub unsafe fn append_params_string(jni_env: *mut JNIEnv, frame_str: jstring, param_str: jstring) -> Result<jstring, AgentError> {
+    let mut ret = java_string(jni_env, frame_str, usize::max_value())?;
+    ret.push(' ');
+    ret.push_str(&java_string(jni_env, param_str, usize::max_value())?);
+    return new_string(jni_env, &ret);
+}
+
+unsafe fn render_params(state: &AgentState,
+                        jni_env: *mut JNIEnv,
+                        params: jobjectArray,
+                        elem: jobject) -> Result<String, AgentError> {
+    let config = RenderConfig::global();
+    let max_len = util::result_or_jni_ex((**jni_env).GetStaticIntField.unwrap()(jni_env,
+                                                                                state.native_class,
+                                                                                state.render_meths.max_len_field),
+                                         jni_env,
+                                         "GetStaticIntField")?;
+    let budget = trace_budget_for(jni_env, elem);
+    let mut ctx = Renderer {
+        state: state,
+        jni_env: jni_env,
+        config: config,
+        max_len: cmp::max(max_len, 0) as usize,
+        remaining: budget.unwrap_or(config.trace_budget),
+        ancestors: Vec::new(),
+    };
+    let format = config.frame_format.with_runtime(state, jni_env)?;
+    let mut ret = String::new();
+    ctx.render_vars(&mut ret, params, &format)?;
+    // Locals, if there, are an extra array after the param triplets
+    let params_len = util::result_or_jni_ex((**jni_env).GetArrayLength.unwrap()(jni_env, params), jni_env, "GetArrayLength")?;
+    if params_len % 3 == 1 {
+        let locals = util::result_or_jni_ex((**jni_env).GetObjectArrayElement.unwrap()(jni_env, params, params_len - 1),
+                                            jni_env,
+                                            "GetObjectArrayElement")?;
+        if !locals.is_null() {
+            ret.push_str(" locals ");
+            let res = ctx.render_vars(&mut ret, locals, &format);
+            (**jni_env).DeleteLocalRef.unwrap()(jni_env, locals);
+            res?;
+        }
+    }
+    if budget.is_some() {
+        let remaining = ctx.remaining;
+        TRACE_BUDGET.with(|cell| {
+            if let Some(ref mut budget) = *cell.borrow_mut() { budget.remaining = remaining; }
+        });
+    }
+    return Result::Ok(ret);
+}
+
+/// Replaces the values of each frame's params, and locals if there, with what the throwable should
+/// keep: their rendered strings when eager, summarized or too big, and weak references when weak.
+/// Nulls, strings and boxed primitives are left as is. The trace budget covers all the frames.
+/// For the attached side table, values are never kept strongly since they can refer back to the
+/// throwable. Gives back false, leaving the values as is, for throwables filled in while this runs
+/// on the thread.
+pub unsafe fn retain_params(state: &AgentState,
+                            jni_env: *mut JNIEnv,
+                            frames: jobjectArray,
+                            side_table: bool) -> Result<bool, AgentError> {
+    if RETAINING.with(|retaining| retaining.replace(true)) {
+        return Result::Ok(false);
+    }
+    let res = retain_frames(state, jni_env, frames, side_table);
+    RETAINING.with(|retaining| retaining.set(false));
+    return res.map(|_| true);
+}
+
+unsafe fn retain_frames(state: &AgentState, jni_env: *mut JNIEnv, frames: jobjectArray, side_table: bool) -> Result<(), AgentError> {
+    let config = RenderConfig::global();
+    let mut ctx = Renderer {
+        state: state,
+        jni_env: jni_env,
+        config: config,
+        max_len: config.eager_max_len,
+        remaining: config.trace_budget,
+        ancestors: Vec::new(),
+    };
+    let len = util::result_or_jni_ex((**jni_env).GetArrayLength.unwrap()(jni_env, frames), jni_env, "GetArrayLength")?;
+    for index in 0..len {
+        util::with_local_frame(jni_env, RENDER_LOCAL_REFS, || {
+            let vars = util::result_or_jni_ex((**jni_env).GetObjectArrayElement.unwrap()(jni_env, frames, index),
+                                              jni_env,
+                                              "GetObjectArrayElement")?;
+            if !vars.is_null() {
+                ctx.retain_vars(vars, side_table)?;
+            }
+            return Result::Ok(ptr::null_mut());
+        })?;
+    }
+    return Result::Ok(());
+}
+
+/// A single var with its value rendered, as given to the JSON export
+pub struct RenderedVar {
+    pub name: String,
+    /// The JVM type signature, e.g. "I" or "Ljava/lang/String;"
+    pub sig: String,
+    pub value: String,
+}
+
+///
+/// Renders the vars of many frames one by one, all of them sharing a single trace budget
+pub struct VarRenderer<'a> {
+    ctx: Renderer<'a>,
+}
+
+impl<'a> VarRenderer<'a> {
+    pub unsafe fn new(state: &'a AgentState, jni_env: *mut JNIEnv) -> Result<VarRenderer<'a>, AgentError> {
+        let config = RenderConfig::global();
+        let max_len = util::result_or_jni_ex((**jni_env).GetStaticIntField.unwrap()(jni_env,
+                                                                                    state.native_class,
+                                                                                    state.render_meths.max_len_field),
+                                             jni_env,
+                                             "GetStaticIntField")?;
+        return Result::Ok(VarRenderer {
+            ctx: Renderer {
+                state: state,
+                jni_env: jni_env,
+                config: config,
+                max_len: cmp::max(max_len, 0) as usize,
+                remaining: config.trace_budget,
+                ancestors: Vec::new(),
+            },
+        });
+    }
+
+    /// The frame's params and, if captured, its locals
+    pub unsafe fn render_frame(&mut self, params: jobjectArray) -> Result<(Vec<RenderedVar>, Option<Vec<RenderedVar>>), AgentError> {
+        let jni_env = self.ctx.jni_env;
+        let vars = self.ctx.rendered_vars(params)?;
+        let params_len = util::result_or_jni_ex((**jni_env).GetArrayLength.unwrap()(jni_env, params), jni_env, "GetArrayLength")?;
+        if params_len % 3 != 1 {
+            return Result::Ok((vars, None));
+        }
+        let locals = util::result_or_jni_ex((**jni_env).GetObjectArrayElement.unwrap()(jni_env, params, params_len - 1),
+                                            jni_env,
+                                            "GetObjectArrayElement")?;
+        if locals.is_null() {
+            return Result::Ok((vars, None));
+        }
+        let res = self.ctx.rendered_vars(locals);
+        (**jni_env).DeleteLocalRef.unwrap()(jni_env, locals);
+        return Result::Ok((vars, Some(res?)));
+    }
+}
+
+/// What is left of the budget if the element is from this thread's last trace
+unsafe fn trace_budget_for(jni_env: *mut JNIEnv, elem: jobject) -> Option<usize> {
+    if elem.is_null() { return None; }
+    return TRACE_BUDGET.with(|cell| {
+        let mut cell = cell.borrow_mut();
+        let budget = match *cell {
+            Some(ref mut budget) => budget,
+            None => return None,
+        };
+        let trace = (**jni_env).NewLocalRef.unwrap()(jni_env, budget.trace);
+        if trace.is_null() { return None; }
+        let len = (**jni_env).GetArrayLength.unwrap()(jni_env, trace);
+        // Traces are almost always rendered in order, so try where the last one left off first
+        let found = (0..len).map(|offset| (budget.next_index + offset) % len).find(|index| {
+            let candidate = (**jni_env).GetObjectArrayElement.unwrap()(jni_env, trace, *index);
+            let same = (**jni_env).IsSameObject.unwrap()(jni_env, candidate, elem) != JNI_FALSE;
+            (**jni_env).DeleteLocalRef.unwrap()(jni_env, candidate);
+            same
+        });
+        (**jni_env).DeleteLocalRef.unwrap()(jni_env, trace);
+        return found.map(|index| {
+            budget.next_index = index + 1;
+            budget.remaining
+        });
+    });
+}
+
+/// Text that stops taking more once it hits its limit
+struct Bounded {
+    text: String,
+    limit: usize,
+    chars: usize,
+    full: bool,
+}
+
+impl Bounded {
+    fn new(limit: usize) -> Bounded {
+        return Bounded { text: String::new(), limit: limit, chars: 0, full: false };
+    }
+
+    fn push(&mut self, str: &str) {
+        for c in str.chars() {
+            if self.chars >= self.limit {
+                self.full = true;
+                return;
+            }
+            self.text.push(c);
+            self.chars += 1;
+        }
+    }
+
+    fn room(&self) -> usize {
+        return self.limit - self.chars;
+    }
+}
+
+struct Renderer<'a> {
+    state: &'a AgentState,
+    jni_env: *mut JNIEnv,
+    config: &'a RenderConfig,
+    max_len: usize,
+    /// Bytes left in the budget
+    remaining: usize,
+    /// The containers being rendered, to catch cycles
+    ancestors: Vec<jobject>,
+}
+
+impl<'a> Renderer<'a> {
+    /// Renders the name/sig/value triplets in the format, by default as "[name=value, ...]"
+    unsafe fn render_vars(&mut self, ret: &mut String, vars: jobjectArray, format: &FrameFormat) -> Result<(), AgentError> {
+        let jni_env = self.jni_env;
+        let len = util::result_or_jni_ex((**jni_env).GetArrayLength.unwrap()(jni_env, vars), jni_env, "GetArrayLength")?;
+        let vars_index = format.frame.find(VARS_PLACEHOLDER).unwrap_or(format.frame.len());
+        ret.push_str(&format.frame[..vars_index]);
+        let mut first = true;
+        for index in 0..(len / 3) {
+            let name = array_string(jni_env, vars, index * 3)?;
+            if format.hide_this && name == "this" { continue; }
+            if !first { ret.push_str(&format.separator); }
+            first = false;
+            if self.remaining == 0 {
+                ret.push_str("...");
+                break;
+            }
+            // Only look at the sig if it is shown
+            let typ = if format.var.contains("{type}") {
+                java_type_name(&array_string(jni_env, vars, index * 3 + 1)?)
+            } else {
+                String::new()
+            };
+            let value = self.render_param(vars, index * 3 + 2);
+            let mut var = fill_var_format(&format.var, &name, &typ, &value);
+            self.cut_to_budget(&mut var);
+            ret.push_str(&var);
+        }
+        ret.push_str(&format.frame[cmp::min(vars_index + VARS_PLACEHOLDER.len(), format.frame.len())..]);
+        return Result::Ok(());
+    }
+
+    /// Renders each name/sig/value triplet on its own. Values past the budget are just "...".
+    unsafe fn rendered_vars(&mut self, vars: jobjectArray) -> Result<Vec<RenderedVar>, AgentError> {
+        let jni_env = self.jni_env;
+        let len = util::result_or_jni_ex((**jni_env).GetArrayLength.unwrap()(jni_env, vars), jni_env, "GetArrayLength")?;
+        let mut ret = Vec::new();
+        for index in 0..(len / 3) {
+            let value = if self.remaining == 0 {
+                "...".to_string()
+            } else {
+                let mut value = self.render_param(vars, index * 3 + 2);
+                self.cut_to_budget(&mut value);
+                value
+            };
+            ret.push(RenderedVar {
+                name: array_string(jni_env, vars, index * 3)?,
+                sig: array_string(jni_env, vars, index * 3 + 1)?,
+                value: value,
+            });
+        }
+        return Result::Ok(ret);
+    }
+
+    /// Cuts the text to what is left of the budget and takes it from the budget
+    fn cut_to_budget(&mut self, text: &mut String) {
+        if text.len() > self.remaining {
+            let mut end = self.remaining;
+            while !text.is_char_boundary(end) { end -= 1; }
+            text.truncate(end);
+            text.push_str("...");
+            self.remaining = 0;
+        } else {
+            self.remaining -= text.len();
+        }
+    }
+
+    /// Replaces the values of the name/sig/value triplets, and the trailing locals, in place
+    unsafe fn retain_vars(&mut self, vars: jobjectArray, side_table: bool) -> Result<(), AgentError> {
+        let jni_env = self.jni_env;
+        let len = util::result_or_jni_ex((**jni_env).GetArrayLength.unwrap()(jni_env, vars), jni_env, "GetArrayLength")?;
+        for index in 0..(len / 3) {
+            let val_index = index * 3 + 2;
+            let val = util::result_or_jni_ex((**jni_env).GetObjectArrayElement.unwrap()(jni_env, vars, val_index),
+                                             jni_env,
+                                             "GetObjectArrayElement")?;
+            let keep = self.retained_as(val, side_table);
+            if !val.is_null() { (**jni_env).DeleteLocalRef.unwrap()(jni_env, val); }
+            let new_val = match keep? {
+                Retention::Strong => continue,
+                Retention::Weak => self.weak_ref(vars, val_index)?,
+                Retention::Summary => self.summary(vars, val_index)?,
+            };
+            (**jni_env).SetObjectArrayElement.unwrap()(jni_env, vars, val_index, new_val);
+            (**jni_env).DeleteLocalRef.unwrap()(jni_env, new_val);
+            util::result_or_jni_ex((), jni_env, "SetObjectArrayElement")?;
+        }
+        // Locals, if there, are an extra array after the param triplets
+        if len % 3 == 1 {
+            let locals = util::result_or_jni_ex((**jni_env).GetObjectArrayElement.unwrap()(jni_env, vars, len - 1),
+                                                jni_env,
+                                                "GetObjectArrayElement")?;
+            if !locals.is_null() {
+                let res = self.retain_vars(locals, side_table);
+                (**jni_env).DeleteLocalRef.unwrap()(jni_env, locals);
+                res?;
+            }
+        }
+        return Result::Ok(());
+    }
+
+    /// How this particular value is kept, leaves always being kept as is
+    unsafe fn retained_as(&self, val: jobject, side_table: bool) -> Result<Retention, AgentError> {
+        if self.is_leaf(val)? {
+            return Result::Ok(Retention::Strong);
+        }
+        if side_table && self.config.retention == Retention::Weak && !self.config.eager {
+            return Result::Ok(Retention::Weak);
+        }
+        if self.config.eager || self.config.retention == Retention::Summary || side_table {
+            return Result::Ok(Retention::Summary);
+        }
+        if let Some(max_size) = self.config.max_retained_size {
+            if self.state.jvmti.object_size(val)? as usize > max_size {
+                return Result::Ok(Retention::Summary);
+            }
+        }
+        return Result::Ok(self.config.retention);
+    }
+
+    unsafe fn weak_ref(&self, vars: jobjectArray, index: jsize) -> Result<jobject, AgentError> {
+        let jni_env = self.jni_env;
+        let meths = &self.state.render_meths;
+        let val = util::result_or_jni_ex((**jni_env).GetObjectArrayElement.unwrap()(jni_env, vars, index),
+                                         jni_env,
+                                         "GetObjectArrayElement")?;
+        let weak = util::result_or_jni_ex((**jni_env).NewObject.unwrap()(jni_env, meths.weak_ref_class, meths.weak_ref_init, val),
+                                          jni_env,
+                                          "NewObject");
+        (**jni_env).DeleteLocalRef.unwrap()(jni_env, val);
+        return weak;
+    }
+
+    /// The rendered string of the value, counted against the budget
+    unsafe fn summary(&mut self, vars: jobjectArray, index: jsize) -> Result<jstring, AgentError> {
+        let mut text = if self.remaining == 0 { "...".to_string() } else { self.render_param(vars, index) };
+        self.cut_to_budget(&mut text);
+        return new_string(self.jni_env, &text);
+    }
+
+    /// Whether the value is null or can't change after capture
+    unsafe fn is_leaf(&self, val: jobject) -> Result<bool, AgentError> {
+        if val.is_null() {
+            return Result::Ok(true);
+        }
+        let jni_env = self.jni_env;
+        let class = util::result_or_jni_ex((**jni_env).GetObjectClass.unwrap()(jni_env, val), jni_env, "GetObjectClass")?;
+        let sig = self.state.jvmti.class_signature(class);
+        (**jni_env).DeleteLocalRef.unwrap()(jni_env, class);
+        return Result::Ok(fields::LEAF_TYPES.contains(&sig?.as_ref()));
+    }
+
+    /// The value of the param cut to the max length, or the error getting it
+    unsafe fn render_param(&mut self, vars: jobjectArray, index: jsize) -> String {
+        let jni_env = self.jni_env;
+        let mut out = Bounded::new(self.max_len);
+        let res = util::with_local_frame(jni_env, RENDER_LOCAL_REFS, || {
+            let val = util::result_or_jni_ex((**jni_env).GetObjectArrayElement.unwrap()(jni_env, vars, index),
+                                             jni_env,
+                                             "GetObjectArrayElement")?;
+            match self.unwrap_weak(val)? {
+                Some(val) => self.render_value(&mut out, val, 0)?,
+                None => out.push("<collected>"),
+            }
+            return Result::Ok(ptr::null_mut());
+        });
+        self.ancestors.clear();
+        return match res {
+            Ok(_) if out.full => out.text + "...",
+            Ok(_) => out.text,
+            Err(AgentError::JavaException { ref exception, .. }) => match exception.message {
+                Some(ref msg) => format!("toString err: {}: {}", exception.class_name, msg),
+                None => format!("toString err: {}", exception.class_name),
+            },
+            Err(err) => format!("toString err: {}", err),
+        };
+    }
+
+    /// The referent if the value is one of our weak references, `None` if it was collected
+    unsafe fn unwrap_weak(&self, val: jobject) -> Result<Option<jobject>, AgentError> {
+        if self.config.retention != Retention::Weak || val.is_null() {
+            return Result::Ok(Some(val));
+        }
+        let jni_env = self.jni_env;
+        let meths = &self.state.render_meths;
+        let class = util::result_or_jni_ex((**jni_env).GetObjectClass.unwrap()(jni_env, val), jni_env, "GetObjectClass")?;
+        let is_weak = (**jni_env).IsSameObject.unwrap()(jni_env, class, meths.weak_ref_class) != JNI_FALSE;
+        (**jni_env).DeleteLocalRef.unwrap()(jni_env, class);
+        if !is_weak {
+            return Result::Ok(Some(val));
+        }
+        let referent = util::result_or_jni_ex((**jni_env).CallObjectMethod.unwrap()(jni_env, val, meths.weak_ref_get),
+                                              jni_env,
+                                              "CallObjectMethod")?;
+        return Result::Ok(if referent.is_null() { None } else { Some(referent) });
+    }
+
+    unsafe fn render_value(&mut self, out: &mut Bounded, val: jobject, depth: usize) -> Result<(), AgentError> {
+        let jni_env = self.jni_env;
+        if val.is_null() {
+            out.push("null");
+            return Result::Ok(());
+        }
+        let class = util::result_or_jni_ex((**jni_env).GetObjectClass.unwrap()(jni_env, val), jni_env, "GetObjectClass")?;
+        let sig = self.state.jvmti.class_signature(class);
+        (**jni_env).DeleteLocalRef.unwrap()(jni_env, class);
+        let sig = sig?;
+        let meths = &self.state.render_meths;
+        let is_array = sig.starts_with('[');
+        // Arrays never run any code, but in safe mode others only do when trusted
+        if !is_array && self.config.safe && !self.config.is_trusted(&sig) {
+            return self.render_fields(out, val, &sig);
+        }
+        let is_collection = !is_array && (**jni_env).IsInstanceOf.unwrap()(jni_env, val, meths.collection_class) != JNI_FALSE;
+        let is_map = !is_array && !is_collection && (**jni_env).IsInstanceOf.unwrap()(jni_env, val, meths.map_class) != JNI_FALSE;
+        if !is_array && !is_collection && !is_map {
+            return self.render_to_string(out, val);
+        }
+        let (open, close) = if is_map { ("{", "}") } else { ("[", "]") };
+        if depth >= self.config.max_depth {
+            out.push(open);
+            out.push("...");
+            out.push(close);
+            return Result::Ok(());
+        }
+        for ancestor in self.ancestors.iter() {
+            if (**jni_env).IsSameObject.unwrap()(jni_env, *ancestor, val) != JNI_FALSE {
+                out.push("<cycle>");
+                return Result::Ok(());
+            }
+        }
+        self.ancestors.push(val);
+        let res = if is_array {
+            self.render_array(out, val, &sig[1..], depth)
+        } else if is_collection {
+            self.render_iterable(out, val, meths.collection_iterator, false, depth)
+        } else {
+            self.render_iterable(out, val, meths.map_entry_set, true, depth)
+        };
+        self.ancestors.pop();
+        return res;
+    }
+
+    unsafe fn render_to_string(&mut self, out: &mut Bounded, val: jobject) -> Result<(), AgentError> {
+        let jni_env = self.jni_env;
+        let str = util::result_or_jni_ex((**jni_env).CallObjectMethod.unwrap()(jni_env, val, self.state.render_meths.to_string),
+                                         jni_env,
+                                         "CallObjectMethod")?;
+        self.push_java_string(out, str)
+    }
+
+    /// Renders like "com.foo.Bar@1b6d3586{id=5, name=foo}" from the primitive and string instance fields, read
+    /// without running any of the object's code. Fields the redaction rules match show the mask.
+    unsafe fn render_fields(&mut self, out: &mut Bounded, val: jobject, sig: &str) -> Result<(), AgentError> {
+        let jni_env = self.jni_env;
+        let hash = self.state.jvmti.object_hash_code(val)?;
+        out.push(&format!("{}@{:x}", java_class_name(sig), hash));
+        let mut class = util::result_or_jni_ex((**jni_env).GetObjectClass.unwrap()(jni_env, val), jni_env, "GetObjectClass")?;
+        let mut count = 0;
+        let res = (|| {
+            while !class.is_null() {
+                for field in self.state.jvmti.class_fields(class)? {
+                    if out.full { return Result::Ok(()); }
+                    // Statics aren't part of the instance
+                    if self.state.jvmti.field_modifiers(class, field)? & 0x0008 != 0 { continue; }
+                    let (name, typ) = self.state.jvmti.field_name(class, field)?;
+                    let mut field_str = match self.field_string(val, field, &typ)? {
+                        Some(field_str) => field_str,
+                        None => continue,
+                    };
+                    if self.field_redacted(val, field, &name, &typ)? {
+                        field_str = redact::mask_text().to_string();
+                    }
+                    out.push(if count == 0 { "{" } else { ", " });
+                    if count >= self.config.max_elements {
+                        out.push("...}");
+                        return Result::Ok(());
+                    }
+                    out.push(&name);
+                    out.push("=");
+                    out.push(&field_str);
+                    count += 1;
+                }
+                let super_class = (**jni_env).GetSuperclass.unwrap()(jni_env, class);
+                (**jni_env).DeleteLocalRef.unwrap()(jni_env, class);
+                class = super_class;
+            }
+            if count > 0 { out.push("}"); }
+            return Result::Ok(());
+        })();
+        if !class.is_null() { (**jni_env).DeleteLocalRef.unwrap()(jni_env, class); }
+        return res;
+    }
+
+    /// Whether the redaction rules match the field by its name, declared type or string value
+    unsafe fn field_redacted(&mut self, obj: jobject, field: jfieldID, name: &str, typ: &str) -> Result<bool, AgentError> {
+        let config = match RedactConfig::global() {
+            Some(config) => config,
+            None => return Result::Ok(false),
+        };
+        if config.matches_var(name, typ) {
+            return Result::Ok(true);
+        }
+        // Only strings are shown of the types the value patterns look at
+        if typ != "Ljava/lang/String;" {
+            return Result::Ok(false);
+        }
+        let jni_env = self.jni_env;
+        let field_val = util::result_or_jni_ex((**jni_env).GetObjectField.unwrap()(jni_env, obj, field),
+                                               jni_env,
+                                               "GetObjectField")?;
+        let ret = config.matches_value(self.state.jvmti, jni_env, field_val);
+        if !field_val.is_null() { (**jni_env).DeleteLocalRef.unwrap()(jni_env, field_val); }
+        return ret;
+    }
+
+    /// The field value as a string if it is a primitive or string, `None` otherwise
+    unsafe fn field_string(&mut self, obj: jobject, field: jfieldID, typ: &str) -> Result<Option<String>, AgentError> {
+        let jni_env = self.jni_env;
+        let ret = match typ {
+            "Z" => ((**jni_env).GetBooleanField.unwrap()(jni_env, obj, field) != JNI_FALSE).to_string(),
+            "B" => (**jni_env).GetByteField.unwrap()(jni_env, obj, field).to_string(),
+            "C" => String::from_utf16_lossy(&[(**jni_env).GetCharField.unwrap()(jni_env, obj, field)]),
+            "S" => (**jni_env).GetShortField.unwrap()(jni_env, obj, field).to_string(),
+            "I" => (**jni_env).GetIntField.unwrap()(jni_env, obj, field).to_string(),
+            "J" => (**jni_env).GetLongField.unwrap()(jni_env, obj, field).to_string(),
+            "F" | "D" => {
+                let (val, class, meth) = if typ == "F" {
+                    ((**jni_env).GetFloatField.unwrap()(jni_env, obj, field) as c_double,
+                     self.state.box_meths.float.0,
+                     self.state.render_meths.float_to_string)
+                } else {
+                    ((**jni_env).GetDoubleField.unwrap()(jni_env, obj, field),
+                     self.state.box_meths.double.0,
+                     self.state.render_meths.double_to_string)
+                };
+                let mut text = Bounded::new(usize::max_value());
+                let str = util::result_or_jni_ex((**jni_env).CallStaticObjectMethod.unwrap()(jni_env, class, meth, val),
+                                                 jni_env,
+                                                 "CallStaticObjectMethod")?;
+                self.push_java_string(&mut text, str)?;
+                text.text
+            },
+            "Ljava/lang/String;" => {
+                let str = util::result_or_jni_ex((**jni_env).GetObjectField.unwrap()(jni_env, obj, field),
+                                                 jni_env,
+                                                 "GetObjectField")?;
+                let mut text = Bounded::new(self.max_len + 1);
+                self.push_java_string(&mut text, str)?;
+                text.text
+            },
+            _ => return Result::Ok(None),
+        };
+        util::result_or_jni_ex((), jni_env, "Get<Type>Field")?;
+        return Result::Ok(Some(ret));
+    }
+
+    unsafe fn push_java_string(&mut self, out: &mut Bounded, str: jstring) -> Result<(), AgentError> {
+        if str.is_null() {
+            out.push("null");
+            return Result::Ok(());
+        }
+        // One more than there is room for so the cut is noticed
+        let text = java_string(self.jni_env, str, out.room().saturating_add(1));
+        (**self.jni_env).DeleteLocalRef.unwrap()(self.jni_env, str);
+        out.push(&text?);
+        return Result::Ok(());
+    }
+
+    unsafe fn render_array(&mut self, out: &mut Bounded, arr: jobject, elem_sig: &str, depth: usize) -> Result<(), AgentError> {
+        let jni_env = self.jni_env;
+        let len = util::result_or_jni_ex((**jni_env).GetArrayLength.unwrap()(jni_env, arr), jni_env, "GetArrayLength")?;
+        let count = cmp::min(len as usize, self.config.max_elements) as jsize;
+        let more = count < len;
+        match elem_sig {
+            "B" if self.config.byte_mode != ByteMode::List => {
+                let mut bytes: Vec<jbyte> = vec![0; count as usize];
+                (**jni_env).GetByteArrayRegion.unwrap()(jni_env, arr, 0, count, bytes.as_mut_ptr());
+                util::result_or_jni_ex((), jni_env, "GetByteArrayRegion")?;
+                let bytes: Vec<u8> = bytes.into_iter().map(|b| b as u8).collect();
+                out.push(&if self.config.byte_mode == ByteMode::Hex { to_hex(&bytes) } else { to_base64(&bytes) });
+                if more { out.push("..."); }
+                return Result::Ok(());
+            },
+            "Z" => {
+                let vals = prim_region(jni_env, arr, count, (**jni_env).GetBooleanArrayRegion.unwrap())?;
+                push_list(out, vals.into_iter().map(|v| (v != JNI_FALSE).to_string()), more);
+            },
+            "B" => {
+                let vals = prim_region(jni_env, arr, count, (**jni_env).GetByteArrayRegion.unwrap())?;
+                push_list(out, vals.into_iter().map(|v| v.to_string()), more);
+            },
+            "C" => {
+                let vals = prim_region(jni_env, arr, count, (**jni_env).GetCharArrayRegion.unwrap())?;
+                push_list(out, vals.into_iter().map(|v| String::from_utf16_lossy(&[v])), more);
+            },
+            "S" => {
+                let vals = prim_region(jni_env, arr, count, (**jni_env).GetShortArrayRegion.unwrap())?;
+                push_list(out, vals.into_iter().map(|v| v.to_string()), more);
+            },
+            "I" => {
+                let vals = prim_region(jni_env, arr, count, (**jni_env).GetIntArrayRegion.unwrap())?;
+                push_list(out, vals.into_iter().map(|v| v.to_string()), more);
+            },
+            "J" => {
+                let vals = prim_region(jni_env, arr, count, (**jni_env).GetLongArrayRegion.unwrap())?;
+                push_list(out, vals.into_iter().map(|v| v.to_string()), more);
+            },
+            // Java and Rust format floating point differently, so Java does it
+            "F" => {
+                let vals = prim_region(jni_env, arr, count, (**jni_env).GetFloatArrayRegion.unwrap())?;
+                let (class, meth) = (self.state.box_meths.float.0, self.state.render_meths.float_to_string);
+                self.push_java_list(out, vals.into_iter().map(|v| v as c_double), class, meth, more)?;
+            },
+            "D" => {
+                let vals = prim_region(jni_env, arr, count, (**jni_env).GetDoubleArrayRegion.unwrap())?;
+                let (class, meth) = (self.state.box_meths.double.0, self.state.render_meths.double_to_string);
+                self.push_java_list(out, vals.into_iter().map(|v| v as c_double), class, meth, more)?;
+            },
+            _ => {
+                out.push("[");
+                for index in 0..count {
+                    if out.full { return Result::Ok(()); }
+                    if index > 0 { out.push(", "); }
+                    let elem = util::result_or_jni_ex((**jni_env).GetObjectArrayElement.unwrap()(jni_env, arr, index),
+                                                      jni_env,
+                                                      "GetObjectArrayElement")?;
+                    let res = self.render_value(out, elem, depth + 1);
+                    (**jni_env).DeleteLocalRef.unwrap()(jni_env, elem);
+                    res?;
+                }
+                if more { out.push(", ..."); }
+                out.push("]");
+            },
+        }
+        return Result::Ok(());
+    }
+
+    /// Like `push_list` but each value is made a string by the given static method
+    unsafe fn push_java_list<I>(&mut self, out: &mut Bounded, vals: I, class: jclass, meth: jmethodID, more: bool) -> Result<(), AgentError>
+        where I: Iterator<Item = c_double> {
+        let jni_env = self.jni_env;
+        out.push("[");
+        for (index, val) in vals.enumerate() {
+            if out.full { return Result::Ok(()); }
+            if index > 0 { out.push(", "); }
+            let str = util::result_or_jni_ex((**jni_env).CallStaticObjectMethod.unwrap()(jni_env, class, meth, val),
+                                             jni_env,
+                                             "CallStaticObjectMethod")?;
+            self.push_java_string(out, str)?;
+        }
+        if more { out.push(", ..."); }
+        out.push("]");
+        return Result::Ok(());
+    }
+
+    /// Renders a collection's elements or, when entries, a map's "key=value" pairs
+    unsafe fn render_iterable(&mut self,
+                              out: &mut Bounded,
+                              val: jobject,
+                              iterable_meth: jmethodID,
+                              entries: bool,
+                              depth: usize) -> Result<(), AgentError> {
+        let jni_env = self.jni_env;
+        let meths = &self.state.render_meths;
+        let iterable = util::result_or_jni_ex((**jni_env).CallObjectMethod.unwrap()(jni_env, val, iterable_meth),
+                                              jni_env,
+                                              "CallObjectMethod")?;
+        // Maps give the entry set which we then need the iterator of
+        let iter = if entries && !iterable.is_null() {
+            let iter = util::result_or_jni_ex((**jni_env).CallObjectMethod.unwrap()(jni_env, iterable, meths.collection_iterator),
+                                              jni_env,
+                                              "CallObjectMethod");
+            (**jni_env).DeleteLocalRef.unwrap()(jni_env, iterable);
+            iter?
+        } else {
+            iterable
+        };
+        if iter.is_null() {
+            out.push("null");
+            return Result::Ok(());
+        }
+        out.push(if entries { "{" } else { "[" });
+        let res = (|| {
+            let mut index = 0;
+            loop {
+                if out.full { return Result::Ok(()); }
+                let has_next = util::result_or_jni_ex((**jni_env).CallBooleanMethod.unwrap()(jni_env, iter, meths.iterator_has_next),
+                                                      jni_env,
+                                                      "CallBooleanMethod")?;
+                if has_next == JNI_FALSE { break; }
+                if index > 0 { out.push(", "); }
+                if index >= self.config.max_elements {
+                    out.push("...");
+                    break;
+                }
+                let next = util::result_or_jni_ex((**jni_env).CallObjectMethod.unwrap()(jni_env, iter, meths.iterator_next),
+                                                  jni_env,
+                                                  "CallObjectMethod")?;
+                let res = if entries { self.render_entry(out, next, depth) } else { self.render_value(out, next, depth + 1) };
+                (**jni_env).DeleteLocalRef.unwrap()(jni_env, next);
+                res?;
+                index += 1;
+            }
+            out.push(if entries { "}" } else { "]" });
+            return Result::Ok(());
+        })();
+        (**jni_env).DeleteLocalRef.unwrap()(jni_env, iter);
+        return res;
+    }
+
+    unsafe fn render_entry(&mut self, out: &mut Bounded, entry: jobject, depth: usize) -> Result<(), AgentError> {
+        let jni_env = self.jni_env;
+        let meths = &self.state.render_meths;
+        for (index, meth) in [meths.entry_key, meths.entry_value].iter().enumerate() {
+            if index > 0 { out.push("="); }
+            let val = util::result_or_jni_ex((**jni_env).CallObjectMethod.unwrap()(jni_env, entry, *meth),
+                                             jni_env,
+                                             "CallObjectMethod")?;
+            let res = self.render_value(out, val, depth + 1);
+            if !val.is_null() { (**jni_env).DeleteLocalRef.unwrap()(jni_env, val); }
+            res?;
+        }
+        return Result::Ok(());
+    }
+}
+
+unsafe fn prim_region<T: Copy + Default>(jni_env: *mut JNIEnv,
+                                         arr: jobject,
+                                         count: jsize,
+                                         getter: unsafe extern "system" fn(*mut JNIEnv, jobject, jsize, jsize, *mut T))
+                                         -> Result<Vec<T>, AgentError> {
+    let mut vals: Vec<T> = vec![T::default(); count as usize];
+    getter(jni_env, arr, 0, count, vals.as_mut_ptr());
+    util::result_or_jni_ex((), jni_env, "Get<Primitive>ArrayRegion")?;
+    return Result::Ok(vals);
+}
+
+fn push_list<I: Iterator<Item = String>>(out: &mut Bounded, vals: I, more: bool) {
+    out.push("[");
+    for (index, val) in vals.enumerate() {
+        if out.full { return; }
+        if index > 0 { out.push(", "); }
+        out.push(&val);
+    }
+    if more { out.push(", ..."); }
+    out.push("]");
+}
+
+/// The var format with each of "{name}", "{type}" and "{value}" replaced. Replaced text isn't looked
+/// at again, so a value with "{name}" in it is left alone.
+fn fill_var_format(format: &str, name: &str, typ: &str, value: &str) -> String {
+    let mut ret = String::new();
+    let mut rest = format;
+    while let Some(start) = rest.find('{') {
+        ret.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let (replacement, len) = if rest.starts_with("{name}") {
+            (name, "{name}".len())
+        } else if rest.starts_with("{type}") {
+            (typ, "{type}".len())
+        } else if rest.starts_with("{value}") {
+            (value, "{value}".len())
+        } else {
+            ("{", 1)
+        };
+        ret.push_str(replacement);
+        rest = &rest[len..];
+    }
+    ret.push_str(rest);
+    return ret;
+}
+
+/// E.g. "I" to "int", "[Ljava/lang/String;" to "java.lang.String[]"
+fn java_type_name(sig: &str) -> String {
+    let dims = sig.chars().take_while(|c| *c == '[').count();
+    let mut ret = match &sig[dims..] {
+        "Z" => "boolean".to_string(),
+        "B" => "byte".to_string(),
+        "C" => "char".to_string(),
+        "S" => "short".to_string(),
+        "I" => "int".to_string(),
+        "J" => "long".to_string(),
+        "F" => "float".to_string(),
+        "D" => "double".to_string(),
+        "V" => "void".to_string(),
+        class_sig => java_class_name(class_sig),
+    };
+    for _ in 0..dims { ret.push_str("[]"); }
+    return ret;
+}
+
+/// E.g. "Lcom/foo/Bar$Baz;" to "com.foo.Bar$Baz"
+pub fn java_class_name(class_sig: &str) -> String {
+    let class_name = if class_sig.starts_with('L') && class_sig.ends_with(';') {
+        &class_sig[1..class_sig.len() - 1]
+    } else {
+        class_sig
+    };
+    return class_name.replace('/', ".");
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    return bytes.iter().map(|b| format!("{:02x}", b)).collect();
+}
+
+fn to_base64(bytes: &[u8]) -> String {
+    let mut ret = String::with_capacity((bytes.len() + 2) / 3 * 4);
+    for chunk in bytes.chunks(3) {
+        let b = [chunk[0], *chunk.get(1).unwrap_or(&0), *chunk.get(2).unwrap_or(&0)];
+        let n = ((b[0] as usize) << 16) | ((b[1] as usize) << 8) | (b[2] as usize);
+        ret.push(BASE64_CHARS[(n >> 18) & 63] as char);
+        ret.push(BASE64_CHARS[(n >> 12) & 63] as char);
+        ret.push(if chunk.len() > 1 { BASE64_CHARS[(n >> 6) & 63] as char } else { '=' });
+        ret.push(if chunk.len() > 2 { BASE64_CHARS[n & 63] as char } else { '=' });
+    }
+    return ret;
+}
+
+/// Up to the given number of UTF-16 units of the string, without copying the rest
+pub unsafe fn java_string(jni_env: *mut JNIEnv, str: jstring, max_chars: usize) -> Result<String, AgentError> {
+    let len = util::result_or_jni_ex((**jni_env).GetStringLength.unwrap()(jni_env, str), jni_env, "GetStringLength")?;
+    let count = cmp::min(len as usize, max_chars);
+    let mut chars: Vec<jchar> = vec![0; count];
+    (**jni_env).GetStringRegion.unwrap()(jni_env, str, 0, count as jsize, chars.as_mut_ptr());
+    util::result_or_jni_ex((), jni_env, "GetStringRegion")?;
+    return Result::Ok(String::from_utf16_lossy(&chars));
+}
+
+/// The string at the index of the array, which must be a string array
+unsafe fn array_string(jni_env: *mut JNIEnv, arr: jobjectArray, index: jsize) -> Result<String, AgentError> {
+    let str = util::result_or_jni_ex((**jni_env).GetObjectArrayElement.unwrap()(jni_env, arr, index),
+                                     jni_env,
+                                     "GetObjectArrayElement")?;
+    let ret = java_string(jni_env, str, usize::max_value());
+    (**jni_env).DeleteLocalRef.unwrap()(jni_env, str);
+    return ret;
+}
+
+/// Like `array_string` but the element can be null
+unsafe fn array_string_or_none(jni_env: *mut JNIEnv, arr: jobjectArray, index: jsize) -> Result<Option<String>, AgentError> {
+    let str = util::result_or_jni_ex((**jni_env).GetObjectArrayElement.unwrap()(jni_env, arr, index),
+                                     jni_env,
+                                     "GetObjectArrayElement")?;
+    if str.is_null() {
+        return Result::Ok(None);
+    }
+    let ret = java_string(jni_env, str, usize::max_value());
+    (**jni_env).DeleteLocalRef.unwrap()(jni_env, str);
+    return ret.map(Some);
+}
+
+pub unsafe fn new_string(jni_env: *mut JNIEnv, str: &str) -> Result<jstring, AgentError> {
+    let chars: Vec<jchar> = str.encode_utf16().collect();
+    return util::result_or_jni_ex((**jni_env).NewString.unwrap()(jni_env, chars.as_ptr(), chars.len() as jsize),
+                                  jni_env,
+                                  "NewString");
+}
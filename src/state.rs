@@ -0,0 +1,475 @@
+extern crate jni_sys;
+
+use error::AgentError;
+use jni_sys::{JNIEnv, _jmethodID, jclass, jfieldID, jmethodID};
+use jvmti::Jvmti;
+use jvmti_sys::jvmtiEvent;
+use std::ffi::CString;
+use std::ops::Deref;
+use std::ptr;
+use std::sync::{Condvar, Mutex};
+use std::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use util;
+
+// Not set until after VM init on purpose
+static GLOBAL_STATE: AtomicPtr<AgentState> = AtomicPtr::new(0 as *mut AgentState);
+
+// How many StateRefs are out, so the state isn't freed from under them
+static STATE_REFS: AtomicUsize = AtomicUsize::new(0);
+
+// Signalled when the last StateRef goes away after release. Made with the first global state and
+// never freed, so it can be used without holding a StateRef.
+static RELEASE_WAIT: AtomicPtr<ReleaseWait> = AtomicPtr::new(0 as *mut ReleaseWait);
+
+// How long release waits on natives still using the state before leaving it be
+const RELEASE_WAIT_MILLIS: u64 = 1000;
+
+pub type MethodRef = (jclass, jmethodID);
+
+// What the natives call in place of their work before VM init, after release, or when disabled
+pub static ORIG_TRACE_METH: CachedMethod = CachedMethod {
+    class_name: "java/lang/Throwable",
+    name: "$$stack_param$$getOurStackTrace",
+    sig: "()[Ljava/lang/StackTraceElement;",
+    meth: AtomicPtr::new(0 as jmethodID),
+};
+
+pub static ORIG_TO_STRING_METH: CachedMethod = CachedMethod {
+    class_name: "java/lang/StackTraceElement",
+    name: "$$stack_param$$toString",
+    sig: "()Ljava/lang/String;",
+    meth: AtomicPtr::new(0 as jmethodID),
+};
+
+pub static DEFAULT_WRITE_OBJECT_METH: CachedMethod = CachedMethod {
+    class_name: "java/io/ObjectOutputStream",
+    name: "defaultWriteObject",
+    sig: "()V",
+    meth: AtomicPtr::new(0 as jmethodID),
+};
+
+pub struct PrimitiveBoxMethods {
+    pub boolean: MethodRef,
+    pub byte: MethodRef,
+    pub char: MethodRef,
+    pub short: MethodRef,
+    pub int: MethodRef,
+    pub long: MethodRef,
+    pub float: MethodRef,
+    pub double: MethodRef,
+}
+
+///
+/// Everything the natives need that is looked up once at VM init. Classes are held as global refs
+/// so they are valid from any thread and any local frame.
+pub struct AgentState {
+    pub jvmti: Jvmti,
+    /// Whether the agent was attached to a running VM instead of loaded at startup
+    pub attached: bool,
+    pub object_class: jclass,
+    pub object_array_class: jclass,
+    pub throwable_class: jclass,
+    pub stack_depth_meth: jmethodID,
+    pub stack_trace_field: jfieldID,
+    pub elem_class: jclass,
+    pub native_class: jclass,
+    /// Empties the attached side table
+    pub clear_attached_meth: jmethodID,
+    pub box_meths: PrimitiveBoxMethods,
+    pub render_meths: RenderMethods,
+    pub json_meths: JsonMethods,
+    replaced: Option<ReplacedMembers>,
+}
+
+///
+/// What the renderer uses to look into values
+pub struct RenderMethods {
+    pub max_len_field: jfieldID,
+    pub to_string: jmethodID,
+    pub float_to_string: jmethodID,
+    pub double_to_string: jmethodID,
+    pub collection_class: jclass,
+    pub collection_iterator: jmethodID,
+    pub map_class: jclass,
+    pub map_entry_set: jmethodID,
+    pub iterator_has_next: jmethodID,
+    pub iterator_next: jmethodID,
+    pub entry_key: jmethodID,
+    pub entry_value: jmethodID,
+    /// For values kept weakly
+    pub weak_ref_class: jclass,
+    pub weak_ref_init: jmethodID,
+    pub weak_ref_get: jmethodID,
+    pub frame_format_field: jfieldID,
+    pub boolean_value: jmethodID,
+}
+
+///
+/// What the JSON export reads throwables and their elements with
+pub struct JsonMethods {
+    pub get_message: jmethodID,
+    pub get_stack_trace: jmethodID,
+    pub get_cause: jmethodID,
+    pub get_suppressed: jmethodID,
+    pub elem_class_name: jmethodID,
+    pub elem_method_name: jmethodID,
+    pub elem_file_name: jmethodID,
+    pub elem_line_number: jmethodID,
+    /// Where the params are when attached
+    pub get_attached: jmethodID,
+}
+
+///
+/// The members we add to or rename in Throwable and StackTraceElement along with those only our
+/// replacement natives use. Retransformed classes can't get new members, so these are only there
+/// when loaded at startup.
+pub struct ReplacedMembers {
+    pub stack_params_field: jfieldID,
+    pub trace_orig_meth: jmethodID,
+    pub param_info_field: jfieldID,
+    pub param_str_field: jfieldID,
+    pub str_orig_meth: jmethodID,
+}
+
+// Global refs and IDs can be used from any thread
+unsafe impl Send for AgentState {}
+unsafe impl Sync for AgentState {}
+
+impl AgentState {
+    /// Looks everything up, failing on the first thing that can't be found. Our native class must
+    /// already be defined.
+    pub unsafe fn init(jvmti: Jvmti, jni_env: *mut JNIEnv, attached: bool) -> Result<AgentState, AgentError> {
+        // Deletes the global refs made so far if anything after fails
+        let mut refs = GlobalRefs { jni_env: jni_env, classes: Vec::new() };
+        let throwable_class = refs.class("java/lang/Throwable")?;
+        let elem_class = refs.class("java/lang/StackTraceElement")?;
+        let native_class = refs.class("stackparam/StackParamNative")?;
+        let object_class = refs.class("java/lang/Object")?;
+        let box_meths = PrimitiveBoxMethods {
+            boolean: refs.box_method("java/lang/Boolean", "(Z)Ljava/lang/Boolean;")?,
+            byte: refs.box_method("java/lang/Byte", "(B)Ljava/lang/Byte;")?,
+            char: refs.box_method("java/lang/Character", "(C)Ljava/lang/Character;")?,
+            short: refs.box_method("java/lang/Short", "(S)Ljava/lang/Short;")?,
+            int: refs.box_method("java/lang/Integer", "(I)Ljava/lang/Integer;")?,
+            long: refs.box_method("java/lang/Long", "(J)Ljava/lang/Long;")?,
+            float: refs.box_method("java/lang/Float", "(F)Ljava/lang/Float;")?,
+            double: refs.box_method("java/lang/Double", "(D)Ljava/lang/Double;")?,
+        };
+        let collection_class = refs.class("java/util/Collection")?;
+        let map_class = refs.class("java/util/Map")?;
+        let weak_ref_class = refs.class("java/lang/ref/WeakReference")?;
+        // Iterator and Map.Entry are only needed for their method IDs, their local refs go with the frame
+        let mut render_meths = None;
+        util::with_local_frame(jni_env, 2, || {
+            let iterator_class = find_class(jni_env, "java/util/Iterator")?;
+            let entry_class = find_class(jni_env, "java/util/Map$Entry")?;
+            render_meths = Some(RenderMethods {
+                max_len_field: static_field_id(jni_env, native_class, "MAX_PARAM_STR_LEN", "I")?,
+                to_string: method_id(jni_env, object_class, "toString", "()Ljava/lang/String;")?,
+                float_to_string: static_method_id(jni_env, box_meths.float.0, "toString", "(F)Ljava/lang/String;")?,
+                double_to_string: static_method_id(jni_env, box_meths.double.0, "toString", "(D)Ljava/lang/String;")?,
+                collection_class: collection_class,
+                collection_iterator: method_id(jni_env, collection_class, "iterator", "()Ljava/util/Iterator;")?,
+                map_class: map_class,
+                map_entry_set: method_id(jni_env, map_class, "entrySet", "()Ljava/util/Set;")?,
+                iterator_has_next: method_id(jni_env, iterator_class, "hasNext", "()Z")?,
+                iterator_next: method_id(jni_env, iterator_class, "next", "()Ljava/lang/Object;")?,
+                entry_key: method_id(jni_env, entry_class, "getKey", "()Ljava/lang/Object;")?,
+                entry_value: method_id(jni_env, entry_class, "getValue", "()Ljava/lang/Object;")?,
+                weak_ref_class: weak_ref_class,
+                weak_ref_init: method_id(jni_env, weak_ref_class, "<init>", "(Ljava/lang/Object;)V")?,
+                weak_ref_get: method_id(jni_env, weak_ref_class, "get", "()Ljava/lang/Object;")?,
+                frame_format_field: static_field_id(jni_env, native_class, "frameFormat", "[Ljava/lang/Object;")?,
+                boolean_value: method_id(jni_env, box_meths.boolean.0, "booleanValue", "()Z")?,
+            });
+            return Result::Ok(ptr::null_mut());
+        })?;
+        let render_meths = render_meths.unwrap();
+        let json_meths = JsonMethods {
+            get_message: method_id(jni_env, throwable_class, "getMessage", "()Ljava/lang/String;")?,
+            get_stack_trace: method_id(jni_env, throwable_class, "getStackTrace", "()[Ljava/lang/StackTraceElement;")?,
+            get_cause: method_id(jni_env, throwable_class, "getCause", "()Ljava/lang/Throwable;")?,
+            get_suppressed: method_id(jni_env, throwable_class, "getSuppressed", "()[Ljava/lang/Throwable;")?,
+            elem_class_name: method_id(jni_env, elem_class, "getClassName", "()Ljava/lang/String;")?,
+            elem_method_name: method_id(jni_env, elem_class, "getMethodName", "()Ljava/lang/String;")?,
+            elem_file_name: method_id(jni_env, elem_class, "getFileName", "()Ljava/lang/String;")?,
+            elem_line_number: method_id(jni_env, elem_class, "getLineNumber", "()I")?,
+            get_attached: static_method_id(jni_env, native_class, "getAttached", "(Ljava/lang/Object;)Ljava/lang/Object;")?,
+        };
+        let replaced = if attached {
+            None
+        } else {
+            Some(ReplacedMembers {
+                stack_params_field: field_id(jni_env, throwable_class, "stackParams", "[[Ljava/lang/Object;")?,
+                trace_orig_meth: method_id(jni_env,
+                                           throwable_class,
+                                           "$$stack_param$$getOurStackTrace",
+                                           "()[Ljava/lang/StackTraceElement;")?,
+                param_info_field: field_id(jni_env, elem_class, "paramInfo", "[Ljava/lang/Object;")?,
+                param_str_field: field_id(jni_env, elem_class, "paramStr", "Ljava/lang/String;")?,
+                str_orig_meth: method_id(jni_env, elem_class, "$$stack_param$$toString", "()Ljava/lang/String;")?,
+            })
+        };
+        let state = AgentState {
+            jvmti: jvmti,
+            attached: attached,
+            object_class: object_class,
+            object_array_class: refs.class("[Ljava/lang/Object;")?,
+            throwable_class: throwable_class,
+            stack_depth_meth: method_id(jni_env, throwable_class, "getStackTraceDepth", "()I")?,
+            stack_trace_field: field_id(jni_env, throwable_class, "stackTrace", "[Ljava/lang/StackTraceElement;")?,
+            elem_class: elem_class,
+            native_class: native_class,
+            clear_attached_meth: static_method_id(jni_env, native_class, "clearAttached", "()V")?,
+            box_meths: box_meths,
+            render_meths: render_meths,
+            json_meths: json_meths,
+            replaced: replaced,
+        };
+        // The state deletes them on release from here on
+        refs.classes.clear();
+        return Result::Ok(state);
+    }
+
+    pub fn replaced(&self) -> Result<&ReplacedMembers, AgentError> {
+        return self.replaced.as_ref().ok_or_else(|| {
+            AgentError::Other("Throwable and StackTraceElement members are not replaced when attached".to_string())
+        });
+    }
+
+    /// The state made global at VM init or `None` before then or after release
+    pub fn global() -> Option<StateRef> {
+        // Counted before the load so release can't miss us
+        STATE_REFS.fetch_add(1, Ordering::SeqCst);
+        let state = GLOBAL_STATE.load(Ordering::SeqCst);
+        if state.is_null() {
+            drop_state_ref();
+            return None;
+        }
+        return Some(StateRef { state: unsafe { &*state } });
+    }
+
+    /// Makes this the global state until released
+    pub fn set_global(self) {
+        if RELEASE_WAIT.load(Ordering::SeqCst).is_null() {
+            let wait = ReleaseWait { lock: Mutex::new(()), done: Condvar::new() };
+            RELEASE_WAIT.store(Box::into_raw(Box::new(wait)), Ordering::SeqCst);
+        }
+        GLOBAL_STATE.store(Box::into_raw(Box::new(self)), Ordering::SeqCst);
+    }
+
+    /// Stops every event the agent enabled and takes away the global state, after which the natives
+    /// act as the originals. The attached side table is emptied too if there's a JNI env to do it
+    /// with. Once no native is using the state anymore, its global refs are deleted. If some are
+    /// still using it after a while (e.g. this is called from within one), the state is left
+    /// allocated instead and false is given back. The first event or table error is given back
+    /// after all have been tried.
+    pub unsafe fn release(jni_env: *mut JNIEnv) -> Result<bool, AgentError> {
+        let state = GLOBAL_STATE.swap(0 as *mut AgentState, Ordering::SeqCst);
+        if state.is_null() { return Result::Ok(true); }
+        let events = [jvmtiEvent::JVMTI_EVENT_VM_INIT,
+                      jvmtiEvent::JVMTI_EVENT_THREAD_END,
+                      jvmtiEvent::JVMTI_EVENT_CLASS_FILE_LOAD_HOOK];
+        let mut release_res = events.iter().fold(Result::Ok(()), |res, event| {
+            let disable_res = (*state).jvmti.disable_event(*event);
+            res.and(disable_res)
+        });
+        if !jni_env.is_null() && (*state).attached {
+            (**jni_env).CallStaticVoidMethod.unwrap()(jni_env, (*state).native_class, (*state).clear_attached_meth);
+            release_res = release_res.and(util::result_or_jni_ex((), jni_env, "CallStaticVoidMethod"));
+        }
+        if !wait_for_state_refs() {
+            warn!("Agent state still in use after {}ms, not freeing it", RELEASE_WAIT_MILLIS);
+            return release_res.map(|_| false);
+        }
+        let state = Box::from_raw(state);
+        if !jni_env.is_null() {
+            state.delete_global_refs(jni_env);
+        }
+        return release_res.map(|_| true);
+    }
+
+    unsafe fn delete_global_refs(&self, jni_env: *mut JNIEnv) {
+        let classes = [self.object_class, self.object_array_class, self.throwable_class, self.elem_class,
+                       self.native_class, self.box_meths.boolean.0, self.box_meths.byte.0, self.box_meths.char.0,
+                       self.box_meths.short.0, self.box_meths.int.0, self.box_meths.long.0,
+                       self.box_meths.float.0, self.box_meths.double.0, self.render_meths.collection_class,
+                       self.render_meths.map_class, self.render_meths.weak_ref_class];
+        for class in classes.iter() {
+            (**jni_env).DeleteGlobalRef.unwrap()(jni_env, *class);
+        }
+    }
+}
+
+///
+/// A use of the global state. The state isn't freed while any of these are around, so they should
+/// only live as long as the native call that took them.
+pub struct StateRef {
+    state: &'static AgentState,
+}
+
+impl Deref for StateRef {
+    type Target = AgentState;
+
+    fn deref(&self) -> &AgentState {
+        return self.state;
+    }
+}
+
+impl Drop for StateRef {
+    fn drop(&mut self) {
+        drop_state_ref();
+    }
+}
+
+struct ReleaseWait {
+    lock: Mutex<()>,
+    done: Condvar,
+}
+
+/// Uncounts a StateRef, waking a release waiting on the last one
+fn drop_state_ref() {
+    // A release takes away the global state before it waits, so only then is anyone waiting
+    if STATE_REFS.fetch_sub(1, Ordering::SeqCst) != 1 || !GLOBAL_STATE.load(Ordering::SeqCst).is_null() {
+        return;
+    }
+    let wait = RELEASE_WAIT.load(Ordering::SeqCst);
+    if wait.is_null() { return; }
+    let wait = unsafe { &*wait };
+    // Signalled under the lock so it can't come between the waiter's check and its wait
+    let _guard = wait.lock.lock().unwrap();
+    wait.done.notify_all();
+}
+
+/// Waits for all the StateRefs to be dropped, false if they aren't in time
+fn wait_for_state_refs() -> bool {
+    let wait = RELEASE_WAIT.load(Ordering::SeqCst);
+    if wait.is_null() { return STATE_REFS.load(Ordering::SeqCst) == 0; }
+    let wait = unsafe { &*wait };
+    let deadline = Instant::now() + Duration::from_millis(RELEASE_WAIT_MILLIS);
+    let mut guard = wait.lock.lock().unwrap();
+    while STATE_REFS.load(Ordering::SeqCst) > 0 {
+        let now = Instant::now();
+        if now >= deadline { return false; }
+        guard = wait.done.wait_timeout(guard, deadline - now).unwrap().0;
+    }
+    return true;
+}
+
+///
+/// A method looked up on first use and kept from then on, apart from the releasable state. Only for
+/// methods of classes that are never unloaded, since nothing holds on to the class.
+pub struct CachedMethod {
+    class_name: &'static str,
+    name: &'static str,
+    sig: &'static str,
+    meth: AtomicPtr<_jmethodID>,
+}
+
+impl CachedMethod {
+    pub unsafe fn get(&self, jni_env: *mut JNIEnv) -> Result<jmethodID, AgentError> {
+        let meth = self.meth.load(Ordering::SeqCst);
+        if !meth.is_null() {
+            return Result::Ok(meth);
+        }
+        // Threads racing here all find the same ID
+        let meth = lookup_method(jni_env, self.class_name, self.name, self.sig)?;
+        self.meth.store(meth, Ordering::SeqCst);
+        return Result::Ok(meth);
+    }
+}
+
+unsafe fn lookup_method(jni_env: *mut JNIEnv, class_name: &str, name: &str, sig: &str) -> Result<jmethodID, AgentError> {
+    let class = find_class(jni_env, class_name)?;
+    let ret = method_id(jni_env, class, name, sig);
+    (**jni_env).DeleteLocalRef.unwrap()(jni_env, class);
+    return ret;
+}
+
+unsafe fn find_class(jni_env: *mut JNIEnv, name: &str) -> Result<jclass, AgentError> {
+    let name_str = CString::new(name).unwrap();
+    let class = util::result_or_jni_ex((**jni_env).FindClass.unwrap()(jni_env, name_str.as_ptr()), jni_env, "FindClass")?;
+    if class.is_null() { return Result::Err(AgentError::Other(format!("Class {} not found", name))); }
+    return Result::Ok(class);
+}
+
+unsafe fn global_class(jni_env: *mut JNIEnv, name: &str) -> Result<jclass, AgentError> {
+    let local_class = find_class(jni_env, name)?;
+    let class = (**jni_env).NewGlobalRef.unwrap()(jni_env, local_class);
+    (**jni_env).DeleteLocalRef.unwrap()(jni_env, local_class);
+    if class.is_null() { return Result::Err(AgentError::Other(format!("Unable to make global ref to class {}", name))); }
+    return Result::Ok(class);
+}
+
+pub unsafe fn method_id(jni_env: *mut JNIEnv, class: jclass, name: &str, sig: &str) -> Result<jmethodID, AgentError> {
+    let name_str = CString::new(name).unwrap();
+    let sig_str = CString::new(sig).unwrap();
+    let meth = util::result_or_jni_ex((**jni_env).GetMethodID.unwrap()(jni_env,
+                                                                       class,
+                                                                       name_str.as_ptr(),
+                                                                       sig_str.as_ptr()), jni_env, "GetMethodID")?;
+    if meth.is_null() { return Result::Err(AgentError::Other(format!("Method {}{} not found", name, sig))); }
+    return Result::Ok(meth);
+}
+
+pub unsafe fn static_method_id(jni_env: *mut JNIEnv, class: jclass, name: &str, sig: &str) -> Result<jmethodID, AgentError> {
+    let name_str = CString::new(name).unwrap();
+    let sig_str = CString::new(sig).unwrap();
+    let meth = util::result_or_jni_ex((**jni_env).GetStaticMethodID.unwrap()(jni_env,
+                                                                             class,
+                                                                             name_str.as_ptr(),
+                                                                             sig_str.as_ptr()), jni_env, "GetStaticMethodID")?;
+    if meth.is_null() { return Result::Err(AgentError::Other(format!("Static method {}{} not found", name, sig))); }
+    return Result::Ok(meth);
+}
+
+unsafe fn field_id(jni_env: *mut JNIEnv, class: jclass, name: &str, sig: &str) -> Result<jfieldID, AgentError> {
+    let name_str = CString::new(name).unwrap();
+    let sig_str = CString::new(sig).unwrap();
+    let field = util::result_or_jni_ex((**jni_env).GetFieldID.unwrap()(jni_env,
+                                                                       class,
+                                                                       name_str.as_ptr(),
+                                                                       sig_str.as_ptr()), jni_env, "GetFieldID")?;
+    if field.is_null() { return Result::Err(AgentError::Other(format!("Field {} not found", name))); }
+    return Result::Ok(field);
+}
+
+unsafe fn static_field_id(jni_env: *mut JNIEnv, class: jclass, name: &str, sig: &str) -> Result<jfieldID, AgentError> {
+    let name_str = CString::new(name).unwrap();
+    let sig_str = CString::new(sig).unwrap();
+    let field = util::result_or_jni_ex((**jni_env).GetStaticFieldID.unwrap()(jni_env,
+                                                                             class,
+                                                                             name_str.as_ptr(),
+                                                                             sig_str.as_ptr()), jni_env, "GetStaticFieldID")?;
+    if field.is_null() { return Result::Err(AgentError::Other(format!("Static field {} not found", name))); }
+    return Result::Ok(field);
+}
+
+///
+/// The global refs made while initializing the state, deleted when dropped unless cleared first
+struct GlobalRefs {
+    jni_env: *mut JNIEnv,
+    classes: Vec<jclass>,
+}
+
+impl GlobalRefs {
+    unsafe fn class(&mut self, name: &str) -> Result<jclass, AgentError> {
+        let class = global_class(self.jni_env, name)?;
+        self.classes.push(class);
+        return Result::Ok(class);
+    }
+
+    unsafe fn box_method(&mut self, class_name: &str, sig: &str) -> Result<MethodRef, AgentError> {
+        let class = self.class(class_name)?;
+        return Result::Ok((class, static_method_id(self.jni_env, class, "valueOf", sig)?));
+    }
+}
+
+impl Drop for GlobalRefs {
+    fn drop(&mut self) {
+        for class in self.classes.iter() {
+            unsafe { (**self.jni_env).DeleteGlobalRef.unwrap()(self.jni_env, *class) };
+        }
+    }
+}
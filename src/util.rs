@@ -1,49 +1,202 @@
 extern crate jni_sys;
 extern crate env_logger;
 
-use jni_sys::{JNIEnv, jclass, jmethodID, jint};
+use error::{AgentError, JavaThrowable};
+use jni_sys::{JNIEnv, jclass, jmethodID, jint, jobject, jstring, jthrowable};
 use jvmti_sys::{jvmtiEnv, jvmtiError};
-use std::ffi::CStr;
+use std::cell::Cell;
+use std::cmp;
+use std::ffi::{CStr, CString};
+use std::panic::{self, AssertUnwindSafe};
 use std::ptr;
 use std::slice;
 use std::os::raw::c_char;
 
-pub unsafe fn result_or_jni_ex<T>(res: T, jni_env: *mut JNIEnv) -> Result<T, String> {
-    if (**jni_env).ExceptionCheck.unwrap()(jni_env) == 1 {
-        // TODO: extract the exception info instead of dumping to stderr
-        (**jni_env).ExceptionDescribe.unwrap()(jni_env);
+const MAX_EXCEPTION_FRAMES: usize = 5;
+
+// Set while gathering exception details, since getting the frames goes back through our natives
+thread_local!(static DESCRIBING_EXCEPTION: Cell<bool> = Cell::new(false));
+
+/// Runs the body of a function called by the JVM, which a panic must not unwind into. A panic is
+/// logged, any Java exception it left pending is cleared and the default is returned instead.
+/// The JNI env may be null when there is none.
+pub unsafe fn guard_ffi<T, F>(jni_env: *mut JNIEnv, name: &'static str, default: T, f: F) -> T
+    where F: FnOnce() -> T {
+    return match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Result::Ok(ret) => ret,
+        Result::Err(cause) => {
+            let msg = if let Some(msg) = cause.downcast_ref::<&str>() {
+                msg.to_string()
+            } else if let Some(msg) = cause.downcast_ref::<String>() {
+                msg.clone()
+            } else {
+                "<unknown>".to_string()
+            };
+            error!("Unexpected panic in {}: {}", name, msg);
+            if !jni_env.is_null() && (**jni_env).ExceptionCheck.unwrap()(jni_env) != 0 {
+                (**jni_env).ExceptionClear.unwrap()(jni_env);
+            }
+            default
+        }
+    };
+}
+
+/// Returns the result unless the JNI operation left an exception pending. A pending exception is
+/// cleared and its details are put in the error.
+pub unsafe fn result_or_jni_ex<T>(res: T, jni_env: *mut JNIEnv, op: &'static str) -> Result<T, AgentError> {
+    let ex = (**jni_env).ExceptionOccurred.unwrap()(jni_env);
+    if ex.is_null() {
+        return Result::Ok(res);
+    }
+    // No other JNI calls can be made while it is pending
+    (**jni_env).ExceptionClear.unwrap()(jni_env);
+    let exception = describe_exception(jni_env, ex);
+    (**jni_env).DeleteLocalRef.unwrap()(jni_env, ex);
+    return Result::Err(AgentError::JavaException { op: op, exception: exception });
+}
+
+unsafe fn describe_exception(jni_env: *mut JNIEnv, ex: jthrowable) -> JavaThrowable {
+    let mut ret = JavaThrowable {
+        class_name: "<unknown>".to_string(),
+        message: None,
+        frames: Vec::new(),
+    };
+    let class = (**jni_env).GetObjectClass.unwrap()(jni_env, ex);
+    if let Some(class_name) = call_string_method(jni_env, class, "java/lang/Class", "getName") {
+        ret.class_name = class_name;
+    }
+    (**jni_env).DeleteLocalRef.unwrap()(jni_env, class);
+    // Only the class name if this exception came from describing another one
+    if DESCRIBING_EXCEPTION.with(|describing| describing.replace(true)) {
+        return ret;
+    }
+    ret.message = call_string_method(jni_env, ex, "java/lang/Throwable", "getMessage");
+    ret.frames = exception_frames(jni_env, ex).unwrap_or(Vec::new());
+    DESCRIBING_EXCEPTION.with(|describing| describing.set(false));
+    return ret;
+}
+
+unsafe fn exception_frames(jni_env: *mut JNIEnv, ex: jthrowable) -> Option<Vec<String>> {
+    let trace_meth = method_id(jni_env, "java/lang/Throwable", "getStackTrace", "()[Ljava/lang/StackTraceElement;")?;
+    let trace = cleared_or_none(jni_env, (**jni_env).CallObjectMethod.unwrap()(jni_env, ex, trace_meth))?;
+    if trace.is_null() {
+        return None;
+    }
+    let line_meth = method_id(jni_env, "java/lang/StackTraceElement", "getLineNumber", "()I")?;
+    let trace_len = (**jni_env).GetArrayLength.unwrap()(jni_env, trace) as usize;
+    let mut frames = Vec::new();
+    for index in 0..cmp::min(trace_len, MAX_EXCEPTION_FRAMES) {
+        let elem = (**jni_env).GetObjectArrayElement.unwrap()(jni_env, trace, index as jint);
+        let class_name = call_string_method(jni_env, elem, "java/lang/StackTraceElement", "getClassName");
+        let method_name = call_string_method(jni_env, elem, "java/lang/StackTraceElement", "getMethodName");
+        let file_name = call_string_method(jni_env, elem, "java/lang/StackTraceElement", "getFileName");
+        let line = cleared_or_none(jni_env, (**jni_env).CallIntMethod.unwrap()(jni_env, elem, line_meth)).unwrap_or(-1);
+        (**jni_env).DeleteLocalRef.unwrap()(jni_env, elem);
+        // Same as StackTraceElement::toString which we can't call since it is ours
+        let location = match (file_name, line) {
+            (_, -2) => "Native Method".to_string(),
+            (Some(file_name), line) if line >= 0 => format!("{}:{}", file_name, line),
+            (Some(file_name), _) => file_name,
+            (None, _) => "Unknown Source".to_string(),
+        };
+        frames.push(format!("{}.{}({})",
+                            class_name.unwrap_or("<unknown>".to_string()),
+                            method_name.unwrap_or("<unknown>".to_string()),
+                            location));
+    }
+    (**jni_env).DeleteLocalRef.unwrap()(jni_env, trace);
+    return Some(frames);
+}
+
+/// Calls a no-arg method returning a string, giving None on null or exception
+unsafe fn call_string_method(jni_env: *mut JNIEnv, obj: jobject, class_name: &str, meth_name: &str) -> Option<String> {
+    let meth = method_id(jni_env, class_name, meth_name, "()Ljava/lang/String;")?;
+    let str = cleared_or_none(jni_env, (**jni_env).CallObjectMethod.unwrap()(jni_env, obj, meth))? as jstring;
+    if str.is_null() {
+        return None;
+    }
+    let chars = (**jni_env).GetStringUTFChars.unwrap()(jni_env, str, ptr::null_mut());
+    let ret = if chars.is_null() {
+        None
+    } else {
+        let ret = CStr::from_ptr(chars).to_string_lossy().into_owned();
+        (**jni_env).ReleaseStringUTFChars.unwrap()(jni_env, str, chars);
+        Some(ret)
+    };
+    (**jni_env).DeleteLocalRef.unwrap()(jni_env, str);
+    return ret;
+}
+
+unsafe fn method_id(jni_env: *mut JNIEnv, class_name: &str, meth_name: &str, meth_sig: &str) -> Option<jmethodID> {
+    let class_name_str = CString::new(class_name).unwrap();
+    let class = cleared_or_none(jni_env, (**jni_env).FindClass.unwrap()(jni_env, class_name_str.as_ptr()))?;
+    let meth_name_str = CString::new(meth_name).unwrap();
+    let meth_sig_str = CString::new(meth_sig).unwrap();
+    let meth = (**jni_env).GetMethodID.unwrap()(jni_env, class, meth_name_str.as_ptr(), meth_sig_str.as_ptr());
+    (**jni_env).DeleteLocalRef.unwrap()(jni_env, class);
+    return cleared_or_none(jni_env, meth);
+}
+
+/// Gives None and clears the exception if one is pending
+unsafe fn cleared_or_none<T>(jni_env: *mut JNIEnv, res: T) -> Option<T> {
+    if (**jni_env).ExceptionCheck.unwrap()(jni_env) != 0 {
         (**jni_env).ExceptionClear.unwrap()(jni_env);
-        return Result::Err("Unexpected exception, logged to stderr".to_string());
+        return None;
     }
-    return Result::Ok(res);
+    return Some(res);
 }
 
-pub unsafe fn result_or_jvmti_err<T>(res: T, err_maybe: jvmtiError) -> Result<T, String> {
-    if err_maybe as i32 != 0 {
-        return Result::Err(format!("Unexpected jvmti error: {:?}", err_maybe));
+/// Runs the function in a new local reference frame. Only the returned object survives, as a new
+/// local ref in the outer frame.
+pub unsafe fn with_local_frame<F>(jni_env: *mut JNIEnv, capacity: jint, f: F) -> Result<jobject, AgentError>
+    where F: FnOnce() -> Result<jobject, AgentError> {
+    let push_res = (**jni_env).PushLocalFrame.unwrap()(jni_env, capacity);
+    if push_res < 0 {
+        result_or_jni_ex((), jni_env, "PushLocalFrame")?;
+        return Result::Err(AgentError::Jni { op: "PushLocalFrame", code: push_res });
     }
-    return Result::Ok(res);
+    return match f() {
+        Result::Ok(obj) => Result::Ok((**jni_env).PopLocalFrame.unwrap()(jni_env, obj)),
+        Result::Err(err) => {
+            (**jni_env).PopLocalFrame.unwrap()(jni_env, ptr::null_mut());
+            Result::Err(err)
+        }
+    };
 }
 
-pub unsafe fn unit_or_jvmti_err(res: jvmtiError) -> Result<(), String> {
-    if res as i32 != 0 {
-        return Result::Err(format!("Unexpected jvmti error: {:?}", res));
+/// Makes sure at least so many more local refs can be created in the current frame
+pub unsafe fn ensure_local_capacity(jni_env: *mut JNIEnv, capacity: jint) -> Result<(), AgentError> {
+    let ensure_res = (**jni_env).EnsureLocalCapacity.unwrap()(jni_env, capacity);
+    if ensure_res < 0 {
+        result_or_jni_ex((), jni_env, "EnsureLocalCapacity")?;
+        return Result::Err(AgentError::Jni { op: "EnsureLocalCapacity", code: ensure_res });
     }
     return Result::Ok(());
 }
 
+pub fn result_or_jvmti_err<T>(res: T, err_maybe: jvmtiError, op: &'static str) -> Result<T, AgentError> {
+    if err_maybe as i32 != 0 {
+        return Result::Err(AgentError::Jvmti { op: op, code: err_maybe });
+    }
+    return Result::Ok(res);
+}
+
+pub fn unit_or_jvmti_err(res: jvmtiError, op: &'static str) -> Result<(), AgentError> {
+    return result_or_jvmti_err((), res, op);
+}
+
 #[allow(dead_code)]
 pub unsafe fn find_method(jvmti_env: *mut jvmtiEnv,
                           class: jclass,
                           name: &str)
-                          -> Result<jmethodID, String> {
+                          -> Result<jmethodID, AgentError> {
     // TODO: sad we can't use GetMethodID
     // ref: http://stackoverflow.com/questions/42746496/call-class-method-from-manually-defined-class-in-jvmti
     let mut method_count: jint = 0;
     let mut methods: *mut jmethodID = ptr::null_mut();
     let meth_ret =
         (**jvmti_env).GetClassMethods.unwrap()(jvmti_env, class, &mut method_count, &mut methods);
-    try!(unit_or_jvmti_err(meth_ret));
+    try!(unit_or_jvmti_err(meth_ret, "GetClassMethods"));
     let method_slice: &[jmethodID] = slice::from_raw_parts_mut(methods, method_count as usize);
     let ret = method_slice.into_iter().find(|&&m| {
         let mut method_name: *mut c_char = ptr::null_mut();
@@ -57,6 +210,6 @@ pub unsafe fn find_method(jvmti_env: *mut jvmtiEnv,
     });
     return match ret {
         Some(&method) => Result::Ok(method),
-        None => Result::Err("Method not found".to_string()),
+        None => Result::Err(AgentError::Other("Method not found".to_string())),
     };
 }
@@ -3,7 +3,10 @@ extern crate log;
 extern crate env_logger;
 extern crate zip;
 extern crate stackparam;
+#[cfg(feature = "serde")]
+extern crate serde_json;
 
+use std::borrow::Cow;
 use std::env;
 use std::path::PathBuf;
 use std::fs::File;
@@ -11,6 +14,13 @@ use zip::ZipArchive;
 use std::io::{Cursor, Read};
 use stackparam::bytecode::io::reader::ClassReader;
 use stackparam::bytecode::io::writer::ClassWriter;
+use stackparam::bytecode::classfile::{AccessFlags, Attribute, Code, ConstantPool, ConstantPoolIndex, Constant, Field, Instruction, Method};
+use stackparam::bytecode::verifier::ClassVerifier;
+use stackparam::bytecode::visitor::{ClassFilter, ClassHeader, ClassVisitor, FieldVisitor, MethodVisitor};
+use std::io::Error;
+
+const NATIVE_CLASS_BYTES: &'static [u8] =
+    include_bytes!("../javalib/native/build/classes/main/stackparam/StackParamNative.class");
 
 #[test]
 #[ignore]
@@ -50,4 +60,293 @@ fn bytecode_tests() {
             assert_eq!(in_bytes.as_slice(), out_bytes.as_slice(), "Not same for {}", file.name());
         }
     }
-}
\ No newline at end of file
+}
+
+#[test]
+fn visitor_pass_through() {
+    // Reading through an empty filter into a writer should give back the same bytes
+    let mut out_bytes: Vec<u8> = Vec::new();
+    {
+        let mut writer = ClassWriter::new(&mut out_bytes);
+        let mut filter = ClassFilter::new(&mut writer);
+        ClassReader::accept(NATIVE_CLASS_BYTES, &mut filter).unwrap();
+    }
+    assert_eq!(NATIVE_CLASS_BYTES, out_bytes.as_slice());
+}
+
+#[test]
+fn visitor_field_filter() {
+    // Drop every field and add a single new one in their place
+    struct ReplaceField;
+    impl<'a> FieldVisitor<'a> for ReplaceField {
+        fn visit_field(&mut self, _cp: &mut ConstantPool<'a>, _field: Field<'a>) -> Result<Option<Field<'a>>, Error> {
+            Ok(None)
+        }
+
+        fn visit_end(&mut self, cp: &mut ConstantPool<'a>) -> Result<Vec<Field<'a>>, Error> {
+            cp.constants.push(Constant::Utf8(Cow::Borrowed(b"newField")));
+            let name_idx = cp.constants.len() - 1;
+            cp.constants.push(Constant::Utf8(Cow::Borrowed(b"J")));
+            let desc_idx = cp.constants.len() - 1;
+            Ok(vec![Field {
+                access_flags: AccessFlags::of(0x0008),
+                name_index: ConstantPoolIndex::new(name_idx),
+                descriptor_index: ConstantPoolIndex::new(desc_idx),
+                attributes: vec![]
+            }])
+        }
+    }
+
+    let mut out_bytes: Vec<u8> = Vec::new();
+    {
+        let mut writer = ClassWriter::new(&mut out_bytes);
+        let mut filter = ClassFilter::new(&mut writer).with_field_visitor(ReplaceField);
+        ClassReader::accept(NATIVE_CLASS_BYTES, &mut filter).unwrap();
+    }
+    let in_class = ClassReader::read_class(&mut Cursor::new(NATIVE_CLASS_BYTES)).unwrap();
+    let out_class = ClassReader::read_class(&mut Cursor::new(out_bytes)).unwrap();
+    assert_eq!(1, out_class.fields.len());
+    let field = &out_class.fields[0];
+    assert_eq!(Some("newField".to_string()), out_class.constant_pool.get_utf8_string(field.name_index.idx as u16));
+    assert_eq!(Some("J".to_string()), out_class.constant_pool.get_utf8_string(field.descriptor_index.idx as u16));
+    assert_eq!(in_class.methods.len(), out_class.methods.len());
+    assert_eq!(in_class.constant_pool.constants.len() + 2, out_class.constant_pool.constants.len());
+}
+
+#[test]
+fn visitor_chained_filters() {
+    // The second filter should see the field the first adds, and the writer should get it before any method
+    struct AddField;
+    impl<'a> FieldVisitor<'a> for AddField {
+        fn visit_end(&mut self, cp: &mut ConstantPool<'a>) -> Result<Vec<Field<'a>>, Error> {
+            cp.constants.push(Constant::Utf8(Cow::Borrowed(b"newField")));
+            let name_idx = cp.constants.len() - 1;
+            cp.constants.push(Constant::Utf8(Cow::Borrowed(b"J")));
+            let desc_idx = cp.constants.len() - 1;
+            Ok(vec![Field {
+                access_flags: AccessFlags::of(0x0008),
+                name_index: ConstantPoolIndex::new(name_idx),
+                descriptor_index: ConstantPoolIndex::new(desc_idx),
+                attributes: vec![]
+            }])
+        }
+    }
+
+    struct SeenFields<'s> {
+        names: &'s mut Vec<String>
+    }
+    impl<'a, 's> FieldVisitor<'a> for SeenFields<'s> {
+        fn visit_field(&mut self, cp: &mut ConstantPool<'a>, field: Field<'a>) -> Result<Option<Field<'a>>, Error> {
+            self.names.push(cp.get_utf8_string(field.name_index.idx as u16).unwrap());
+            Ok(Some(field))
+        }
+    }
+
+    // Records the kind of each part in the order it comes
+    struct PartOrder {
+        parts: Vec<&'static str>
+    }
+    impl<'a> ClassVisitor<'a> for PartOrder {
+        fn visit_header(&mut self, _cp: &mut ConstantPool<'a>, _header: ClassHeader) -> Result<(), Error> {
+            Ok(self.parts.push("header"))
+        }
+
+        fn visit_field(&mut self, _cp: &mut ConstantPool<'a>, _field: Field<'a>) -> Result<(), Error> {
+            Ok(self.parts.push("field"))
+        }
+
+        fn visit_method(&mut self, _cp: &mut ConstantPool<'a>, _method: Method<'a>) -> Result<(), Error> {
+            Ok(self.parts.push("method"))
+        }
+
+        fn visit_attribute(&mut self, _cp: &mut ConstantPool<'a>, _attribute: Attribute<'a>) -> Result<(), Error> {
+            Ok(self.parts.push("attribute"))
+        }
+
+        fn visit_end(&mut self, _cp: &mut ConstantPool<'a>) -> Result<(), Error> {
+            Ok(self.parts.push("end"))
+        }
+    }
+
+    let mut names: Vec<String> = Vec::new();
+    let mut order = PartOrder { parts: vec![] };
+    {
+        let mut second = ClassFilter::new(&mut order).with_field_visitor(SeenFields { names: &mut names });
+        let mut first = ClassFilter::new(&mut second).with_field_visitor(AddField);
+        ClassReader::accept(NATIVE_CLASS_BYTES, &mut first).unwrap();
+    }
+    let in_class = ClassReader::read_class(&mut Cursor::new(NATIVE_CLASS_BYTES)).unwrap();
+    assert_eq!(in_class.fields.len() + 1, names.len());
+    assert_eq!(Some(&"newField".to_string()), names.last());
+    let mut expected = vec!["header"];
+    expected.extend(vec!["field"; in_class.fields.len() + 1]);
+    expected.extend(vec!["method"; in_class.methods.len()]);
+    expected.extend(vec!["attribute"; in_class.attributes.len()]);
+    expected.push("end");
+    assert_eq!(expected, order.parts);
+}
+
+#[test]
+fn visitor_added_method_reaches_later_visitors() {
+    // A method one visitor adds should still go through the code of the visitors after it in the same filter
+    struct MoveToEnd<'a> {
+        moved: Option<Method<'a>>
+    }
+    impl<'a> MethodVisitor<'a> for MoveToEnd<'a> {
+        fn visit_method(&mut self, _cp: &mut ConstantPool<'a>, method: Method<'a>) -> Result<Option<Method<'a>>, Error> {
+            let has_code = method.attributes.iter().any(|attr| match attr { &Attribute::Code { .. } => true, _ => false });
+            if self.moved.is_none() && has_code {
+                self.moved = Some(method);
+                return Ok(None);
+            }
+            Ok(Some(method))
+        }
+
+        fn visit_end(&mut self, cp: &mut ConstantPool<'a>) -> Result<Vec<Method<'a>>, Error> {
+            let mut method = self.moved.take().unwrap();
+            cp.constants.push(Constant::Utf8(Cow::Borrowed(b"movedMethod")));
+            method.name_index = ConstantPoolIndex::new(cp.constants.len() - 1);
+            Ok(vec![method])
+        }
+    }
+
+    struct SeenCode<'s> {
+        names: &'s mut Vec<String>
+    }
+    impl<'a, 's> MethodVisitor<'a> for SeenCode<'s> {
+        fn visit_code(&mut self,
+                      cp: &mut ConstantPool<'a>,
+                      name_index: &ConstantPoolIndex,
+                      _descriptor_index: &ConstantPoolIndex,
+                      _code: &mut Code<'a>) -> Result<(), Error> {
+            self.names.push(cp.get_utf8_string(name_index.idx as u16).unwrap());
+            Ok(())
+        }
+    }
+
+    let mut names: Vec<String> = Vec::new();
+    let mut out_bytes: Vec<u8> = Vec::new();
+    {
+        let mut writer = ClassWriter::new(&mut out_bytes);
+        let mut filter = ClassFilter::new(&mut writer)
+            .with_method_visitor(MoveToEnd { moved: None })
+            .with_method_visitor(SeenCode { names: &mut names });
+        ClassReader::accept(NATIVE_CLASS_BYTES, &mut filter).unwrap();
+    }
+    let in_class = ClassReader::read_class(&mut Cursor::new(NATIVE_CLASS_BYTES)).unwrap();
+    let code_count = in_class.methods.iter()
+        .filter(|m| m.attributes.iter().any(|attr| match attr { &Attribute::Code { .. } => true, _ => false }))
+        .count();
+    assert_eq!(code_count, names.len());
+    assert_eq!(Some(&"movedMethod".to_string()), names.last());
+    let out_class = ClassReader::read_class(&mut Cursor::new(&out_bytes)).unwrap();
+    assert_eq!(in_class.methods.len(), out_class.methods.len());
+}
+
+#[test]
+fn borrowed_read_class() {
+    // Constants should point into the class bytes and still write back the same
+    let class_file = ClassReader::read_class_bytes(NATIVE_CLASS_BYTES).unwrap();
+    let bytes_range = NATIVE_CLASS_BYTES.as_ptr() as usize..NATIVE_CLASS_BYTES.as_ptr() as usize + NATIVE_CLASS_BYTES.len();
+    let mut utf8_count = 0;
+    for constant in class_file.constant_pool.constants.iter() {
+        if let &Constant::Utf8(ref bytes) = constant {
+            match bytes {
+                &Cow::Borrowed(slice) => assert!(bytes_range.contains(&(slice.as_ptr() as usize))),
+                &Cow::Owned(_) => panic!("Expected borrowed constant")
+            }
+            utf8_count += 1;
+        }
+    }
+    assert!(utf8_count > 0);
+
+    let owned_class = class_file.into_owned();
+    let mut out_bytes: Vec<u8> = Vec::new();
+    ClassWriter::new(&mut out_bytes).write_class(&owned_class).unwrap();
+    assert_eq!(NATIVE_CLASS_BYTES, out_bytes.as_slice());
+}
+
+#[test]
+fn lazy_code_decoding() {
+    // Code should only be decoded when asked for and write back the same either way
+    let mut class_file = ClassReader::read_class_bytes(NATIVE_CLASS_BYTES).unwrap();
+    let mut code_count = 0;
+    for method in class_file.methods.iter_mut() {
+        for attr in method.attributes.iter_mut() {
+            if let &mut Attribute::Code { ref mut code, .. } = attr {
+                assert!(!code.is_decoded());
+                if code_count == 0 {
+                    assert!(!code.instructions_mut().is_empty());
+                    assert!(code.is_decoded());
+                }
+                code_count += 1;
+            }
+        }
+    }
+    assert!(code_count > 1);
+
+    let mut out_bytes: Vec<u8> = Vec::new();
+    ClassWriter::new(&mut out_bytes).write_class(&class_file).unwrap();
+    assert_eq!(NATIVE_CLASS_BYTES, out_bytes.as_slice());
+}
+
+#[test]
+fn verifier_accepts_class() {
+    let class_file = ClassReader::read_class_bytes(NATIVE_CLASS_BYTES).unwrap();
+    ClassVerifier::verify(&class_file).unwrap();
+}
+
+#[test]
+fn verifier_rejects_bad_code() {
+    // Popping an empty stack at the start of a method should fail there
+    let mut class_file = ClassReader::read_class_bytes(NATIVE_CLASS_BYTES).unwrap();
+    let mut method_name = None;
+    for method in class_file.methods.iter_mut() {
+        for attr in method.attributes.iter_mut() {
+            if let &mut Attribute::Code { ref mut code, .. } = attr {
+                if method_name.is_none() {
+                    code.instructions_mut().insert(0, Instruction::POP);
+                    method_name = class_file.constant_pool.get_utf8_string(method.name_index.idx as u16);
+                }
+            }
+        }
+    }
+    let err = ClassVerifier::verify(&class_file).unwrap_err();
+    assert_eq!("stackparam/StackParamNative", err.class_name);
+    assert!(err.method.unwrap().starts_with(&method_name.unwrap()));
+    assert_eq!(Some(0), err.offset);
+}
+
+#[test]
+fn verifier_rejects_pop_of_wide_value() {
+    // POP only takes a category 1 value, so a long on top of the stack must be rejected
+    let mut class_file = ClassReader::read_class_bytes(NATIVE_CLASS_BYTES).unwrap();
+    let mut patched = false;
+    for method in class_file.methods.iter_mut() {
+        for attr in method.attributes.iter_mut() {
+            if let &mut Attribute::Code { ref mut max_stack, ref mut code, .. } = attr {
+                if !patched {
+                    *max_stack += 2;
+                    code.instructions_mut().insert(0, Instruction::POP);
+                    code.instructions_mut().insert(0, Instruction::LCONST_0);
+                    patched = true;
+                }
+            }
+        }
+    }
+    let err = ClassVerifier::verify(&class_file).unwrap_err();
+    assert_eq!(Some(1), err.offset);
+    assert!(err.message.contains("long or double"), "unexpected message: {}", err.message);
+}
+
+#[test]
+#[cfg(feature = "serde")]
+fn serde_json_round_trip() {
+    // A class read back from JSON should write the same bytes as the original
+    let class_file = ClassReader::read_class_bytes(NATIVE_CLASS_BYTES).unwrap();
+    let json = serde_json::to_string(&class_file).unwrap();
+    let json_class: stackparam::bytecode::classfile::Classfile = serde_json::from_str(&json).unwrap();
+    let mut out_bytes: Vec<u8> = Vec::new();
+    ClassWriter::new(&mut out_bytes).write_class(&json_class).unwrap();
+    assert_eq!(NATIVE_CLASS_BYTES, out_bytes.as_slice());
+}
@@ -19,7 +19,7 @@ fn java_tests() {
     let output = Command::new(gradle_path)
         .current_dir(javalib_path)
         .arg("--no-daemon")
-        .arg(":agent-tests:cleanTest")
+        .arg(":agent-tests:cleanAgentTests")
         .arg(":agent-tests:test")
         .output()
         .expect("Couldn't start gradle");